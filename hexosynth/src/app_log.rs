@@ -0,0 +1,206 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of entries kept in the log, the oldest are dropped first.
+pub const LOG_CAPACITY: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "debug" => Some(LogLevel::Debug),
+            "info" => Some(LogLevel::Info),
+            "warn" => Some(LogLevel::Warn),
+            "error" => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    /// Increases with every entry, to find the entries added since a query.
+    pub seq: u64,
+    /// Milliseconds since the UNIX epoch.
+    pub time_ms: u64,
+    pub level: LogLevel,
+    /// Where the entry comes from, like `wlambda`, `matrix` or the
+    /// thread name of a `hexodsp::log` message.
+    pub source: String,
+    pub message: String,
+}
+
+impl LogEntry {
+    /// The time of day in UTC, as `HH:MM:SS.mmm`.
+    pub fn time_of_day(&self) -> String {
+        let ms = self.time_ms % (24 * 3600 * 1000);
+        format!(
+            "{:02}:{:02}:{:02}.{:03}",
+            ms / 3_600_000,
+            (ms / 60_000) % 60,
+            (ms / 1000) % 60,
+            ms % 1000
+        )
+    }
+
+    pub fn to_line(&self) -> String {
+        format!(
+            "{} {:5} [{}] {}",
+            self.time_of_day(),
+            self.level.as_str().to_uppercase(),
+            self.source,
+            self.message
+        )
+    }
+}
+
+/// Selects the entries returned by [log_query].
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    pub min_level: LogLevel,
+    /// Only entries from this source.
+    pub source: Option<String>,
+    /// Only entries that contain this text, case insensitive.
+    pub text: Option<String>,
+    /// Only entries with a higher `seq`.
+    pub since: u64,
+    /// At most this many of the newest entries, 0 for no limit.
+    pub limit: usize,
+}
+
+impl LogFilter {
+    pub fn new() -> Self {
+        Self { min_level: LogLevel::Debug, source: None, text: None, since: 0, limit: 0 }
+    }
+
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        if entry.level < self.min_level || entry.seq <= self.since {
+            return false;
+        }
+        if let Some(source) = &self.source {
+            if &entry.source != source {
+                return false;
+            }
+        }
+        if let Some(text) = &self.text {
+            if !entry.message.to_lowercase().contains(&text.to_lowercase()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+static LOG: Mutex<Vec<LogEntry>> = Mutex::new(Vec::new());
+static LOG_SEQ: AtomicU64 = AtomicU64::new(1);
+
+/// Adds an entry to the log of the process. Warnings and errors are
+/// also printed to stdout, for the users of the standalone builds.
+pub fn log_msg(level: LogLevel, source: &str, message: &str) {
+    let time_ms =
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+
+    let entry = LogEntry {
+        seq: LOG_SEQ.fetch_add(1, Ordering::Relaxed),
+        time_ms,
+        level,
+        source: source.to_string(),
+        message: message.to_string(),
+    };
+
+    if level >= LogLevel::Warn {
+        println!("{}", entry.to_line());
+    }
+
+    if let Ok(mut log) = LOG.lock() {
+        if log.len() >= LOG_CAPACITY {
+            let drop = log.len() + 1 - LOG_CAPACITY;
+            log.drain(0..drop);
+        }
+        log.push(entry);
+    }
+}
+
+pub fn log_error(source: &str, message: &str) {
+    log_msg(LogLevel::Error, source, message);
+}
+
+pub fn log_warn(source: &str, message: &str) {
+    log_msg(LogLevel::Warn, source, message);
+}
+
+pub fn log_info(source: &str, message: &str) {
+    log_msg(LogLevel::Info, source, message);
+}
+
+/// Moves the messages of `hexodsp::log` into the log, with the
+/// name of the thread logger as source.
+pub fn collect_hexodsp_log() {
+    hexodsp::log::retrieve_log_messages(|name, s| log_msg(LogLevel::Info, name, s));
+}
+
+/// The entries that match `filter`, the oldest first.
+pub fn log_query(filter: &LogFilter) -> Vec<LogEntry> {
+    let log = match LOG.lock() {
+        Ok(log) => log,
+        Err(_) => return vec![],
+    };
+
+    let mut entries: Vec<LogEntry> = log.iter().filter(|e| filter.matches(e)).cloned().collect();
+    if filter.limit > 0 && entries.len() > filter.limit {
+        entries.drain(0..(entries.len() - filter.limit));
+    }
+    entries
+}
+
+/// The `seq` of the newest entry, 0 if the log is empty.
+pub fn log_latest_seq() -> u64 {
+    LOG.lock().ok().and_then(|log| log.last().map(|e| e.seq)).unwrap_or(0)
+}
+
+pub fn log_clear() {
+    if let Ok(mut log) = LOG.lock() {
+        log.clear();
+    }
+}
+
+/// Formats the entries one per line, for copying them somewhere else.
+pub fn log_entries2text(entries: &[LogEntry]) -> String {
+    let mut text = String::new();
+    for entry in entries.iter() {
+        text += &entry.to_line();
+        text += "\n";
+    }
+    text
+}
+
+pub fn set_clipboard_text(text: &str) -> Result<(), String> {
+    let mut clipboard = arboard::Clipboard::new().map_err(|e| e.to_string())?;
+    clipboard.set_text(text.to_string()).map_err(|e| e.to_string())
+}
+
+pub fn get_clipboard_text() -> Result<String, String> {
+    let mut clipboard = arboard::Clipboard::new().map_err(|e| e.to_string())?;
+    clipboard.get_text().map_err(|e| e.to_string())
+}
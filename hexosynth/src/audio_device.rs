@@ -0,0 +1,133 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use wlambda::VVal;
+
+use std::sync::Arc;
+
+/// A range of stream configurations an audio device supports.
+#[derive(Debug, Clone, Default)]
+pub struct AudioConfigRange {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    /// The minimum and maximum buffer size in frames, if the device reports it.
+    pub buffer_size: Option<(u32, u32)>,
+    pub sample_format: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AudioDevice {
+    pub name: String,
+    pub default_input: bool,
+    pub default_output: bool,
+    pub input_configs: Vec<AudioConfigRange>,
+    pub output_configs: Vec<AudioConfigRange>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AudioHost {
+    pub name: String,
+    pub devices: Vec<AudioDevice>,
+}
+
+/// The audio and MIDI setup a standalone frontend is currently running with.
+#[derive(Debug, Clone, Default)]
+pub struct AudioSetup {
+    pub host: String,
+    pub output_device: String,
+    pub input_device: Option<String>,
+    pub sample_rate: u32,
+    pub buffer_size: Option<u32>,
+    pub sample_format: String,
+    pub midi_input: Option<String>,
+}
+
+/// Everything a standalone frontend knows about the available audio
+/// hosts, devices and MIDI ports.
+#[derive(Debug, Clone, Default)]
+pub struct AudioDeviceInfo {
+    pub current: Option<AudioSetup>,
+    pub hosts: Vec<AudioHost>,
+    pub midi_inputs: Vec<String>,
+}
+
+/// Called by the UI to query the [AudioDeviceInfo] from the frontend.
+pub type AudioDeviceInfoFn = Arc<dyn Fn() -> AudioDeviceInfo + Send + Sync>;
+
+fn opt_str2vv(s: &Option<String>) -> VVal {
+    s.as_ref().map(|s| VVal::new_str(s)).unwrap_or(VVal::None)
+}
+
+fn config_range2vv(cfg: &AudioConfigRange) -> VVal {
+    let v = VVal::map();
+    let _ = v.set_key_str("channels", VVal::Int(cfg.channels as i64));
+    let _ = v.set_key_str("min_sample_rate", VVal::Int(cfg.min_sample_rate as i64));
+    let _ = v.set_key_str("max_sample_rate", VVal::Int(cfg.max_sample_rate as i64));
+    let _ = v.set_key_str(
+        "buffer_size",
+        cfg.buffer_size.map(|(min, max)| VVal::ivec2(min as i64, max as i64)).unwrap_or(VVal::None),
+    );
+    let _ = v.set_key_str("sample_format", VVal::new_str(&cfg.sample_format));
+    v
+}
+
+fn configs2vv(configs: &[AudioConfigRange]) -> VVal {
+    let v = VVal::vec();
+    for cfg in configs.iter() {
+        v.push(config_range2vv(cfg));
+    }
+    v
+}
+
+fn device2vv(dev: &AudioDevice) -> VVal {
+    let v = VVal::map();
+    let _ = v.set_key_str("name", VVal::new_str(&dev.name));
+    let _ = v.set_key_str("default_input", VVal::Bol(dev.default_input));
+    let _ = v.set_key_str("default_output", VVal::Bol(dev.default_output));
+    let _ = v.set_key_str("input_configs", configs2vv(&dev.input_configs));
+    let _ = v.set_key_str("output_configs", configs2vv(&dev.output_configs));
+    v
+}
+
+fn setup2vv(setup: &AudioSetup) -> VVal {
+    let v = VVal::map();
+    let _ = v.set_key_str("host", VVal::new_str(&setup.host));
+    let _ = v.set_key_str("output_device", VVal::new_str(&setup.output_device));
+    let _ = v.set_key_str("input_device", opt_str2vv(&setup.input_device));
+    let _ = v.set_key_str("sample_rate", VVal::Int(setup.sample_rate as i64));
+    let _ = v.set_key_str(
+        "buffer_size",
+        setup.buffer_size.map(|bs| VVal::Int(bs as i64)).unwrap_or(VVal::None),
+    );
+    let _ = v.set_key_str("sample_format", VVal::new_str(&setup.sample_format));
+    let _ = v.set_key_str("midi_input", opt_str2vv(&setup.midi_input));
+    v
+}
+
+pub fn audio_device_info2vv(info: &AudioDeviceInfo) -> VVal {
+    let hosts = VVal::vec();
+    for host in info.hosts.iter() {
+        let devices = VVal::vec();
+        for dev in host.devices.iter() {
+            devices.push(device2vv(dev));
+        }
+
+        hosts.push(VVal::map2("name", VVal::new_str(&host.name), "devices", devices));
+    }
+
+    let midi_inputs = VVal::vec();
+    for name in info.midi_inputs.iter() {
+        midi_inputs.push(VVal::new_str(name));
+    }
+
+    VVal::map3(
+        "current",
+        info.current.as_ref().map(setup2vv).unwrap_or(VVal::None),
+        "hosts",
+        hosts,
+        "midi_inputs",
+        midi_inputs,
+    )
+}
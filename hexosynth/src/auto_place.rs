@@ -0,0 +1,548 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use crate::cluster::{hex_dir_idx_is_output, hex_neighbour, HEX_DIRS};
+
+use hexodsp::{Cell, Matrix, NodeId, SAtom};
+
+use std::collections::{HashMap, HashSet};
+
+/// A node of a [PlaceGraph].
+#[derive(Debug, Clone)]
+pub struct PlaceNode {
+    /// The name the edges use to refer to this node.
+    pub key: String,
+    /// The node type, the instance is allocated when the graph is placed.
+    pub node_id: NodeId,
+    /// `(param name, value)`, the values of `SAtom::Param` are denormalized,
+    /// like the values that are shown on the knobs.
+    pub params: Vec<(String, SAtom)>,
+}
+
+/// A connection from the output `output` of the node `from` to the
+/// input `input` of the node `to`, referring to [PlaceNode::key].
+#[derive(Debug, Clone)]
+pub struct PlaceEdge {
+    pub from: String,
+    pub output: String,
+    pub to: String,
+    pub input: String,
+}
+
+/// An abstract node graph for [auto_place].
+#[derive(Debug, Clone, Default)]
+pub struct PlaceGraph {
+    pub nodes: Vec<PlaceNode>,
+    pub edges: Vec<PlaceEdge>,
+}
+
+/// Where [auto_place] put the graph.
+#[derive(Debug, Clone, Default)]
+pub struct PlaceResult {
+    /// The node instance that was allocated for every [PlaceNode::key].
+    pub nodes: Vec<(String, NodeId)>,
+    /// The positions of the first cell of every node.
+    pub cells: Vec<(usize, usize)>,
+    /// The positions of the additional cells of a node, that expose an
+    /// output or input where it could not be adjacent to its main cell.
+    pub helper_cells: Vec<(usize, usize)>,
+}
+
+/// An edge with resolved node indices and port indices.
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    /// The index in [PlaceGraph::edges], for the error messages.
+    edge: usize,
+    from: usize,
+    out: u8,
+    to: usize,
+    inp: u8,
+}
+
+#[derive(Debug, Clone)]
+struct PlanCell {
+    node: usize,
+    pos: (usize, usize),
+    /// The ports in the order of [HEX_DIRS].
+    ports: [Option<u8>; 6],
+    helper: bool,
+}
+
+impl PlanCell {
+    fn has_output(&self, port: u8) -> bool {
+        (0..6).any(|idx| hex_dir_idx_is_output(idx) && self.ports[idx] == Some(port))
+    }
+}
+
+/// The layout that is computed before the matrix is touched.
+struct Plan<'a> {
+    m: &'a Matrix,
+    origin: (usize, usize),
+    cells: Vec<PlanCell>,
+    by_pos: HashMap<(usize, usize), usize>,
+    node_cells: Vec<Vec<usize>>,
+}
+
+/// The edge on the other side of the edge `idx`, for example T for B.
+fn opposite(idx: usize) -> usize {
+    (idx + 3) % 6
+}
+
+fn dist(a: (usize, usize), b: (usize, usize)) -> usize {
+    a.0.abs_diff(b.0).max(a.1.abs_diff(b.1))
+}
+
+impl<'a> Plan<'a> {
+    fn new(m: &'a Matrix, origin: (usize, usize), node_count: usize) -> Self {
+        Self {
+            m,
+            origin,
+            cells: vec![],
+            by_pos: HashMap::new(),
+            node_cells: vec![vec![]; node_count],
+        }
+    }
+
+    /// Positions right and below of the origin, that are neither used by
+    /// the matrix nor by the plan.
+    fn is_free(&self, pos: (usize, usize)) -> bool {
+        let (w, h) = self.m.size();
+        if pos.0 < self.origin.0 || pos.1 < self.origin.1 || pos.0 >= w || pos.1 >= h {
+            return false;
+        }
+        if self.by_pos.contains_key(&pos) {
+            return false;
+        }
+
+        self.m.get(pos.0, pos.1).map(|c| c.node_id() == NodeId::Nop).unwrap_or(false)
+    }
+
+    fn free_positions(&self) -> Vec<(usize, usize)> {
+        let (w, h) = self.m.size();
+        let mut out = vec![];
+        for y in self.origin.1..h {
+            for x in self.origin.0..w {
+                if self.is_free((x, y)) {
+                    out.push((x, y));
+                }
+            }
+        }
+        out
+    }
+
+    fn add_cell(&mut self, node: usize, pos: (usize, usize), helper: bool) -> usize {
+        let idx = self.cells.len();
+        self.cells.push(PlanCell { node, pos, ports: [None; 6], helper });
+        self.by_pos.insert(pos, idx);
+        self.node_cells[node].push(idx);
+        idx
+    }
+
+    /// The cell of node `from` that is at the `in_edge` of `pos` and
+    /// can carry the output `out` on the opposite edge.
+    fn direct_source(
+        &self,
+        pos: (usize, usize),
+        in_edge: usize,
+        from: usize,
+        out: u8,
+    ) -> Option<usize> {
+        let cell_idx = *self.by_pos.get(&hex_neighbour(pos, in_edge)?)?;
+        let cell = &self.cells[cell_idx];
+
+        if cell.node == from && cell.ports[opposite(in_edge)].is_none() && !cell.has_output(out) {
+            Some(cell_idx)
+        } else {
+            None
+        }
+    }
+
+    /// Matches the `links` to the inputs of a new cell at `pos`, that are
+    /// adjacent to a cell of the source node. Returns `(link, in_edge,
+    /// source cell)` for every link that can be connected this way.
+    fn match_direct(&self, pos: (usize, usize), links: &[Link]) -> Vec<(usize, usize, usize)> {
+        let mut matched: Vec<(usize, usize, usize)> = vec![];
+
+        for (link_idx, link) in links.iter().enumerate() {
+            for in_edge in (0..6).filter(|idx| !hex_dir_idx_is_output(*idx)) {
+                if matched.iter().any(|(_, e, _)| *e == in_edge) {
+                    continue;
+                }
+
+                if let Some(src) = self.direct_source(pos, in_edge, link.from, link.out) {
+                    let src_busy =
+                        matched.iter().any(|(l, _, s)| *s == src && links[*l].out == link.out);
+                    if !src_busy {
+                        matched.push((link_idx, in_edge, src));
+                        break;
+                    }
+                }
+            }
+        }
+
+        matched
+    }
+
+    /// The score of `pos` for the main cell of a node, lower is better.
+    /// Prefers the positions that connect most of the `links` directly,
+    /// have room for the other links and the outputs and are closest to
+    /// the sources or the origin.
+    fn place_score(
+        &self,
+        pos: (usize, usize),
+        links: &[Link],
+        out_count: usize,
+    ) -> (i64, usize, usize, usize, usize, usize) {
+        let direct = self.match_direct(pos, links).len();
+
+        // The links that are not direct need free cells at the inputs
+        // for helper cells, the outputs need free cells for the targets:
+        let free_at = |output: bool| {
+            (0..6)
+                .filter(|idx| hex_dir_idx_is_output(*idx) == output)
+                .filter(|idx| hex_neighbour(pos, *idx).map(|p| self.is_free(p)).unwrap_or(false))
+                .count()
+        };
+        let ins_missing = (links.len() - direct).saturating_sub(free_at(false));
+        let outs_missing = out_count.min(3).saturating_sub(free_at(true));
+
+        let d = if links.is_empty() {
+            dist(pos, self.origin)
+        } else {
+            links
+                .iter()
+                .map(|l| {
+                    self.node_cells[l.from]
+                        .iter()
+                        .map(|c| dist(pos, self.cells[*c].pos))
+                        .min()
+                        .unwrap_or(0)
+                })
+                .sum()
+        };
+
+        (-(direct as i64), ins_missing, outs_missing, d, pos.0, pos.1)
+    }
+
+    /// Places the main cell of `node` at the free position with the best
+    /// [Plan::place_score] and connects the direct `links`.
+    fn place_node(&mut self, node: usize, links: &[Link], out_count: usize) -> Result<(), String> {
+        let pos = self
+            .free_positions()
+            .into_iter()
+            .min_by_key(|pos| self.place_score(*pos, links, out_count))
+            .ok_or_else(|| "No free cell left for the node".to_string())?;
+
+        let matched = self.match_direct(pos, links);
+        let cell_idx = self.add_cell(node, pos, false);
+
+        for (link_idx, in_edge, src) in matched.iter() {
+            let link = links[*link_idx];
+            self.cells[cell_idx].ports[*in_edge] = Some(link.inp);
+            self.cells[*src].ports[opposite(*in_edge)] = Some(link.out);
+        }
+
+        Ok(())
+    }
+
+    fn is_connected(&self, link: &Link) -> bool {
+        self.node_cells[link.to].iter().any(|c| {
+            let cell = &self.cells[*c];
+            (0..6).any(|idx| !hex_dir_idx_is_output(idx) && cell.ports[idx] == Some(link.inp))
+        })
+    }
+
+    /// Connects `link` with helper cells, that are additional cells
+    /// of the source or the destination node.
+    fn route(&mut self, link: Link) -> bool {
+        // A helper cell of the source at a free input edge of the destination:
+        for c in self.node_cells[link.to].clone() {
+            for in_edge in (0..6).filter(|idx| !hex_dir_idx_is_output(*idx)) {
+                if self.cells[c].ports[in_edge].is_some() {
+                    continue;
+                }
+
+                if let Some(pos) = hex_neighbour(self.cells[c].pos, in_edge) {
+                    if self.is_free(pos) {
+                        let helper = self.add_cell(link.from, pos, true);
+                        self.cells[helper].ports[opposite(in_edge)] = Some(link.out);
+                        self.cells[c].ports[in_edge] = Some(link.inp);
+                        return true;
+                    }
+                }
+            }
+        }
+
+        // A helper cell of the destination at a free output edge of the source:
+        for c in self.node_cells[link.from].clone() {
+            if self.cells[c].has_output(link.out) {
+                continue;
+            }
+
+            for out_edge in (0..6).filter(|idx| hex_dir_idx_is_output(*idx)) {
+                if self.cells[c].ports[out_edge].is_some() {
+                    continue;
+                }
+
+                if let Some(pos) = hex_neighbour(self.cells[c].pos, out_edge) {
+                    if self.is_free(pos) {
+                        let helper = self.add_cell(link.to, pos, true);
+                        self.cells[helper].ports[opposite(out_edge)] = Some(link.inp);
+                        self.cells[c].ports[out_edge] = Some(link.out);
+                        return true;
+                    }
+                }
+            }
+        }
+
+        // A pair of helper cells, as close as possible to the destination:
+        let target = self.cells[self.node_cells[link.to][0]].pos;
+        let mut free = self.free_positions();
+        free.sort_by_key(|p| dist(*p, target));
+
+        for pos in free {
+            for out_edge in (0..6).filter(|idx| hex_dir_idx_is_output(*idx)) {
+                if let Some(dst_pos) = hex_neighbour(pos, out_edge) {
+                    if self.is_free(dst_pos) {
+                        let src = self.add_cell(link.from, pos, true);
+                        self.cells[src].ports[out_edge] = Some(link.out);
+                        let dst = self.add_cell(link.to, dst_pos, true);
+                        self.cells[dst].ports[opposite(out_edge)] = Some(link.inp);
+                        return true;
+                    }
+                }
+            }
+        }
+
+        false
+    }
+}
+
+/// Checks the graph and resolves the edges to [Link]s.
+fn resolve_links(graph: &PlaceGraph) -> Result<Vec<Link>, Vec<String>> {
+    let mut errors = vec![];
+
+    let mut keys: HashMap<&str, usize> = HashMap::new();
+    for (idx, node) in graph.nodes.iter().enumerate() {
+        if node.node_id == NodeId::Nop {
+            errors.push(format!("Node '{}' has an unknown node type", node.key));
+        }
+        if keys.insert(&node.key, idx).is_some() {
+            errors.push(format!("Node '{}' is defined twice", node.key));
+        }
+        for (param, _) in node.params.iter() {
+            if node.node_id.inp_param(param).is_none() {
+                errors.push(format!(
+                    "Node '{}' ({}) has no parameter '{}'",
+                    node.key,
+                    node.node_id.name(),
+                    param
+                ));
+            }
+        }
+    }
+
+    let mut links = vec![];
+    let mut inputs: HashSet<(usize, u8)> = HashSet::new();
+
+    for (edge_idx, edge) in graph.edges.iter().enumerate() {
+        let from = keys.get(edge.from.as_str()).copied();
+        let to = keys.get(edge.to.as_str()).copied();
+
+        if from.is_none() {
+            errors.push(format!("Edge from unknown node '{}'", edge.from));
+        }
+        if to.is_none() {
+            errors.push(format!("Edge to unknown node '{}'", edge.to));
+        }
+        let (from, to) = match (from, to) {
+            (Some(from), Some(to)) => (from, to),
+            _ => continue,
+        };
+
+        let from_id = graph.nodes[from].node_id;
+        let to_id = graph.nodes[to].node_id;
+        let out = from_id.out(&edge.output);
+        let inp = to_id.inp(&edge.input);
+
+        if out.is_none() {
+            errors.push(format!(
+                "Node '{}' ({}) has no output '{}'",
+                edge.from,
+                from_id.name(),
+                edge.output
+            ));
+        }
+        if inp.is_none() {
+            errors.push(format!(
+                "Node '{}' ({}) has no input '{}'",
+                edge.to,
+                to_id.name(),
+                edge.input
+            ));
+        }
+        let (out, inp) = match (out, inp) {
+            (Some(out), Some(inp)) => (out, inp),
+            _ => continue,
+        };
+
+        if from == to {
+            errors.push(format!("Node '{}' is connected to itself", edge.from));
+            continue;
+        }
+        if !inputs.insert((to, inp)) {
+            errors.push(format!("Input '{}' of node '{}' is connected twice", edge.input, edge.to));
+            continue;
+        }
+
+        links.push(Link { edge: edge_idx, from, out, to, inp });
+    }
+
+    if errors.is_empty() {
+        Ok(links)
+    } else {
+        Err(errors)
+    }
+}
+
+/// The nodes in an order where every node comes after its sources,
+/// in the order of the graph otherwise.
+fn topological_order(graph: &PlaceGraph, links: &[Link]) -> Result<Vec<usize>, String> {
+    let n = graph.nodes.len();
+    let mut in_count = vec![0; n];
+    for l in links.iter() {
+        in_count[l.to] += 1;
+    }
+
+    let mut order = vec![];
+    let mut done = vec![false; n];
+    while order.len() < n {
+        let next = (0..n).find(|i| !done[*i] && in_count[*i] == 0);
+        let next = match next {
+            Some(next) => next,
+            None => {
+                let cycle: Vec<&str> =
+                    (0..n).filter(|i| !done[*i]).map(|i| graph.nodes[i].key.as_str()).collect();
+                return Err(format!(
+                    "The graph has a cycle between the nodes {}, use FbWr and FbRd for feedback",
+                    cycle.join(", ")
+                ));
+            }
+        };
+
+        done[next] = true;
+        order.push(next);
+        for l in links.iter().filter(|l| l.from == next) {
+            in_count[l.to] -= 1;
+        }
+    }
+
+    Ok(order)
+}
+
+/// Finds a placement of `graph` on the free cells of the matrix, right and
+/// below of `origin`, and places it. Every node gets a new instance.
+///
+/// Connections are made by adjacent cells. Where an output can't be next to
+/// the input it is connected to, additional helper cells of the source or
+/// destination node are placed. Nothing is placed if the graph is invalid,
+/// has a cycle or does not fit; the reasons are returned then.
+///
+/// Syncs the matrix, because the parameters can only be set for existing nodes.
+pub fn auto_place(
+    m: &mut Matrix,
+    graph: &PlaceGraph,
+    origin: (usize, usize),
+) -> Result<PlaceResult, Vec<String>> {
+    let links = resolve_links(graph)?;
+    let order = topological_order(graph, &links).map_err(|e| vec![e])?;
+
+    let mut errors = vec![];
+    let mut plan = Plan::new(m, origin, graph.nodes.len());
+
+    for node in order.iter() {
+        let incoming: Vec<Link> = links.iter().filter(|l| l.to == *node).copied().collect();
+        let out_count = links.iter().filter(|l| l.from == *node).count();
+
+        if let Err(e) = plan.place_node(*node, &incoming, out_count) {
+            errors.push(format!("Can't place node '{}': {}", graph.nodes[*node].key, e));
+            continue;
+        }
+
+        for link in incoming.iter() {
+            if plan.node_cells[link.from].is_empty() || plan.is_connected(link) {
+                continue;
+            }
+
+            if !plan.route(*link) {
+                let edge = &graph.edges[link.edge];
+                errors.push(format!(
+                    "No free cells to connect output '{}' of '{}' to input '{}' of '{}'",
+                    edge.output, edge.from, edge.input, edge.to
+                ));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let cells = plan.cells;
+
+    let mut used: HashSet<NodeId> = HashSet::new();
+    m.for_each(|_x, _y, cell| {
+        used.insert(cell.node_id());
+    });
+
+    let mut result = PlaceResult::default();
+    let mut node_ids = vec![];
+    for node in graph.nodes.iter() {
+        let mut node_id = m.get_unused_instance_node_id(node.node_id);
+        while used.contains(&node_id) {
+            node_id = node_id.to_instance(node_id.instance() + 1);
+        }
+        used.insert(node_id);
+        node_ids.push(node_id);
+        result.nodes.push((node.key.clone(), node_id));
+    }
+
+    for cell in cells.iter() {
+        let mut new_cell = Cell::empty_at(node_ids[cell.node], cell.pos.0 as u8, cell.pos.1 as u8);
+        for (idx, port) in cell.ports.iter().enumerate() {
+            if let Some(port) = port {
+                new_cell.set_io_dir(HEX_DIRS[idx], *port as usize);
+            }
+        }
+        m.place(cell.pos.0, cell.pos.1, new_cell);
+
+        if cell.helper {
+            result.helper_cells.push(cell.pos);
+        } else {
+            result.cells.push(cell.pos);
+        }
+    }
+
+    if let Err(e) = m.sync() {
+        for cell in cells.iter() {
+            m.place(cell.pos.0, cell.pos.1, Cell::empty(NodeId::Nop));
+        }
+        let _ = m.sync();
+        return Err(vec![format!("Can't place the graph: {:?}", e)]);
+    }
+
+    for (node, node_id) in graph.nodes.iter().zip(node_ids.iter()) {
+        for (param, atom) in node.params.iter() {
+            if let Some(param_id) = node_id.inp_param(param) {
+                let atom = match atom {
+                    SAtom::Param(v) => SAtom::param(param_id.norm(*v)),
+                    atom => atom.clone(),
+                };
+                m.set_param(param_id, atom);
+            }
+        }
+    }
+
+    Ok(result)
+}
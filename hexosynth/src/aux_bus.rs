@@ -0,0 +1,158 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexodsp::dsp::MAX_BLOCK_SIZE;
+use hexodsp::{DynNode1x1Context, DynamicNode1x1};
+
+use crate::dyn_nodes::DynNodeRegistry;
+
+use std::sync::{Arc, Mutex};
+
+/// The name of the [AuxOutNode] prototype in the [DynNodeRegistry].
+pub const AUX_OUT_NODE_NAME: &str = "aux_out";
+/// The name of the [SidechainInNode] prototype in the [DynNodeRegistry].
+pub const SIDECHAIN_IN_NODE_NAME: &str = "sidechain_in";
+
+/// Number of the stereo aux outputs.
+pub const AUX_OUTPUTS: usize = 4;
+/// The channels of all aux outputs, Aux 1 L, Aux 1 R, Aux 2 L, ...
+pub const AUX_OUT_CHANNELS: usize = 2 * AUX_OUTPUTS;
+/// The channels of the stereo sidechain input.
+pub const SIDECHAIN_CHANNELS: usize = 2;
+
+/// Maps `alpha` (0.0 to 1.0) to one of `channels` channels.
+fn norm2channel(v: f32, channels: usize) -> usize {
+    (v.clamp(0.0, 1.0) * (channels - 1) as f32).round() as usize
+}
+
+/// The buffers between the aux bus nodes in the DSP graph and the plugin,
+/// which has more buses than the two channels of the `Out` and `Inp` nodes.
+///
+/// The plugin sets the sidechain and clears the aux outputs before each
+/// `NodeExecutor::process` call and reads the aux outputs after it.
+/// Both ends are only used from the audio thread, the locks never wait.
+pub struct AuxBuses {
+    outputs: Mutex<[[f32; MAX_BLOCK_SIZE]; AUX_OUT_CHANNELS]>,
+    sidechain: Mutex<[[f32; MAX_BLOCK_SIZE]; SIDECHAIN_CHANNELS]>,
+}
+
+impl AuxBuses {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            outputs: Mutex::new([[0.0; MAX_BLOCK_SIZE]; AUX_OUT_CHANNELS]),
+            sidechain: Mutex::new([[0.0; MAX_BLOCK_SIZE]; SIDECHAIN_CHANNELS]),
+        })
+    }
+
+    /// Copies the sidechain `input` for the next block and clears the
+    /// aux outputs. Missing channels are silent.
+    pub fn start_block(&self, input: &[&[f32]]) {
+        if let Ok(mut sidechain) = self.sidechain.try_lock() {
+            for (ch, buf) in sidechain.iter_mut().enumerate() {
+                match input.get(ch) {
+                    Some(src) => {
+                        let len = src.len().min(MAX_BLOCK_SIZE);
+                        buf[0..len].copy_from_slice(&src[0..len]);
+                    }
+                    None => buf.fill(0.0),
+                }
+            }
+        }
+
+        if let Ok(mut outputs) = self.outputs.try_lock() {
+            for buf in outputs.iter_mut() {
+                buf.fill(0.0);
+            }
+        }
+    }
+
+    /// Copies the aux outputs of the block into `output`, channel by channel.
+    pub fn read_outputs(&self, output: &mut [&mut [f32]]) {
+        if let Ok(outputs) = self.outputs.try_lock() {
+            for (out, buf) in output.iter_mut().zip(outputs.iter()) {
+                let len = out.len().min(MAX_BLOCK_SIZE);
+                out[0..len].copy_from_slice(&buf[0..len]);
+            }
+        }
+    }
+}
+
+/// Adds the input to an aux output channel of the plugin. `alpha` selects
+/// the channel, 0.0 to 1.0 for Aux 1 L, Aux 1 R, Aux 2 L up to Aux 4 R.
+/// The input is passed through to the output.
+pub struct AuxOutNode {
+    buses: Arc<AuxBuses>,
+}
+
+impl AuxOutNode {
+    pub fn new(buses: Arc<AuxBuses>) -> Self {
+        Self { buses }
+    }
+}
+
+impl DynamicNode1x1 for AuxOutNode {
+    fn set_sample_rate(&mut self, _srate: f32) {}
+    fn reset(&mut self) {}
+
+    fn process(&mut self, input: &[f32], output: &mut [f32], ctx: &DynNode1x1Context) {
+        let alpha = ctx.alpha_slice();
+
+        if let Ok(mut outputs) = self.buses.outputs.try_lock() {
+            for (i, inp) in input.iter().enumerate().take(MAX_BLOCK_SIZE) {
+                outputs[norm2channel(alpha[i], AUX_OUT_CHANNELS)][i] += *inp;
+            }
+        }
+
+        for (out, inp) in output.iter_mut().zip(input.iter()) {
+            *out = *inp;
+        }
+    }
+}
+
+/// Outputs a channel of the sidechain input of the plugin, `alpha` below
+/// 0.5 selects the left and above the right channel. The input is ignored.
+pub struct SidechainInNode {
+    buses: Arc<AuxBuses>,
+}
+
+impl SidechainInNode {
+    pub fn new(buses: Arc<AuxBuses>) -> Self {
+        Self { buses }
+    }
+}
+
+impl DynamicNode1x1 for SidechainInNode {
+    fn set_sample_rate(&mut self, _srate: f32) {}
+    fn reset(&mut self) {}
+
+    fn process(&mut self, _input: &[f32], output: &mut [f32], ctx: &DynNode1x1Context) {
+        let alpha = ctx.alpha_slice();
+
+        if let Ok(sidechain) = self.buses.sidechain.try_lock() {
+            for (i, out) in output.iter_mut().enumerate().take(MAX_BLOCK_SIZE) {
+                *out = sidechain[norm2channel(alpha[i], SIDECHAIN_CHANNELS)][i];
+            }
+        } else {
+            output.fill(0.0);
+        }
+    }
+}
+
+/// Registers the [AuxOutNode] and [SidechainInNode] as prototypes
+/// [AUX_OUT_NODE_NAME] and [SIDECHAIN_IN_NODE_NAME].
+pub fn register_aux_bus_nodes(registry: &DynNodeRegistry, buses: Arc<AuxBuses>) {
+    let out_buses = buses.clone();
+    registry.register(
+        AUX_OUT_NODE_NAME,
+        "Adds the input to an aux output of the plugin. `alpha` selects the channel, \
+         Aux 1 L to Aux 4 R.",
+        move || Box::new(AuxOutNode::new(out_buses.clone())),
+    );
+    registry.register(
+        SIDECHAIN_IN_NODE_NAME,
+        "Outputs the sidechain input of the plugin. `alpha` selects the left or \
+         right channel.",
+        move || Box::new(SidechainInNode::new(buses.clone())),
+    );
+}
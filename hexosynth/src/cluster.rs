@@ -2,9 +2,407 @@
 // This file is a part of HexoSynth. Released under GPL-3.0-or-later.
 // See README.md and COPYING for details.
 
+use crate::matrix_history::for_node_params;
+
 use hexodsp::matrix::MatrixError;
-use hexodsp::{Cell, CellDir, Matrix, NodeId};
-use std::collections::HashSet;
+use hexodsp::matrix_repr::MatrixRepr;
+use hexodsp::{Cell, CellDir, Matrix, NodeId, ParamId, SAtom};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use std::collections::{HashMap, HashSet};
+
+/// The version of the text form of a cluster, see [Cluster::to_text].
+pub const CLUSTER_TEXT_VERSION: i64 = 1;
+
+/// The text form of a cluster. The tuples are serialized as JSON arrays,
+/// which keeps the text short enough to share it in a chat.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct ClusterText {
+    hexosynth_cluster: i64,
+    /// `(node, instance, x, y, ports)`, the positions are relative to the
+    /// top left of the cluster, with an even x to keep the hex grid layout.
+    cells: Vec<(String, usize, usize, usize, [Option<u8>; 6])>,
+    /// `(node, instance, param, atom, mod_amt)`
+    params: Vec<(String, usize, String, Value, Option<f32>)>,
+    /// `(TSeq instance, pattern)` in the format of the patch files.
+    patterns: Vec<(usize, Value)>,
+    /// `(Code instance, block function)` in the format of the patch files.
+    block_funs: Vec<(usize, Value)>,
+}
+
+fn satom2json(atom: &SAtom) -> Value {
+    match atom {
+        SAtom::Param(v) => json!(["p", v]),
+        SAtom::Setting(i) => json!(["i", i]),
+        SAtom::Str(s) => json!(["s", s]),
+        SAtom::MicroSample(ms) => json!(["m", ms]),
+        SAtom::AudioSample((name, _)) => json!(["a", name]),
+    }
+}
+
+fn json2satom(v: &Value) -> Option<SAtom> {
+    let v = v.as_array()?;
+    let arg = v.get(1)?;
+
+    match v.get(0)?.as_str()? {
+        "p" => Some(SAtom::param(arg.as_f64()? as f32)),
+        "i" => Some(SAtom::setting(arg.as_i64()?)),
+        "s" => Some(SAtom::str(arg.as_str()?)),
+        "m" => Some(SAtom::MicroSample(
+            arg.as_array()?.iter().map(|v| v.as_f64().unwrap_or(0.0) as f32).collect(),
+        )),
+        "a" => Some(SAtom::AudioSample((arg.as_str()?.to_string(), None))),
+        _ => None,
+    }
+}
+
+fn node_id_from_name(name: &str, instance: usize) -> Option<NodeId> {
+    let node_id = NodeId::from_str(name);
+    if node_id == NodeId::Nop && name != "nop" {
+        return None;
+    }
+
+    Some(node_id.to_instance(instance))
+}
+
+fn param_by_name(node_id: NodeId, name: &str) -> Option<ParamId> {
+    let mut found = None;
+    for_node_params(node_id, |param_id| {
+        if found.is_none() && param_id.name() == name {
+            found = Some(param_id);
+        }
+    });
+    found
+}
+
+fn uses_pattern(node_id: NodeId) -> bool {
+    node_id.to_instance(0) == NodeId::TSeq(0)
+}
+
+fn uses_block_fun(node_id: NodeId) -> bool {
+    node_id.to_instance(0) == NodeId::Code(0)
+}
+
+/// Finds the position closest to `pos` where all `cells` fit on empty
+/// cells of the matrix. The x of the position is even, to keep the
+/// connections of the hex grid intact.
+fn find_free_origin(
+    m: &Matrix,
+    cells: &[(NodeId, (usize, usize), [Option<u8>; 6])],
+    pos: (usize, usize),
+) -> Option<(usize, usize)> {
+    let (w, h) = m.size();
+    let dist =
+        |x: usize, y: usize| (x as i64 - pos.0 as i64).abs().max((y as i64 - pos.1 as i64).abs());
+
+    let mut candidates = vec![];
+    for y in 0..h {
+        for x in (0..w).step_by(2) {
+            candidates.push((x, y));
+        }
+    }
+    candidates.sort_by_key(|(x, y)| dist(*x, *y));
+
+    candidates.into_iter().find(|(ox, oy)| {
+        cells.iter().all(|(_, (x, y), _)| {
+            let (x, y) = (ox + x, oy + y);
+            x < w && y < h && m.get(x, y).map(|c| c.node_id() == NodeId::Nop).unwrap_or(false)
+        })
+    })
+}
+
+/// The parsed text form of a cluster with the node ids of its cells.
+struct ParsedCluster {
+    data: ClusterText,
+    cells: Vec<(NodeId, (usize, usize), [Option<u8>; 6])>,
+}
+
+fn parse_cluster_text(text: &str) -> Result<ParsedCluster, String> {
+    let data: ClusterText =
+        serde_json::from_str(text.trim()).map_err(|_| "Not a HexoSynth cluster".to_string())?;
+    if data.hexosynth_cluster <= 0 {
+        return Err("Not a HexoSynth cluster".to_string());
+    }
+    if data.hexosynth_cluster > CLUSTER_TEXT_VERSION {
+        return Err(format!(
+            "Cluster version {} is newer than the supported version {}",
+            data.hexosynth_cluster, CLUSTER_TEXT_VERSION
+        ));
+    }
+
+    let mut cells = vec![];
+    for (name, instance, x, y, ports) in data.cells.iter() {
+        let node_id = node_id_from_name(name, *instance)
+            .ok_or_else(|| format!("Unknown node '{}' in cluster", name))?;
+        cells.push((node_id, (*x, *y), *ports));
+    }
+    if cells.is_empty() {
+        return Err("The cluster is empty".to_string());
+    }
+
+    Ok(ParsedCluster { data, cells })
+}
+
+/// Places a cluster from the text form of [Cluster::to_text] into the matrix,
+/// at the free position closest to `pos`. All nodes get new instances, that
+/// are allocated with `get_unused_instance_node_id`.
+///
+/// Syncs the matrix, because the parameters can only be set for existing
+/// nodes. Returns the positions of the placed cells.
+pub fn paste_cluster_text(
+    m: &mut Matrix,
+    text: &str,
+    pos: (usize, usize),
+) -> Result<Vec<(usize, usize)>, String> {
+    let cluster = parse_cluster_text(text)?;
+
+    let origin = find_free_origin(m, &cluster.cells, pos)
+        .ok_or_else(|| "Not enough free space in the matrix for the cluster".to_string())?;
+
+    place_parsed_cluster(m, cluster, origin)
+}
+
+/// Places a cluster from the text form of [Cluster::to_text] with its top
+/// left at `pos`, like [paste_cluster_text]. An odd x of `pos` is rounded
+/// down, to keep the connections of the hex grid intact.
+///
+/// Instead of looking for free space, this fails with the first cell of
+/// the target area that is occupied or outside of the matrix.
+pub fn insert_cluster_text(
+    m: &mut Matrix,
+    text: &str,
+    pos: (usize, usize),
+) -> Result<Vec<(usize, usize)>, String> {
+    let cluster = parse_cluster_text(text)?;
+
+    let (ox, oy) = (pos.0 & !1, pos.1);
+    let (w, h) = m.size();
+
+    let mut poses: Vec<(usize, usize)> =
+        cluster.cells.iter().map(|(_, (x, y), _)| (ox + x, oy + y)).collect();
+    poses.sort();
+
+    for (x, y) in poses {
+        if x >= w || y >= h {
+            return Err(format!(
+                "The cluster does not fit into the matrix at ({}, {}), \
+                 the cell ({}, {}) is outside of it",
+                ox, oy, x, y
+            ));
+        }
+
+        if let Some(cell) = m.get(x, y) {
+            if cell.node_id() != NodeId::Nop {
+                return Err(format!(
+                    "The target area at ({}, {}) is blocked by {} {} at ({}, {})",
+                    ox,
+                    oy,
+                    cell.node_id().name(),
+                    cell.node_id().instance(),
+                    x,
+                    y
+                ));
+            }
+        }
+    }
+
+    place_parsed_cluster(m, cluster, (ox, oy))
+}
+
+fn place_parsed_cluster(
+    m: &mut Matrix,
+    cluster: ParsedCluster,
+    origin: (usize, usize),
+) -> Result<Vec<(usize, usize)>, String> {
+    let ParsedCluster { data, cells } = cluster;
+    let (ox, oy) = origin;
+
+    let mut used: HashSet<NodeId> = HashSet::new();
+    m.for_each(|_x, _y, cell| {
+        used.insert(cell.node_id());
+    });
+
+    let mut id_map: HashMap<NodeId, NodeId> = HashMap::new();
+    let mut placed = vec![];
+
+    for (node_id, (x, y), ports) in cells.iter() {
+        let new_id = match id_map.get(node_id) {
+            Some(new_id) => *new_id,
+            None => {
+                let mut new_id = m.get_unused_instance_node_id(*node_id);
+                while used.contains(&new_id) {
+                    new_id = new_id.to_instance(new_id.instance() + 1);
+                }
+                used.insert(new_id);
+                id_map.insert(*node_id, new_id);
+                new_id
+            }
+        };
+
+        let (x, y) = (ox + x, oy + y);
+        let mut cell = Cell::empty_at(new_id, x as u8, y as u8);
+        for (edge, idx) in ports.iter().enumerate() {
+            if let Some(idx) = idx {
+                cell.set_io_dir(CellDir::from(edge as u8), *idx as usize);
+            }
+        }
+        m.place(x, y, cell);
+        placed.push((x, y));
+    }
+
+    m.sync().map_err(|e| format!("Can't place the cluster: {:?}", e))?;
+
+    for (name, instance, param, atom, mod_amt) in data.params.iter() {
+        let new_id = match node_id_from_name(name, *instance).and_then(|id| id_map.get(&id)) {
+            Some(new_id) => *new_id,
+            None => continue,
+        };
+
+        if let Some(param_id) = param_by_name(new_id, param) {
+            if let Some(atom) = json2satom(atom) {
+                m.set_param(param_id, atom);
+            }
+            let _ = m.set_param_modamt(param_id, *mod_amt);
+        }
+    }
+
+    // The pattern and code data is restored through the patch format,
+    // based on the current patch so that all required fields are there:
+    if !data.patterns.is_empty() || !data.block_funs.is_empty() {
+        let mut patch: Value = serde_json::from_str(&m.to_repr().serialize())
+            .map_err(|e| format!("Can't serialize patch: {}", e))?;
+
+        let remap = |list: &[(usize, Value)], node_id: NodeId| {
+            let mut out = vec![];
+            for (instance, v) in list.iter() {
+                if let Some(new_id) = id_map.get(&node_id.to_instance(*instance)) {
+                    let idx = new_id.instance();
+                    if out.len() <= idx {
+                        out.resize(idx + 1, Value::Null);
+                    }
+                    out[idx] = v.clone();
+                }
+            }
+            Value::Array(out)
+        };
+        patch["patterns"] = remap(&data.patterns, NodeId::TSeq(0));
+        patch["block_funs"] = remap(&data.block_funs, NodeId::Code(0));
+
+        let repr = MatrixRepr::deserialize(&patch.to_string())
+            .map_err(|e| format!("Can't deserialize the cluster data: {:?}", e))?;
+
+        for (idx, pat) in repr.patterns.iter().enumerate() {
+            if let (Some(pat), Some(pdata)) = (pat, m.get_pattern_data(idx)) {
+                if let Ok(mut pdata) = pdata.lock() {
+                    pdata.from_repr(pat);
+                }
+                m.check_pattern_data(idx);
+            }
+        }
+
+        for (idx, bf) in repr.block_funs.iter().enumerate() {
+            if let (Some(bf), Some(fun)) = (bf, m.get_block_function(idx)) {
+                if let Ok(mut fun) = fun.lock() {
+                    fun.load_snapshot(bf);
+                }
+                let _ = m.check_block_function(idx);
+            }
+        }
+    }
+
+    Ok(placed)
+}
+
+/// The edges of a cell in clockwise order, starting at the top right.
+pub(crate) const HEX_DIRS: [CellDir; 6] =
+    [CellDir::TR, CellDir::BR, CellDir::B, CellDir::BL, CellDir::TL, CellDir::T];
+
+/// The offset to the neighbour cell at the edges of [HEX_DIRS], in cube
+/// coordinates `(q, r, s)`.
+const HEX_DIR_CUBE: [(i64, i64, i64); 6] =
+    [(1, -1, 0), (1, 0, -1), (0, 1, -1), (-1, 1, 0), (-1, 0, 1), (0, -1, 1)];
+
+fn hex_dir_idx(dir: CellDir) -> Option<usize> {
+    match dir {
+        CellDir::TR => Some(0),
+        CellDir::BR => Some(1),
+        CellDir::B => Some(2),
+        CellDir::BL => Some(3),
+        CellDir::TL => Some(4),
+        CellDir::T => Some(5),
+        _ => None,
+    }
+}
+
+/// Edges TR, BR and B are outputs, T, TL and BL are inputs.
+pub(crate) fn hex_dir_idx_is_output(idx: usize) -> bool {
+    idx < 3
+}
+
+/// Converts a matrix position to cube coordinates. The columns with
+/// an odd x are shifted down by half a cell.
+fn pos2cube(pos: (usize, usize)) -> (i64, i64, i64) {
+    let (x, y) = (pos.0 as i64, pos.1 as i64);
+    let r = y - (x - (x & 1)) / 2;
+    (x, r, -x - r)
+}
+
+fn cube2pos(c: (i64, i64, i64)) -> Option<(usize, usize)> {
+    let (x, y) = (c.0, c.1 + (c.0 - (c.0 & 1)) / 2);
+    if x < 0 || y < 0 || x > 255 || y > 255 {
+        return None;
+    }
+    Some((x as usize, y as usize))
+}
+
+/// The position of the neighbour cell at the edge `HEX_DIRS[idx]` of `pos`.
+pub(crate) fn hex_neighbour(pos: (usize, usize), idx: usize) -> Option<(usize, usize)> {
+    let c = pos2cube(pos);
+    let d = HEX_DIR_CUBE[idx % 6];
+    cube2pos((c.0 + d.0, c.1 + d.1, c.2 + d.2))
+}
+
+/// A rotation or mirroring of the hex grid, see [Cluster::rotate]
+/// and [Cluster::mirror].
+#[derive(Debug, Clone, Copy)]
+enum HexTransform {
+    /// Clockwise 60° steps.
+    Rotate(usize),
+    /// Mirroring about the axis through the edge with this index
+    /// of [HEX_DIRS] and the opposite edge.
+    Mirror(usize),
+}
+
+impl HexTransform {
+    fn dir_idx(&self, idx: usize) -> usize {
+        match self {
+            HexTransform::Rotate(steps) => (idx + steps) % 6,
+            HexTransform::Mirror(axis) => (2 * axis + 6 - idx) % 6,
+        }
+    }
+
+    fn cube(&self, c: (i64, i64, i64)) -> (i64, i64, i64) {
+        match self {
+            HexTransform::Rotate(steps) => {
+                let mut c = c;
+                for _ in 0..*steps {
+                    c = (-c.1, -c.2, -c.0);
+                }
+                c
+            }
+            HexTransform::Mirror(axis) => {
+                // Rotate the axis onto T, mirror about the T-B axis and rotate back:
+                let to_t = (5 + 6 - axis) % 6;
+                let c = HexTransform::Rotate(to_t).cube(c);
+                let c = (-c.0, -c.2, -c.1);
+                HexTransform::Rotate((6 - to_t) % 6).cube(c)
+            }
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Cluster {
@@ -30,6 +428,10 @@ impl Cluster {
         }
     }
 
+    pub fn cells(&self) -> &[Cell] {
+        &self.cells[..]
+    }
+
     pub fn ignore_pos(&mut self, pos: (usize, usize)) {
         self.ignore_pos.insert(pos);
     }
@@ -86,6 +488,180 @@ impl Cluster {
         m.place_multiple(&self.cells)
     }
 
+    /// Serializes the cells of the cluster with their ports, parameters,
+    /// modulation amounts and the pattern and code data of `TSeq` and `Code`
+    /// nodes into a compact text. See [paste_cluster_text].
+    pub fn to_text(&self, m: &mut Matrix) -> Result<String, String> {
+        let base_x = self.cells.iter().map(|c| c.pos().0).min().unwrap_or(0) & !1;
+        let base_y = self.cells.iter().map(|c| c.pos().1).min().unwrap_or(0);
+
+        let mut data =
+            ClusterText { hexosynth_cluster: CLUSTER_TEXT_VERSION, ..ClusterText::default() };
+
+        let mut cells = self.cells.clone();
+        cells.sort_by_key(|c| (c.pos().1, c.pos().0));
+
+        let mut node_ids = vec![];
+        for cell in cells.iter() {
+            let node_id = cell.node_id();
+            let mut ports = [None; 6];
+            for (edge, port) in ports.iter_mut().enumerate() {
+                *port = cell.local_port_idx(CellDir::from(edge as u8));
+            }
+
+            data.cells.push((
+                node_id.name().to_string(),
+                node_id.instance(),
+                cell.pos().0 - base_x,
+                cell.pos().1 - base_y,
+                ports,
+            ));
+
+            if !node_ids.contains(&node_id) {
+                node_ids.push(node_id);
+            }
+        }
+
+        let needs_repr = node_ids.iter().any(|id| uses_pattern(*id) || uses_block_fun(*id));
+        let patch: Value = if needs_repr {
+            serde_json::from_str(&m.to_repr().serialize())
+                .map_err(|e| format!("Can't serialize patch: {}", e))?
+        } else {
+            Value::Null
+        };
+
+        for node_id in node_ids.iter() {
+            for_node_params(*node_id, |param_id| {
+                if let Some(atom) = m.get_param(&param_id) {
+                    data.params.push((
+                        node_id.name().to_string(),
+                        node_id.instance(),
+                        param_id.name().to_string(),
+                        satom2json(&atom),
+                        m.get_param_modamt(&param_id),
+                    ));
+                }
+            });
+
+            let instance = node_id.instance();
+            if uses_pattern(*node_id) && !patch["patterns"][instance].is_null() {
+                data.patterns.push((instance, patch["patterns"][instance].clone()));
+            }
+            if uses_block_fun(*node_id) && !patch["block_funs"][instance].is_null() {
+                data.block_funs.push((instance, patch["block_funs"][instance].clone()));
+            }
+        }
+
+        serde_json::to_string(&data).map_err(|e| format!("Can't serialize cluster: {}", e))
+    }
+
+    /// The cell the cluster is rotated and mirrored around by default,
+    /// the one closest to the center of the cluster.
+    fn center_pos(&self) -> Option<(usize, usize)> {
+        let n = self.cells.len() as i64;
+        if n == 0 {
+            return None;
+        }
+
+        let cubes: Vec<(i64, i64, i64)> = self.cells.iter().map(|c| pos2cube(c.pos())).collect();
+        let sum = cubes.iter().fold((0, 0, 0), |a, c| (a.0 + c.0, a.1 + c.1, a.2 + c.2));
+        let dist = |c: &(i64, i64, i64)| {
+            (c.0 * n - sum.0).abs() + (c.1 * n - sum.1).abs() + (c.2 * n - sum.2).abs()
+        };
+
+        self.cells.iter().zip(cubes.iter()).min_by_key(|(_, c)| dist(c)).map(|(cell, _)| cell.pos())
+    }
+
+    fn transform(&mut self, t: HexTransform, pivot: Option<(usize, usize)>) -> Result<(), String> {
+        let pivot = match pivot.or_else(|| self.center_pos()) {
+            Some(pivot) => pos2cube(pivot),
+            None => return Ok(()),
+        };
+
+        let mut cells = vec![];
+        for cell in self.cells.iter() {
+            let cube = pos2cube(cell.pos());
+            let rel = (cube.0 - pivot.0, cube.1 - pivot.1, cube.2 - pivot.2);
+            let rel = t.cube(rel);
+            let (x, y) = cube2pos((rel.0 + pivot.0, rel.1 + pivot.1, rel.2 + pivot.2))
+                .ok_or_else(|| "The cluster does not fit into the matrix".to_string())?;
+
+            let mut new_cell = Cell::empty_at(cell.node_id(), x as u8, y as u8);
+            for (idx, dir) in HEX_DIRS.iter().enumerate() {
+                let port = match cell.local_port_idx(*dir) {
+                    Some(port) => port,
+                    None => continue,
+                };
+
+                let new_idx = t.dir_idx(idx);
+                if hex_dir_idx_is_output(idx) == hex_dir_idx_is_output(new_idx) {
+                    new_cell.set_io_dir(HEX_DIRS[new_idx], port as usize);
+                    continue;
+                }
+
+                // Inputs can only be on the T, TL and BL edges, so ports that end
+                // up on the other side are dropped, unless they are connected:
+                let neighbour = hex_neighbour(cell.pos(), idx);
+                if neighbour.map(|pos| self.poses.contains(&pos)).unwrap_or(false) {
+                    return Err(format!(
+                        "The connection at the {:?} edge of {} {} at ({}, {}) would end up \
+                         on the {:?} edge, which is on the wrong side for an {}",
+                        dir,
+                        cell.node_id().name(),
+                        cell.node_id().instance(),
+                        cell.pos().0,
+                        cell.pos().1,
+                        HEX_DIRS[new_idx],
+                        if hex_dir_idx_is_output(idx) { "output" } else { "input" }
+                    ));
+                }
+            }
+
+            cells.push(new_cell);
+        }
+
+        self.poses.clear();
+        for c in &cells {
+            self.poses.insert(c.pos());
+        }
+        self.cells = cells;
+
+        Ok(())
+    }
+
+    /// Rotates the cluster clockwise by `steps` of 60° around `pivot` or
+    /// the cell closest to its center. The edges of the ports are rotated
+    /// along, negative `steps` rotate counter clockwise.
+    ///
+    /// As inputs are always on the T, TL and BL edges, a connection that
+    /// would end up on the wrong side is an error. Unconnected ports on
+    /// the wrong side are dropped.
+    pub fn rotate(&mut self, steps: i64, pivot: Option<(usize, usize)>) -> Result<(), String> {
+        self.transform(HexTransform::Rotate(steps.rem_euclid(6) as usize), pivot)
+    }
+
+    /// Mirrors the cluster about the hex axis through the `axis` edge and
+    /// the opposite edge of `pivot` or the cell closest to its center.
+    /// Connections that end up on the wrong side are an error, like for
+    /// [Cluster::rotate].
+    pub fn mirror(&mut self, axis: CellDir, pivot: Option<(usize, usize)>) -> Result<(), String> {
+        let axis = hex_dir_idx(axis).ok_or_else(|| format!("Not a hex axis: {:?}", axis))?;
+        self.transform(HexTransform::Mirror(axis), pivot)
+    }
+
+    /// Returns the first position of the cluster that is outside of the
+    /// matrix or occupied by a cell that is not part of the cluster.
+    /// The cluster cells have to be removed from the matrix before.
+    pub fn find_collision(&self, m: &Matrix) -> Option<(usize, usize)> {
+        let (w, h) = m.size();
+        let mut poses: Vec<(usize, usize)> = self.poses.iter().copied().collect();
+        poses.sort();
+
+        poses.into_iter().find(|(x, y)| {
+            *x >= w || *y >= h || m.get(*x, *y).map(|c| c.node_id() != NodeId::Nop).unwrap_or(true)
+        })
+    }
+
     pub fn move_cluster_cells_dir_path(&mut self, path: &[CellDir]) -> Result<(), MatrixError> {
         let mut cells = self.cells.clone();
 
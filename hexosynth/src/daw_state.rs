@@ -0,0 +1,165 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use crate::app_log::{log_error, log_info};
+use crate::patch_migration::{
+    load_patch_migrated_from_mem, load_patch_repaired_from_mem, PatchLoadReport,
+};
+use hexodsp::Matrix;
+
+use std::sync::{Arc, Mutex};
+
+/// The state from the DAW project that could not be loaded, or only
+/// without some of its parts.
+#[derive(Debug, Clone)]
+pub struct FailedDawState {
+    /// The state exactly as the DAW passed it in.
+    pub data: String,
+    /// Why loading the state failed.
+    pub error: String,
+}
+
+/// The patch state the plugin exchanges with the DAW.
+///
+/// If the state of a project can't be loaded completely, it is kept and
+/// written back to the DAW unchanged, instead of the patch that is currently
+/// loaded.
+/// This way saving the project does not destroy the patch before the user
+/// decided to [DawState::repair] or to [DawState::discard] it.
+pub struct DawState {
+    failed: Mutex<Option<FailedDawState>>,
+    /// The result of the repair queued by the GUI, see [DawState::take_repair_result].
+    repair_result: Mutex<Option<Result<PatchLoadReport, String>>>,
+}
+
+impl DawState {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self { failed: Mutex::new(None), repair_result: Mutex::new(None) })
+    }
+
+    /// Loads the state from the DAW into the matrix. If that fails,
+    /// the state is kept and the error is logged. The state is also kept
+    /// if parts of it were ignored, because saving the loaded patch would
+    /// drop them from the project.
+    pub fn load(&self, matrix: &mut Matrix, data: String) -> Result<PatchLoadReport, String> {
+        let res = load_patch_migrated_from_mem(matrix, data.as_bytes());
+
+        let mut failed = self.failed.lock().expect("DawState is ok");
+        match res {
+            Ok(report) if !report.ignored.is_empty() => {
+                let error = format!(
+                    "The patch was loaded without the parts unknown to this version: {}",
+                    report.ignored.join(", ")
+                );
+                log_error(
+                    "daw_state",
+                    &format!(
+                        "{}. The patch of the DAW project is kept unchanged \
+                         until it is repaired or discarded.",
+                        error
+                    ),
+                );
+                *failed = Some(FailedDawState { data, error });
+                Ok(report)
+            }
+            Ok(report) => {
+                *failed = None;
+                Ok(report)
+            }
+            Err(error) => {
+                log_error(
+                    "daw_state",
+                    &format!(
+                        "Can't load the patch from the DAW project, \
+                         it is kept unchanged until it is repaired or discarded: {}",
+                        error
+                    ),
+                );
+                *failed = Some(FailedDawState { data, error: error.clone() });
+                Err(error)
+            }
+        }
+    }
+
+    /// The state to store in the DAW project. That is the kept state if
+    /// loading it failed, and the current patch of the matrix otherwise.
+    pub fn save(&self, matrix: &mut Matrix) -> String {
+        if let Some(failed) = &*self.failed.lock().expect("DawState is ok") {
+            return failed.data.clone();
+        }
+
+        let mut repr = matrix.to_repr();
+        repr.serialize()
+    }
+
+    /// The kept state, if loading it failed.
+    pub fn failed(&self) -> Option<FailedDawState> {
+        self.failed.lock().expect("DawState is ok").clone()
+    }
+
+    /// Tries to load the kept state again with [load_patch_repaired_from_mem].
+    /// On success the state is not kept anymore and the repaired patch is
+    /// saved to the DAW project from now on.
+    pub fn repair(&self, matrix: &mut Matrix) -> Result<PatchLoadReport, String> {
+        let mut failed = self.failed.lock().expect("DawState is ok");
+
+        let data = match &*failed {
+            Some(failed) => failed.data.clone(),
+            None => return Err("There is no DAW state to repair".to_string()),
+        };
+
+        match load_patch_repaired_from_mem(matrix, data.as_bytes()) {
+            Ok(report) => {
+                log_info("daw_state", "Repaired the patch from the DAW project");
+                *failed = None;
+                Ok(report)
+            }
+            Err(error) => {
+                log_error(
+                    "daw_state",
+                    &format!("Can't repair the patch from the DAW project: {}", error),
+                );
+                if let Some(failed) = &mut *failed {
+                    failed.error = error.clone();
+                }
+                Err(error)
+            }
+        }
+    }
+
+    /// Stores the result of a repair the GUI queued, for [DawState::take_repair_result].
+    pub fn set_repair_result(&self, res: Result<PatchLoadReport, String>) {
+        *self.repair_result.lock().expect("DawState is ok") = Some(res);
+    }
+
+    /// The result of the queued repair, once it was done.
+    pub fn take_repair_result(&self) -> Option<Result<PatchLoadReport, String>> {
+        self.repair_result.lock().expect("DawState is ok").take()
+    }
+
+    /// Drops the kept state, the current patch is saved
+    /// to the DAW project from now on.
+    pub fn discard(&self) {
+        if self.failed.lock().expect("DawState is ok").take().is_some() {
+            log_info("daw_state", "Discarded the patch from the DAW project");
+        }
+    }
+
+    /// Writes the kept state to a file, so it is not lost on [DawState::discard].
+    pub fn export(&self, filename: &str) -> Result<(), String> {
+        let data = match self.failed() {
+            Some(failed) => failed.data,
+            None => return Err("There is no DAW state to export".to_string()),
+        };
+
+        if let Some(dir) = std::path::Path::new(filename).parent() {
+            if !dir.as_os_str().is_empty() {
+                std::fs::create_dir_all(dir)
+                    .map_err(|e| format!("Can't create '{}': {}", dir.display(), e))?;
+            }
+        }
+
+        std::fs::write(filename, data).map_err(|e| format!("Can't write '{}': {}", filename, e))
+    }
+}
@@ -0,0 +1,292 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexodsp::{DynNode1x1Context, DynamicNode1x1, Matrix, SAtom};
+
+use synfx_dsp::{Comb, DCBlockFilter, DelayBuffer};
+
+use crate::app_log::log_warn;
+use crate::poly::{VoiceSendNode, VoiceSumNode, VOICE_SEND_NODE_NAME, VOICE_SUM_NODE_NAME};
+use crate::prop_watch::PropWatcher;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// The matrix property that holds the slot assignments as JSON object,
+/// mapping the `Rust1x1` instance to the name of the prototype.
+pub const DYN_NODES_PROP: &str = "dyn_nodes";
+
+/// Number of `Rust1x1` instances that can be assigned a prototype.
+pub const MAX_DYN_NODE_SLOTS: usize = 32;
+
+/// Returns `false` for the slots that are out of range.
+pub fn is_assignable_dyn_node_slot(slot: usize) -> bool {
+    slot < MAX_DYN_NODE_SLOTS
+}
+
+pub fn dyn_nodes_from_matrix(matrix: &Matrix) -> Option<BTreeMap<usize, String>> {
+    match matrix.get_prop(DYN_NODES_PROP) {
+        Some(SAtom::Str(s)) => serde_json::from_str(&s).ok(),
+        _ => None,
+    }
+}
+
+pub fn dyn_nodes_store_in_matrix(matrix: &mut Matrix, slots: &BTreeMap<usize, String>) {
+    let s = serde_json::to_string(slots).unwrap_or_default();
+    matrix.set_prop(DYN_NODES_PROP, SAtom::str(&s));
+}
+
+type DynNodeFactory = Box<dyn Fn() -> Box<dyn DynamicNode1x1> + Send + Sync>;
+
+struct DynNodeProto {
+    name: String,
+    description: String,
+    factory: DynNodeFactory,
+}
+
+/// The name and description of a registered prototype.
+#[derive(Debug, Clone)]
+pub struct DynNodeInfo {
+    pub name: String,
+    pub description: String,
+}
+
+/// The registry of the named [DynamicNode1x1] prototypes, that can be
+/// assigned to the `Rust1x1` node instances of a patch.
+///
+/// The assignments are stored in the [DYN_NODES_PROP] of the matrix,
+/// [DynNodeRegistry::load_from_matrix] has to be called regularly to install
+/// the prototypes when they change. Patches without assignments get the
+/// defaults of the frontend.
+pub struct DynNodeRegistry {
+    protos: RwLock<Vec<DynNodeProto>>,
+    defaults: RwLock<BTreeMap<usize, String>>,
+    prop: PropWatcher,
+    /// The prototypes that are currently installed in the matrix.
+    installed: Mutex<BTreeMap<usize, String>>,
+}
+
+impl DynNodeRegistry {
+    /// Creates a registry with the prototypes that come with HexoSynth.
+    pub fn new() -> Arc<Self> {
+        let registry = Arc::new(Self {
+            protos: RwLock::new(vec![]),
+            defaults: RwLock::new(BTreeMap::new()),
+            prop: PropWatcher::new(DYN_NODES_PROP),
+            installed: Mutex::new(BTreeMap::new()),
+        });
+
+        registry.register("bypass", "Passes the input through unchanged.", || Box::new(BypassNode));
+        registry.register(
+            "delay_comb",
+            "Delay with a comb filter in the feedback path. `alpha` is the delay time \
+             up to 2 seconds, `beta` the feedback, `gamma` the comb time up to 10ms \
+             and `delta` the comb gain.",
+            || Box::new(DelayCombNode::new()),
+        );
+        registry.register(
+            "soft_clip",
+            "Saturates the input with a tanh curve. `alpha` is the drive, \
+             from 0dB to 24dB.",
+            || Box::new(SoftClipNode),
+        );
+        registry.register(
+            VOICE_SEND_NODE_NAME,
+            "Adds the voice at the input to the sum of the previous voices at `alpha`. \
+             Assigned by `cluster.make_voices`.",
+            || Box::new(VoiceSendNode),
+        );
+        registry.register(
+            VOICE_SUM_NODE_NAME,
+            "Outputs the sum of the voices at the input, scaled by `alpha`. \
+             Assigned by `cluster.make_voices`.",
+            || Box::new(VoiceSumNode),
+        );
+
+        registry
+    }
+
+    /// Registers a prototype, a previously registered one
+    /// with the same name is replaced.
+    pub fn register<F>(&self, name: &str, description: &str, factory: F)
+    where
+        F: Fn() -> Box<dyn DynamicNode1x1> + Send + Sync + 'static,
+    {
+        if let Ok(mut protos) = self.protos.write() {
+            protos.retain(|p| p.name != name);
+            protos.push(DynNodeProto {
+                name: name.to_string(),
+                description: description.to_string(),
+                factory: Box::new(factory),
+            });
+        }
+
+        // Install the new prototype on the next load, also where it replaces one:
+        if let Ok(mut installed) = self.installed.lock() {
+            installed.retain(|_, installed_name| installed_name != name);
+        }
+        self.prop.reset();
+    }
+
+    /// The prototypes in the order they were registered.
+    pub fn list(&self) -> Vec<DynNodeInfo> {
+        self.protos
+            .read()
+            .map(|protos| {
+                protos
+                    .iter()
+                    .map(|p| DynNodeInfo {
+                        name: p.name.clone(),
+                        description: p.description.clone(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.protos.read().map(|protos| protos.iter().any(|p| p.name == name)).unwrap_or(false)
+    }
+
+    pub fn instantiate(&self, name: &str) -> Option<Box<dyn DynamicNode1x1>> {
+        let protos = self.protos.read().ok()?;
+        protos.iter().find(|p| p.name == name).map(|p| (p.factory)())
+    }
+
+    /// Sets the prototype that is assigned to `slot` if the patch
+    /// has no assignments.
+    pub fn set_default(&self, slot: usize, name: &str) {
+        if let Ok(mut defaults) = self.defaults.write() {
+            defaults.insert(slot, name.to_string());
+        }
+
+        self.prop.reset();
+    }
+
+    /// The assignments of the patch, or the defaults if it has none.
+    pub fn assignments(&self, matrix: &Matrix) -> BTreeMap<usize, String> {
+        dyn_nodes_from_matrix(matrix)
+            .unwrap_or_else(|| self.defaults.read().map(|d| d.clone()).unwrap_or_default())
+    }
+
+    /// Installs the assigned prototypes if the assignments of the matrix
+    /// changed. Only the slots that changed get a new instance, slots that
+    /// are not assigned anymore get a `bypass` node. Slots with an unknown
+    /// prototype are left alone.
+    pub fn load_from_matrix(&self, matrix: &Matrix) -> bool {
+        if self.prop.changed(matrix).is_none() {
+            return false;
+        }
+
+        let assignments = self.assignments(matrix);
+
+        if let Ok(mut installed) = self.installed.lock() {
+            for slot in installed.keys().copied().collect::<Vec<usize>>() {
+                if !assignments.contains_key(&slot) {
+                    matrix.set_dynamic_node1x1(slot, Box::new(BypassNode));
+                    installed.remove(&slot);
+                }
+            }
+
+            for (slot, name) in assignments.iter() {
+                if installed.get(slot) == Some(name) {
+                    continue;
+                }
+
+                if !is_assignable_dyn_node_slot(*slot) {
+                    log_warn(
+                        "dyn_nodes",
+                        &format!("Rust1x1 instance {} of '{}' is out of range", slot, name),
+                    );
+                    continue;
+                }
+
+                if let Some(node) = self.instantiate(name) {
+                    matrix.set_dynamic_node1x1(*slot, node);
+                    installed.insert(*slot, name.clone());
+                } else {
+                    log_warn(
+                        "dyn_nodes",
+                        &format!("Unknown prototype '{}' for Rust1x1 instance {}", name, slot),
+                    );
+                }
+            }
+        }
+
+        true
+    }
+}
+
+/// Passes the input through.
+pub struct BypassNode;
+
+impl DynamicNode1x1 for BypassNode {
+    fn set_sample_rate(&mut self, _srate: f32) {}
+
+    fn reset(&mut self) {}
+
+    fn process(&mut self, input: &[f32], output: &mut [f32], _ctx: &DynNode1x1Context) {
+        output.copy_from_slice(input);
+    }
+}
+
+/// A delay with a comb filter in the feedback path, mixed 50/50
+/// with the input.
+pub struct DelayCombNode {
+    buf: DelayBuffer<f32>,
+    comb: Comb,
+    dc: DCBlockFilter<f32>,
+}
+
+impl DelayCombNode {
+    pub fn new() -> Self {
+        Self { buf: DelayBuffer::new(), comb: Comb::new(), dc: DCBlockFilter::new() }
+    }
+}
+
+impl DynamicNode1x1 for DelayCombNode {
+    fn set_sample_rate(&mut self, srate: f32) {
+        self.buf.set_sample_rate(srate);
+        self.comb.set_sample_rate(srate);
+        self.dc.set_sample_rate(srate);
+    }
+
+    fn reset(&mut self) {}
+
+    fn process(&mut self, input: &[f32], output: &mut [f32], ctx: &DynNode1x1Context) {
+        let drywet = 0.5;
+
+        for (i, (inp, out)) in input.iter().zip(output.iter_mut()).enumerate() {
+            let del_time_ms = ctx.alpha_slice()[i].clamp(0.0, 1.0) * 2000.0;
+            let fb = ctx.beta_slice()[i];
+            let comb_ms = ctx.gamma_slice()[i].clamp(0.0, 1.0) * 10.0;
+            let g = ctx.delta_slice()[i].clamp(-1.0, 1.0);
+
+            let delayed = self.buf.tap_c(del_time_ms);
+            let mix = delayed + inp;
+            let comb_out = self.comb.next_feedforward(comb_ms, -1.0 * g, mix * fb);
+            let comb_out = self.dc.next(comb_out);
+            self.buf.feed(comb_out);
+            *out = delayed * drywet + inp * (1.0 - drywet);
+        }
+    }
+}
+
+/// A tanh saturation, `alpha` is the drive from 0dB to 24dB.
+pub struct SoftClipNode;
+
+impl DynamicNode1x1 for SoftClipNode {
+    fn set_sample_rate(&mut self, _srate: f32) {}
+
+    fn reset(&mut self) {}
+
+    fn process(&mut self, input: &[f32], output: &mut [f32], ctx: &DynNode1x1Context) {
+        let alpha = ctx.alpha_slice();
+
+        for (i, (inp, out)) in input.iter().zip(output.iter_mut()).enumerate() {
+            let gain = 10.0_f32.powf(alpha[i].clamp(0.0, 1.0) * 24.0 / 20.0);
+            *out = (*inp * gain).tanh();
+        }
+    }
+}
@@ -2,6 +2,8 @@
 // This file is a part of HexoSynth. Released under GPL-3.0-or-later.
 // See README.md and COPYING for details.
 
+use crate::host_params::HostParamLabels;
+
 use hexotk::{ChangeRes, ParamModel};
 
 use std::sync::atomic::{AtomicU64, Ordering};
@@ -21,6 +23,7 @@ pub struct ExtParam {
     name: String,
     cbs: Arc<Mutex<ExtCallbacks>>,
     ext_counter: Option<Arc<AtomicU64>>,
+    labels: HostParamLabels,
 }
 
 impl ExtParam {
@@ -34,9 +37,19 @@ impl ExtParam {
                 on_get: None,
             })),
             ext_counter: None,
+            labels: HostParamLabels::new(),
         }
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Sets the labels from the patch, that are shown instead of the name.
+    pub fn set_labels(&mut self, labels: HostParamLabels) {
+        self.labels = labels;
+    }
+
     pub fn set_getter(&mut self, get: Box<dyn Fn() -> f32 + Send>) {
         if let Ok(mut cbs) = self.cbs.lock() {
             cbs.on_get = Some(get);
@@ -114,6 +127,13 @@ impl ParamModel for ExtParam {
     fn fmt(&self, buf: &mut [u8]) -> usize {
         let mut bw = std::io::BufWriter::new(buf);
 
+        if let Some(info) = self.labels.info(&self.name) {
+            return match write!(&mut bw, "{}", info.format_value(self.get())) {
+                Ok(_) => bw.buffer().len(),
+                _ => 0,
+            };
+        }
+
         match write!(&mut bw, "{:6.4}", self.get()) {
             Ok(_) => bw.buffer().len(),
             _ => 0,
@@ -136,7 +156,7 @@ impl ParamModel for ExtParam {
     fn fmt_name(&self, buf: &mut [u8]) -> usize {
         let mut bw = std::io::BufWriter::new(buf);
 
-        match write!(bw, "{}", self.name) {
+        match write!(bw, "{}", self.labels.label(&self.name)) {
             Ok(_) => bw.buffer().len(),
             Err(_) => 0,
         }
@@ -0,0 +1,119 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use crate::host_params::EXT_PARAM_NAMES;
+use crate::ExtParamSet;
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Holds the values of the external parameters `A1` to `F3` in the
+/// standalone builds, where there is no host that provides them.
+///
+/// The store is passed to `NodeExecutor::set_external_params` for the
+/// `ExtA` to `ExtF` nodes and backs the [ExtParamSet] of the GUI, see
+/// [ExtParamStore::attach]. The values are saved with the session.
+pub struct ExtParamStore {
+    values: [AtomicU32; 18],
+    /// Increased on every change, so the knobs of the GUI are redrawn.
+    generation: Arc<AtomicU64>,
+}
+
+impl ExtParamStore {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self { values: Default::default(), generation: Arc::new(AtomicU64::new(0)) })
+    }
+
+    /// The value of the parameter at `idx`, in the order of [EXT_PARAM_NAMES].
+    pub fn get(&self, idx: usize) -> f32 {
+        self.values.get(idx).map(|v| f32::from_bits(v.load(Ordering::Relaxed))).unwrap_or(0.0)
+    }
+
+    /// Sets the parameter at `idx`, the value is clamped to 0.0 to 1.0.
+    pub fn set(&self, idx: usize, v: f32) {
+        if let Some(value) = self.values.get(idx) {
+            value.store(v.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+            self.generation.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Option<f32> {
+        EXT_PARAM_NAMES.iter().position(|n| *n == name).map(|idx| self.get(idx))
+    }
+
+    /// Backs the parameters of `param_set` by this store, so the Ext knob
+    /// panel of the GUI reads and changes the values of the store.
+    pub fn attach(self: &Arc<Self>, param_set: &mut ExtParamSet) {
+        for (idx, param) in param_set.params_mut().enumerate() {
+            param.set_counter(self.generation.clone());
+
+            let store = self.clone();
+            param.set_getter(Box::new(move || store.get(idx)));
+
+            let store = self.clone();
+            param.set_changers(
+                Box::new(|| ()),
+                Box::new(move |v| store.set(idx, v)),
+                Box::new(|| ()),
+            );
+        }
+    }
+}
+
+impl hexodsp::nodes::ExternalParams for ExtParamStore {
+    fn a1(&self) -> f32 {
+        self.get(0)
+    }
+    fn a2(&self) -> f32 {
+        self.get(1)
+    }
+    fn a3(&self) -> f32 {
+        self.get(2)
+    }
+    fn b1(&self) -> f32 {
+        self.get(3)
+    }
+    fn b2(&self) -> f32 {
+        self.get(4)
+    }
+    fn b3(&self) -> f32 {
+        self.get(5)
+    }
+    fn c1(&self) -> f32 {
+        self.get(6)
+    }
+    fn c2(&self) -> f32 {
+        self.get(7)
+    }
+    fn c3(&self) -> f32 {
+        self.get(8)
+    }
+    fn d1(&self) -> f32 {
+        self.get(9)
+    }
+    fn d2(&self) -> f32 {
+        self.get(10)
+    }
+    fn d3(&self) -> f32 {
+        self.get(11)
+    }
+    fn e1(&self) -> f32 {
+        self.get(12)
+    }
+    fn e2(&self) -> f32 {
+        self.get(13)
+    }
+    fn e3(&self) -> f32 {
+        self.get(14)
+    }
+    fn f1(&self) -> f32 {
+        self.get(15)
+    }
+    fn f2(&self) -> f32 {
+        self.get(16)
+    }
+    fn f3(&self) -> f32 {
+        self.get(17)
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use crate::dyn_nodes::DynNodeRegistry;
+use crate::midi_expression::{register_expression_nodes, MidiExpression};
+use crate::midi_learn::MidiLearnWorker;
+use crate::midi_output::{register_midi_output_nodes, MidiOutput};
+use crate::oversampling::Oversampling;
+use crate::poly::PolySettings;
+use crate::transport::{register_transport_node, Transport};
+
+use hexodsp::Matrix;
+
+use std::sync::{Arc, Mutex};
+
+/// The state a frontend (plugin, JACK or CPAL standalone) shares between
+/// its audio thread and the GUI.
+#[derive(Clone)]
+pub struct FrontendState {
+    /// Feeds the `transport` prototype of the `Rust1x1` nodes.
+    pub transport: Arc<Transport>,
+    /// Feeds the expression prototypes, its events are shown in the MIDI log.
+    pub midi_expression: Arc<MidiExpression>,
+    /// Is fed the received CCs by the audio thread.
+    pub midi_learn: Arc<MidiLearnWorker>,
+    /// Receives the events of the MIDI output prototypes.
+    pub midi_output: Arc<MidiOutput>,
+    /// The settings of the [crate::VoiceAllocator] of the frontend.
+    pub poly: Arc<PolySettings>,
+    /// The prototypes that can be assigned to the `Rust1x1` nodes.
+    pub dyn_nodes: Arc<DynNodeRegistry>,
+    /// The oversampling factor of the audio thread.
+    pub oversampling: Arc<Oversampling>,
+}
+
+impl FrontendState {
+    /// Registers the transport, MIDI expression and MIDI output prototypes
+    /// in `dyn_nodes`, which may already carry the default assignments of
+    /// the frontend, and loads the settings of the patch in `matrix`.
+    pub fn new(
+        matrix: &Arc<Mutex<Matrix>>,
+        transport: Arc<Transport>,
+        dyn_nodes: Arc<DynNodeRegistry>,
+    ) -> Self {
+        let midi_expression = MidiExpression::new();
+        let midi_output = MidiOutput::new();
+
+        register_transport_node(&dyn_nodes, transport.clone());
+        register_expression_nodes(&dyn_nodes, midi_expression.clone());
+        register_midi_output_nodes(&dyn_nodes, midi_output.clone());
+
+        let poly = PolySettings::new();
+        let oversampling = Oversampling::new();
+
+        if let Ok(m) = matrix.lock() {
+            poly.load_from_matrix(&m);
+            dyn_nodes.load_from_matrix(&m);
+            oversampling.load_from_matrix(&m);
+        }
+
+        Self {
+            transport,
+            midi_expression,
+            midi_learn: MidiLearnWorker::start(matrix.clone()),
+            midi_output,
+            poly,
+            dyn_nodes,
+            oversampling,
+        }
+    }
+}
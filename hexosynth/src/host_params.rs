@@ -0,0 +1,199 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use crate::prop_watch::PropWatcher;
+use hexodsp::{Matrix, NodeId, ParamId, SAtom};
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+/// The matrix property that holds the [HostParamInfo] of the host
+/// parameters as JSON string.
+pub const HOST_PARAMS_PROP: &str = "host_params";
+
+/// Number of the host parameters `P1` to `P32`, that are bound to
+/// matrix parameters. They come in addition to the 18 external
+/// parameters `A1` to `F3`, which are read by the `ExtA` to `ExtF` nodes.
+pub const HOST_PARAM_POOL_SIZE: usize = 32;
+
+/// The names of the external parameters, in the order of the `ExtA` to `ExtF` nodes.
+pub const EXT_PARAM_NAMES: [&str; 18] = [
+    "A1", "A2", "A3", "B1", "B2", "B3", "C1", "C2", "C3", "D1", "D2", "D3", "E1", "E2", "E3", "F1",
+    "F2", "F3",
+];
+
+/// The name of the host parameter at `idx` in the pool, `P1` to `P32`.
+pub fn host_pool_param_name(idx: usize) -> String {
+    format!("P{}", idx + 1)
+}
+
+/// The matrix parameter a pool parameter is bound to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HostParamTarget {
+    pub node: String,
+    pub instance: usize,
+    pub param: String,
+}
+
+impl HostParamTarget {
+    pub fn from_param_id(param_id: ParamId) -> Self {
+        Self {
+            node: param_id.node_id().name().to_string(),
+            instance: param_id.node_id().instance(),
+            param: param_id.name().to_string(),
+        }
+    }
+
+    pub fn param_id(&self) -> Option<ParamId> {
+        NodeId::from_str(&self.node).to_instance(self.instance).inp_param(&self.param)
+    }
+}
+
+/// How a host parameter is presented to the host and the user.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HostParamInfo {
+    /// The name shown instead of `A1` or `P1`, for example "Filter Cutoff".
+    pub label: String,
+    /// Appended to the formatted value, for example " Hz".
+    pub unit: String,
+    /// The displayed value for the normalized host value 0.0.
+    pub min: f32,
+    /// The displayed value for the normalized host value 1.0.
+    pub max: f32,
+    /// Number of decimal places of the displayed value.
+    pub precision: usize,
+    /// Only for the pool parameters: The matrix parameter that is set to the
+    /// displayed value. `min` and `max` are in the units of that parameter.
+    pub target: Option<HostParamTarget>,
+}
+
+impl Default for HostParamInfo {
+    fn default() -> Self {
+        Self {
+            label: String::new(),
+            unit: String::new(),
+            min: 0.0,
+            max: 1.0,
+            precision: 2,
+            target: None,
+        }
+    }
+}
+
+impl HostParamInfo {
+    /// Maps the normalized host value to the displayed value.
+    pub fn denorm(&self, v: f32) -> f32 {
+        self.min + (self.max - self.min) * v.clamp(0.0, 1.0)
+    }
+
+    pub fn format_value(&self, v: f32) -> String {
+        format!("{:.*}{}", self.precision, self.denorm(v), self.unit)
+    }
+}
+
+/// The [HostParamInfo] of the current patch, shared between the plugin
+/// wrapper, the parameter knobs of the GUI and the matrix.
+///
+/// The infos are stored in the [HOST_PARAMS_PROP] of the matrix, so they
+/// are saved with the patch. [HostParamLabels::load_from_matrix] has to be
+/// called regularly to pick up changes, for example after loading a patch.
+#[derive(Debug, Clone)]
+pub struct HostParamLabels {
+    infos: Arc<RwLock<BTreeMap<String, HostParamInfo>>>,
+    prop: Arc<PropWatcher>,
+}
+
+impl Default for HostParamLabels {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HostParamLabels {
+    pub fn new() -> Self {
+        Self {
+            infos: Arc::new(RwLock::new(BTreeMap::new())),
+            prop: Arc::new(PropWatcher::new(HOST_PARAMS_PROP)),
+        }
+    }
+
+    pub fn info(&self, name: &str) -> Option<HostParamInfo> {
+        self.infos.read().ok()?.get(name).cloned()
+    }
+
+    /// The label of the parameter, or its name if it has none.
+    pub fn label(&self, name: &str) -> String {
+        match self.info(name) {
+            Some(info) if !info.label.is_empty() => info.label,
+            _ => name.to_string(),
+        }
+    }
+
+    /// Formats the normalized host value `v` of the parameter `name`.
+    pub fn format_value(&self, name: &str, v: f32) -> String {
+        match self.info(name) {
+            Some(info) if info.label.is_empty() => info.format_value(v),
+            Some(info) => format!("{}: {}", info.label, info.format_value(v)),
+            None => format!("{:.2}", v),
+        }
+    }
+
+    /// All pool parameters that are bound to a matrix parameter,
+    /// with their pool index and target parameter.
+    pub fn pool_targets(&self) -> Vec<(usize, ParamId, HostParamInfo)> {
+        let infos = match self.infos.read() {
+            Ok(infos) => infos,
+            Err(_) => return vec![],
+        };
+
+        (0..HOST_PARAM_POOL_SIZE)
+            .filter_map(|idx| {
+                let info = infos.get(&host_pool_param_name(idx))?;
+                let param_id = info.target.as_ref()?.param_id()?;
+                Some((idx, param_id, info.clone()))
+            })
+            .collect()
+    }
+
+    /// Reloads the infos from the matrix property. Returns true if they changed.
+    pub fn load_from_matrix(&self, matrix: &Matrix) -> bool {
+        let s = match self.prop.changed(matrix) {
+            Some(s) => s,
+            None => return false,
+        };
+
+        if let Ok(mut infos) = self.infos.write() {
+            *infos = parse_host_params(&s);
+        }
+
+        true
+    }
+}
+
+/// Parses the [HOST_PARAMS_PROP], a broken property results in no infos.
+pub fn parse_host_params(s: &str) -> BTreeMap<String, HostParamInfo> {
+    serde_json::from_str(s).unwrap_or_default()
+}
+
+pub fn host_params_from_matrix(matrix: &Matrix) -> BTreeMap<String, HostParamInfo> {
+    match matrix.get_prop(HOST_PARAMS_PROP) {
+        Some(SAtom::Str(s)) => parse_host_params(&s),
+        _ => BTreeMap::new(),
+    }
+}
+
+pub fn host_params_store_in_matrix(matrix: &mut Matrix, infos: &BTreeMap<String, HostParamInfo>) {
+    if let Ok(s) = serde_json::to_string(infos) {
+        matrix.set_prop(HOST_PARAMS_PROP, SAtom::str(&s));
+    }
+}
+
+/// Returns true if `name` is one of the external or pool parameters.
+pub fn is_host_param_name(name: &str) -> bool {
+    EXT_PARAM_NAMES.contains(&name)
+        || (0..HOST_PARAM_POOL_SIZE).any(|idx| host_pool_param_name(idx) == name)
+}
@@ -11,10 +11,97 @@ pub mod wlapi;
 use wlambda::vval::VVal;
 use wlambda::*;
 
+mod app_log;
+mod audio_device;
+mod auto_place;
+mod aux_bus;
+mod daw_state;
+mod dyn_nodes;
 mod ext_param_model;
+mod ext_param_store;
+mod frontend_state;
+mod host_params;
+mod matrix_history;
 mod matrix_param_model;
-
+mod matrix_snapshot;
+mod midi;
+mod midi_expression;
+mod midi_learn;
+mod midi_output;
+mod offline_render;
+mod oversampling;
+mod patch_migration;
+mod poly;
+mod preset_library;
+mod prop_watch;
+mod session;
+mod subpatch_library;
+mod transport;
+
+pub use app_log::{
+    collect_hexodsp_log, get_clipboard_text, log_clear, log_entries2text, log_error, log_info,
+    log_latest_seq, log_msg, log_query, log_warn, set_clipboard_text, LogEntry, LogFilter,
+    LogLevel, LOG_CAPACITY,
+};
+pub use audio_device::{
+    AudioConfigRange, AudioDevice, AudioDeviceInfo, AudioDeviceInfoFn, AudioHost, AudioSetup,
+};
+pub use auto_place::{auto_place, PlaceEdge, PlaceGraph, PlaceNode, PlaceResult};
+pub use aux_bus::{
+    register_aux_bus_nodes, AuxBuses, AuxOutNode, SidechainInNode, AUX_OUTPUTS, AUX_OUT_CHANNELS,
+    AUX_OUT_NODE_NAME, SIDECHAIN_CHANNELS, SIDECHAIN_IN_NODE_NAME,
+};
+pub use daw_state::{DawState, FailedDawState};
+pub use dyn_nodes::{
+    dyn_nodes_from_matrix, dyn_nodes_store_in_matrix, is_assignable_dyn_node_slot, BypassNode,
+    DelayCombNode, DynNodeInfo, DynNodeRegistry, SoftClipNode, DYN_NODES_PROP, MAX_DYN_NODE_SLOTS,
+};
 pub use ext_param_model::ExtParam;
+pub use ext_param_store::ExtParamStore;
+pub use frontend_state::FrontendState;
+pub use host_params::{
+    host_pool_param_name, HostParamInfo, HostParamLabels, HostParamTarget, EXT_PARAM_NAMES,
+    HOST_PARAMS_PROP, HOST_PARAM_POOL_SIZE,
+};
+pub use matrix_snapshot::{
+    MatrixCommand, MatrixSnapshot, MatrixSnapshotDirty, MatrixSnapshotWriter, MatrixView,
+};
+pub use midi::{event2raw_midi, midi_event2timed, raw_midi2event, raw_midi2expr};
+pub use midi_expression::{
+    register_expression_nodes, ExpressionNode, ExpressionOutput, MidiExprEvent, MidiExpression,
+    NoteExpr,
+};
+pub use midi_learn::{
+    MidiBinding, MidiLearn, MidiLearnCurve, MidiLearnState, MidiLearnWorker, MIDI_LEARN_PROP,
+};
+pub use midi_output::{
+    pitch2midi_note, register_midi_output_nodes, MidiCCOutNode, MidiNoteOutNode, MidiOutEvent,
+    MidiOutput, MIDI_CC_OUT_NODE_NAME, MIDI_NOTE_OUT_NODE_NAME,
+};
+pub use offline_render::{write_wav, OfflineRenderer, RenderEvent};
+pub use oversampling::{
+    is_oversampling_factor, oversampling_from_matrix, oversampling_store_in_matrix, Oversampler,
+    Oversampling, OVERSAMPLING_FACTORS, OVERSAMPLING_PROP, OVERSAMPLING_TAPS_PER_PHASE,
+};
+pub use patch_migration::{
+    load_patch_migrated_from_file, load_patch_migrated_from_mem, load_patch_repaired_from_mem,
+    migrate_patch, repair_patch, PatchLoadReport,
+};
+pub use poly::{
+    make_voices, poly_config_from_matrix, poly_config_store_in_matrix, PolyConfig, PolySettings,
+    VoiceAllocator, VoiceSendNode, VoiceStealing, VoiceSumNode, DEFAULT_VOICE_CHANNEL, MAX_VOICES,
+    POLY_PROP, VOICE_SEND_NODE_NAME, VOICE_SUM_NODE_NAME,
+};
+pub use preset_library::{
+    PresetEntry, PresetLibrary, PresetMeta, PresetQuery, PresetSort, PRESET_META_PROP,
+};
+pub use prop_watch::PropWatcher;
+pub use session::{Session, SessionData, AUTOSAVE_INTERVAL};
+pub use subpatch_library::{SubPatchEntry, SubPatchLibrary, SubPatchMeta, SUBPATCH_EXTENSION};
+pub use transport::{
+    register_transport_node, Transport, TransportInfo, TransportNode, TransportOutput,
+    TRANSPORT_NODE_NAME,
+};
 
 use raw_window_handle::RawWindowHandle;
 
@@ -30,6 +117,17 @@ use hexotk::MarkdownWichtextGenerator;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Creates the [Matrix] and DSP executor ([NodeExecutor]) of HexoSynth,
+/// with an empty matrix.
+///
+/// Used by the offline renderer and the golden check, which load a patch
+/// right away and must not depend on an `init.hxy` in the current directory.
+pub fn new_hexosynth_engine() -> (Matrix, NodeExecutor) {
+    let (node_conf, node_exec) = nodes::new_node_engine();
+    let (w, h) = (16, 16);
+    (Matrix::new(node_conf, w, h), node_exec)
+}
+
 /// Initializes the default [Matrix] setup of HexoSynth.
 ///
 /// This routine is used for example by the tests,
@@ -39,9 +137,7 @@ pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 /// It also creates a simple preset so the user won't start out
 /// with an empty matrix.
 pub fn init_hexosynth() -> (Matrix, NodeExecutor) {
-    let (node_conf, node_exec) = nodes::new_node_engine();
-    let (w, h) = (16, 16);
-    let mut matrix = Matrix::new(node_conf, w, h);
+    let (mut matrix, node_exec) = new_hexosynth_engine();
 
     matrix.place(3, 3, Cell::empty(NodeId::Sin(0)).out(Some(0), None, None));
     matrix.place(
@@ -55,14 +151,19 @@ pub fn init_hexosynth() -> (Matrix, NodeExecutor) {
     matrix.set_param(gain_p, gain_p.norm(0.06).into());
 
     if std::path::Path::new("init.hxy").exists() {
-        if let Err(e) = load_patch_from_file(&mut matrix, "init.hxy") {
-            println!("Error loading init.hxy: {:?}", e);
+        match load_patch_migrated_from_file(&mut matrix, "init.hxy") {
+            Ok(report) => {
+                for msg in report.migrated.iter().chain(report.ignored.iter()) {
+                    log_warn("init_patch", &format!("init.hxy: {}", msg));
+                }
+            }
+            Err(e) => log_error("init_patch", &format!("Error loading init.hxy: {}", e)),
         }
     } else {
         if let Err(e) =
-            load_patch_from_mem(&mut matrix, include_bytes!("res/init_patch_2022_1.hxy"))
+            load_patch_migrated_from_mem(&mut matrix, include_bytes!("res/init_patch_2022_1.hxy"))
         {
-            println!("Error loading init_patch_2022_1.hxy: {:?}", e);
+            log_error("init_patch", &format!("Error loading init_patch_2022_1.hxy: {}", e));
         }
     }
 
@@ -71,6 +172,46 @@ pub fn init_hexosynth() -> (Matrix, NodeExecutor) {
     (matrix, node_exec)
 }
 
+/// Like [init_hexosynth], but for the standalone builds. If the previous
+/// instance did not shut down cleanly, the matrix is recovered from the
+/// per user [Session], which is autosaved while HexoSynth is running.
+/// Otherwise HexoSynth starts with the default patch.
+pub fn init_hexosynth_with_session() -> (Matrix, NodeExecutor, Option<Arc<Session>>) {
+    let (mut matrix, node_exec) = init_hexosynth();
+
+    let session = match Session::open() {
+        Ok(session) => session,
+        Err(e) => {
+            log_warn("session", &format!("Session disabled: {}", e));
+            return (matrix, node_exec, None);
+        }
+    };
+
+    if !session.is_recovered() {
+        return (matrix, node_exec, Some(session));
+    }
+
+    log_warn(
+        "session",
+        &format!(
+            "HexoSynth did not shut down cleanly, recovering the session from {}",
+            session.file().display()
+        ),
+    );
+
+    match session.restore_matrix(&mut matrix) {
+        Some(Ok(report)) => {
+            for msg in report.migrated.iter().chain(report.ignored.iter()) {
+                log_warn("session", msg);
+            }
+        }
+        Some(Err(e)) => log_error("session", &format!("Error restoring the session: {}", e)),
+        None => (),
+    }
+
+    (matrix, node_exec, Some(session))
+}
+
 #[derive(Clone)]
 pub struct ExtParamSet {
     pub a: [ExtParam; 3],
@@ -79,18 +220,58 @@ pub struct ExtParamSet {
     pub d: [ExtParam; 3],
     pub e: [ExtParam; 3],
     pub f: [ExtParam; 3],
+    /// The labels of the parameters, from the `host_params` property of the patch.
+    pub labels: HostParamLabels,
 }
 
 impl ExtParamSet {
     pub fn new() -> Self {
-        Self {
+        let mut set = Self {
             a: [ExtParam::new("A1"), ExtParam::new("A2"), ExtParam::new("A3")],
             b: [ExtParam::new("B1"), ExtParam::new("B2"), ExtParam::new("B3")],
             c: [ExtParam::new("C1"), ExtParam::new("C2"), ExtParam::new("C3")],
             d: [ExtParam::new("D1"), ExtParam::new("D2"), ExtParam::new("D3")],
             e: [ExtParam::new("E1"), ExtParam::new("E2"), ExtParam::new("E3")],
             f: [ExtParam::new("F1"), ExtParam::new("F2"), ExtParam::new("F3")],
+            labels: HostParamLabels::new(),
+        };
+        set.set_labels(set.labels.clone());
+        set
+    }
+
+    /// Shares the `labels` with all external parameters. The plugin uses
+    /// this to show the same labels in the GUI as in the host.
+    pub fn set_labels(&mut self, labels: HostParamLabels) {
+        for param in self.params_mut() {
+            param.set_labels(labels.clone());
         }
+        self.labels = labels;
+    }
+
+    /// Iterates over all external parameters, from A1 to F3.
+    pub fn params(&self) -> impl Iterator<Item = &ExtParam> {
+        self.a
+            .iter()
+            .chain(self.b.iter())
+            .chain(self.c.iter())
+            .chain(self.d.iter())
+            .chain(self.e.iter())
+            .chain(self.f.iter())
+    }
+
+    /// The external parameter `A1` to `F3` with the name `name`.
+    pub fn by_name(&self, name: &str) -> Option<&ExtParam> {
+        self.params().find(|p| p.name() == name)
+    }
+
+    pub fn params_mut(&mut self) -> impl Iterator<Item = &mut ExtParam> {
+        self.a
+            .iter_mut()
+            .chain(self.b.iter_mut())
+            .chain(self.c.iter_mut())
+            .chain(self.d.iter_mut())
+            .chain(self.e.iter_mut())
+            .chain(self.f.iter_mut())
     }
 }
 
@@ -98,11 +279,29 @@ impl ExtParamSet {
 #[derive(Clone)]
 pub struct OpenHexoSynthConfig {
     pub param_set: ExtParamSet,
+    pub window_size: (i32, i32),
+    /// The state shared with the audio thread of the frontend. Without
+    /// a frontend the GUI creates its own, with an internal transport.
+    pub frontend: Option<FrontendState>,
+    /// Set by the standalone frontends, provides `hx:audio_device_info[]`.
+    pub audio_device_info: Option<AudioDeviceInfoFn>,
+    /// The session of the standalone frontends, provides `hx:session_info[]`.
+    pub session: Option<Arc<Session>>,
+    /// The patch state of the plugin, provides `hx:daw_state_info[]`
+    /// and the functions to repair or discard a state that failed to load.
+    pub daw_state: Option<Arc<DawState>>,
 }
 
 impl OpenHexoSynthConfig {
     pub fn new() -> Self {
-        Self { param_set: ExtParamSet::new() }
+        Self {
+            param_set: ExtParamSet::new(),
+            window_size: session::DEFAULT_WINDOW_SIZE,
+            frontend: None,
+            audio_device_info: None,
+            session: None,
+            daw_state: None,
+        }
     }
 }
 
@@ -137,6 +336,38 @@ pub fn open_hexosynth(
     open_hexosynth_with_config(parent, matrix, OpenHexoSynthConfig::new())
 }
 
+/// Opens the HexoSynth GUI of the standalone builds, see
+/// [open_hexosynth_with_config]. The window size is restored from the
+/// `session`, the external parameters only if it was recovered after a crash.
+/// The session is autosaved while the GUI is open and closed cleanly when
+/// the GUI returns.
+pub fn open_hexosynth_with_session(
+    matrix: Arc<Mutex<Matrix>>,
+    session: Option<Arc<Session>>,
+    mut config: OpenHexoSynthConfig,
+) {
+    let session = match session {
+        Some(session) => session,
+        None => {
+            open_hexosynth_with_config(None, matrix, config);
+            return;
+        }
+    };
+
+    if session.is_recovered() {
+        session.restore_ext_params(&config.param_set);
+    }
+    session.start_autosave(matrix.clone(), config.param_set.clone(), session::AUTOSAVE_INTERVAL);
+
+    config.window_size = session.window_size();
+    config.session = Some(session.clone());
+
+    let param_set = config.param_set.clone();
+    open_hexosynth_with_config(None, matrix.clone(), config);
+
+    session.close(&matrix, &param_set);
+}
+
 #[derive(Clone)]
 pub struct VUIStyle {
     pub style: Rc<RefCell<Rc<hexotk::Style>>>,
@@ -1116,7 +1347,7 @@ impl VValUserData for VUIWidget {
                                     }
                                 }
                                 Err(e) => {
-                                    println!("ERROR in widget callback: {}", e);
+                                    log_error("wlambda", &format!("In widget callback: {}", e));
                                 }
                             }
                         }
@@ -1346,10 +1577,11 @@ pub fn open_hexosynth_with_config(
     matrix: Arc<Mutex<Matrix>>,
     config: OpenHexoSynthConfig,
 ) -> HexoSynthGUIHandle {
+    let (window_w, window_h) = config.window_size;
     let hexotk_hdl = open_window(
         "HexoSynth",
-        1400,
-        800,
+        window_w,
+        window_h,
         parent,
         Box::new(move || {
             let global_env = GlobalEnv::new_default();
@@ -1560,10 +1792,37 @@ pub fn open_hexosynth_with_config(
             ui_st.set("STD_COLORS", std_clrs);
 
             global_env.borrow_mut().set_module("ui", ui_st);
-            global_env.borrow_mut().set_module("hx", wlapi::setup_hx_module(matrix.clone()));
+            let frontend = config.frontend.clone().unwrap_or_else(|| {
+                FrontendState::new(&matrix, Transport::new_internal(), DynNodeRegistry::new())
+            });
+            let history = Rc::new(RefCell::new(matrix_history::MatrixHistory::new()));
+
+            // The UI models read the snapshot published by the frame callback
+            // and queue their edits, instead of locking the matrix themselves:
+            let snapshot_dirty = MatrixSnapshotDirty::new();
+            let (snapshot_writer, matrix_view) = MatrixSnapshotWriter::new(
+                &mut matrix.lock().unwrap(),
+                snapshot_dirty.clone(),
+                frontend.dyn_nodes.clone(),
+                config.daw_state.clone(),
+            );
+            let snapshot_writer = RefCell::new(snapshot_writer);
+
+            let hx_st = wlapi::setup_hx_module(&wlapi::HxContext {
+                matrix: matrix.clone(),
+                history: history.clone(),
+                view: matrix_view,
+                frontend: frontend.clone(),
+                param_set: config.param_set.clone(),
+                audio_device_info: config.audio_device_info.clone(),
+                session: config.session.clone(),
+                daw_state: config.daw_state.clone(),
+            });
+
+            global_env.borrow_mut().set_module("hx", hx_st);
             global_env.borrow_mut().set_module("node_id", wlapi::setup_node_id_module());
 
-            let matrix_obs = Arc::new(wlapi::MatrixRecorder::new());
+            let matrix_obs = Arc::new(wlapi::MatrixRecorder::new(snapshot_dirty.clone()));
             matrix.lock().unwrap().set_observer(matrix_obs.clone());
 
             let mut roots = vec![];
@@ -1584,20 +1843,27 @@ pub fn open_hexosynth_with_config(
                         if let Some(widget) = vv2widget(v) {
                             roots.push(widget);
                         } else {
-                            println!(
-                                "ERROR: Expected main.wl to return a list of UI root widgets!"
+                            log_error(
+                                "wlambda",
+                                "Expected main.wl to return a list of UI root widgets!",
                             );
                         }
                     }
                 }),
                 Err(e) => {
-                    println!("ERROR: {}", e);
+                    log_error("wlambda", &format!("{}", e));
                 }
             }
 
             let frame_cb = ctx.get_global_var("on_frame").unwrap_or(VVal::None);
             let click_cb = ctx.get_global_var("on_click").unwrap_or(VVal::None);
             let driver_cb = ctx.get_global_var("on_driver").unwrap_or(VVal::None);
+            let host_labels = config.param_set.labels.clone();
+
+            // The first root widget covers the whole window, its size is
+            // stored in the session when the window is resized:
+            let session = config.session.clone();
+            let size_root = roots.first().cloned();
 
             let ctx = Rc::new(RefCell::new(ctx));
             let mut ui = Box::new(UI::new(ctx));
@@ -1607,14 +1873,48 @@ pub fn open_hexosynth_with_config(
                     return;
                 }
 
-                matrix.lock().unwrap().update_filters();
+                // The GUI does not wait for the pool, MIDI learn or state loading
+                // threads, if they hold the matrix the commands stay queued and
+                // the last snapshot is shown for another frame:
+                if let Ok(mut m) = matrix.try_lock() {
+                    m.update_filters();
+
+                    let mut snapshot_writer = snapshot_writer.borrow_mut();
+                    snapshot_writer.apply_commands(&mut m, &mut history.borrow_mut());
+
+                    // The settings are only parsed again if a property was set:
+                    if snapshot_dirty.take_props_changed() {
+                        host_labels.load_from_matrix(&m);
+                        frontend.poly.load_from_matrix(&m);
+                        frontend.dyn_nodes.load_from_matrix(&m);
+                        frontend.oversampling.load_from_matrix(&m);
+                    }
+
+                    snapshot_writer.publish(&mut m);
+
+                    while let Some(err) = m.pop_error() {
+                        log_error("matrix", &err);
+                    }
+                }
+
+                if let (Some(session), Some(root)) = (&session, &size_root) {
+                    let pos = root.pos();
+                    let size = (pos.w.round() as i32, pos.h.round() as i32);
+                    if size.0 > 0 && size.1 > 0 && size != session.window_size() {
+                        session.set_window_size(size);
+                    }
+                }
+
+                collect_hexodsp_log();
+
+                frontend.midi_expression.drain_log(|ev| matrix_obs.record_midi_expr(ev));
 
                 if let Some(ctx) = ctx.downcast_mut::<EvalContext>() {
                     let recs = matrix_obs.get_records();
                     match ctx.call(&frame_cb, &[recs]) {
                         Ok(_) => {}
                         Err(e) => {
-                            println!("ERROR in frame callback: {}", e);
+                            log_error("wlambda", &format!("In frame callback: {}", e));
                         }
                     }
                 }
@@ -1643,7 +1943,7 @@ pub fn open_hexosynth_with_config(
                         match ctx.call(&click_cb, &[arg]) {
                             Ok(_) => {}
                             Err(e) => {
-                                println!("ERROR in frame callback: {}", e);
+                                log_error("wlambda", &format!("In click callback: {}", e));
                             }
                         }
                     }
@@ -0,0 +1,420 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexodsp::matrix::MatrixError;
+use hexodsp::{Cell, Matrix, NodeId, ParamId, SAtom};
+
+use std::collections::{HashMap, VecDeque};
+
+pub const DEFAULT_HISTORY_DEPTH: usize = 100;
+
+/// A single reversible change of the [Matrix].
+#[derive(Debug, Clone)]
+pub enum MatrixEdit {
+    Cell {
+        pos: (usize, usize),
+        old: Cell,
+        new: Cell,
+    },
+    /// The `old` value is `None` if the node did not exist in the
+    /// matrix before the change, there is nothing to restore then.
+    Param {
+        param_id: ParamId,
+        old: Option<SAtom>,
+        new: SAtom,
+    },
+    ModAmt {
+        param_id: ParamId,
+        old: Option<f32>,
+        new: Option<f32>,
+    },
+}
+
+impl MatrixEdit {
+    /// Merges `other` into `self` if both edits target the same
+    /// cell or parameter. The oldest `old` value is kept, so that
+    /// undoing a merged edit goes back to the state before the first one.
+    fn merge(&mut self, other: &MatrixEdit) -> bool {
+        match (self, other) {
+            (MatrixEdit::Cell { pos, new, .. }, MatrixEdit::Cell { pos: pos2, new: new2, .. })
+                if *pos == *pos2 =>
+            {
+                *new = *new2;
+                true
+            }
+            (
+                MatrixEdit::Param { param_id, new, .. },
+                MatrixEdit::Param { param_id: pid2, new: new2, .. },
+            ) if *param_id == *pid2 => {
+                *new = new2.clone();
+                true
+            }
+            (
+                MatrixEdit::ModAmt { param_id, new, .. },
+                MatrixEdit::ModAmt { param_id: pid2, new: new2, .. },
+            ) if *param_id == *pid2 => {
+                *new = *new2;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn is_noop(&self) -> bool {
+        match self {
+            MatrixEdit::Cell { old, new, .. } => old == new,
+            MatrixEdit::Param { old: Some(old), new, .. } => atom_eq(old, new),
+            MatrixEdit::Param { old: None, .. } => false,
+            MatrixEdit::ModAmt { old, new, .. } => old == new,
+        }
+    }
+}
+
+fn atom_eq(a: &SAtom, b: &SAtom) -> bool {
+    match (a, b) {
+        (SAtom::Param(a), SAtom::Param(b)) => a == b,
+        (SAtom::Setting(a), SAtom::Setting(b)) => a == b,
+        (SAtom::Str(a), SAtom::Str(b)) => a == b,
+        (SAtom::MicroSample(a), SAtom::MicroSample(b)) => a == b,
+        (SAtom::AudioSample((a, _)), SAtom::AudioSample((b, _))) => a == b,
+        _ => false,
+    }
+}
+
+/// One undo/redo step, which can consist of multiple [MatrixEdit].
+#[derive(Debug, Clone)]
+pub struct MatrixHistoryStep {
+    label: String,
+    edits: Vec<MatrixEdit>,
+}
+
+impl MatrixHistoryStep {
+    fn new(label: String) -> Self {
+        Self { label, edits: vec![] }
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    fn push(&mut self, edit: MatrixEdit) {
+        for e in self.edits.iter_mut() {
+            if e.merge(&edit) {
+                return;
+            }
+        }
+
+        self.edits.push(edit);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.edits.iter().all(|e| e.is_noop())
+    }
+
+    fn apply(&self, m: &mut Matrix, undo: bool) -> Result<(), MatrixError> {
+        // Cells first, so that the nodes of the restored parameters
+        // are instanciated when the parameters are set.
+        for edit in self.edits.iter() {
+            if let MatrixEdit::Cell { pos, old, new } = edit {
+                m.place(pos.0, pos.1, if undo { *old } else { *new });
+            }
+        }
+
+        m.sync()?;
+
+        for edit in self.edits.iter() {
+            match edit {
+                MatrixEdit::Param { param_id, old, new } => {
+                    if undo {
+                        if let Some(old) = old {
+                            m.set_param(*param_id, old.clone());
+                        }
+                    } else {
+                        m.set_param(*param_id, new.clone());
+                    }
+                }
+                MatrixEdit::ModAmt { param_id, old, new } => {
+                    m.set_param_modamt(*param_id, if undo { *old } else { *new })?;
+                }
+                MatrixEdit::Cell { .. } => (),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A capture of the cells and the parameters of the nodes
+/// in the matrix. Used to record compound changes like placing
+/// a chain or moving a cluster, where it is hard to tell in advance
+/// which cells and parameters will be touched.
+pub struct MatrixCapture {
+    cells: Vec<((usize, usize), Cell)>,
+    params: HashMap<ParamId, (SAtom, Option<f32>)>,
+}
+
+pub(crate) fn for_node_params<F: FnMut(ParamId)>(node_id: NodeId, mut f: F) {
+    let mut i = 0;
+    while let Some(param_id) = node_id.inp_param_by_idx(i) {
+        f(param_id);
+        i += 1;
+    }
+
+    let mut i = 0;
+    while let Some(param_id) = node_id.atom_param_by_idx(i) {
+        f(param_id);
+        i += 1;
+    }
+}
+
+impl MatrixCapture {
+    pub fn capture(m: &Matrix) -> Self {
+        let mut cells = vec![];
+        let mut params = HashMap::new();
+
+        m.for_each(|x, y, cell| {
+            cells.push(((x, y), *cell));
+
+            if cell.node_id() == NodeId::Nop {
+                return;
+            }
+
+            for_node_params(cell.node_id(), |param_id| {
+                if let Some(at) = m.get_param(&param_id) {
+                    params.insert(param_id, (at, m.get_param_modamt(&param_id)));
+                }
+            });
+        });
+
+        Self { cells, params }
+    }
+
+    /// Returns the edits that lead from the captured state to
+    /// the current state of the matrix `m`.
+    pub fn diff(&self, m: &Matrix) -> Vec<MatrixEdit> {
+        let mut edits = vec![];
+        let now = MatrixCapture::capture(m);
+
+        for ((pos, old), (_, new)) in self.cells.iter().zip(now.cells.iter()) {
+            if old != new {
+                edits.push(MatrixEdit::Cell { pos: *pos, old: *old, new: *new });
+            }
+        }
+
+        for (param_id, (new_at, new_ma)) in now.params.iter() {
+            if let Some((old_at, old_ma)) = self.params.get(param_id) {
+                if !atom_eq(old_at, new_at) {
+                    edits.push(MatrixEdit::Param {
+                        param_id: *param_id,
+                        old: Some(old_at.clone()),
+                        new: new_at.clone(),
+                    });
+                }
+                if old_ma != new_ma {
+                    edits.push(MatrixEdit::ModAmt {
+                        param_id: *param_id,
+                        old: *old_ma,
+                        new: *new_ma,
+                    });
+                }
+            } else {
+                edits.push(MatrixEdit::Param {
+                    param_id: *param_id,
+                    old: None,
+                    new: new_at.clone(),
+                });
+            }
+        }
+
+        edits
+    }
+}
+
+/// Multi level undo/redo history of changes to the [Matrix].
+///
+/// Changes are recorded with [MatrixHistory::record]. Multiple
+/// changes can be combined into one step by wrapping them
+/// in [MatrixHistory::begin_group] and [MatrixHistory::end_group].
+pub struct MatrixHistory {
+    undo: VecDeque<MatrixHistoryStep>,
+    redo: Vec<MatrixHistoryStep>,
+    depth: usize,
+    group: Option<MatrixHistoryStep>,
+    group_nesting: usize,
+}
+
+impl MatrixHistory {
+    pub fn new() -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: vec![],
+            depth: DEFAULT_HISTORY_DEPTH,
+            group: None,
+            group_nesting: 0,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        while self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.group = None;
+        self.group_nesting = 0;
+    }
+
+    pub fn in_group(&self) -> bool {
+        self.group_nesting > 0
+    }
+
+    pub fn begin_group(&mut self) {
+        if self.group_nesting == 0 {
+            self.group = Some(MatrixHistoryStep::new(String::new()));
+        }
+        self.group_nesting += 1;
+    }
+
+    /// Closes the current group. The group is committed as one step
+    /// when the outermost group is closed. If `label` is `None`, the label
+    /// of the first recorded change in the group is used.
+    pub fn end_group(&mut self, label: Option<String>) {
+        if self.group_nesting == 0 {
+            return;
+        }
+        self.group_nesting -= 1;
+
+        if let Some(group) = self.group.as_mut() {
+            if let Some(label) = label {
+                group.label = label;
+            }
+        }
+
+        if self.group_nesting == 0 {
+            if let Some(group) = self.group.take() {
+                self.push_step(group);
+            }
+        }
+    }
+
+    /// Commits the open groups as one step. A knob drag that never got
+    /// its `change_end`, for instance because it was aborted, would keep
+    /// the group open and block [MatrixHistory::undo] forever otherwise.
+    pub fn close_groups(&mut self) {
+        self.group_nesting = 0;
+
+        if let Some(group) = self.group.take() {
+            self.push_step(group);
+        }
+    }
+
+    /// Drops all changes recorded in the current group so far.
+    /// Used when the matrix was rolled back, for instance by
+    /// `Matrix::restore_matrix`.
+    pub fn discard_group(&mut self) {
+        if let Some(group) = self.group.as_mut() {
+            group.edits.clear();
+        }
+    }
+
+    pub fn record(&mut self, label: String, edit: MatrixEdit) {
+        self.record_edits(label, vec![edit]);
+    }
+
+    pub fn record_edits(&mut self, label: String, edits: Vec<MatrixEdit>) {
+        if let Some(group) = self.group.as_mut() {
+            if group.label.is_empty() {
+                group.label = label;
+            }
+            for edit in edits {
+                group.push(edit);
+            }
+        } else {
+            let mut step = MatrixHistoryStep::new(label);
+            for edit in edits {
+                step.push(edit);
+            }
+            self.push_step(step);
+        }
+    }
+
+    fn push_step(&mut self, step: MatrixHistoryStep) {
+        if step.is_empty() {
+            return;
+        }
+
+        self.redo.clear();
+        self.undo.push_back(step);
+
+        while self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+    }
+
+    /// Reverts the most recent step. Returns the label of the
+    /// reverted step, or `None` if there is nothing to undo.
+    /// A step that can't be reverted stays on the undo stack.
+    pub fn undo(&mut self, m: &mut Matrix) -> Result<Option<String>, MatrixError> {
+        self.close_groups();
+
+        if let Some(step) = self.undo.pop_back() {
+            if let Err(e) = step.apply(m, true) {
+                self.undo.push_back(step);
+                return Err(e);
+            }
+
+            let label = step.label.clone();
+            self.redo.push(step);
+            Ok(Some(label))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Applies the most recently undone step again. Returns the label of the
+    /// step, or `None` if there is nothing to redo.
+    /// A step that can't be applied stays on the redo stack.
+    pub fn redo(&mut self, m: &mut Matrix) -> Result<Option<String>, MatrixError> {
+        self.close_groups();
+
+        if let Some(step) = self.redo.pop() {
+            if let Err(e) = step.apply(m, false) {
+                self.redo.push(step);
+                return Err(e);
+            }
+
+            let label = step.label.clone();
+            self.undo.push_back(step);
+            Ok(Some(label))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Iterates over the undo steps, the most recent first.
+    pub fn for_undo_steps<F: FnMut(&MatrixHistoryStep)>(&self, mut f: F) {
+        for step in self.undo.iter().rev() {
+            f(step)
+        }
+    }
+
+    /// Iterates over the redo steps, the next one to be redone first.
+    pub fn for_redo_steps<F: FnMut(&MatrixHistoryStep)>(&self, mut f: F) {
+        for step in self.redo.iter().rev() {
+            f(step)
+        }
+    }
+}
+
+pub fn param_label(param_id: &ParamId) -> String {
+    format!("{} {}", param_id.node_id(), param_id.name())
+}
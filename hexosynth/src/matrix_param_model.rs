@@ -4,43 +4,42 @@
 
 use hexotk::{ChangeRes, ParamModel};
 
-use hexodsp::{Matrix, NodeInfo, ParamId};
+use hexodsp::{NodeInfo, ParamId, SAtom};
+
+use crate::matrix_history::param_label;
+use crate::matrix_snapshot::{MatrixCommand, MatrixView};
+use crate::midi_learn::MidiLearn;
 
 use std::io::Write;
 use std::sync::{Arc, Mutex};
 
+/// The model of a knob, it reads the parameter from the [MatrixView]
+/// and queues its changes there, to be recorded in the undo history.
 pub struct KnobParam {
-    matrix: Arc<Mutex<Matrix>>,
+    view: MatrixView,
     param_id: ParamId,
     node_info: NodeInfo,
+    midi_learn: Arc<Mutex<MidiLearn>>,
 }
 
 impl KnobParam {
-    pub fn new(matrix: Arc<Mutex<Matrix>>, param_id: ParamId) -> Self {
-        Self { matrix, param_id, node_info: NodeInfo::from(param_id.node_id().name()) }
-    }
-
-    pub fn with_ref<F, R: Default>(&self, fun: F) -> R
-    where
-        F: FnOnce(&mut Matrix, &ParamId) -> R,
-    {
-        match self.matrix.lock() {
-            Ok(mut lock) => fun(&mut *lock, &self.param_id),
-            Err(e) => {
-                eprintln!("Couldn't lock matrix!: {}", e);
-                R::default()
-            }
-        }
+    pub fn new(view: MatrixView, param_id: ParamId, midi_learn: Arc<Mutex<MidiLearn>>) -> Self {
+        Self { view, param_id, node_info: NodeInfo::from(param_id.node_id().name()), midi_learn }
+    }
+
+    /// Sets the parameter and records the change in the undo history.
+    fn set_param_recorded(&self, at: SAtom) {
+        self.view.push(MatrixCommand::Param { param_id: self.param_id, value: at, record: true });
     }
 }
 
 impl ParamModel for KnobParam {
     fn get(&self) -> f32 {
-        self.with_ref(|m, param_id| m.get_param(param_id).map(|a| a.f()).unwrap_or(0.0))
+        self.view.get_param(&self.param_id).map(|a| a.f()).unwrap_or(0.0)
     }
 
     fn get_generation(&mut self) -> u64 {
-        self.with_ref(|m, _param_id| m.get_generation() as u64)
+        self.view.snapshot().get_generation() as u64
     }
 
     /// Should return true if the UI for the parameter can be changed
@@ -50,11 +49,7 @@ impl ParamModel for KnobParam {
         if self.get_mod_amt().is_some() {
             true
         } else {
-            if let Ok(m) = self.matrix.lock() {
-                !m.param_input_is_used(self.param_id)
-            } else {
-                false
-            }
+            !self.view.snapshot().param_input_is_used(self.param_id)
         }
     }
 
@@ -85,23 +80,13 @@ impl ParamModel for KnobParam {
     /// Should return the modulation amount like it will be applied to the
     /// inputs.
     fn get_mod_amt(&self) -> Option<f32> {
-        if let Ok(m) = self.matrix.lock() {
-            m.get_param_modamt(&self.param_id)
-        } else {
-            None
-        }
+        self.view.get_param_modamt(&self.param_id)
     }
 
     /// Set the UI modulation amount like it will be used in the
     /// modulation later and be returned from [get_mod_amt].
     fn set_mod_amt(&mut self, amt: Option<f32>) {
-        if let Ok(mut m) = self.matrix.lock() {
-            // XXX: We ignore errors here, because setting a mod
-            //      amount does indeed cause a matrix sync, but
-            //      it does not change anything that might cause
-            //      an error!
-            let _ = m.set_param_modamt(self.param_id, amt);
-        }
+        self.view.push(MatrixCommand::ModAmt { param_id: self.param_id, amt, record: true });
     }
 
     /// Should return a coarse step and a fine step for the normalized value.
@@ -154,6 +139,15 @@ impl ParamModel for KnobParam {
     fn fmt_name(&self, buf: &mut [u8]) -> usize {
         let mut bw = std::io::BufWriter::new(buf);
 
+        let armed =
+            self.midi_learn.lock().map(|ml| ml.is_armed_for(self.param_id)).unwrap_or(false);
+        if armed {
+            return match write!(bw, "CC?") {
+                Ok(_) => bw.buffer().len(),
+                Err(_) => 0,
+            };
+        }
+
         match write!(bw, "{}", self.node_info.in_name(self.param_id.inp() as usize).unwrap_or("?"))
         {
             Ok(_) => bw.buffer().len(),
@@ -174,25 +168,26 @@ impl ParamModel for KnobParam {
     }
 
     fn set_denorm(&mut self, v: f32) {
-        if let Ok(mut m) = self.matrix.lock() {
-            m.set_param(self.param_id, self.param_id.norm(v).into())
-        }
+        self.set_param_recorded(self.param_id.norm(v).into())
     }
 
     fn set_default(&mut self) {
-        if let Ok(mut m) = self.matrix.lock() {
-            let at = self.param_id.as_atom_def().into();
-            m.set_param(self.param_id, at);
-
-            // XXX: We ignore errors here, because setting a mod
-            //      amount does indeed cause a matrix sync, but
-            //      it does not change anything that might cause
-            //      an error!
-            let _ = m.set_param_modamt(self.param_id, None);
+        self.view.push(MatrixCommand::BeginGroup);
+        self.set_param_recorded(self.param_id.as_atom_def().into());
+        self.view.push(MatrixCommand::ModAmt { param_id: self.param_id, amt: None, record: true });
+        self.view
+            .push(MatrixCommand::EndGroup(Some(format!("Reset {}", param_label(&self.param_id)))));
+    }
+
+    fn change_start(&mut self) {
+        if let Ok(mut ml) = self.midi_learn.lock() {
+            ml.knob_touched(self.param_id);
         }
+
+        // Collects all changes of one knob drag into one undo step:
+        self.view.push(MatrixCommand::BeginGroup);
     }
 
-    fn change_start(&mut self) {}
     fn change(&mut self, v: f32, res: ChangeRes) {
         let pid = self.param_id;
 
@@ -206,16 +201,13 @@ impl ParamModel for KnobParam {
                 ChangeRes::Free => v.clamp(min, max),
             };
 
-            if let Ok(mut m) = self.matrix.lock() {
-                m.set_param(pid, v.into())
-            }
+            self.set_param_recorded(v.into())
         } else {
-            if let Ok(mut m) = self.matrix.lock() {
-                m.set_param(pid, v.into())
-            }
+            self.set_param_recorded(v.into())
         }
     }
     fn change_end(&mut self, v: f32, res: ChangeRes) {
         self.change(v, res);
+        self.view.push(MatrixCommand::EndGroup(None));
     }
 }
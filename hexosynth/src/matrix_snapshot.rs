@@ -0,0 +1,494 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexodsp::{Cell, CellDir, Matrix, NodeId, ParamId, SAtom, ScopeHandle};
+
+use crate::daw_state::DawState;
+use crate::dyn_nodes::{dyn_nodes_store_in_matrix, DynNodeRegistry};
+use crate::matrix_history::{for_node_params, param_label, MatrixEdit, MatrixHistory};
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// An edit of the matrix done by a UI model or the `hx` functions. The
+/// edits are queued and applied in one go by [MatrixSnapshotWriter::apply_commands],
+/// so that the GUI does not have to lock the matrix itself.
+#[derive(Debug, Clone)]
+pub enum MatrixCommand {
+    /// Sets a parameter, `record` puts the change into the undo history.
+    Param { param_id: ParamId, value: SAtom, record: bool },
+    /// Sets the modulation amount of a parameter.
+    ModAmt { param_id: ParamId, amt: Option<f32>, record: bool },
+    /// See [MatrixHistory::begin_group].
+    BeginGroup,
+    /// See [MatrixHistory::end_group].
+    EndGroup(Option<String>),
+    /// Assigns a prototype of the [DynNodeRegistry] to a `Rust1x1`
+    /// instance, or removes the assignment.
+    DynNodeAssign { slot: usize, name: Option<String> },
+    /// Loads the kept state with [DawState::repair], the result
+    /// is picked up with [DawState::take_repair_result].
+    DawStateRepair,
+}
+
+/// Set by the matrix observer, tells the [MatrixSnapshotWriter]
+/// which parts of the snapshot have to be rebuilt, and the frame
+/// callback whether the settings stored in the properties changed.
+pub struct MatrixSnapshotDirty {
+    graph: AtomicBool,
+    params: Mutex<Vec<ParamId>>,
+    props: AtomicBool,
+}
+
+impl MatrixSnapshotDirty {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            graph: AtomicBool::new(true),
+            params: Mutex::new(vec![]),
+            props: AtomicBool::new(true),
+        })
+    }
+
+    /// A property was set, or the whole patch was replaced.
+    pub fn props_changed(&self) {
+        self.props.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true once after [MatrixSnapshotDirty::props_changed].
+    pub fn take_props_changed(&self) -> bool {
+        self.props.swap(false, Ordering::Relaxed)
+    }
+
+    /// The cells, connections or everything changed.
+    pub fn graph_changed(&self) {
+        self.graph.store(true, Ordering::Relaxed);
+    }
+
+    pub fn param_changed(&self, param_id: ParamId) {
+        if let Ok(mut params) = self.params.lock() {
+            params.push(param_id);
+        }
+    }
+}
+
+/// The edge label of a cell and the output that is connected there.
+#[derive(Debug, Clone)]
+struct SnapshotEdge {
+    label: String,
+    out: Option<(NodeId, u8)>,
+}
+
+/// The parts of the matrix that only change with the generation:
+/// The cells, their edge labels and the scope handles.
+struct SnapshotGraph {
+    generation: usize,
+    size: (usize, usize),
+    cells: Vec<Cell>,
+    /// 6 edges per cell, see [CellDir::as_edge].
+    edges: Vec<Option<SnapshotEdge>>,
+    nodes: Vec<NodeId>,
+    outputs: Vec<(NodeId, u8)>,
+    scope_handles: Vec<Arc<ScopeHandle>>,
+}
+
+impl SnapshotGraph {
+    fn capture(m: &Matrix) -> Self {
+        let (w, h) = m.size();
+
+        let mut cells = vec![Cell::empty(NodeId::Nop); w * h];
+        let mut edges = vec![None; w * h * 6];
+        let mut nodes = vec![];
+        let mut outputs = vec![];
+        let mut buf: [u8; 30] = [0; 30];
+
+        m.for_each(|x, y, cell| {
+            cells[y * w + x] = *cell;
+
+            if cell.node_id() == NodeId::Nop {
+                return;
+            }
+
+            if !nodes.contains(&cell.node_id()) {
+                nodes.push(cell.node_id());
+            }
+
+            for edge in 0..6 {
+                let dir = CellDir::from(edge);
+
+                if let Some((lbl, is_connected)) = m.edge_label(cell, dir, &mut buf[..]) {
+                    let out = if is_connected {
+                        cell.local_port_idx(dir).map(|out_idx| (cell.node_id(), out_idx))
+                    } else {
+                        None
+                    };
+
+                    if let Some(out) = out {
+                        if !outputs.contains(&out) {
+                            outputs.push(out);
+                        }
+                    }
+
+                    edges[(y * w + x) * 6 + edge as usize] =
+                        Some(SnapshotEdge { label: lbl.to_string(), out });
+                }
+            }
+        });
+
+        let mut scope_handles = vec![];
+        while let Some(handle) = m.get_scope_handle(scope_handles.len()) {
+            scope_handles.push(handle);
+        }
+
+        Self {
+            generation: m.get_generation(),
+            size: (w, h),
+            cells,
+            edges,
+            nodes,
+            outputs,
+            scope_handles,
+        }
+    }
+}
+
+/// The parameters of all nodes in the matrix.
+#[derive(Clone)]
+struct SnapshotParams {
+    values: HashMap<ParamId, (SAtom, Option<f32>)>,
+    used_inputs: HashSet<ParamId>,
+}
+
+impl SnapshotParams {
+    fn capture(m: &Matrix, graph: &SnapshotGraph) -> Self {
+        let mut params = Self { values: HashMap::new(), used_inputs: HashSet::new() };
+
+        for node_id in graph.nodes.iter() {
+            for_node_params(*node_id, |param_id| params.update(m, param_id));
+        }
+
+        params
+    }
+
+    fn update(&mut self, m: &Matrix, param_id: ParamId) {
+        if let Some(at) = m.get_param(&param_id) {
+            self.values.insert(param_id, (at, m.get_param_modamt(&param_id)));
+        } else {
+            self.values.remove(&param_id);
+        }
+
+        if m.param_input_is_used(param_id) {
+            self.used_inputs.insert(param_id);
+        } else {
+            self.used_inputs.remove(&param_id);
+        }
+    }
+}
+
+/// A copy of the parts of the [Matrix] the UI models display,
+/// published once per frame by the [MatrixSnapshotWriter].
+///
+/// The cells and parameters are shared between snapshots and only captured
+/// again when they changed, the monitor values are captured every frame.
+#[derive(Clone)]
+pub struct MatrixSnapshot {
+    graph: Arc<SnapshotGraph>,
+    params: Arc<SnapshotParams>,
+    phases: HashMap<NodeId, f32>,
+    leds: HashMap<NodeId, f32>,
+    filtered_leds: HashMap<NodeId, (f32, f32)>,
+    out_fbs: HashMap<(NodeId, u8), (f32, f32)>,
+}
+
+impl MatrixSnapshot {
+    fn capture(m: &mut Matrix) -> Self {
+        let graph = Arc::new(SnapshotGraph::capture(m));
+        let params = Arc::new(SnapshotParams::capture(m, &graph));
+
+        let mut snapshot = Self {
+            graph,
+            params,
+            phases: HashMap::new(),
+            leds: HashMap::new(),
+            filtered_leds: HashMap::new(),
+            out_fbs: HashMap::new(),
+        };
+        snapshot.capture_monitors(m);
+        snapshot
+    }
+
+    /// Captures the monitor values again, the maps keep their memory.
+    fn capture_monitors(&mut self, m: &mut Matrix) {
+        self.phases.clear();
+        self.leds.clear();
+        self.filtered_leds.clear();
+        for node_id in self.graph.nodes.iter() {
+            self.phases.insert(*node_id, m.phase_value_for(node_id));
+            self.leds.insert(*node_id, m.led_value_for(node_id));
+            self.filtered_leds.insert(*node_id, m.filtered_led_for(node_id));
+        }
+
+        self.out_fbs.clear();
+        for (node_id, out) in self.graph.outputs.iter() {
+            self.out_fbs.insert((*node_id, *out), m.filtered_out_fb_for(node_id, *out));
+        }
+    }
+
+    pub fn get_generation(&self) -> usize {
+        self.graph.generation
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        self.graph.size
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<&Cell> {
+        let (w, h) = self.graph.size;
+        if x >= w || y >= h {
+            return None;
+        }
+        self.graph.cells.get(y * w + x)
+    }
+
+    /// The label of the edge `dir` of the cell at `x`/`y`, and the
+    /// output that is connected there.
+    pub fn edge_label(
+        &self,
+        x: usize,
+        y: usize,
+        dir: CellDir,
+    ) -> Option<(&str, Option<(NodeId, u8)>)> {
+        let (w, h) = self.graph.size;
+        let edge = dir.as_edge() as usize;
+        if x >= w || y >= h || edge >= 6 {
+            return None;
+        }
+
+        let edge = self.graph.edges.get((y * w + x) * 6 + edge)?.as_ref()?;
+        Some((&edge.label, edge.out))
+    }
+
+    pub fn get_param(&self, param_id: &ParamId) -> Option<SAtom> {
+        self.params.values.get(param_id).map(|(at, _)| at.clone())
+    }
+
+    pub fn get_param_modamt(&self, param_id: &ParamId) -> Option<f32> {
+        self.params.values.get(param_id).and_then(|(_, ma)| *ma)
+    }
+
+    pub fn param_input_is_used(&self, param_id: ParamId) -> bool {
+        self.params.used_inputs.contains(&param_id)
+    }
+
+    pub fn phase_value_for(&self, node_id: &NodeId) -> f32 {
+        self.phases.get(node_id).copied().unwrap_or(0.0)
+    }
+
+    pub fn led_value_for(&self, node_id: &NodeId) -> f32 {
+        self.leds.get(node_id).copied().unwrap_or(0.0)
+    }
+
+    pub fn filtered_led_for(&self, node_id: &NodeId) -> (f32, f32) {
+        self.filtered_leds.get(node_id).copied().unwrap_or((0.0, 0.0))
+    }
+
+    pub fn filtered_out_fb_for(&self, node_id: &NodeId, out: u8) -> (f32, f32) {
+        self.out_fbs.get(&(*node_id, out)).copied().unwrap_or((0.0, 0.0))
+    }
+
+    pub fn get_scope_handle(&self, instance: usize) -> Option<Arc<ScopeHandle>> {
+        self.graph.scope_handles.get(instance).cloned()
+    }
+}
+
+/// The side of the UI models: Reads the latest [MatrixSnapshot] and
+/// queues [MatrixCommand]s. Cheap to clone, one is shared by all models.
+#[derive(Clone)]
+pub struct MatrixView {
+    current: Rc<RefCell<Arc<MatrixSnapshot>>>,
+    commands: Rc<RefCell<Vec<MatrixCommand>>>,
+}
+
+impl MatrixView {
+    /// The latest published snapshot. The edits that were queued since then
+    /// are not in it, use [MatrixView::get_param] to see them.
+    ///
+    /// The snapshot should not be kept beyond the current frame, the next
+    /// one would have to be copied instead of being updated in place.
+    pub fn snapshot(&self) -> Arc<MatrixSnapshot> {
+        self.current.borrow().clone()
+    }
+
+    pub fn push(&self, cmd: MatrixCommand) {
+        self.commands.borrow_mut().push(cmd);
+    }
+
+    /// Like [MatrixSnapshot::get_param], including the queued edits.
+    pub fn get_param(&self, param_id: &ParamId) -> Option<SAtom> {
+        for cmd in self.commands.borrow().iter().rev() {
+            if let MatrixCommand::Param { param_id: pid, value, .. } = cmd {
+                if pid == param_id {
+                    return Some(value.clone());
+                }
+            }
+        }
+
+        self.snapshot().get_param(param_id)
+    }
+
+    /// Like [MatrixSnapshot::get_param_modamt], including the queued edits.
+    pub fn get_param_modamt(&self, param_id: &ParamId) -> Option<f32> {
+        for cmd in self.commands.borrow().iter().rev() {
+            if let MatrixCommand::ModAmt { param_id: pid, amt, .. } = cmd {
+                if pid == param_id {
+                    return *amt;
+                }
+            }
+        }
+
+        self.snapshot().get_param_modamt(param_id)
+    }
+
+    /// Applies the queued [MatrixCommand::DynNodeAssign] to the
+    /// assignments `slots` of the matrix.
+    pub fn queued_dyn_node_assignments(&self, slots: &mut BTreeMap<usize, String>) {
+        for cmd in self.commands.borrow().iter() {
+            if let MatrixCommand::DynNodeAssign { slot, name } = cmd {
+                match name {
+                    Some(name) => slots.insert(*slot, name.clone()),
+                    None => slots.remove(slot),
+                };
+            }
+        }
+    }
+}
+
+/// The side of the frame callback: Applies the queued [MatrixCommand]s and
+/// publishes a new [MatrixSnapshot], both with the matrix locked only once
+/// per frame. The writer and the [MatrixView] live on the GUI thread.
+pub struct MatrixSnapshotWriter {
+    current: Rc<RefCell<Arc<MatrixSnapshot>>>,
+    commands: Rc<RefCell<Vec<MatrixCommand>>>,
+    dirty: Arc<MatrixSnapshotDirty>,
+    dyn_nodes: Arc<DynNodeRegistry>,
+    daw_state: Option<Arc<DawState>>,
+    /// Swapped with the params of the [MatrixSnapshotDirty], so both keep their memory.
+    changed_params: Vec<ParamId>,
+}
+
+impl MatrixSnapshotWriter {
+    /// The `dyn_nodes` and `daw_state` of the frontend are
+    /// needed for the commands that edit them with the matrix.
+    pub fn new(
+        m: &mut Matrix,
+        dirty: Arc<MatrixSnapshotDirty>,
+        dyn_nodes: Arc<DynNodeRegistry>,
+        daw_state: Option<Arc<DawState>>,
+    ) -> (Self, MatrixView) {
+        let current = Rc::new(RefCell::new(Arc::new(MatrixSnapshot::capture(m))));
+        let commands = Rc::new(RefCell::new(vec![]));
+
+        (
+            Self {
+                current: current.clone(),
+                commands: commands.clone(),
+                dirty,
+                dyn_nodes,
+                daw_state,
+                changed_params: vec![],
+            },
+            MatrixView { current, commands },
+        )
+    }
+
+    /// Applies the edits queued by the UI models and records them
+    /// in the undo `history`.
+    pub fn apply_commands(&mut self, m: &mut Matrix, history: &mut MatrixHistory) {
+        let commands = std::mem::take(&mut *self.commands.borrow_mut());
+
+        for cmd in commands {
+            match cmd {
+                MatrixCommand::Param { param_id, value, record } => {
+                    let old = m.get_param(&param_id);
+                    m.set_param(param_id, value.clone());
+                    self.dirty.param_changed(param_id);
+
+                    if record {
+                        history.record(
+                            format!("Set {}", param_label(&param_id)),
+                            MatrixEdit::Param { param_id, old, new: value },
+                        );
+                    }
+                }
+                MatrixCommand::ModAmt { param_id, amt, record } => {
+                    let old = m.get_param_modamt(&param_id);
+
+                    // XXX: We ignore errors here, because setting a mod
+                    //      amount does indeed cause a matrix sync, but
+                    //      it does not change anything that might cause
+                    //      an error!
+                    let _ = m.set_param_modamt(param_id, amt);
+                    self.dirty.param_changed(param_id);
+
+                    if record {
+                        history.record(
+                            format!("Set modulation of {}", param_label(&param_id)),
+                            MatrixEdit::ModAmt { param_id, old, new: amt },
+                        );
+                    }
+                }
+                MatrixCommand::BeginGroup => history.begin_group(),
+                MatrixCommand::EndGroup(label) => history.end_group(label),
+                MatrixCommand::DynNodeAssign { slot, name } => {
+                    let mut slots = self.dyn_nodes.assignments(m);
+                    match name {
+                        Some(name) => slots.insert(slot, name),
+                        None => slots.remove(&slot),
+                    };
+                    dyn_nodes_store_in_matrix(m, &slots);
+                    self.dyn_nodes.load_from_matrix(m);
+                }
+                MatrixCommand::DawStateRepair => {
+                    if let Some(daw_state) = &self.daw_state {
+                        history.clear();
+
+                        let res = daw_state.repair(m).and_then(|report| {
+                            m.sync().map(|_| report).map_err(|e| format!("{:?}", e))
+                        });
+                        daw_state.set_repair_result(res);
+                        self.dirty.graph_changed();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Captures and publishes the snapshot for the next frame. The snapshot
+    /// is updated in place, unless a model still holds the last one.
+    pub fn publish(&mut self, m: &mut Matrix) {
+        let mut current = self.current.borrow_mut();
+        let snapshot = Arc::make_mut(&mut *current);
+
+        let graph_changed = self.dirty.graph.swap(false, Ordering::Relaxed)
+            || m.get_generation() != snapshot.graph.generation;
+        if let Ok(mut params) = self.dirty.params.lock() {
+            std::mem::swap(&mut *params, &mut self.changed_params);
+        }
+
+        if graph_changed {
+            snapshot.graph = Arc::new(SnapshotGraph::capture(m));
+            snapshot.params = Arc::new(SnapshotParams::capture(m, &snapshot.graph));
+        } else if !self.changed_params.is_empty() {
+            let params = Arc::make_mut(&mut snapshot.params);
+            for param_id in self.changed_params.iter() {
+                params.update(m, *param_id);
+            }
+        }
+        self.changed_params.clear();
+
+        snapshot.capture_monitors(m);
+    }
+}
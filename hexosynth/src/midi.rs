@@ -0,0 +1,84 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexodsp::nodes::HxTimedEvent;
+use hexodsp::HxMidiEvent;
+
+use crate::midi_expression::MidiExprEvent;
+
+/// Converts a raw MIDI message, as delivered by JACK or a MIDI device,
+/// into a [HxMidiEvent]. Returns `None` for messages HexoDSP does not handle.
+pub fn raw_midi2event(bytes: &[u8]) -> Option<HxMidiEvent> {
+    let status = *bytes.first()?;
+    let channel = status & 0x0F;
+
+    match status & 0xF0 {
+        0x90 if *bytes.get(2)? > 0 => Some(HxMidiEvent::NoteOn {
+            channel,
+            note: *bytes.get(1)? & 0x7F,
+            vel: (*bytes.get(2)? & 0x7F) as f32 / 127.0,
+        }),
+        0x80 | 0x90 => Some(HxMidiEvent::NoteOff { channel, note: *bytes.get(1)? & 0x7F }),
+        0xB0 => Some(HxMidiEvent::CC {
+            channel,
+            cc: *bytes.get(1)? & 0x7F,
+            value: (*bytes.get(2)? & 0x7F) as f32 / 127.0,
+        }),
+        _ => None,
+    }
+}
+
+/// Converts the raw MIDI messages that [raw_midi2event] does not handle:
+/// pitch bend, channel pressure, poly pressure and program change.
+pub fn raw_midi2expr(bytes: &[u8]) -> Option<MidiExprEvent> {
+    let status = *bytes.first()?;
+    let channel = status & 0x0F;
+
+    match status & 0xF0 {
+        0xE0 => {
+            let bend = ((*bytes.get(2)? as u16 & 0x7F) << 7) | (*bytes.get(1)? as u16 & 0x7F);
+            Some(MidiExprEvent::PitchBend {
+                channel,
+                value: ((bend as f32 - 8192.0) / 8192.0).clamp(-1.0, 1.0),
+            })
+        }
+        0xD0 => Some(MidiExprEvent::ChannelPressure {
+            channel,
+            value: (*bytes.get(1)? & 0x7F) as f32 / 127.0,
+        }),
+        0xA0 => Some(MidiExprEvent::PolyPressure {
+            channel,
+            note: *bytes.get(1)? & 0x7F,
+            value: (*bytes.get(2)? & 0x7F) as f32 / 127.0,
+        }),
+        0xC0 => Some(MidiExprEvent::ProgramChange { channel, program: *bytes.get(1)? & 0x7F }),
+        _ => None,
+    }
+}
+
+/// Converts a [HxMidiEvent] into a raw MIDI message for JACK or a MIDI device.
+pub fn event2raw_midi(ev: &HxMidiEvent) -> [u8; 3] {
+    let to7bit = |v: f32| (v.clamp(0.0, 1.0) * 127.0).round() as u8;
+
+    match *ev {
+        HxMidiEvent::NoteOn { channel, note, vel } => {
+            [0x90 | (channel & 0x0F), note & 0x7F, to7bit(vel).max(1)]
+        }
+        HxMidiEvent::NoteOff { channel, note } => [0x80 | (channel & 0x0F), note & 0x7F, 0],
+        HxMidiEvent::CC { channel, cc, value } => {
+            [0xB0 | (channel & 0x0F), cc & 0x7F, to7bit(value)]
+        }
+    }
+}
+
+/// Attaches the frame offset `timing` inside the current audio buffer to the event.
+pub fn midi_event2timed(timing: usize, ev: HxMidiEvent) -> HxTimedEvent {
+    match ev {
+        HxMidiEvent::NoteOn { channel, note, vel } => {
+            HxTimedEvent::note_on(timing, channel, note, vel)
+        }
+        HxMidiEvent::NoteOff { channel, note } => HxTimedEvent::note_off(timing, channel, note),
+        HxMidiEvent::CC { channel, cc, value } => HxTimedEvent::cc(timing, channel, cc, value),
+    }
+}
@@ -0,0 +1,361 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexodsp::{DynNode1x1Context, DynamicNode1x1, HxMidiEvent};
+
+use ringbuf::{Consumer, Producer, RingBuffer};
+
+use crate::dyn_nodes::DynNodeRegistry;
+
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+
+const MIDI_CHANNELS: usize = 16;
+const MIDI_NOTES: usize = 128;
+const LOG_QUEUE_SIZE: usize = 256;
+/// Marks a channel without a played note.
+const NO_NOTE: u8 = 0xFF;
+
+/// The per note expressions of nih_plug and CLAP.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoteExpr {
+    /// Gain factor, 0.0 to 4.0.
+    Volume,
+    /// -1.0 to 1.0.
+    Pan,
+    /// In semitones, -128.0 to 128.0.
+    Tuning,
+    Vibrato,
+    Expression,
+    Brightness,
+}
+
+impl NoteExpr {
+    const ALL: [NoteExpr; 6] = [
+        NoteExpr::Volume,
+        NoteExpr::Pan,
+        NoteExpr::Tuning,
+        NoteExpr::Vibrato,
+        NoteExpr::Expression,
+        NoteExpr::Brightness,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NoteExpr::Volume => "volume",
+            NoteExpr::Pan => "pan",
+            NoteExpr::Tuning => "tuning",
+            NoteExpr::Vibrato => "vibrato",
+            NoteExpr::Expression => "expression",
+            NoteExpr::Brightness => "brightness",
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<Self> {
+        NoteExpr::ALL.iter().find(|e| e.name() == s).copied()
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+
+    fn default_value(&self) -> f32 {
+        match self {
+            NoteExpr::Volume => 1.0,
+            _ => 0.0,
+        }
+    }
+}
+
+/// The MIDI events that HexoDSP does not handle itself, next to [HxMidiEvent].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MidiExprEvent {
+    /// -1.0 to 1.0
+    PitchBend {
+        channel: u8,
+        value: f32,
+    },
+    /// 0.0 to 1.0
+    ChannelPressure {
+        channel: u8,
+        value: f32,
+    },
+    /// 0.0 to 1.0
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        value: f32,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    NoteExpression {
+        channel: u8,
+        note: u8,
+        expr: NoteExpr,
+        value: f32,
+    },
+}
+
+/// What an [ExpressionNode] outputs. Each output is registered as
+/// its own prototype, see [register_expression_nodes].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpressionOutput {
+    PitchBend,
+    ChannelPressure,
+    /// Of the last played note on the channel.
+    PolyPressure,
+    /// Of the last played note on the channel.
+    Note(NoteExpr),
+    /// The last program change, 0.0 to 1.0 for the programs 0 to 127.
+    Program,
+}
+
+impl ExpressionOutput {
+    pub const ALL: [ExpressionOutput; 10] = [
+        ExpressionOutput::PitchBend,
+        ExpressionOutput::ChannelPressure,
+        ExpressionOutput::PolyPressure,
+        ExpressionOutput::Note(NoteExpr::Volume),
+        ExpressionOutput::Note(NoteExpr::Pan),
+        ExpressionOutput::Note(NoteExpr::Tuning),
+        ExpressionOutput::Note(NoteExpr::Vibrato),
+        ExpressionOutput::Note(NoteExpr::Expression),
+        ExpressionOutput::Note(NoteExpr::Brightness),
+        ExpressionOutput::Program,
+    ];
+
+    /// The name of the prototype in the [DynNodeRegistry].
+    pub fn proto_name(&self) -> String {
+        match self {
+            ExpressionOutput::PitchBend => "midi_pitch_bend".to_string(),
+            ExpressionOutput::ChannelPressure => "midi_channel_pressure".to_string(),
+            ExpressionOutput::PolyPressure => "midi_poly_pressure".to_string(),
+            ExpressionOutput::Note(expr) => format!("midi_note_{}", expr.name()),
+            ExpressionOutput::Program => "midi_program".to_string(),
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            ExpressionOutput::PitchBend => "Outputs the pitch bend, -1.0 to 1.0.",
+            ExpressionOutput::ChannelPressure => "Outputs the channel pressure, 0.0 to 1.0.",
+            ExpressionOutput::PolyPressure => {
+                "Outputs the poly pressure of the last played note, 0.0 to 1.0."
+            }
+            ExpressionOutput::Note(NoteExpr::Volume) => {
+                "Outputs the volume expression of the last played note, a gain of 0.0 to 4.0."
+            }
+            ExpressionOutput::Note(NoteExpr::Pan) => {
+                "Outputs the pan expression of the last played note, -1.0 to 1.0."
+            }
+            ExpressionOutput::Note(NoteExpr::Tuning) => {
+                "Outputs the tuning expression of the last played note, in semitones."
+            }
+            ExpressionOutput::Note(NoteExpr::Vibrato) => {
+                "Outputs the vibrato expression of the last played note, 0.0 to 1.0."
+            }
+            ExpressionOutput::Note(NoteExpr::Expression) => {
+                "Outputs the expression of the last played note, 0.0 to 1.0."
+            }
+            ExpressionOutput::Note(NoteExpr::Brightness) => {
+                "Outputs the brightness expression of the last played note, 0.0 to 1.0."
+            }
+            ExpressionOutput::Program => {
+                "Outputs the last program change, 0.0 to 1.0 for the programs 0 to 127."
+            }
+        }
+    }
+}
+
+/// The current pitch bend, pressure, program and note expressions of all
+/// MIDI channels. Updated by the frontends from the audio or MIDI thread,
+/// and read by the [ExpressionNode]s in the DSP graph.
+///
+/// The events are also queued for the MIDI log in the GUI.
+pub struct MidiExpression {
+    pitch_bend: Vec<AtomicU32>,
+    channel_pressure: Vec<AtomicU32>,
+    program: Vec<AtomicU8>,
+    last_note: Vec<AtomicU8>,
+    last_channel: AtomicU8,
+    poly_pressure: Vec<AtomicU32>,
+    note_expr: Vec<AtomicU32>,
+    log_prod: Mutex<Producer<MidiExprEvent>>,
+    log_cons: Mutex<Consumer<MidiExprEvent>>,
+}
+
+fn atomic_f32_vec(len: usize, v: f32) -> Vec<AtomicU32> {
+    (0..len).map(|_| AtomicU32::new(v.to_bits())).collect()
+}
+
+fn load_f32(v: &AtomicU32) -> f32 {
+    f32::from_bits(v.load(Ordering::Relaxed))
+}
+
+fn store_f32(v: &AtomicU32, f: f32) {
+    v.store(f.to_bits(), Ordering::Relaxed);
+}
+
+impl MidiExpression {
+    pub fn new() -> Arc<Self> {
+        let (log_prod, log_cons) = RingBuffer::new(LOG_QUEUE_SIZE).split();
+
+        let note_expr = (0..(MIDI_CHANNELS * MIDI_NOTES))
+            .flat_map(|_| NoteExpr::ALL.iter().map(|e| AtomicU32::new(e.default_value().to_bits())))
+            .collect();
+
+        Arc::new(Self {
+            pitch_bend: atomic_f32_vec(MIDI_CHANNELS, 0.0),
+            channel_pressure: atomic_f32_vec(MIDI_CHANNELS, 0.0),
+            program: (0..MIDI_CHANNELS).map(|_| AtomicU8::new(0)).collect(),
+            last_note: (0..MIDI_CHANNELS).map(|_| AtomicU8::new(NO_NOTE)).collect(),
+            last_channel: AtomicU8::new(0),
+            poly_pressure: atomic_f32_vec(MIDI_CHANNELS * MIDI_NOTES, 0.0),
+            note_expr,
+            log_prod: Mutex::new(log_prod),
+            log_cons: Mutex::new(log_cons),
+        })
+    }
+
+    fn note_idx(channel: u8, note: u8) -> usize {
+        (channel as usize % MIDI_CHANNELS) * MIDI_NOTES + (note as usize % MIDI_NOTES)
+    }
+
+    /// Tracks the last played note of each channel, the per note
+    /// values of the [ExpressionNode] are taken from that note.
+    pub fn note_event(&self, ev: &HxMidiEvent) {
+        if let HxMidiEvent::NoteOn { channel, note, .. } = ev {
+            self.note_on(*channel, *note);
+        }
+    }
+
+    /// See [MidiExpression::note_event].
+    pub fn note_on(&self, channel: u8, note: u8) {
+        let ch = channel as usize % MIDI_CHANNELS;
+        self.last_note[ch].store(note, Ordering::Relaxed);
+        self.last_channel.store(ch as u8, Ordering::Relaxed);
+
+        // A new note starts without the expression of the previous one:
+        let idx = Self::note_idx(channel, note);
+        store_f32(&self.poly_pressure[idx], 0.0);
+
+        let expr_idx = idx * NoteExpr::ALL.len();
+        for e in NoteExpr::ALL.iter() {
+            store_f32(&self.note_expr[expr_idx + e.index()], e.default_value());
+        }
+    }
+
+    /// Stores the event and queues it for the MIDI log. Does not block,
+    /// the event is not logged if the log is currently read by the GUI.
+    pub fn handle(&self, ev: MidiExprEvent) {
+        match ev {
+            MidiExprEvent::PitchBend { channel, value } => {
+                store_f32(&self.pitch_bend[channel as usize % MIDI_CHANNELS], value);
+            }
+            MidiExprEvent::ChannelPressure { channel, value } => {
+                store_f32(&self.channel_pressure[channel as usize % MIDI_CHANNELS], value);
+            }
+            MidiExprEvent::PolyPressure { channel, note, value } => {
+                store_f32(&self.poly_pressure[Self::note_idx(channel, note)], value);
+            }
+            MidiExprEvent::ProgramChange { channel, program } => {
+                self.program[channel as usize % MIDI_CHANNELS].store(program, Ordering::Relaxed);
+            }
+            MidiExprEvent::NoteExpression { channel, note, expr, value } => {
+                let idx = Self::note_idx(channel, note) * NoteExpr::ALL.len() + expr.index();
+                store_f32(&self.note_expr[idx], value);
+            }
+        }
+
+        if let Ok(mut prod) = self.log_prod.try_lock() {
+            let _ = prod.push(ev);
+        }
+    }
+
+    pub fn program(&self, channel: u8) -> u8 {
+        self.program[channel as usize % MIDI_CHANNELS].load(Ordering::Relaxed)
+    }
+
+    /// Returns the current value of `output` on `channel`.
+    /// If `channel` is `None`, the channel of the last played note is used.
+    pub fn value(&self, output: ExpressionOutput, channel: Option<u8>) -> f32 {
+        let ch = channel.unwrap_or_else(|| self.last_channel.load(Ordering::Relaxed)) as usize
+            % MIDI_CHANNELS;
+        let note = self.last_note[ch].load(Ordering::Relaxed);
+
+        match output {
+            ExpressionOutput::PitchBend => load_f32(&self.pitch_bend[ch]),
+            ExpressionOutput::ChannelPressure => load_f32(&self.channel_pressure[ch]),
+            ExpressionOutput::PolyPressure if note != NO_NOTE => {
+                load_f32(&self.poly_pressure[Self::note_idx(ch as u8, note)])
+            }
+            ExpressionOutput::Note(expr) if note != NO_NOTE => {
+                let idx = Self::note_idx(ch as u8, note) * NoteExpr::ALL.len() + expr.index();
+                load_f32(&self.note_expr[idx])
+            }
+            ExpressionOutput::PolyPressure => 0.0,
+            ExpressionOutput::Note(expr) => expr.default_value(),
+            ExpressionOutput::Program => self.program(ch as u8) as f32 / 127.0,
+        }
+    }
+
+    /// Calls `f` for every event that was received since the last call.
+    pub fn drain_log<F: FnMut(MidiExprEvent)>(&self, mut f: F) {
+        if let Ok(mut cons) = self.log_cons.lock() {
+            while let Some(ev) = cons.pop() {
+                f(ev);
+            }
+        }
+    }
+}
+
+/// Outputs one of the [ExpressionOutput]s. `alpha` selects the MIDI channel:
+/// 0.0 follows the channel of the last played note, 1/16 to 16/16 are
+/// the channels 1 to 16. The input is ignored.
+pub struct ExpressionNode {
+    expression: Arc<MidiExpression>,
+    output: ExpressionOutput,
+}
+
+impl ExpressionNode {
+    pub fn new(expression: Arc<MidiExpression>, output: ExpressionOutput) -> Self {
+        Self { expression, output }
+    }
+}
+
+impl DynamicNode1x1 for ExpressionNode {
+    fn set_sample_rate(&mut self, _srate: f32) {}
+
+    fn reset(&mut self) {}
+
+    fn process(&mut self, _input: &[f32], output: &mut [f32], ctx: &DynNode1x1Context) {
+        let alpha = ctx.alpha_slice();
+
+        for (i, out) in output.iter_mut().enumerate() {
+            let channel = (alpha[i].clamp(0.0, 1.0) * MIDI_CHANNELS as f32).round() as u8;
+            let channel = if channel == 0 { None } else { Some(channel - 1) };
+
+            *out = self.expression.value(self.output, channel);
+        }
+    }
+}
+
+/// Registers an [ExpressionNode] prototype for each of the [ExpressionOutput]s,
+/// named by [ExpressionOutput::proto_name].
+pub fn register_expression_nodes(registry: &DynNodeRegistry, expression: Arc<MidiExpression>) {
+    for output in ExpressionOutput::ALL.iter().copied() {
+        let expression = expression.clone();
+        registry.register(
+            &output.proto_name(),
+            &format!(
+                "{} `alpha` selects the MIDI channel, 0.0 follows the channel of \
+                 the last played note.",
+                output.description()
+            ),
+            move || Box::new(ExpressionNode::new(expression.clone(), output)),
+        );
+    }
+}
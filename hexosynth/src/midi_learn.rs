@@ -0,0 +1,367 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexodsp::{HxMidiEvent, Matrix, NodeId, ParamId, SAtom};
+
+use ringbuf::{Consumer, Producer, RingBuffer};
+use serde::{Deserialize, Serialize};
+
+use crate::prop_watch::PropWatcher;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{JoinHandle, Thread};
+use std::time::Duration;
+
+/// The matrix property that holds the MIDI learn bindings as JSON string.
+pub const MIDI_LEARN_PROP: &str = "midi_learn";
+
+const CC_QUEUE_SIZE: usize = 256;
+
+/// How the CC value is mapped to the parameter range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MidiLearnCurve {
+    Linear,
+    /// Squared, more resolution at the start of the range.
+    Exp,
+    /// Square root, more resolution at the end of the range.
+    Log,
+}
+
+impl MidiLearnCurve {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MidiLearnCurve::Linear => "lin",
+            MidiLearnCurve::Exp => "exp",
+            MidiLearnCurve::Log => "log",
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "lin" => Some(MidiLearnCurve::Linear),
+            "exp" => Some(MidiLearnCurve::Exp),
+            "log" => Some(MidiLearnCurve::Log),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, x: f32) -> f32 {
+        match self {
+            MidiLearnCurve::Linear => x,
+            MidiLearnCurve::Exp => x * x,
+            MidiLearnCurve::Log => x.sqrt(),
+        }
+    }
+}
+
+/// Binds a MIDI CC on a channel to a parameter of the matrix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MidiBinding {
+    pub channel: u8,
+    pub cc: u8,
+    pub param_id: ParamId,
+    /// The normalized parameter value for the CC value 0.
+    pub min: f32,
+    /// The normalized parameter value for the CC value 127.
+    pub max: f32,
+    pub curve: MidiLearnCurve,
+}
+
+impl MidiBinding {
+    /// Maps the CC value (0.0 to 1.0) to the normalized parameter value.
+    pub fn map(&self, value: f32) -> f32 {
+        self.min + (self.max - self.min) * self.curve.apply(value.clamp(0.0, 1.0))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BindingRepr {
+    channel: u8,
+    cc: u8,
+    node: String,
+    instance: usize,
+    param: String,
+    min: f32,
+    max: f32,
+    curve: String,
+}
+
+impl BindingRepr {
+    fn from_binding(b: &MidiBinding) -> Self {
+        Self {
+            channel: b.channel,
+            cc: b.cc,
+            node: b.param_id.node_id().name().to_string(),
+            instance: b.param_id.node_id().instance(),
+            param: b.param_id.name().to_string(),
+            min: b.min,
+            max: b.max,
+            curve: b.curve.name().to_string(),
+        }
+    }
+
+    fn to_binding(&self) -> Option<MidiBinding> {
+        let param_id =
+            NodeId::from_str(&self.node).to_instance(self.instance).inp_param(&self.param)?;
+
+        Some(MidiBinding {
+            channel: self.channel,
+            cc: self.cc,
+            param_id,
+            min: self.min,
+            max: self.max,
+            curve: MidiLearnCurve::from_name(&self.curve).unwrap_or(MidiLearnCurve::Linear),
+        })
+    }
+}
+
+/// The state of the MIDI learn.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MidiLearnState {
+    Idle,
+    /// Waiting for the user to touch a knob, which is then armed.
+    WaitKnob,
+    /// Waiting for a CC, which is then bound to the parameter.
+    Armed {
+        param_id: ParamId,
+        min: f32,
+        max: f32,
+        curve: MidiLearnCurve,
+    },
+}
+
+/// The MIDI learn mapping layer.
+///
+/// The CC events are passed to [MidiLearn::handle_cc] by the
+/// [MidiLearnWorker], which then applies the queued parameter changes
+/// to the matrix with [MidiLearn::apply].
+#[derive(Debug, Clone)]
+pub struct MidiLearn {
+    bindings: Vec<MidiBinding>,
+    state: MidiLearnState,
+    pending: Vec<(ParamId, f32)>,
+    bindings_changed: bool,
+    /// The property the bindings were last loaded from or stored in.
+    prop: PropWatcher,
+}
+
+impl MidiLearn {
+    pub fn new() -> Self {
+        Self {
+            bindings: vec![],
+            state: MidiLearnState::Idle,
+            pending: vec![],
+            bindings_changed: false,
+            prop: PropWatcher::new(MIDI_LEARN_PROP),
+        }
+    }
+
+    pub fn state(&self) -> &MidiLearnState {
+        &self.state
+    }
+
+    pub fn bindings(&self) -> &[MidiBinding] {
+        &self.bindings
+    }
+
+    /// Arms the learn for the next knob the user touches.
+    pub fn start(&mut self) {
+        self.state = MidiLearnState::WaitKnob;
+    }
+
+    /// Arms the learn for `param_id`. The next CC is bound to it.
+    pub fn arm(&mut self, param_id: ParamId, min: f32, max: f32, curve: MidiLearnCurve) {
+        self.state = MidiLearnState::Armed { param_id, min, max, curve };
+    }
+
+    pub fn cancel(&mut self) {
+        self.state = MidiLearnState::Idle;
+    }
+
+    pub fn is_armed_for(&self, param_id: ParamId) -> bool {
+        matches!(&self.state, MidiLearnState::Armed { param_id: p, .. } if *p == param_id)
+    }
+
+    /// Called by the knobs when the user starts changing them.
+    pub fn knob_touched(&mut self, param_id: ParamId) {
+        if self.state == MidiLearnState::WaitKnob {
+            self.arm(param_id, 0.0, 1.0, MidiLearnCurve::Linear);
+        }
+    }
+
+    /// Removes the binding at `idx`, returns false if there is none.
+    pub fn remove(&mut self, idx: usize) -> bool {
+        if idx >= self.bindings.len() {
+            return false;
+        }
+
+        self.bindings.remove(idx);
+        self.bindings_changed = true;
+        true
+    }
+
+    /// Called for every received CC. Binds the CC if the learn is armed
+    /// and queues the new values of the bound parameters.
+    pub fn handle_cc(&mut self, channel: u8, cc: u8, value: f32) {
+        if let MidiLearnState::Armed { param_id, min, max, curve } = self.state.clone() {
+            // A parameter is controlled by one CC only, learning it again replaces it:
+            self.bindings.retain(|b| b.param_id != param_id);
+            self.bindings.push(MidiBinding { channel, cc, param_id, min, max, curve });
+            self.bindings_changed = true;
+            self.state = MidiLearnState::Idle;
+        }
+
+        for b in self.bindings.iter() {
+            if b.channel == channel && b.cc == cc {
+                let v = b.map(value);
+
+                if let Some(p) = self.pending.iter_mut().find(|(pid, _)| *pid == b.param_id) {
+                    p.1 = v;
+                } else {
+                    self.pending.push((b.param_id, v));
+                }
+            }
+        }
+    }
+
+    /// Applies the queued parameter changes and keeps the bindings
+    /// in sync with the matrix property.
+    pub fn apply(&mut self, matrix: &mut Matrix) {
+        if self.bindings_changed {
+            self.store_in_matrix(matrix);
+        } else {
+            self.load_from_matrix(matrix);
+        }
+
+        for (param_id, v) in self.pending.drain(..) {
+            matrix.set_param(param_id, SAtom::param(v));
+        }
+    }
+
+    /// Stores the bindings in the matrix property, so they are
+    /// saved with the patch.
+    pub fn store_in_matrix(&mut self, matrix: &mut Matrix) {
+        let reprs: Vec<BindingRepr> = self.bindings.iter().map(BindingRepr::from_binding).collect();
+
+        if let Ok(s) = serde_json::to_string(&reprs) {
+            self.prop.store(matrix, &s);
+        }
+
+        self.bindings_changed = false;
+    }
+
+    /// Loads the bindings from the matrix property, if it changed.
+    /// For example because a different patch was loaded.
+    pub fn load_from_matrix(&mut self, matrix: &Matrix) {
+        if let Some(s) = self.prop.changed(matrix) {
+            let reprs: Vec<BindingRepr> = serde_json::from_str(&s).unwrap_or_default();
+            self.bindings = reprs.iter().filter_map(|r| r.to_binding()).collect();
+        }
+    }
+}
+
+impl Default for MidiLearn {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs the [MidiLearn] on a thread of its own, so the bound parameters
+/// follow the CCs also while the GUI is closed.
+///
+/// The frontends pass the received MIDI events from the audio thread to
+/// [MidiLearnWorker::handle_midi_event], which queues the CCs without
+/// blocking and wakes up the worker. The worker locks the matrix only
+/// when CCs were received. The thread is stopped and joined on drop.
+pub struct MidiLearnWorker {
+    midi_learn: Arc<Mutex<MidiLearn>>,
+    cc_prod: Mutex<Producer<(u8, u8, f32)>>,
+    stop: Arc<AtomicBool>,
+    thread: Thread,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl MidiLearnWorker {
+    pub fn start(matrix: Arc<Mutex<Matrix>>) -> Arc<Self> {
+        let midi_learn = Arc::new(Mutex::new(MidiLearn::new()));
+        let (cc_prod, cc_cons) = RingBuffer::new(CC_QUEUE_SIZE).split();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = std::thread::spawn({
+            let midi_learn = midi_learn.clone();
+            let stop = stop.clone();
+            move || Self::run(matrix, midi_learn, cc_cons, stop)
+        });
+
+        Arc::new(Self {
+            midi_learn,
+            cc_prod: Mutex::new(cc_prod),
+            stop,
+            thread: handle.thread().clone(),
+            handle: Mutex::new(Some(handle)),
+        })
+    }
+
+    fn run(
+        matrix: Arc<Mutex<Matrix>>,
+        midi_learn: Arc<Mutex<MidiLearn>>,
+        mut cc_cons: Consumer<(u8, u8, f32)>,
+        stop: Arc<AtomicBool>,
+    ) {
+        let mut ccs = vec![];
+
+        while !stop.load(Ordering::Relaxed) {
+            // The timeout only guards against a missed wake up:
+            std::thread::park_timeout(Duration::from_millis(100));
+
+            while let Some(cc) = cc_cons.pop() {
+                ccs.push(cc);
+            }
+            if ccs.is_empty() {
+                continue;
+            }
+
+            // The matrix is locked before the MIDI learn, like in the WLambda API:
+            let mut m = match matrix.lock() {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+            if let Ok(mut ml) = midi_learn.lock() {
+                for (channel, cc, value) in ccs.drain(..) {
+                    ml.handle_cc(channel, cc, value);
+                }
+                ml.apply(&mut m);
+            }
+            ccs.clear();
+        }
+    }
+
+    /// The MIDI learn state, for the GUI.
+    pub fn learn(&self) -> &Arc<Mutex<MidiLearn>> {
+        &self.midi_learn
+    }
+
+    /// Queues the CCs for the worker. Does not block, the CC is dropped
+    /// if the queue is full or currently used by another thread.
+    pub fn handle_midi_event(&self, ev: &HxMidiEvent) {
+        if let HxMidiEvent::CC { channel, cc, value } = *ev {
+            if let Ok(mut prod) = self.cc_prod.try_lock() {
+                let _ = prod.push((channel, cc, value));
+            }
+            self.thread.unpark();
+        }
+    }
+}
+
+impl Drop for MidiLearnWorker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.unpark();
+
+        if let Some(handle) = self.handle.lock().ok().and_then(|mut h| h.take()) {
+            let _ = handle.join();
+        }
+    }
+}
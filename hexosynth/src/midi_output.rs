@@ -0,0 +1,240 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexodsp::{DynNode1x1Context, DynamicNode1x1, HxMidiEvent};
+
+use ringbuf::{Consumer, Producer, RingBuffer};
+
+use crate::dyn_nodes::DynNodeRegistry;
+
+use std::sync::{Arc, Mutex};
+
+/// The name of the [MidiNoteOutNode] prototype in the [DynNodeRegistry].
+pub const MIDI_NOTE_OUT_NODE_NAME: &str = "midi_note_out";
+/// The name of the [MidiCCOutNode] prototype in the [DynNodeRegistry].
+pub const MIDI_CC_OUT_NODE_NAME: &str = "midi_cc_out";
+
+const MIDI_OUT_QUEUE_SIZE: usize = 1024;
+
+/// A MIDI event sent by a node, `timing` is the frame offset
+/// inside the block that was processed.
+#[derive(Debug, Clone, Copy)]
+pub struct MidiOutEvent {
+    pub timing: usize,
+    pub event: HxMidiEvent,
+}
+
+/// The queue between the MIDI output nodes in the DSP graph and the
+/// frontend, which sends the events to the host or a MIDI port after
+/// each processed block.
+///
+/// Both ends are only used from the audio thread, the locks never wait.
+/// If the queue is full, the events are dropped.
+pub struct MidiOutput {
+    prod: Mutex<Producer<MidiOutEvent>>,
+    cons: Mutex<Consumer<MidiOutEvent>>,
+    /// Preallocated, for sorting the events of a block by their timing.
+    sorted: Mutex<Vec<MidiOutEvent>>,
+    /// The events that are delayed into the next block, see [MidiOutput::drain_block].
+    delayed: Mutex<Vec<MidiOutEvent>>,
+}
+
+impl MidiOutput {
+    pub fn new() -> Arc<Self> {
+        let (prod, cons) = RingBuffer::new(MIDI_OUT_QUEUE_SIZE).split();
+        Arc::new(Self {
+            prod: Mutex::new(prod),
+            cons: Mutex::new(cons),
+            sorted: Mutex::new(Vec::with_capacity(MIDI_OUT_QUEUE_SIZE)),
+            delayed: Mutex::new(Vec::with_capacity(MIDI_OUT_QUEUE_SIZE)),
+        })
+    }
+
+    pub fn send(&self, timing: usize, event: HxMidiEvent) {
+        if let Ok(mut prod) = self.prod.try_lock() {
+            let _ = prod.push(MidiOutEvent { timing, event });
+        }
+    }
+
+    /// Calls `f` for every event that was sent since the last call,
+    /// ordered by their timing. JACK and the hosts expect them in order,
+    /// but each node sends the events of the whole block at once.
+    pub fn drain<F: FnMut(MidiOutEvent)>(&self, mut f: F) {
+        let (mut cons, mut sorted) = match (self.cons.try_lock(), self.sorted.try_lock()) {
+            (Ok(cons), Ok(sorted)) => (cons, sorted),
+            _ => return,
+        };
+
+        // An insertion sort, which keeps the order of the events with the same timing:
+        while let Some(ev) = cons.pop() {
+            let pos = sorted.iter().rposition(|e| e.timing <= ev.timing).map_or(0, |p| p + 1);
+            sorted.insert(pos, ev);
+        }
+
+        for ev in sorted.drain(..) {
+            f(ev);
+        }
+    }
+
+    /// Like [MidiOutput::drain], for the frontends that send the events
+    /// with a frame offset in the block of `nframes` frames. `offs` is the
+    /// frame where the processed chunk starts, the timing of the events is
+    /// divided by the oversampling `factor` and delayed by the `latency` of
+    /// the oversampling filters, so the events stay in sync with the audio.
+    ///
+    /// Events that are delayed past the end of the block are passed to `f`
+    /// at the start of the next block, which has to be drained with `offs`
+    /// 0 first. The timings passed to `f` are in order and below `nframes`.
+    pub fn drain_block<F: FnMut(MidiOutEvent)>(
+        &self,
+        offs: usize,
+        factor: usize,
+        latency: usize,
+        nframes: usize,
+        mut f: F,
+    ) {
+        let mut delayed = match self.delayed.try_lock() {
+            Ok(delayed) => delayed,
+            Err(_) => return,
+        };
+
+        let last_frame = nframes.saturating_sub(1);
+
+        if offs == 0 {
+            for ev in delayed.drain(..) {
+                f(MidiOutEvent { timing: ev.timing.min(last_frame), ..ev });
+            }
+        }
+
+        self.drain(|ev| {
+            let timing = offs + ev.timing / factor.max(1) + latency;
+
+            if timing < nframes {
+                f(MidiOutEvent { timing, ..ev });
+            } else if delayed.len() < delayed.capacity() {
+                delayed.push(MidiOutEvent { timing: timing - nframes, ..ev });
+            }
+        });
+    }
+}
+
+/// Maps a HexoDSP pitch signal (0.0 is A4, 0.1 per octave) to a MIDI note.
+pub fn pitch2midi_note(pitch: f32) -> u8 {
+    (69.0 + pitch * 120.0).round().clamp(0.0, 127.0) as u8
+}
+
+fn norm2channel(v: f32) -> u8 {
+    (v.clamp(0.0, 1.0) * 15.0).round() as u8
+}
+
+/// Sends a note while the input gate is above 0.5. The note is taken from
+/// the pitch at `alpha` when the gate opens, `beta` is the velocity and
+/// `gamma` selects the MIDI channel 1 to 16 (0.0 to 1.0).
+/// The gate is passed through to the output.
+pub struct MidiNoteOutNode {
+    output: Arc<MidiOutput>,
+    /// The channel and note that is currently held.
+    playing: Option<(u8, u8)>,
+}
+
+impl MidiNoteOutNode {
+    pub fn new(output: Arc<MidiOutput>) -> Self {
+        Self { output, playing: None }
+    }
+
+    fn note_off(&mut self, timing: usize) {
+        if let Some((channel, note)) = self.playing.take() {
+            self.output.send(timing, HxMidiEvent::NoteOff { channel, note });
+        }
+    }
+}
+
+impl DynamicNode1x1 for MidiNoteOutNode {
+    fn set_sample_rate(&mut self, _srate: f32) {}
+
+    fn reset(&mut self) {
+        self.note_off(0);
+    }
+
+    fn process(&mut self, input: &[f32], output: &mut [f32], ctx: &DynNode1x1Context) {
+        let alpha = ctx.alpha_slice();
+        let beta = ctx.beta_slice();
+        let gamma = ctx.gamma_slice();
+
+        for (i, (inp, out)) in input.iter().zip(output.iter_mut()).enumerate() {
+            let gate = *inp > 0.5;
+
+            if gate && self.playing.is_none() {
+                let channel = norm2channel(gamma[i]);
+                let note = pitch2midi_note(alpha[i]);
+                let vel = beta[i].clamp(0.0, 1.0);
+                self.output.send(i, HxMidiEvent::NoteOn { channel, note, vel });
+                self.playing = Some((channel, note));
+            } else if !gate {
+                self.note_off(i);
+            }
+
+            *out = *inp;
+        }
+    }
+}
+
+/// Sends the input signal (0.0 to 1.0) as MIDI CC whenever its 7 bit value
+/// changes. `alpha` selects the CC number 0 to 127 (0.0 to 1.0) and `beta`
+/// the MIDI channel 1 to 16. The input is passed through to the output.
+pub struct MidiCCOutNode {
+    output: Arc<MidiOutput>,
+    /// The channel, CC number and value that was sent last.
+    last: Option<(u8, u8, u8)>,
+}
+
+impl MidiCCOutNode {
+    pub fn new(output: Arc<MidiOutput>) -> Self {
+        Self { output, last: None }
+    }
+}
+
+impl DynamicNode1x1 for MidiCCOutNode {
+    fn set_sample_rate(&mut self, _srate: f32) {}
+
+    fn reset(&mut self) {
+        self.last = None;
+    }
+
+    fn process(&mut self, input: &[f32], output: &mut [f32], ctx: &DynNode1x1Context) {
+        let alpha = ctx.alpha_slice();
+        let beta = ctx.beta_slice();
+
+        for (i, (inp, out)) in input.iter().zip(output.iter_mut()).enumerate() {
+            let cc = (alpha[i].clamp(0.0, 1.0) * 127.0).round() as u8;
+            let channel = norm2channel(beta[i]);
+            let value = (inp.clamp(0.0, 1.0) * 127.0).round() as u8;
+
+            if self.last != Some((channel, cc, value)) {
+                self.output.send(i, HxMidiEvent::CC { channel, cc, value: value as f32 / 127.0 });
+                self.last = Some((channel, cc, value));
+            }
+
+            *out = *inp;
+        }
+    }
+}
+
+/// Registers the [MidiNoteOutNode] and [MidiCCOutNode] as prototypes
+/// [MIDI_NOTE_OUT_NODE_NAME] and [MIDI_CC_OUT_NODE_NAME].
+pub fn register_midi_output_nodes(registry: &DynNodeRegistry, output: Arc<MidiOutput>) {
+    let note_output = output.clone();
+    registry.register(
+        MIDI_NOTE_OUT_NODE_NAME,
+        "Sends a MIDI note while the input gate is above 0.5. `alpha` is the pitch, \
+         `beta` the velocity and `gamma` the MIDI channel.",
+        move || Box::new(MidiNoteOutNode::new(note_output.clone())),
+    );
+    registry.register(
+        MIDI_CC_OUT_NODE_NAME,
+        "Sends the input as MIDI CC whenever its 7 bit value changes. `alpha` is \
+         the CC number and `beta` the MIDI channel.",
+        move || Box::new(MidiCCOutNode::new(output.clone())),
+    );
+}
@@ -0,0 +1,176 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexodsp::dsp::MAX_BLOCK_SIZE;
+use hexodsp::{Context, HxMidiEvent, Matrix, NodeExecutor, ParamId, SAtom};
+
+use crate::midi::midi_event2timed;
+
+/// An event that is sent to the DSP graph while rendering offline.
+#[derive(Debug, Clone)]
+pub enum RenderEvent {
+    Midi(HxMidiEvent),
+    Param(ParamId, SAtom),
+}
+
+/// Renders the audio of a [Matrix] without an audio device.
+///
+/// The [NodeExecutor] is driven in chunks of at most [MAX_BLOCK_SIZE] frames.
+/// Parameter changes are applied sample accurate by splitting the chunks
+/// at the frame of the change. MIDI events are passed with their
+/// offset inside the chunk.
+pub struct OfflineRenderer {
+    matrix: Matrix,
+    node_exec: NodeExecutor,
+    sample_rate: f32,
+    events: Vec<(usize, RenderEvent)>,
+    next_event: usize,
+    frame: usize,
+}
+
+impl OfflineRenderer {
+    pub fn new(matrix: Matrix, mut node_exec: NodeExecutor, sample_rate: f32) -> Self {
+        node_exec.set_sample_rate(sample_rate);
+
+        Self { matrix, node_exec, sample_rate, events: vec![], next_event: 0, frame: 0 }
+    }
+
+    pub fn matrix(&mut self) -> &mut Matrix {
+        &mut self.matrix
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    /// The number of frames rendered so far.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn secs2frames(&self, secs: f64) -> usize {
+        (secs * self.sample_rate as f64).round().max(0.0) as usize
+    }
+
+    /// Schedules an event at the absolute frame `frame`.
+    /// Events scheduled before the already rendered frames are
+    /// sent at the start of the next rendered chunk.
+    pub fn add_event(&mut self, frame: usize, event: RenderEvent) {
+        let idx = self.events[self.next_event..]
+            .iter()
+            .position(|(f, _)| *f > frame)
+            .map(|i| i + self.next_event)
+            .unwrap_or(self.events.len());
+        self.events.insert(idx, (frame, event));
+    }
+
+    /// Renders the next `nframes` frames and appends them to
+    /// the left and right channel buffers.
+    pub fn render_into(&mut self, nframes: usize, out_l: &mut Vec<f32>, out_r: &mut Vec<f32>) {
+        let input_bufs = [[0.0; MAX_BLOCK_SIZE]; 2];
+        let mut output_bufs = [[0.0; MAX_BLOCK_SIZE]; 2];
+
+        let mut frames_left = nframes;
+
+        while frames_left > 0 {
+            let mut cur_nframes = frames_left.min(MAX_BLOCK_SIZE);
+
+            // Apply the parameter changes that are due and split the chunk
+            // at the next parameter change:
+            for (ev_frame, ev) in self.events[self.next_event..].iter() {
+                if *ev_frame >= self.frame + cur_nframes {
+                    break;
+                }
+
+                if let RenderEvent::Param(param_id, at) = ev {
+                    if *ev_frame <= self.frame {
+                        self.matrix.set_param(*param_id, at.clone());
+                    } else {
+                        cur_nframes = *ev_frame - self.frame;
+                        break;
+                    }
+                }
+            }
+
+            self.node_exec.process_graph_updates();
+
+            let events = &self.events[self.next_event..];
+            let frame = self.frame;
+            let mut ev_idx = 0;
+            self.node_exec.feed_midi_events_from(|| {
+                while let Some((ev_frame, ev)) = events.get(ev_idx) {
+                    if *ev_frame >= frame + cur_nframes {
+                        return None;
+                    }
+                    ev_idx += 1;
+
+                    if let RenderEvent::Midi(midi_ev) = ev {
+                        let timing = ev_frame.saturating_sub(frame);
+                        return Some(midi_event2timed(timing, *midi_ev));
+                    }
+                }
+
+                None
+            });
+
+            while let Some((ev_frame, _)) = self.events.get(self.next_event) {
+                if *ev_frame >= self.frame + cur_nframes {
+                    break;
+                }
+                self.next_event += 1;
+            }
+
+            let input = &[&input_bufs[0][0..cur_nframes], &input_bufs[1][0..cur_nframes]];
+
+            let split = output_bufs.split_at_mut(1);
+
+            let mut output =
+                [&mut ((split.0[0])[0..cur_nframes]), &mut ((split.1[0])[0..cur_nframes])];
+
+            let mut context = Context { nframes: cur_nframes, output: &mut output[..], input };
+
+            context.output[0].fill(0.0);
+            context.output[1].fill(0.0);
+
+            self.node_exec.process(&mut context);
+
+            out_l.extend_from_slice(&context.output[0][..]);
+            out_r.extend_from_slice(&context.output[1][..]);
+
+            self.frame += cur_nframes;
+            frames_left -= cur_nframes;
+        }
+    }
+
+    /// Renders the next `nframes` frames and returns the
+    /// left and right channel.
+    pub fn render(&mut self, nframes: usize) -> (Vec<f32>, Vec<f32>) {
+        let mut out_l = Vec::with_capacity(nframes);
+        let mut out_r = Vec::with_capacity(nframes);
+        self.render_into(nframes, &mut out_l, &mut out_r);
+        (out_l, out_r)
+    }
+}
+
+/// Writes a stereo 32 bit float WAV file.
+pub fn write_wav(
+    filename: &str,
+    sample_rate: u32,
+    out_l: &[f32],
+    out_r: &[f32],
+) -> Result<(), hound::Error> {
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+
+    let mut writer = hound::WavWriter::create(filename, spec)?;
+    for (l, r) in out_l.iter().zip(out_r.iter()) {
+        writer.write_sample(*l)?;
+        writer.write_sample(*r)?;
+    }
+    writer.finalize()
+}
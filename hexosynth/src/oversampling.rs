@@ -0,0 +1,251 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexodsp::dsp::MAX_BLOCK_SIZE;
+use hexodsp::{Matrix, SAtom};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The matrix property that holds the oversampling factor of the patch.
+pub const OVERSAMPLING_PROP: &str = "oversampling";
+
+/// The oversampling factors that can be selected. 1 turns it off.
+pub const OVERSAMPLING_FACTORS: [usize; 4] = [1, 2, 4, 8];
+
+/// Number of filter taps per oversampled phase. The up and down filters
+/// together delay the signal by exactly this many samples at the
+/// sample rate of the frontend.
+pub const OVERSAMPLING_TAPS_PER_PHASE: usize = 32;
+
+pub fn is_oversampling_factor(factor: usize) -> bool {
+    OVERSAMPLING_FACTORS.contains(&factor)
+}
+
+/// The oversampling factor of the patch, 1 if it has none.
+pub fn oversampling_from_matrix(matrix: &Matrix) -> usize {
+    match matrix.get_prop(OVERSAMPLING_PROP) {
+        Some(SAtom::Setting(i)) if is_oversampling_factor(i.max(0) as usize) => i as usize,
+        _ => 1,
+    }
+}
+
+pub fn oversampling_store_in_matrix(matrix: &mut Matrix, factor: usize) {
+    matrix.set_prop(OVERSAMPLING_PROP, SAtom::setting(factor as i64));
+}
+
+/// The oversampling factor, shared between the GUI and the [Oversampler]
+/// of the audio thread. [Oversampling::load_from_matrix] has to be called
+/// regularly to pick up changes of the [OVERSAMPLING_PROP].
+pub struct Oversampling {
+    factor: AtomicUsize,
+}
+
+impl Oversampling {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self { factor: AtomicUsize::new(1) })
+    }
+
+    pub fn factor(&self) -> usize {
+        self.factor.load(Ordering::Relaxed)
+    }
+
+    pub fn load_from_matrix(&self, matrix: &Matrix) -> bool {
+        let factor = oversampling_from_matrix(matrix);
+        self.factor.swap(factor, Ordering::Relaxed) != factor
+    }
+}
+
+/// A linear phase windowed sinc lowpass (Blackman window) with the cutoff
+/// a bit below the Nyquist frequency of the frontend sample rate.
+fn lowpass_coeffs(factor: usize) -> Vec<f32> {
+    let len = OVERSAMPLING_TAPS_PER_PHASE * factor + 1;
+    let m = (len - 1) as f64;
+    let fc = 0.45 / factor as f64;
+
+    let mut coeffs: Vec<f64> = (0..len)
+        .map(|i| {
+            let x = i as f64 - m / 2.0;
+            let sinc = if x == 0.0 {
+                2.0 * fc
+            } else {
+                (2.0 * std::f64::consts::PI * fc * x).sin() / (std::f64::consts::PI * x)
+            };
+            let phase = 2.0 * std::f64::consts::PI * i as f64 / m;
+            let window = 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+            sinc * window
+        })
+        .collect();
+
+    let sum: f64 = coeffs.iter().sum();
+    for c in coeffs.iter_mut() {
+        *c /= sum;
+    }
+
+    coeffs.into_iter().map(|c| c as f32).collect()
+}
+
+/// The history of a FIR filter, each sample is written twice
+/// so that the last `len` samples are always a contiguous slice.
+struct FirHistory {
+    buf: Vec<f32>,
+    len: usize,
+    pos: usize,
+}
+
+impl FirHistory {
+    /// Allocates the history for up to `max_len` samples.
+    fn new(max_len: usize) -> Self {
+        Self { buf: vec![0.0; 2 * max_len], len: max_len, pos: 0 }
+    }
+
+    fn reset(&mut self, len: usize) {
+        self.buf.fill(0.0);
+        self.len = len.min(self.buf.len() / 2);
+        self.pos = 0;
+    }
+
+    fn push(&mut self, s: f32) {
+        self.pos = (self.pos + 1) % self.len;
+        self.buf[self.pos] = s;
+        self.buf[self.pos + self.len] = s;
+    }
+
+    /// The last `len` samples, the oldest first.
+    fn window(&self) -> &[f32] {
+        &self.buf[(self.pos + 1)..(self.pos + 1 + self.len)]
+    }
+}
+
+/// Runs the DSP graph at a multiple of the sample rate of the frontend.
+/// The inputs are upsampled and the outputs downsampled with polyphase
+/// FIR filters, which add [Oversampler::latency] samples of latency.
+///
+/// Everything is allocated in [Oversampler::new], changing the factor
+/// in the audio thread is fine. The blocks passed to [Oversampler::process]
+/// must not be longer than [Oversampler::max_frames].
+pub struct Oversampler<const IN: usize, const OUT: usize> {
+    factor: usize,
+    /// The filter coefficients for each factor, in the order of [OVERSAMPLING_FACTORS].
+    coeffs: Vec<Vec<f32>>,
+    up: Vec<FirHistory>,
+    down: Vec<FirHistory>,
+    up_bufs: Vec<Vec<f32>>,
+    down_bufs: Vec<Vec<f32>>,
+}
+
+impl<const IN: usize, const OUT: usize> Oversampler<IN, OUT> {
+    pub fn new() -> Self {
+        let max_factor = OVERSAMPLING_FACTORS[OVERSAMPLING_FACTORS.len() - 1];
+        let max_len = OVERSAMPLING_TAPS_PER_PHASE * max_factor + 1;
+
+        Self {
+            factor: 1,
+            coeffs: OVERSAMPLING_FACTORS.iter().map(|f| lowpass_coeffs(*f)).collect(),
+            up: (0..IN).map(|_| FirHistory::new(OVERSAMPLING_TAPS_PER_PHASE + 1)).collect(),
+            down: (0..OUT).map(|_| FirHistory::new(max_len)).collect(),
+            up_bufs: (0..IN).map(|_| vec![0.0; MAX_BLOCK_SIZE]).collect(),
+            down_bufs: (0..OUT).map(|_| vec![0.0; MAX_BLOCK_SIZE]).collect(),
+        }
+    }
+
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+
+    /// Selects the factor and clears the filters. Unknown factors turn
+    /// the oversampling off.
+    pub fn set_factor(&mut self, factor: usize) {
+        self.factor = if is_oversampling_factor(factor) { factor } else { 1 };
+
+        for h in self.up.iter_mut() {
+            h.reset(OVERSAMPLING_TAPS_PER_PHASE + 1);
+        }
+        for h in self.down.iter_mut() {
+            h.reset(OVERSAMPLING_TAPS_PER_PHASE * self.factor + 1);
+        }
+    }
+
+    /// The maximum number of frames per block, so that the oversampled
+    /// block fits into one `NodeExecutor::process` call.
+    pub fn max_frames(&self) -> usize {
+        MAX_BLOCK_SIZE / self.factor
+    }
+
+    /// The latency in samples at the sample rate of the frontend.
+    pub fn latency(&self) -> usize {
+        if self.factor == 1 {
+            0
+        } else {
+            OVERSAMPLING_TAPS_PER_PHASE
+        }
+    }
+
+    /// Upsamples the `input`, calls `f` with the oversampled input and
+    /// output buffers and their length (usually to run the `NodeExecutor`),
+    /// and downsamples the result into `output`.
+    pub fn process<F>(&mut self, input: &[&[f32]; IN], output: &mut [&mut [f32]; OUT], mut f: F)
+    where
+        F: FnMut(usize, &[&[f32]; IN], &mut [&mut [f32]; OUT]),
+    {
+        let nframes = input.get(0).map(|i| i.len()).unwrap_or(0);
+
+        if self.factor == 1 {
+            f(nframes, input, output);
+            return;
+        }
+
+        let factor = self.factor;
+        let os_frames = nframes * factor;
+        let coeffs = &self.coeffs[OVERSAMPLING_FACTORS.iter().position(|f| *f == factor).unwrap()];
+
+        for ((inp, hist), buf) in input.iter().zip(self.up.iter_mut()).zip(self.up_bufs.iter_mut())
+        {
+            for (i, s) in inp.iter().enumerate() {
+                hist.push(*s);
+                let window = hist.window();
+                let newest = window.len() - 1;
+
+                for p in 0..factor {
+                    let mut sum = 0.0;
+                    let mut j = 0;
+                    while p + j * factor < coeffs.len() {
+                        sum += coeffs[p + j * factor] * window[newest - j];
+                        j += 1;
+                    }
+                    // Makes up for the zeros between the input samples:
+                    buf[i * factor + p] = sum * factor as f32;
+                }
+            }
+        }
+
+        {
+            let mut up_bufs = self.up_bufs.iter();
+            let os_input: [&[f32]; IN] =
+                std::array::from_fn(|_| up_bufs.next().map(|b| &b[0..os_frames]).unwrap());
+
+            let mut down_bufs = self.down_bufs.iter_mut();
+            let mut os_output: [&mut [f32]; OUT] =
+                std::array::from_fn(|_| down_bufs.next().map(|b| &mut b[0..os_frames]).unwrap());
+            for out in os_output.iter_mut() {
+                out.fill(0.0);
+            }
+
+            f(os_frames, &os_input, &mut os_output);
+        }
+
+        for ((out, hist), buf) in
+            output.iter_mut().zip(self.down.iter_mut()).zip(self.down_bufs.iter())
+        {
+            for (i, s) in out.iter_mut().enumerate().take(nframes) {
+                for os in buf[(i * factor)..((i + 1) * factor)].iter() {
+                    hist.push(*os);
+                }
+
+                // The filter is symmetric, the order of the window does not matter:
+                *s = hist.window().iter().zip(coeffs.iter()).map(|(x, c)| x * c).sum();
+            }
+        }
+    }
+}
@@ -0,0 +1,394 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexodsp::matrix_repr::MatrixRepr;
+use hexodsp::{Matrix, NodeId, ParamId};
+
+use serde_json::{json, Value};
+
+/// The patch file version that is written by the current HexoDSP.
+pub const PATCH_VERSION: i64 = 2;
+
+/// Report about the changes that were done to a patch while loading it.
+#[derive(Debug, Clone, Default)]
+pub struct PatchLoadReport {
+    /// The file format version of the loaded patch.
+    pub version: i64,
+    /// Human readable description of every remapped or rescaled parameter.
+    pub migrated: Vec<String>,
+    /// Human readable description of every node, parameter or setting
+    /// that was unknown to the DSP engine and thus ignored.
+    pub ignored: Vec<String>,
+}
+
+impl PatchLoadReport {
+    pub fn is_clean(&self) -> bool {
+        self.migrated.is_empty() && self.ignored.is_empty()
+    }
+}
+
+/// One step of the patch migration. The step is applied if `applies`
+/// returns true for the version of the patch and its current content.
+struct MigrationStep {
+    applies: fn(i64, &Value) -> bool,
+    apply: fn(&mut Value, &mut PatchLoadReport),
+}
+
+/// The migration steps, in the order they are applied.
+///
+/// 0.2.0-alpha-2 changed the `Mix3` and `Amp` knobs without a new patch
+/// version, so these steps look at the content of the patch instead.
+const MIGRATION_STEPS: &[MigrationStep] = &[
+    MigrationStep { applies: is_v1, apply: migrate_v1_denormalize },
+    MigrationStep { applies: has_mix3_gain_params, apply: migrate_mix3_gain2vol },
+    MigrationStep { applies: has_out_of_range_db_params, apply: migrate_clamp_db_ranges },
+];
+
+fn is_v1(version: i64, _patch: &Value) -> bool {
+    version < 2
+}
+
+fn json2node_id(v: &[Value]) -> Option<NodeId> {
+    let name = v.get(0)?.as_str()?;
+    let node_id = NodeId::from_str(name);
+    if node_id == NodeId::Nop && name != "nop" {
+        return None;
+    }
+
+    Some(node_id.to_instance(v.get(1)?.as_u64()? as usize))
+}
+
+fn json2param_id(v: &[Value]) -> Option<ParamId> {
+    json2node_id(v)?.inp_param(v.get(2)?.as_str()?)
+}
+
+fn json2atom_param_id(v: &[Value]) -> Option<ParamId> {
+    let node_id = json2node_id(v)?;
+    let name = v.get(2)?.as_str()?;
+
+    let mut i = 0;
+    while let Some(param_id) = node_id.atom_param_by_idx(i) {
+        if param_id.name() == name {
+            return Some(param_id);
+        }
+        i += 1;
+    }
+
+    None
+}
+
+fn for_each_param<F: FnMut(&mut Vec<Value>)>(patch: &mut Value, mut f: F) {
+    if let Some(params) = patch["params"].as_array_mut() {
+        for param in params.iter_mut() {
+            if let Some(param) = param.as_array_mut() {
+                if param.len() >= 4 {
+                    f(param);
+                }
+            }
+        }
+    }
+}
+
+fn any_param<F: Fn(&[Value]) -> bool>(patch: &Value, f: F) -> bool {
+    patch["params"].as_array().map_or(false, |params| {
+        params.iter().any(|param| match param.as_array() {
+            Some(param) if param.len() >= 4 => f(param),
+            _ => false,
+        })
+    })
+}
+
+fn param_desc(param: &[Value]) -> String {
+    format!(
+        "{} {} '{}'",
+        param[0].as_str().unwrap_or("?"),
+        param[1].as_u64().unwrap_or(0),
+        param[2].as_str().unwrap_or("?")
+    )
+}
+
+/// Is this one of the gain knobs that got a dB range in 0.2.0-alpha-2?
+fn is_old_exp_gain(node: &str, name: &str) -> bool {
+    match (node, name) {
+        ("amp", "gain") => true,
+        ("mix3", "gain1") | ("mix3", "gain2") | ("mix3", "gain3") | ("mix3", "ogain") => true,
+        _ => false,
+    }
+}
+
+/// Version 1 patches stored the normalized parameter values. Version 2 stores
+/// the denormalized ones, which are robust against changes of the knob
+/// ranges. The old gain knobs had a 0.0 to 2.0 range with a squared mapping,
+/// these are converted explicitly.
+fn migrate_v1_denormalize(patch: &mut Value, report: &mut PatchLoadReport) {
+    for_each_param(patch, |param| {
+        let v = param[3].as_f64().unwrap_or(0.0) as f32;
+
+        let node = param[0].as_str().unwrap_or("");
+        let name = param[2].as_str().unwrap_or("");
+
+        if is_old_exp_gain(node, name) {
+            let amp = 2.0 * v * v;
+            report.migrated.push(format!(
+                "{}: rescaled normalized gain {:.3} to amplitude {:.3}",
+                param_desc(param),
+                v,
+                amp
+            ));
+            param[3] = json!(amp);
+        } else if let Some(param_id) = json2param_id(param) {
+            param[3] = json!(param_id.denorm(v));
+        }
+    });
+
+    patch["VERSION"] = json!(2);
+}
+
+fn mix3_vol_name(param: &[Value]) -> Option<&'static str> {
+    if param[0].as_str() != Some("mix3") {
+        return None;
+    }
+
+    match param[2].as_str().unwrap_or("") {
+        "gain1" => Some("vol1"),
+        "gain2" => Some("vol2"),
+        "gain3" => Some("vol3"),
+        "ogain" => Some("ovol"),
+        _ => None,
+    }
+}
+
+fn has_mix3_gain_params(_version: i64, patch: &Value) -> bool {
+    any_param(patch, |param| mix3_vol_name(param).is_some())
+}
+
+/// The `Mix3` gain knobs were replaced by volume knobs, the denormalized
+/// amplitude stays the same.
+fn migrate_mix3_gain2vol(patch: &mut Value, report: &mut PatchLoadReport) {
+    for_each_param(patch, |param| {
+        let new_name = match mix3_vol_name(param) {
+            Some(new_name) => new_name,
+            None => return,
+        };
+
+        report.migrated.push(format!("{}: renamed to '{}'", param_desc(param), new_name));
+        param[2] = json!(new_name);
+    });
+}
+
+/// Returns the amplitude and the clamped amplitude of an `Amp` gain or
+/// `Mix3` volume knob that is outside of its dB range.
+fn out_of_range_db_param(param: &[Value]) -> Option<(f32, f32)> {
+    match (param[0].as_str().unwrap_or(""), param[2].as_str().unwrap_or("")) {
+        ("amp", "gain") => (),
+        ("mix3", "vol1") | ("mix3", "vol2") | ("mix3", "vol3") | ("mix3", "ovol") => (),
+        _ => return None,
+    }
+
+    let param_id = json2param_id(param)?;
+    let v = param[3].as_f64().unwrap_or(0.0) as f32;
+    let norm = param_id.norm(v);
+
+    // The tolerance keeps already clamped values from being
+    // clamped again after the round trip through norm/denorm.
+    if (-1e-4..=1.0 + 1e-4).contains(&norm) {
+        None
+    } else {
+        Some((v, param_id.denorm(norm.clamp(0.0, 1.0))))
+    }
+}
+
+fn has_out_of_range_db_params(_version: i64, patch: &Value) -> bool {
+    any_param(patch, |param| out_of_range_db_param(param).is_some())
+}
+
+/// The new dB ranges of the `Amp` gain and `Mix3` volume knobs are limited,
+/// old amplitudes outside of that range are clamped.
+fn migrate_clamp_db_ranges(patch: &mut Value, report: &mut PatchLoadReport) {
+    for_each_param(patch, |param| {
+        if let Some((v, clamped)) = out_of_range_db_param(param) {
+            report.migrated.push(format!(
+                "{}: amplitude {:.3} is out of range, clamped to {:.3}",
+                param_desc(param),
+                v,
+                clamped
+            ));
+            param[3] = json!(clamped);
+        }
+    });
+}
+
+/// Removes all nodes, parameters and settings the DSP engine does not know.
+fn remove_unknown(patch: &mut Value, report: &mut PatchLoadReport) {
+    if let Some(cells) = patch["cells"].as_array_mut() {
+        cells.retain(|cell| {
+            if cell.as_array().and_then(|c| json2node_id(c)).is_some() {
+                return true;
+            }
+
+            report.ignored.push(format!(
+                "Unknown node '{}' at ({}, {})",
+                cell[0].as_str().unwrap_or("?"),
+                cell[2],
+                cell[3]
+            ));
+            false
+        });
+    }
+
+    if let Some(params) = patch["params"].as_array_mut() {
+        params.retain(|param| {
+            if param.as_array().and_then(|p| json2param_id(p)).is_some() {
+                return true;
+            }
+
+            report.ignored.push(format!("Unknown parameter {}", param_desc_v(param)));
+            false
+        });
+    }
+
+    if let Some(atoms) = patch["atoms"].as_array_mut() {
+        atoms.retain(|atom| {
+            if atom.as_array().and_then(|a| json2atom_param_id(a)).is_some() {
+                return true;
+            }
+
+            report.ignored.push(format!("Unknown setting {}", param_desc_v(atom)));
+            false
+        });
+    }
+}
+
+fn param_desc_v(v: &Value) -> String {
+    match v.as_array() {
+        Some(param) if param.len() >= 3 => param_desc(param),
+        _ => v.to_string(),
+    }
+}
+
+/// Applies all migration steps to the JSON representation of a patch
+/// and removes everything the DSP engine does not know.
+///
+/// The migration works on the JSON data and not on the [MatrixRepr],
+/// because renamed or removed parameters can't be represented by a [ParamId].
+pub fn migrate_patch(patch: &mut Value) -> Result<PatchLoadReport, String> {
+    let version = patch["VERSION"].as_i64().ok_or_else(|| "Patch has no VERSION".to_string())?;
+    if version > PATCH_VERSION {
+        return Err(format!(
+            "Patch version {} is newer than the supported version {}",
+            version, PATCH_VERSION
+        ));
+    }
+
+    let mut report = PatchLoadReport { version, ..PatchLoadReport::default() };
+
+    for step in MIGRATION_STEPS.iter() {
+        if (step.applies)(version, patch) {
+            (step.apply)(patch, &mut report);
+        }
+    }
+
+    remove_unknown(patch, &mut report);
+
+    Ok(report)
+}
+
+/// Repairs the damage that makes [migrate_patch] or the deserialization
+/// of a patch fail. A missing VERSION is assumed to be 1, patches from a newer
+/// version are migrated like the current one and missing lists or malformed
+/// entries of nodes, parameters and settings are removed.
+///
+/// Every repair is added to the `migrated` entries of the report.
+pub fn repair_patch(patch: &mut Value) -> Result<PatchLoadReport, String> {
+    if !patch.is_object() {
+        return Err("Patch is not a JSON object, can't repair it".to_string());
+    }
+
+    let mut repairs = vec![];
+
+    match patch["VERSION"].as_i64() {
+        None => {
+            repairs.push("Patch has no VERSION, assumed version 1".to_string());
+            patch["VERSION"] = json!(1);
+        }
+        Some(version) if version > PATCH_VERSION => {
+            repairs.push(format!(
+                "Patch version {} is newer than the supported version {}, loaded it as {}",
+                version, PATCH_VERSION, PATCH_VERSION
+            ));
+            patch["VERSION"] = json!(PATCH_VERSION);
+        }
+        _ => (),
+    }
+
+    for key in ["cells", "params", "atoms"].iter() {
+        if !patch[*key].is_array() {
+            repairs.push(format!("Patch has no valid '{}' list, replaced it by an empty one", key));
+            patch[*key] = json!([]);
+        }
+
+        if let Some(list) = patch[*key].as_array_mut() {
+            let len = list.len();
+            list.retain(|entry| entry.as_array().map(|e| e.len() >= 4).unwrap_or(false));
+            if list.len() < len {
+                repairs.push(format!(
+                    "Removed {} malformed entries from the '{}' list",
+                    len - list.len(),
+                    key
+                ));
+            }
+        }
+    }
+
+    let mut report = migrate_patch(patch)?;
+    repairs.append(&mut report.migrated);
+    report.migrated = repairs;
+
+    Ok(report)
+}
+
+/// Loads a patch into the matrix, migrating it from older versions.
+/// Returns the report about the migrated and ignored parts of the patch.
+pub fn load_patch_migrated_from_mem(
+    matrix: &mut Matrix,
+    data: &[u8],
+) -> Result<PatchLoadReport, String> {
+    let mut patch: Value =
+        serde_json::from_slice(data).map_err(|e| format!("Patch is not valid JSON: {}", e))?;
+
+    let report = migrate_patch(&mut patch)?;
+
+    let repr = MatrixRepr::deserialize(&patch.to_string())
+        .map_err(|e| format!("Can't deserialize patch: {:?}", e))?;
+    matrix.from_repr(&repr).map_err(|e| format!("Can't load patch: {:?}", e))?;
+
+    Ok(report)
+}
+
+/// Like [load_patch_migrated_from_mem], but tries to [repair_patch] first.
+/// Used to load patches that could not be loaded otherwise.
+pub fn load_patch_repaired_from_mem(
+    matrix: &mut Matrix,
+    data: &[u8],
+) -> Result<PatchLoadReport, String> {
+    let mut patch: Value =
+        serde_json::from_slice(data).map_err(|e| format!("Patch is not valid JSON: {}", e))?;
+
+    let report = repair_patch(&mut patch)?;
+
+    let repr = MatrixRepr::deserialize(&patch.to_string())
+        .map_err(|e| format!("Can't deserialize patch: {:?}", e))?;
+    matrix.from_repr(&repr).map_err(|e| format!("Can't load patch: {:?}", e))?;
+
+    Ok(report)
+}
+
+/// Like [load_patch_migrated_from_mem], but reads the patch from a file.
+pub fn load_patch_migrated_from_file(
+    matrix: &mut Matrix,
+    filename: &str,
+) -> Result<PatchLoadReport, String> {
+    let data = std::fs::read(filename).map_err(|e| format!("Can't read '{}': {}", filename, e))?;
+    load_patch_migrated_from_mem(matrix, &data)
+}
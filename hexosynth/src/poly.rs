@@ -0,0 +1,581 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexodsp::{
+    Cell, CellDir, DynNode1x1Context, DynamicNode1x1, HxMidiEvent, Matrix, NodeId, SAtom,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::{hex_neighbour, HEX_DIRS};
+use crate::dyn_nodes::{dyn_nodes_store_in_matrix, MAX_DYN_NODE_SLOTS};
+use crate::matrix_history::for_node_params;
+use crate::prop_watch::PropWatcher;
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The matrix property that holds the [PolyConfig] as JSON string.
+pub const POLY_PROP: &str = "poly";
+
+pub const MAX_VOICES: usize = 8;
+
+/// The name of the [VoiceSendNode] prototype in the `DynNodeRegistry`.
+pub const VOICE_SEND_NODE_NAME: &str = "voice_send";
+/// The name of the [VoiceSumNode] prototype in the `DynNodeRegistry`.
+pub const VOICE_SUM_NODE_NAME: &str = "voice_sum";
+
+/// By default the voices play on the MIDI channels starting with this
+/// one (channel 9), see [PolyConfig::voice_channel].
+pub const DEFAULT_VOICE_CHANNEL: u8 = 8;
+
+const VOICE_EVENT_QUEUE_SIZE: usize = 64;
+
+/// Which voice is taken for a new note if all voices are playing.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VoiceStealing {
+    /// The voice that plays the longest.
+    Oldest,
+    /// The voice that was started last.
+    Newest,
+    /// The voice with the lowest note.
+    Lowest,
+    /// The voice with the highest note.
+    Highest,
+    /// No voice is stolen, the new note is not played.
+    Off,
+}
+
+impl VoiceStealing {
+    const ALL: [VoiceStealing; 5] = [
+        VoiceStealing::Oldest,
+        VoiceStealing::Newest,
+        VoiceStealing::Lowest,
+        VoiceStealing::Highest,
+        VoiceStealing::Off,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            VoiceStealing::Oldest => "oldest",
+            VoiceStealing::Newest => "newest",
+            VoiceStealing::Lowest => "lowest",
+            VoiceStealing::Highest => "highest",
+            VoiceStealing::Off => "off",
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<Self> {
+        VoiceStealing::ALL.iter().find(|m| m.name() == s).copied()
+    }
+
+    fn from_index(idx: u8) -> Self {
+        VoiceStealing::ALL.get(idx as usize).copied().unwrap_or(VoiceStealing::Oldest)
+    }
+
+    fn index(&self) -> u8 {
+        *self as u8
+    }
+}
+
+/// The polyphony of the patch, stored in the [POLY_PROP] of the matrix.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PolyConfig {
+    /// Number of voices notes are distributed to, at most `copies`.
+    pub voices: usize,
+    /// Number of voices that were instantiated in the matrix.
+    pub copies: usize,
+    pub stealing: VoiceStealing,
+    /// The MIDI channel of the notes that are distributed to the voices.
+    pub channel: u8,
+    /// The MIDI channel of the first voice, the other voices play on the
+    /// following channels. The `MidiP` and `MidiCC` nodes of the voices
+    /// are set to their channel by [make_voices].
+    pub voice_channel: u8,
+}
+
+impl Default for PolyConfig {
+    fn default() -> Self {
+        Self {
+            voices: 0,
+            copies: 0,
+            stealing: VoiceStealing::Oldest,
+            channel: 0,
+            voice_channel: DEFAULT_VOICE_CHANNEL,
+        }
+    }
+}
+
+pub fn poly_config_from_matrix(matrix: &Matrix) -> Option<PolyConfig> {
+    match matrix.get_prop(POLY_PROP) {
+        Some(SAtom::Str(s)) => serde_json::from_str(&s).ok(),
+        _ => None,
+    }
+}
+
+/// Stores the config in the matrix, `None` turns the polyphony off.
+pub fn poly_config_store_in_matrix(matrix: &mut Matrix, config: Option<&PolyConfig>) {
+    let s = match config {
+        Some(config) => serde_json::to_string(config).unwrap_or_default(),
+        None => String::new(),
+    };
+    matrix.set_prop(POLY_PROP, SAtom::str(&s));
+}
+
+/// The output of a voice, that adds the voice at the input to the sum of
+/// the previous voices at `alpha`. The voice sends are chained in the matrix
+/// by [make_voices] and end in the [VoiceSumNode].
+pub struct VoiceSendNode;
+
+impl DynamicNode1x1 for VoiceSendNode {
+    fn set_sample_rate(&mut self, _srate: f32) {}
+
+    fn reset(&mut self) {}
+
+    fn process(&mut self, input: &[f32], output: &mut [f32], ctx: &DynNode1x1Context) {
+        let alpha = ctx.alpha_slice();
+
+        for (i, (out, inp)) in output.iter_mut().zip(input.iter()).enumerate() {
+            *out = *inp + alpha[i];
+        }
+    }
+}
+
+/// Outputs the sum of all voices at the input, scaled by `alpha`.
+pub struct VoiceSumNode;
+
+impl DynamicNode1x1 for VoiceSumNode {
+    fn set_sample_rate(&mut self, _srate: f32) {}
+
+    fn reset(&mut self) {}
+
+    fn process(&mut self, input: &[f32], output: &mut [f32], ctx: &DynNode1x1Context) {
+        let alpha = ctx.alpha_slice();
+
+        for (i, (out, inp)) in output.iter_mut().zip(input.iter()).enumerate() {
+            *out = *inp * alpha[i];
+        }
+    }
+}
+
+/// The polyphony settings, shared between the GUI and the [VoiceAllocator]
+/// of the audio thread. [PolySettings::load_from_matrix] has to be called
+/// regularly to pick up changes of the [POLY_PROP].
+pub struct PolySettings {
+    voices: AtomicUsize,
+    stealing: AtomicU8,
+    channel: AtomicU8,
+    voice_channel: AtomicU8,
+    prop: PropWatcher,
+}
+
+impl PolySettings {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            voices: AtomicUsize::new(0),
+            stealing: AtomicU8::new(VoiceStealing::Oldest.index()),
+            channel: AtomicU8::new(0),
+            voice_channel: AtomicU8::new(DEFAULT_VOICE_CHANNEL),
+            prop: PropWatcher::new(POLY_PROP),
+        })
+    }
+
+    /// The number of voices, 0 if the polyphony is off.
+    pub fn voices(&self) -> usize {
+        self.voices.load(Ordering::Relaxed)
+    }
+
+    pub fn stealing(&self) -> VoiceStealing {
+        VoiceStealing::from_index(self.stealing.load(Ordering::Relaxed))
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel.load(Ordering::Relaxed)
+    }
+
+    pub fn voice_channel(&self) -> u8 {
+        self.voice_channel.load(Ordering::Relaxed)
+    }
+
+    /// Reloads the settings from the matrix property.
+    pub fn load_from_matrix(&self, matrix: &Matrix) -> bool {
+        let s = match self.prop.changed(matrix) {
+            Some(s) => s,
+            None => return false,
+        };
+
+        let config: PolyConfig = serde_json::from_str(&s).unwrap_or_default();
+        // The voices must not play on channels above 16:
+        let voice_channel = config.voice_channel.min(15);
+        let copies = config.copies.min(MAX_VOICES).min(16 - voice_channel as usize);
+
+        self.voices.store(config.voices.min(copies), Ordering::Relaxed);
+        self.stealing.store(config.stealing.index(), Ordering::Relaxed);
+        self.channel.store(config.channel & 0x0F, Ordering::Relaxed);
+        self.voice_channel.store(voice_channel, Ordering::Relaxed);
+
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Voice {
+    note: Option<u8>,
+    /// When the note was started or released, for the voice stealing.
+    time: u64,
+}
+
+/// Distributes the notes on the channel of the [PolySettings] to the
+/// channels of the voices, starting with [PolyConfig::voice_channel]. The CCs on that
+/// channel are copied to all voices. Owned by the audio thread of the frontend.
+pub struct VoiceAllocator {
+    settings: Arc<PolySettings>,
+    voices: [Voice; MAX_VOICES],
+    active_voices: usize,
+    time: u64,
+    queue: VecDeque<(usize, HxMidiEvent)>,
+}
+
+impl VoiceAllocator {
+    pub fn new(settings: Arc<PolySettings>) -> Self {
+        Self {
+            settings,
+            voices: [Voice::default(); MAX_VOICES],
+            active_voices: 0,
+            time: 0,
+            queue: VecDeque::with_capacity(VOICE_EVENT_QUEUE_SIZE),
+        }
+    }
+
+    fn push(&mut self, timing: usize, ev: HxMidiEvent) {
+        // Don't allocate in the audio thread:
+        if self.queue.len() < VOICE_EVENT_QUEUE_SIZE {
+            self.queue.push_back((timing, ev));
+        }
+    }
+
+    fn voice_channel(&self, v: usize) -> u8 {
+        self.settings.voice_channel() + v as u8
+    }
+
+    fn release(&mut self, timing: usize, v: usize) {
+        if let Some(note) = self.voices[v].note.take() {
+            self.voices[v].time = self.time;
+            self.time += 1;
+            self.push(timing, HxMidiEvent::NoteOff { channel: self.voice_channel(v), note });
+        }
+    }
+
+    /// The free voice that was released first.
+    fn free_voice(&self, n: usize) -> Option<usize> {
+        (0..n).filter(|v| self.voices[*v].note.is_none()).min_by_key(|v| self.voices[*v].time)
+    }
+
+    fn steal_voice(&self, n: usize) -> Option<usize> {
+        let playing = (0..n).filter(|v| self.voices[*v].note.is_some());
+
+        match self.settings.stealing() {
+            VoiceStealing::Oldest => playing.min_by_key(|v| self.voices[*v].time),
+            VoiceStealing::Newest => playing.max_by_key(|v| self.voices[*v].time),
+            VoiceStealing::Lowest => playing.min_by_key(|v| self.voices[*v].note),
+            VoiceStealing::Highest => playing.max_by_key(|v| self.voices[*v].note),
+            VoiceStealing::Off => None,
+        }
+    }
+
+    /// Routes the event, the resulting events are returned by [VoiceAllocator::pop].
+    pub fn route(&mut self, timing: usize, ev: HxMidiEvent) {
+        let n = self.settings.voices().min(MAX_VOICES);
+        let poly_channel = self.settings.channel();
+
+        // Release the notes of the voices that are not used anymore:
+        if n < self.active_voices {
+            for v in n..self.active_voices {
+                self.release(timing, v);
+            }
+        }
+        self.active_voices = n;
+
+        if n == 0 {
+            self.push(timing, ev);
+            return;
+        }
+
+        match ev {
+            HxMidiEvent::NoteOn { channel, note, vel } if channel == poly_channel => {
+                if let Some(v) = self.free_voice(n).or_else(|| self.steal_voice(n)) {
+                    self.release(timing, v);
+                    self.voices[v] = Voice { note: Some(note), time: self.time };
+                    self.time += 1;
+                    self.push(
+                        timing,
+                        HxMidiEvent::NoteOn { channel: self.voice_channel(v), note, vel },
+                    );
+                }
+            }
+            HxMidiEvent::NoteOff { channel, note } if channel == poly_channel => {
+                if let Some(v) = (0..n).find(|v| self.voices[*v].note == Some(note)) {
+                    self.release(timing, v);
+                }
+            }
+            HxMidiEvent::CC { channel, cc, value } if channel == poly_channel => {
+                self.push(timing, ev);
+                for v in 0..n {
+                    self.push(
+                        timing,
+                        HxMidiEvent::CC { channel: self.voice_channel(v), cc, value },
+                    );
+                }
+            }
+            _ => self.push(timing, ev),
+        }
+    }
+
+    /// Returns the next routed event with its timing.
+    pub fn pop(&mut self) -> Option<(usize, HxMidiEvent)> {
+        self.queue.pop_front()
+    }
+}
+
+fn set_voice_channel(m: &mut Matrix, node_id: NodeId, channel: u8) {
+    if node_id.to_instance(0) == NodeId::MidiP(0) || node_id.to_instance(0) == NodeId::MidiCC(0) {
+        if let Some(pid) = node_id.inp_param("chan") {
+            m.set_param(pid, SAtom::setting(channel as i64));
+        }
+    }
+}
+
+fn set_rust1x1_alpha(m: &mut Matrix, instance: usize, alpha: f32) {
+    if let Some(pid) = NodeId::Rust1x1(instance).inp_param("alpha") {
+        m.set_param(pid, SAtom::param(alpha));
+    }
+}
+
+/// The index in [HEX_DIRS] of the output edge of a cell in the voice chain
+/// at `pos`. The chain runs to the right on the rows `row` and `row + 1`.
+fn voice_chain_dir(pos: (usize, usize), row: usize) -> usize {
+    if pos.1 == row {
+        1 // BR
+    } else {
+        0 // TR
+    }
+}
+
+/// Uses the `cells` of a cluster as voice template and places
+/// `config.voices - 1` copies of it to the right of the template, with new
+/// node instances and the same parameters. The `MidiP` and `MidiCC` nodes of
+/// each voice are set to the MIDI channel of the voice.
+///
+/// The `Rust1x1` node of the template is the output of the voice, it becomes
+/// a [VoiceSendNode]. Below the voices a chain of cells connects the voice
+/// sends one after the other and ends in a [VoiceSumNode], which outputs the
+/// sum of all voices. The prototypes of these `Rust1x1` instances are added
+/// to the `assignments` and stored in the matrix together with the [PolyConfig].
+///
+/// Does not sync the matrix. Returns an error message and leaves the matrix
+/// untouched if the template is not usable or there is not enough space.
+pub fn make_voices(
+    m: &mut Matrix,
+    cells: &[Cell],
+    config: &PolyConfig,
+    mut assignments: BTreeMap<usize, String>,
+) -> Result<PolyConfig, String> {
+    let copies = config.voices;
+    if copies == 0 || copies > MAX_VOICES {
+        return Err(format!("The number of voices must be between 1 and {}", MAX_VOICES));
+    }
+    if config.voice_channel as usize + copies > 16 {
+        return Err(format!(
+            "The {} voices don't fit on the MIDI channels from {} to 16",
+            copies,
+            config.voice_channel + 1
+        ));
+    }
+    if (config.voice_channel..(config.voice_channel + copies as u8)).contains(&config.channel) {
+        return Err(format!(
+            "The MIDI channel {} is used by the voices, which play on the channels {} to {}",
+            config.channel + 1,
+            config.voice_channel + 1,
+            config.voice_channel as usize + copies
+        ));
+    }
+
+    let is_type = |cell: &Cell, node_id: NodeId| cell.node_id().to_instance(0) == node_id;
+
+    let template_send = match cells.iter().filter(|c| is_type(c, NodeId::Rust1x1(0))).count() {
+        1 => cells
+            .iter()
+            .find(|c| is_type(c, NodeId::Rust1x1(0)))
+            .map(|c| c.node_id().instance())
+            .unwrap_or(0),
+        0 => return Err("The voice template needs a Rust1x1 node as voice output".to_string()),
+        _ => return Err("The voice template must contain only one Rust1x1 node".to_string()),
+    };
+    if !cells.iter().any(|c| is_type(c, NodeId::MidiP(0))) {
+        return Err("The voice template needs a MidiP node".to_string());
+    }
+
+    let min_x = cells.iter().map(|c| c.pos().0).min().unwrap_or(0);
+    let max_x = cells.iter().map(|c| c.pos().0).max().unwrap_or(0);
+    let max_y = cells.iter().map(|c| c.pos().1).max().unwrap_or(0);
+    // An even offset keeps the hex grid layout, and one free column
+    // between the voices keeps them from connecting to each other:
+    let shift = (max_x - min_x + 2 + 1) & !1;
+
+    let is_free = |m: &Matrix, x: usize, y: usize| {
+        m.get(x, y).map(|c| c.node_id() == NodeId::Nop).unwrap_or(true)
+    };
+
+    let (mw, mh) = m.size();
+    for v in 1..copies {
+        for cell in cells.iter() {
+            let (x, y) = (cell.pos().0 + v * shift, cell.pos().1);
+            if x >= mw || y >= mh {
+                return Err(format!("Not enough space for voice {} in the matrix", v + 1));
+            }
+            if !is_free(m, x, y) {
+                return Err(format!("The space for voice {} is not free at ({}, {})", v + 1, x, y));
+            }
+        }
+    }
+
+    // The chain of the voice sends and the sum, one free row below the voices:
+    let chain_row = max_y + 2;
+    let mut chain = vec![(min_x, chain_row)];
+    for _ in 0..copies {
+        let pos = chain[chain.len() - 1];
+        match hex_neighbour(pos, voice_chain_dir(pos, chain_row)) {
+            Some(next) => chain.push(next),
+            None => return Err("Not enough space for the voice sum in the matrix".to_string()),
+        }
+    }
+    for (x, y) in chain.iter().copied() {
+        if x >= mw || y >= mh {
+            return Err("Not enough space for the voice sum in the matrix".to_string());
+        }
+        if !is_free(m, x, y) {
+            return Err(format!("The space for the voice sum is not free at ({}, {})", x, y));
+        }
+    }
+
+    let mut used: HashSet<NodeId> = HashSet::new();
+    m.for_each(|_x, _y, cell| {
+        used.insert(cell.node_id());
+    });
+
+    // Assigned prototypes might be used by a patch that is not in the grid yet:
+    for slot in assignments.keys() {
+        if *slot != template_send {
+            used.insert(NodeId::Rust1x1(*slot));
+        }
+    }
+
+    let mut alloc_instance = |node_id: NodeId| {
+        let mut i = 0;
+        while used.contains(&node_id.to_instance(i)) {
+            i += 1;
+        }
+        let new_id = node_id.to_instance(i);
+        used.insert(new_id);
+        new_id
+    };
+
+    let mut sends = vec![template_send];
+    for _ in 1..copies {
+        sends.push(alloc_instance(NodeId::Rust1x1(0)).instance());
+    }
+    let sum = alloc_instance(NodeId::Rust1x1(0)).instance();
+    if sends.iter().chain(std::iter::once(&sum)).any(|i| *i >= MAX_DYN_NODE_SLOTS) {
+        return Err(format!(
+            "Not enough free Rust1x1 instances for the voices, only {} can be used",
+            MAX_DYN_NODE_SLOTS
+        ));
+    }
+
+    // The Rust1x1 node has only one output:
+    let sig_out = 0;
+    let (alpha_inp, sig_inp) =
+        match (NodeId::Rust1x1(0).inp("alpha"), NodeId::Rust1x1(0).inp("inp")) {
+            (Some(a), Some(i)) => (a as usize, i as usize),
+            _ => return Err("The Rust1x1 node has no `inp` and `alpha` inputs".to_string()),
+        };
+
+    for v in 0..copies {
+        let mut id_map: HashMap<NodeId, NodeId> = HashMap::new();
+
+        for cell in cells.iter() {
+            let old_id = cell.node_id();
+            let new_id = if is_type(cell, NodeId::Rust1x1(0)) {
+                old_id.to_instance(sends[v])
+            } else if v == 0 {
+                old_id
+            } else {
+                id_map.get(&old_id).copied().unwrap_or_else(|| alloc_instance(old_id))
+            };
+            id_map.insert(old_id, new_id);
+
+            let (x, y) = (cell.pos().0 + v * shift, cell.pos().1);
+            let mut new_cell = Cell::empty_at(new_id, x as u8, y as u8);
+            for edge in 0..6 {
+                let dir = CellDir::from(edge);
+                if let Some(idx) = cell.local_port_idx(dir) {
+                    new_cell.set_io_dir(dir, idx as usize);
+                }
+            }
+            m.place(x, y, new_cell);
+        }
+
+        for (old_id, new_id) in id_map.iter() {
+            if old_id != new_id {
+                let (old_id, new_id) = (*old_id, *new_id);
+                for_node_params(old_id, |pid| {
+                    let new_pid = match new_id.inp_param(pid.name()) {
+                        Some(new_pid) => new_pid,
+                        None => return,
+                    };
+                    if let Some(at) = m.get_param(&pid) {
+                        m.set_param(new_pid, at);
+                    }
+                    let _ = m.set_param_modamt(new_pid, m.get_param_modamt(&pid));
+                });
+            }
+
+            set_voice_channel(m, *new_id, config.voice_channel + v as u8);
+        }
+    }
+
+    // The first send has nothing at `alpha` to add to its voice:
+    for send in sends.iter() {
+        set_rust1x1_alpha(m, *send, 0.0);
+    }
+    set_rust1x1_alpha(m, sum, 1.0);
+
+    for (k, (x, y)) in chain.iter().copied().enumerate() {
+        let instance = if k < copies { sends[k] } else { sum };
+        let mut cell = Cell::empty_at(NodeId::Rust1x1(instance), x as u8, y as u8);
+
+        cell.set_io_dir(HEX_DIRS[voice_chain_dir((x, y), chain_row)], sig_out);
+        if k > 0 {
+            let prev_dir = voice_chain_dir(chain[k - 1], chain_row);
+            let inp = if k < copies { alpha_inp } else { sig_inp };
+            cell.set_io_dir(HEX_DIRS[(prev_dir + 3) % 6], inp);
+        }
+
+        m.place(x, y, cell);
+    }
+
+    for send in sends.iter() {
+        assignments.insert(*send, VOICE_SEND_NODE_NAME.to_string());
+    }
+    assignments.insert(sum, VOICE_SUM_NODE_NAME.to_string());
+    dyn_nodes_store_in_matrix(m, &assignments);
+
+    let config = PolyConfig { copies, channel: config.channel & 0x0F, ..config.clone() };
+    poly_config_store_in_matrix(m, Some(&config));
+
+    Ok(config)
+}
@@ -0,0 +1,276 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexodsp::{Matrix, SAtom};
+
+use directories::UserDirs;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The matrix property that holds the [PresetMeta] as JSON string.
+pub const PRESET_META_PROP: &str = "preset_meta";
+
+/// The metadata that is embedded in a patch.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PresetMeta {
+    pub name: String,
+    pub author: String,
+    pub tags: Vec<String>,
+    pub description: String,
+    /// The creation date, as ISO 8601 date like "2022-08-14".
+    pub created: String,
+}
+
+impl PresetMeta {
+    pub fn from_matrix(matrix: &Matrix) -> Option<Self> {
+        if let Some(SAtom::Str(s)) = matrix.get_prop(PRESET_META_PROP) {
+            serde_json::from_str(&s).ok()
+        } else {
+            None
+        }
+    }
+
+    /// Stores the metadata in the matrix, it is saved with the next patch save.
+    pub fn store_in_matrix(&self, matrix: &mut Matrix) {
+        if let Ok(s) = serde_json::to_string(self) {
+            matrix.set_prop(PRESET_META_PROP, SAtom::str(&s));
+        }
+    }
+
+    /// Reads the metadata from the `props` of a patch file,
+    /// which are stored as `[key, ["s", value]]`.
+    fn from_patch_json(patch: &Value) -> Option<Self> {
+        for prop in patch["props"].as_array()?.iter() {
+            if prop[0].as_str() != Some(PRESET_META_PROP) {
+                continue;
+            }
+
+            let s = prop[1][1].as_str()?;
+            return serde_json::from_str(s).ok();
+        }
+
+        None
+    }
+}
+
+/// A patch file found in the preset library.
+#[derive(Debug, Clone)]
+pub struct PresetEntry {
+    pub path: PathBuf,
+    /// The path relative to the library root, with `/` as separator.
+    pub rel_path: String,
+    /// The metadata of the patch. If the patch has no metadata,
+    /// the name is the file name without extension.
+    pub meta: PresetMeta,
+    pub has_meta: bool,
+    pub modified: Option<SystemTime>,
+}
+
+impl PresetEntry {
+    fn matches_text(&self, word: &str) -> bool {
+        let word = word.to_lowercase();
+
+        self.meta.name.to_lowercase().contains(&word)
+            || self.meta.author.to_lowercase().contains(&word)
+            || self.meta.description.to_lowercase().contains(&word)
+            || self.rel_path.to_lowercase().contains(&word)
+            || self.meta.tags.iter().any(|t| t.to_lowercase().contains(&word))
+    }
+
+    fn has_tag(&self, tag: &str) -> bool {
+        self.meta.tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PresetSort {
+    #[default]
+    Name,
+    Author,
+    Created,
+    Modified,
+    Path,
+}
+
+impl PresetSort {
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "name" => Some(PresetSort::Name),
+            "author" => Some(PresetSort::Author),
+            "created" => Some(PresetSort::Created),
+            "modified" => Some(PresetSort::Modified),
+            "path" => Some(PresetSort::Path),
+            _ => None,
+        }
+    }
+}
+
+/// Filter and sort order for [PresetLibrary::query].
+#[derive(Debug, Clone, Default)]
+pub struct PresetQuery {
+    /// Whitespace separated words, every word must be found in the name,
+    /// author, description, tags or path of the preset. Case insensitive.
+    pub text: String,
+    /// The preset must have all of these tags.
+    pub tags: Vec<String>,
+    /// The author must contain this, case insensitive.
+    pub author: String,
+    pub sort: PresetSort,
+    pub reverse: bool,
+}
+
+/// Index of the patch files in the patches directory and their [PresetMeta].
+#[derive(Debug, Clone)]
+pub struct PresetLibrary {
+    root: PathBuf,
+    entries: Vec<PresetEntry>,
+    scanned: bool,
+}
+
+impl PresetLibrary {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root, entries: vec![], scanned: false }
+    }
+
+    /// The patches directory in the documents directory of the user,
+    /// the same that is returned by `hx:get_directory_patches`.
+    pub fn default_root() -> Option<PathBuf> {
+        let user = UserDirs::new()?;
+        Some(user.document_dir()?.join("HexoSynth").join("patches"))
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn is_scanned(&self) -> bool {
+        self.scanned
+    }
+
+    pub fn entries(&self) -> &[PresetEntry] {
+        &self.entries
+    }
+
+    /// Rebuilds the index from the patch files in the library root and all
+    /// its subdirectories. Returns the messages about the files that could
+    /// not be read; these files are still listed, without metadata.
+    pub fn rescan(&mut self) -> Vec<String> {
+        let mut errors = vec![];
+        let mut files = vec![];
+        collect_patch_files(&self.root, &mut files, &mut errors);
+
+        self.entries = files
+            .into_iter()
+            .map(|path| {
+                let (meta, err) = read_meta(&path);
+                if let Some(err) = err {
+                    errors.push(format!("{}: {}", path.display(), err));
+                }
+
+                let rel_path = path
+                    .strip_prefix(&self.root)
+                    .unwrap_or(&path)
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy().to_string())
+                    .collect::<Vec<String>>()
+                    .join("/");
+
+                let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+
+                let has_meta = meta.is_some();
+                let mut meta = meta.unwrap_or_default();
+                if meta.name.is_empty() {
+                    meta.name = path
+                        .file_stem()
+                        .map(|s| s.to_string_lossy().to_string())
+                        .unwrap_or_default();
+                }
+
+                PresetEntry { path, rel_path, meta, has_meta, modified }
+            })
+            .collect();
+
+        self.scanned = true;
+
+        errors
+    }
+
+    /// All tags used in the library, sorted and without duplicates.
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags: Vec<String> =
+            self.entries.iter().flat_map(|e| e.meta.tags.iter().cloned()).collect();
+        tags.sort_by_key(|t| t.to_lowercase());
+        tags.dedup_by(|a, b| a.eq_ignore_ascii_case(b));
+        tags
+    }
+
+    pub fn query(&self, query: &PresetQuery) -> Vec<&PresetEntry> {
+        let author = query.author.to_lowercase();
+
+        let mut res: Vec<&PresetEntry> = self
+            .entries
+            .iter()
+            .filter(|e| query.text.split_whitespace().all(|w| e.matches_text(w)))
+            .filter(|e| query.tags.iter().all(|t| e.has_tag(t)))
+            .filter(|e| author.is_empty() || e.meta.author.to_lowercase().contains(&author))
+            .collect();
+
+        res.sort_by(|a, b| {
+            let ord = match query.sort {
+                PresetSort::Name => a.meta.name.to_lowercase().cmp(&b.meta.name.to_lowercase()),
+                PresetSort::Author => {
+                    a.meta.author.to_lowercase().cmp(&b.meta.author.to_lowercase())
+                }
+                PresetSort::Created => a.meta.created.cmp(&b.meta.created),
+                PresetSort::Modified => a.modified.cmp(&b.modified),
+                PresetSort::Path => a.rel_path.cmp(&b.rel_path),
+            };
+
+            ord.then_with(|| a.rel_path.cmp(&b.rel_path))
+        });
+
+        if query.reverse {
+            res.reverse();
+        }
+
+        res
+    }
+}
+
+fn collect_patch_files(dir: &Path, files: &mut Vec<PathBuf>, errors: &mut Vec<String>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            errors.push(format!("{}: {}", dir.display(), e));
+            return;
+        }
+    };
+
+    let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok().map(|e| e.path())).collect();
+    paths.sort();
+
+    for path in paths {
+        if path.is_dir() {
+            collect_patch_files(&path, files, errors);
+        } else if path.extension().map(|e| e == "hxy").unwrap_or(false) {
+            files.push(path);
+        }
+    }
+}
+
+fn read_meta(path: &Path) -> (Option<PresetMeta>, Option<String>) {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) => return (None, Some(e.to_string())),
+    };
+
+    match serde_json::from_slice::<Value>(&data) {
+        Ok(patch) => (PresetMeta::from_patch_json(&patch), None),
+        Err(e) => (None, Some(format!("Patch is not valid JSON: {}", e))),
+    }
+}
@@ -0,0 +1,68 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexodsp::{Matrix, SAtom};
+
+use std::sync::Mutex;
+
+/// Watches a string property of the matrix, for the settings that are
+/// saved with the patch and parsed from it when it changed.
+///
+/// A missing property or one that is not a string counts as the empty
+/// string. The watcher starts out with no string, so the first
+/// [PropWatcher::changed] always returns the property.
+#[derive(Debug)]
+pub struct PropWatcher {
+    prop: &'static str,
+    last: Mutex<Option<String>>,
+}
+
+impl PropWatcher {
+    pub fn new(prop: &'static str) -> Self {
+        Self { prop, last: Mutex::new(None) }
+    }
+
+    /// Returns the property string if it changed since the last call.
+    pub fn changed(&self, matrix: &Matrix) -> Option<String> {
+        let s = match matrix.get_prop(self.prop) {
+            Some(SAtom::Str(s)) => s.to_string(),
+            _ => String::new(),
+        };
+
+        let mut last = self.last.lock().ok()?;
+        if last.as_deref() == Some(&s[..]) {
+            return None;
+        }
+        *last = Some(s.clone());
+
+        Some(s)
+    }
+
+    /// Stores `s` in the property, unless it is the string the watcher
+    /// has seen last. The watcher does not report the own change.
+    pub fn store(&self, matrix: &mut Matrix, s: &str) {
+        if let Ok(mut last) = self.last.lock() {
+            if last.as_deref() == Some(s) {
+                return;
+            }
+            *last = Some(s.to_string());
+        }
+
+        matrix.set_prop(self.prop, SAtom::str(s));
+    }
+
+    /// The next [PropWatcher::changed] returns the property, also if it
+    /// did not change. For example to apply it with new defaults.
+    pub fn reset(&self) {
+        if let Ok(mut last) = self.last.lock() {
+            *last = None;
+        }
+    }
+}
+
+impl Clone for PropWatcher {
+    fn clone(&self) -> Self {
+        Self { prop: self.prop, last: Mutex::new(self.last.lock().ok().and_then(|l| l.clone())) }
+    }
+}
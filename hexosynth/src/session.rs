@@ -0,0 +1,283 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use crate::app_log::{log_error, log_warn};
+use crate::patch_migration::{load_patch_migrated_from_mem, PatchLoadReport};
+use crate::ExtParamSet;
+
+use hexodsp::Matrix;
+use hexotk::ParamModel;
+
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const SESSION_FILE: &str = "session.json";
+/// Exists while a standalone instance is running. If it is still there
+/// on the next start, the previous instance did not shut down cleanly.
+const RUNNING_MARKER: &str = "session.running";
+
+pub const DEFAULT_WINDOW_SIZE: (i32, i32) = (1400, 800);
+
+/// How often the standalone builds save the session while running.
+pub const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Everything that is stored in the session file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionData {
+    /// The serialized matrix, in the same format as a `.hxy` patch file.
+    pub patch: Option<serde_json::Value>,
+    /// The values of the external parameters A1 to F3.
+    pub ext_params: BTreeMap<String, f32>,
+    pub window_size: (i32, i32),
+    pub last_patch_path: Option<String>,
+}
+
+impl Default for SessionData {
+    fn default() -> Self {
+        Self {
+            patch: None,
+            ext_params: BTreeMap::new(),
+            window_size: DEFAULT_WINDOW_SIZE,
+            last_patch_path: None,
+        }
+    }
+}
+
+/// The per user session of the standalone builds.
+///
+/// The session file is autosaved while HexoSynth is running. If the
+/// application crashed, the patch is recovered from it on the next start.
+pub struct Session {
+    dir: PathBuf,
+    data: Mutex<SessionData>,
+    recovered: bool,
+    stop_autosave: Arc<AtomicBool>,
+    autosave_thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Session {
+    /// Opens the session in the per user data directory of HexoSynth.
+    pub fn open() -> Result<Arc<Self>, String> {
+        let dirs = ProjectDirs::from("de", "m8geil", "HexoSynth")
+            .ok_or_else(|| "Can't determine the user data directory".to_string())?;
+        Self::open_in(dirs.data_dir())
+    }
+
+    /// Opens the session stored in `dir`, and marks it as running.
+    pub fn open_in(dir: &Path) -> Result<Arc<Self>, String> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Can't create '{}': {}", dir.display(), e))?;
+
+        let file = dir.join(SESSION_FILE);
+        let data = if file.exists() {
+            std::fs::read_to_string(&file)
+                .map_err(|e| e.to_string())
+                .and_then(|s| serde_json::from_str(&s).map_err(|e| e.to_string()))
+                .unwrap_or_else(|e| {
+                    // Keep the broken file, the autosave would overwrite it:
+                    let backup = dir.join(format!("{}.broken", SESSION_FILE));
+                    let _ = std::fs::rename(&file, &backup);
+                    log_warn(
+                        "session",
+                        &format!(
+                            "Ignoring broken session file '{}', moved it to '{}': {}",
+                            file.display(),
+                            backup.display(),
+                            e
+                        ),
+                    );
+                    SessionData::default()
+                })
+        } else {
+            SessionData::default()
+        };
+
+        let marker = dir.join(RUNNING_MARKER);
+        let recovered = marker.exists();
+        std::fs::write(&marker, format!("{}", std::process::id()))
+            .map_err(|e| format!("Can't write '{}': {}", marker.display(), e))?;
+
+        Ok(Arc::new(Self {
+            dir: dir.to_path_buf(),
+            data: Mutex::new(data),
+            recovered,
+            stop_autosave: Arc::new(AtomicBool::new(false)),
+            autosave_thread: Mutex::new(None),
+        }))
+    }
+
+    pub fn file(&self) -> PathBuf {
+        self.dir.join(SESSION_FILE)
+    }
+
+    /// Returns true if the previous instance did not shut down cleanly,
+    /// only then the patch is recovered from the last autosave.
+    pub fn is_recovered(&self) -> bool {
+        self.recovered
+    }
+
+    pub fn window_size(&self) -> (i32, i32) {
+        self.data.lock().map(|d| d.window_size).unwrap_or(DEFAULT_WINDOW_SIZE)
+    }
+
+    pub fn set_window_size(&self, size: (i32, i32)) {
+        if let Ok(mut data) = self.data.lock() {
+            data.window_size = size;
+        }
+    }
+
+    pub fn last_patch_path(&self) -> Option<String> {
+        self.data.lock().ok().and_then(|d| d.last_patch_path.clone())
+    }
+
+    /// Remembers the last loaded or saved patch file, as absolute path if possible.
+    pub fn set_last_patch_path(&self, path: &str) {
+        let path = std::fs::canonicalize(path)
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_else(|_| path.to_string());
+
+        if let Ok(mut data) = self.data.lock() {
+            data.last_patch_path = Some(path);
+        }
+    }
+
+    /// Loads the patch of the session into the matrix. Returns `None`
+    /// if the session does not contain a patch yet.
+    pub fn restore_matrix(&self, matrix: &mut Matrix) -> Option<Result<PatchLoadReport, String>> {
+        let patch = self.data.lock().ok()?.patch.clone()?;
+        let res = load_patch_migrated_from_mem(matrix, patch.to_string().as_bytes());
+        let _ = matrix.sync();
+        Some(res)
+    }
+
+    /// Sets the external parameters to the values stored in the session.
+    pub fn restore_ext_params(&self, param_set: &ExtParamSet) {
+        let data = match self.data.lock() {
+            Ok(data) => data,
+            Err(_) => return,
+        };
+
+        for param in param_set.params() {
+            if let Some(v) = data.ext_params.get(param.name()) {
+                param.clone().set_denorm(*v);
+            }
+        }
+    }
+
+    /// Captures the current state of the matrix and the external parameters.
+    fn update(&self, matrix: &Mutex<Matrix>, param_set: &ExtParamSet) -> Result<(), String> {
+        let patch = {
+            let mut m = matrix.lock().map_err(|e| format!("Matrix lock failed: {}", e))?;
+            let mut repr = m.to_repr();
+            repr.serialize()
+        };
+        let patch: serde_json::Value =
+            serde_json::from_str(&patch).map_err(|e| format!("Can't serialize patch: {}", e))?;
+
+        let mut data = self.data.lock().map_err(|e| format!("Session lock failed: {}", e))?;
+        data.patch = Some(patch);
+        for param in param_set.params() {
+            data.ext_params.insert(param.name().to_string(), param.get());
+        }
+
+        Ok(())
+    }
+
+    /// Writes the session file. The file is replaced atomically, so a crash
+    /// while writing does not destroy the previous session.
+    fn write(&self) -> Result<(), String> {
+        let s = {
+            let data = self.data.lock().map_err(|e| format!("Session lock failed: {}", e))?;
+            serde_json::to_string_pretty(&*data).map_err(|e| e.to_string())?
+        };
+
+        let file = self.file();
+        let tmp = self.dir.join(format!("{}.tmp", SESSION_FILE));
+        std::fs::write(&tmp, s).map_err(|e| format!("Can't write '{}': {}", tmp.display(), e))?;
+        std::fs::rename(&tmp, &file)
+            .map_err(|e| format!("Can't write '{}': {}", file.display(), e))?;
+
+        Ok(())
+    }
+
+    /// Saves the current state to the session file.
+    pub fn save(&self, matrix: &Mutex<Matrix>, param_set: &ExtParamSet) -> Result<(), String> {
+        self.update(matrix, param_set)?;
+        self.write()
+    }
+
+    /// Starts a thread that saves the session every `interval`,
+    /// if anything changed since the last save.
+    pub fn start_autosave(
+        self: &Arc<Self>,
+        matrix: Arc<Mutex<Matrix>>,
+        param_set: ExtParamSet,
+        interval: Duration,
+    ) {
+        let session = self.clone();
+        let stop = self.stop_autosave.clone();
+
+        let thread = std::thread::spawn(move || {
+            let mut last_saved = String::new();
+            let mut waited = Duration::ZERO;
+            let tick = Duration::from_millis(100);
+
+            while !stop.load(Ordering::Relaxed) {
+                std::thread::sleep(tick);
+                waited += tick;
+                if waited < interval {
+                    continue;
+                }
+                waited = Duration::ZERO;
+
+                if let Err(e) = session.update(&matrix, &param_set) {
+                    log_error("session", &format!("Session autosave failed: {}", e));
+                    continue;
+                }
+
+                let now = session
+                    .data
+                    .lock()
+                    .ok()
+                    .and_then(|d| serde_json::to_string(&*d).ok())
+                    .unwrap_or_default();
+                if now == last_saved {
+                    continue;
+                }
+
+                match session.write() {
+                    Ok(()) => last_saved = now,
+                    Err(e) => log_error("session", &format!("Session autosave failed: {}", e)),
+                }
+            }
+        });
+
+        if let Ok(mut t) = self.autosave_thread.lock() {
+            *t = Some(thread);
+        }
+    }
+
+    /// Stops the autosave, saves the session a last time and
+    /// marks the session as cleanly shut down.
+    pub fn close(&self, matrix: &Mutex<Matrix>, param_set: &ExtParamSet) {
+        self.stop_autosave.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.autosave_thread.lock().ok().and_then(|mut t| t.take()) {
+            let _ = thread.join();
+        }
+
+        if let Err(e) = self.save(matrix, param_set) {
+            log_error("session", &format!("Saving the session failed: {}", e));
+        }
+
+        let _ = std::fs::remove_file(self.dir.join(RUNNING_MARKER));
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use directories::UserDirs;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The file extension of the sub-patch files.
+pub const SUBPATCH_EXTENSION: &str = "hxc";
+
+/// The key of the [SubPatchMeta] in a sub-patch file.
+const SUBPATCH_META_KEY: &str = "subpatch";
+
+/// The name and description that are stored in a sub-patch file,
+/// next to the cluster data.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SubPatchMeta {
+    pub name: String,
+    pub description: String,
+}
+
+/// A sub-patch file found in the sub-patch library.
+#[derive(Debug, Clone)]
+pub struct SubPatchEntry {
+    pub path: PathBuf,
+    /// The path relative to the library root, with `/` as separator.
+    pub rel_path: String,
+    /// If the file has no metadata, the name is the file name without extension.
+    pub meta: SubPatchMeta,
+    /// The node types of the cells, like `"adsr"` or `"amp"`, in the
+    /// order of the cells and without duplicates.
+    pub nodes: Vec<String>,
+    pub cell_count: usize,
+    pub modified: Option<SystemTime>,
+}
+
+/// The sub-patches are clusters in the text form of `Cluster::to_text`,
+/// saved as `.hxc` files next to the `patches` and `samples` directories.
+#[derive(Debug, Clone)]
+pub struct SubPatchLibrary {
+    root: PathBuf,
+    entries: Vec<SubPatchEntry>,
+    scanned: bool,
+}
+
+impl SubPatchLibrary {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root, entries: vec![], scanned: false }
+    }
+
+    /// The subpatches directory in the documents directory of the user.
+    pub fn default_root() -> Option<PathBuf> {
+        let user = UserDirs::new()?;
+        Some(user.document_dir()?.join("HexoSynth").join("subpatches"))
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn is_scanned(&self) -> bool {
+        self.scanned
+    }
+
+    /// The sub-patches sorted by name.
+    pub fn entries(&self) -> &[SubPatchEntry] {
+        &self.entries
+    }
+
+    /// Rebuilds the index from the sub-patch files in the library root and
+    /// all its subdirectories. Returns the messages about the files that
+    /// could not be read, these files are not listed.
+    pub fn rescan(&mut self) -> Vec<String> {
+        let mut errors = vec![];
+        let mut files = vec![];
+        if self.root.is_dir() {
+            collect_subpatch_files(&self.root, &mut files, &mut errors);
+        }
+
+        self.entries = files
+            .into_iter()
+            .filter_map(|path| match read_entry(&self.root, &path) {
+                Ok(entry) => Some(entry),
+                Err(err) => {
+                    errors.push(format!("{}: {}", path.display(), err));
+                    None
+                }
+            })
+            .collect();
+
+        self.entries.sort_by(|a, b| {
+            a.meta
+                .name
+                .to_lowercase()
+                .cmp(&b.meta.name.to_lowercase())
+                .then_with(|| a.rel_path.cmp(&b.rel_path))
+        });
+
+        self.scanned = true;
+
+        errors
+    }
+
+    /// Saves the `cluster_text` of `Cluster::to_text` with the `meta` to a new
+    /// file in the library root. The file name is derived from the name
+    /// and never overwrites an existing sub-patch. Returns the path.
+    pub fn save(&mut self, meta: &SubPatchMeta, cluster_text: &str) -> Result<PathBuf, String> {
+        let mut data: Value = serde_json::from_str(cluster_text.trim())
+            .map_err(|_| "Not a HexoSynth cluster".to_string())?;
+        if data.get("hexosynth_cluster").is_none() {
+            return Err("Not a HexoSynth cluster".to_string());
+        }
+
+        data[SUBPATCH_META_KEY] = serde_json::to_value(meta)
+            .map_err(|e| format!("Can't serialize sub-patch metadata: {}", e))?;
+
+        std::fs::create_dir_all(&self.root)
+            .map_err(|e| format!("Can't create '{}': {}", self.root.display(), e))?;
+
+        let stem = file_stem_for_name(&meta.name);
+        let mut path = self.root.join(format!("{}.{}", stem, SUBPATCH_EXTENSION));
+        let mut n = 2;
+        while path.exists() {
+            path = self.root.join(format!("{}_{}.{}", stem, n, SUBPATCH_EXTENSION));
+            n += 1;
+        }
+
+        let text = serde_json::to_string_pretty(&data)
+            .map_err(|e| format!("Can't serialize sub-patch: {}", e))?;
+        std::fs::write(&path, text)
+            .map_err(|e| format!("Can't write '{}': {}", path.display(), e))?;
+
+        if self.scanned {
+            self.rescan();
+        }
+
+        Ok(path)
+    }
+
+    /// Reads the cluster text of a sub-patch file, for `insert_cluster_text`.
+    pub fn load_text(&self, path: &Path) -> Result<String, String> {
+        std::fs::read_to_string(path).map_err(|e| format!("Can't read '{}': {}", path.display(), e))
+    }
+}
+
+/// Turns the name of a sub-patch into a file name without extension,
+/// only keeping the characters that are safe on all platforms.
+fn file_stem_for_name(name: &str) -> String {
+    let stem: String = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+
+    if stem.is_empty() {
+        "subpatch".to_string()
+    } else {
+        stem
+    }
+}
+
+fn collect_subpatch_files(dir: &Path, files: &mut Vec<PathBuf>, errors: &mut Vec<String>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            errors.push(format!("{}: {}", dir.display(), e));
+            return;
+        }
+    };
+
+    let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok().map(|e| e.path())).collect();
+    paths.sort();
+
+    for path in paths {
+        if path.is_dir() {
+            collect_subpatch_files(&path, files, errors);
+        } else if path.extension().map(|e| e == SUBPATCH_EXTENSION).unwrap_or(false) {
+            files.push(path);
+        }
+    }
+}
+
+fn read_entry(root: &Path, path: &Path) -> Result<SubPatchEntry, String> {
+    let data = std::fs::read(path).map_err(|e| e.to_string())?;
+    let data: Value =
+        serde_json::from_slice(&data).map_err(|e| format!("Sub-patch is not valid JSON: {}", e))?;
+    if data.get("hexosynth_cluster").is_none() {
+        return Err("Not a HexoSynth cluster".to_string());
+    }
+
+    let mut meta: SubPatchMeta =
+        serde_json::from_value(data[SUBPATCH_META_KEY].clone()).unwrap_or_default();
+    if meta.name.is_empty() {
+        meta.name = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    }
+
+    let mut nodes: Vec<String> = vec![];
+    let cells = data["cells"].as_array().map(|c| &c[..]).unwrap_or(&[]);
+    for cell in cells.iter() {
+        if let Some(name) = cell[0].as_str() {
+            if !nodes.iter().any(|n| n == name) {
+                nodes.push(name.to_string());
+            }
+        }
+    }
+
+    let rel_path = path
+        .strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect::<Vec<String>>()
+        .join("/");
+
+    Ok(SubPatchEntry {
+        path: path.to_path_buf(),
+        rel_path,
+        meta,
+        nodes,
+        cell_count: cells.len(),
+        modified: std::fs::metadata(path).and_then(|m| m.modified()).ok(),
+    })
+}
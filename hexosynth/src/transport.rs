@@ -0,0 +1,220 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexodsp::{DynNode1x1Context, DynamicNode1x1};
+
+use crate::dyn_nodes::DynNodeRegistry;
+
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The name of the [TransportNode] prototype in the [DynNodeRegistry].
+pub const TRANSPORT_NODE_NAME: &str = "transport";
+
+pub const DEFAULT_BPM: f64 = 120.0;
+
+/// A snapshot of the transport of the host or the internal clock.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransportInfo {
+    pub playing: bool,
+    pub bpm: f64,
+    pub time_sig: (u32, u32),
+    /// The song position in quarter notes (PPQ position).
+    pub pos_beats: f64,
+}
+
+impl Default for TransportInfo {
+    fn default() -> Self {
+        Self { playing: false, bpm: DEFAULT_BPM, time_sig: (4, 4), pos_beats: 0.0 }
+    }
+}
+
+impl TransportInfo {
+    /// The length of a bar in quarter notes.
+    pub fn bar_beats(&self) -> f64 {
+        self.time_sig.0.max(1) as f64 * 4.0 / self.time_sig.1.max(1) as f64
+    }
+
+    pub fn beats_per_sample(&self, sample_rate: f64) -> f64 {
+        self.bpm / (60.0 * sample_rate)
+    }
+}
+
+/// The transport shared between the audio thread of the frontend,
+/// that updates it every block, and the [TransportNode] in the DSP graph.
+///
+/// The plugin and the JACK standalone get the transport from the host, the
+/// CPAL standalone has an internal clock that is started and stopped from the GUI.
+#[derive(Debug)]
+pub struct Transport {
+    internal: bool,
+    playing: AtomicBool,
+    bpm: AtomicU64,
+    pos_beats: AtomicU64,
+    sig_num: AtomicU32,
+    sig_den: AtomicU32,
+}
+
+impl Transport {
+    /// A transport that is driven by the host with [Transport::update].
+    pub fn new_host() -> Arc<Self> {
+        Arc::new(Self::new(false))
+    }
+
+    /// An internal clock, that is driven by [Transport::advance].
+    pub fn new_internal() -> Arc<Self> {
+        Arc::new(Self::new(true))
+    }
+
+    fn new(internal: bool) -> Self {
+        let info = TransportInfo::default();
+        Self {
+            internal,
+            playing: AtomicBool::new(info.playing),
+            bpm: AtomicU64::new(info.bpm.to_bits()),
+            pos_beats: AtomicU64::new(info.pos_beats.to_bits()),
+            sig_num: AtomicU32::new(info.time_sig.0),
+            sig_den: AtomicU32::new(info.time_sig.1),
+        }
+    }
+
+    pub fn is_internal(&self) -> bool {
+        self.internal
+    }
+
+    pub fn info(&self) -> TransportInfo {
+        TransportInfo {
+            playing: self.playing.load(Ordering::Relaxed),
+            bpm: f64::from_bits(self.bpm.load(Ordering::Relaxed)),
+            time_sig: (self.sig_num.load(Ordering::Relaxed), self.sig_den.load(Ordering::Relaxed)),
+            pos_beats: f64::from_bits(self.pos_beats.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Sets the transport, called by the audio thread before processing a block.
+    pub fn update(&self, info: &TransportInfo) {
+        self.playing.store(info.playing, Ordering::Relaxed);
+        self.set_bpm(info.bpm);
+        self.set_time_sig(info.time_sig.0, info.time_sig.1);
+        self.pos_beats.store(info.pos_beats.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Moves the position of the internal clock forward by `nframes`,
+    /// called by the audio thread after processing a block.
+    pub fn advance(&self, nframes: usize, sample_rate: f64) {
+        let info = self.info();
+        if info.playing {
+            let pos = info.pos_beats + nframes as f64 * info.beats_per_sample(sample_rate);
+            self.pos_beats.store(pos.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_playing(&self, playing: bool) {
+        self.playing.store(playing, Ordering::Relaxed);
+    }
+
+    pub fn set_bpm(&self, bpm: f64) {
+        self.bpm.store(bpm.clamp(1.0, 999.0).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_time_sig(&self, num: u32, den: u32) {
+        self.sig_num.store(num.max(1), Ordering::Relaxed);
+        self.sig_den.store(den.max(1), Ordering::Relaxed);
+    }
+
+    /// Moves the position back to the start of the song.
+    pub fn rewind(&self) {
+        self.pos_beats.store(0.0_f64.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// What the [TransportNode] outputs, selected by its `alpha` parameter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportOutput {
+    /// The position inside a loop of 1 to 16 bars (`beta`), from 0.0 to 1.0.
+    /// Meant for the phase clock input of `TSeq`.
+    Phase,
+    /// A clock with 50% duty cycle, 1 to 32 pulses per quarter note (`beta`).
+    Clock,
+    /// 1.0 while the transport is playing.
+    Play,
+}
+
+impl TransportOutput {
+    pub fn from_alpha(alpha: f32) -> Self {
+        match (alpha.clamp(0.0, 1.0) * 2.0).round() as usize {
+            0 => TransportOutput::Phase,
+            1 => TransportOutput::Clock,
+            _ => TransportOutput::Play,
+        }
+    }
+}
+
+/// The transport source in the matrix, a `Rust1x1` prototype that outputs the
+/// song position, a tempo synced clock or the play state. See [TransportOutput].
+pub struct TransportNode {
+    transport: Arc<Transport>,
+    sample_rate: f64,
+}
+
+impl TransportNode {
+    pub fn new(transport: Arc<Transport>) -> Self {
+        Self { transport, sample_rate: 44100.0 }
+    }
+}
+
+impl DynamicNode1x1 for TransportNode {
+    fn set_sample_rate(&mut self, srate: f32) {
+        self.sample_rate = srate as f64;
+    }
+
+    fn reset(&mut self) {}
+
+    fn process(&mut self, _input: &[f32], output: &mut [f32], ctx: &DynNode1x1Context) {
+        let info = self.transport.info();
+        let step = if info.playing { info.beats_per_sample(self.sample_rate) } else { 0.0 };
+
+        let alpha = ctx.alpha_slice();
+        let beta = ctx.beta_slice();
+
+        for (i, out) in output.iter_mut().enumerate() {
+            let beats = info.pos_beats + i as f64 * step;
+            let b = beta[i].clamp(0.0, 1.0);
+
+            *out = match TransportOutput::from_alpha(alpha[i]) {
+                TransportOutput::Phase => {
+                    let bars = (1.0 + (b * 15.0).round()) as f64;
+                    (beats / (bars * info.bar_beats())).rem_euclid(1.0) as f32
+                }
+                TransportOutput::Clock => {
+                    let pulses = (1 << ((b * 5.0).round() as u32)) as f64;
+                    if info.playing && (beats * pulses).rem_euclid(1.0) < 0.5 {
+                        1.0
+                    } else {
+                        0.0
+                    }
+                }
+                TransportOutput::Play => {
+                    if info.playing {
+                        1.0
+                    } else {
+                        0.0
+                    }
+                }
+            };
+        }
+    }
+}
+
+/// Registers the [TransportNode] as [TRANSPORT_NODE_NAME] prototype,
+/// that can be assigned to any `Rust1x1` instance of a patch.
+pub fn register_transport_node(registry: &DynNodeRegistry, transport: Arc<Transport>) {
+    registry.register(
+        TRANSPORT_NODE_NAME,
+        "Outputs the transport of the host or the internal clock. `alpha` selects \
+         the phase of the loop, a tempo synced clock or the play state, `beta` is \
+         the loop length or the clock rate.",
+        move || Box::new(TransportNode::new(transport.clone())),
+    );
+}
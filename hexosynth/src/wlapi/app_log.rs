@@ -0,0 +1,147 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use crate::app_log::{
+    get_clipboard_text, log_clear, log_entries2text, log_latest_seq, log_msg, log_query,
+    set_clipboard_text, LogEntry, LogFilter, LogLevel,
+};
+
+use wlambda::*;
+
+/// The filter of `hx:log_query` and `hx:log_copy_to_clipboard`, unknown
+/// levels are an error.
+fn vv2log_filter(v: &VVal) -> Result<LogFilter, String> {
+    let mut filter = LogFilter::new();
+
+    if v.v_k("level").is_some() {
+        let level = v.v_s_rawk("level");
+        filter.min_level =
+            LogLevel::from_str(&level).ok_or_else(|| format!("Unknown log level: {}", level))?;
+    }
+    if v.v_k("source").is_some() {
+        filter.source = Some(v.v_s_rawk("source"));
+    }
+    if v.v_k("text").is_some() && !v.v_s_rawk("text").is_empty() {
+        filter.text = Some(v.v_s_rawk("text"));
+    }
+    filter.since = v.v_ik("since").max(0) as u64;
+    filter.limit = v.v_ik("limit").max(0) as usize;
+
+    Ok(filter)
+}
+
+fn log_entry2vv(entry: &LogEntry) -> VVal {
+    let v = VVal::map3(
+        "seq",
+        VVal::Int(entry.seq as i64),
+        "level",
+        VVal::new_sym(entry.level.as_str()),
+        "source",
+        VVal::new_str(&entry.source),
+    );
+    let _ = v.set_key_str("message", VVal::new_str(&entry.message));
+    let _ = v.set_key_str("time_ms", VVal::Int(entry.time_ms as i64));
+    let _ = v.set_key_str("time", VVal::new_str_mv(entry.time_of_day()));
+    v
+}
+
+pub fn setup_log_fns(st: &mut SymbolTable) {
+    st.fun(
+        "log",
+        move |env: &mut Env, _argc: usize| {
+            let level = env.arg(0).s_raw();
+            let level = match LogLevel::from_str(&level) {
+                Some(level) => level,
+                None => return Ok(VVal::err_msg(&format!("Unknown log level: {}", level))),
+            };
+
+            log_msg(level, &env.arg(1).s_raw(), &env.arg(2).s_raw());
+            Ok(VVal::Bol(true))
+        },
+        Some(3),
+        Some(3),
+        false,
+    );
+
+    st.fun(
+        "log_query",
+        move |env: &mut Env, _argc: usize| {
+            let filter = match vv2log_filter(&env.arg(0)) {
+                Ok(filter) => filter,
+                Err(e) => return Ok(VVal::err_msg(&e)),
+            };
+
+            let entries = VVal::vec();
+            for entry in log_query(&filter).iter() {
+                entries.push(log_entry2vv(entry));
+            }
+            Ok(entries)
+        },
+        Some(0),
+        Some(1),
+        false,
+    );
+
+    st.fun(
+        "log_latest_seq",
+        move |_env: &mut Env, _argc: usize| Ok(VVal::Int(log_latest_seq() as i64)),
+        Some(0),
+        Some(0),
+        false,
+    );
+
+    st.fun(
+        "log_clear",
+        move |_env: &mut Env, _argc: usize| {
+            log_clear();
+            Ok(VVal::Bol(true))
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+
+    st.fun(
+        "log_copy_to_clipboard",
+        move |env: &mut Env, _argc: usize| {
+            let filter = match vv2log_filter(&env.arg(0)) {
+                Ok(filter) => filter,
+                Err(e) => return Ok(VVal::err_msg(&e)),
+            };
+
+            let entries = log_query(&filter);
+            match set_clipboard_text(&log_entries2text(&entries)) {
+                Ok(()) => Ok(VVal::Int(entries.len() as i64)),
+                Err(e) => Ok(VVal::err_msg(&format!("Can't copy to the clipboard: {}", e))),
+            }
+        },
+        Some(0),
+        Some(1),
+        false,
+    );
+}
+
+pub fn setup_clipboard_fns(st: &mut SymbolTable) {
+    st.fun(
+        "clipboard_set_text",
+        move |env: &mut Env, _argc: usize| match set_clipboard_text(&env.arg(0).s_raw()) {
+            Ok(()) => Ok(VVal::Bol(true)),
+            Err(e) => Ok(VVal::err_msg(&format!("Can't copy to the clipboard: {}", e))),
+        },
+        Some(1),
+        Some(1),
+        false,
+    );
+
+    st.fun(
+        "clipboard_get_text",
+        move |_env: &mut Env, _argc: usize| match get_clipboard_text() {
+            Ok(text) => Ok(VVal::new_str_mv(text)),
+            Err(e) => Ok(VVal::err_msg(&format!("Can't read the clipboard: {}", e))),
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+}
@@ -0,0 +1,97 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use super::{patch_load_report2vv, HxContext};
+use crate::matrix_snapshot::MatrixCommand;
+
+use wlambda::*;
+
+pub fn setup_daw_state_fns(st: &mut SymbolTable, ctx: &HxContext) {
+    let daw_state = ctx.daw_state.clone();
+    st.fun(
+        "daw_state_info",
+        move |_env: &mut Env, _argc: usize| {
+            let failed = daw_state.as_ref().and_then(|ds| ds.failed());
+            if let Some(failed) = failed {
+                Ok(VVal::map2(
+                    "error",
+                    VVal::new_str_mv(failed.error),
+                    "size",
+                    VVal::Int(failed.data.len() as i64),
+                ))
+            } else {
+                Ok(VVal::None)
+            }
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+
+    let daw_state = ctx.daw_state.clone();
+    let view = ctx.view.clone();
+    st.fun(
+        "daw_state_repair",
+        move |_env: &mut Env, _argc: usize| {
+            if daw_state.as_ref().and_then(|ds| ds.failed()).is_none() {
+                return Ok(VVal::err_msg("There is no DAW state to repair"));
+            }
+
+            view.push(MatrixCommand::DawStateRepair);
+            Ok(VVal::Bol(true))
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+
+    let daw_state = ctx.daw_state.clone();
+    st.fun(
+        "daw_state_repair_result",
+        move |_env: &mut Env, _argc: usize| match daw_state
+            .as_ref()
+            .and_then(|ds| ds.take_repair_result())
+        {
+            Some(Ok(report)) => Ok(patch_load_report2vv(&report)),
+            Some(Err(e)) => Ok(VVal::err_msg(&e)),
+            None => Ok(VVal::None),
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+
+    let daw_state = ctx.daw_state.clone();
+    st.fun(
+        "daw_state_discard",
+        move |_env: &mut Env, _argc: usize| {
+            if let Some(daw_state) = &daw_state {
+                daw_state.discard();
+            }
+            Ok(VVal::None)
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+
+    let daw_state = ctx.daw_state.clone();
+    st.fun(
+        "daw_state_export",
+        move |env: &mut Env, _argc: usize| {
+            let daw_state = match &daw_state {
+                Some(daw_state) => daw_state,
+                None => return Ok(VVal::err_msg("There is no DAW state to export")),
+            };
+
+            match daw_state.export(&env.arg(0).s_raw()) {
+                Ok(()) => Ok(VVal::Bol(true)),
+                Err(e) => Ok(VVal::err_msg(&e)),
+            }
+        },
+        Some(1),
+        Some(1),
+        false,
+    );
+}
@@ -0,0 +1,80 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use super::HxContext;
+use crate::dyn_nodes::is_assignable_dyn_node_slot;
+use crate::matrix_snapshot::MatrixCommand;
+
+use wlambda::*;
+
+pub fn setup_dyn_node_fns(st: &mut SymbolTable, ctx: &HxContext) {
+    let registry = ctx.frontend.dyn_nodes.clone();
+    st.fun(
+        "dyn_node_list",
+        move |_env: &mut Env, _argc: usize| {
+            let list = VVal::vec();
+            for info in registry.list() {
+                list.push(VVal::map2(
+                    "name",
+                    VVal::new_str_mv(info.name),
+                    "description",
+                    VVal::new_str_mv(info.description),
+                ));
+            }
+            Ok(list)
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+
+    let registry = ctx.frontend.dyn_nodes.clone();
+    let matrix = ctx.matrix.clone();
+    let view = ctx.view.clone();
+    st.fun(
+        "dyn_node_assignments",
+        move |_env: &mut Env, _argc: usize| {
+            let mut slots = registry.assignments(&matrix.lock().unwrap());
+            view.queued_dyn_node_assignments(&mut slots);
+
+            let out = VVal::vec();
+            for (slot, name) in slots.iter() {
+                out.push(VVal::pair(VVal::Int(*slot as i64), VVal::new_str(name)));
+            }
+            Ok(out)
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+
+    let registry = ctx.frontend.dyn_nodes.clone();
+    let view = ctx.view.clone();
+    st.fun(
+        "dyn_node_assign",
+        move |env: &mut Env, _argc: usize| {
+            let slot = env.arg(0).i();
+            if slot < 0 || !is_assignable_dyn_node_slot(slot as usize) {
+                return Ok(VVal::err_msg(&format!(
+                    "The Rust1x1 instance {} can't be assigned a prototype",
+                    slot
+                )));
+            }
+
+            let name = env.arg(1);
+            if name.is_some() && !registry.contains(&name.s_raw()) {
+                return Ok(VVal::err_msg(&format!("Unknown Rust1x1 prototype: {}", name.s_raw())));
+            }
+
+            view.push(MatrixCommand::DynNodeAssign {
+                slot: slot as usize,
+                name: if name.is_some() { Some(name.s_raw()) } else { None },
+            });
+            Ok(VVal::Bol(true))
+        },
+        Some(2),
+        Some(2),
+        false,
+    );
+}
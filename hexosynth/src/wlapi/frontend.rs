@@ -0,0 +1,208 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use super::{HxContext, VValMidiOutQueue};
+use crate::audio_device::audio_device_info2vv;
+use crate::midi_expression::{MidiExprEvent, NoteExpr};
+
+use wlambda::*;
+
+fn vv2midi_expr(v: &VVal) -> Option<MidiExprEvent> {
+    let channel = v.v_ik("channel") as u8;
+    let note = v.v_ik("note") as u8;
+    let value = v.v_fk("value") as f32;
+
+    match &v.v_s_rawk("type")[..] {
+        "pitch_bend" => Some(MidiExprEvent::PitchBend { channel, value }),
+        "channel_pressure" => Some(MidiExprEvent::ChannelPressure { channel, value }),
+        "poly_pressure" => Some(MidiExprEvent::PolyPressure { channel, note, value }),
+        "program_change" => {
+            Some(MidiExprEvent::ProgramChange { channel, program: v.v_ik("program") as u8 })
+        }
+        "note_expression" => Some(MidiExprEvent::NoteExpression {
+            channel,
+            note,
+            expr: NoteExpr::from_name(&v.v_s_rawk("expression"))?,
+            value,
+        }),
+        _ => None,
+    }
+}
+
+pub fn setup_audio_device_fns(st: &mut SymbolTable, ctx: &HxContext) {
+    let audio_device_info = ctx.audio_device_info.clone();
+    st.fun(
+        "audio_device_info",
+        move |_env: &mut Env, _argc: usize| {
+            if let Some(info_fn) = &audio_device_info {
+                Ok(audio_device_info2vv(&info_fn()))
+            } else {
+                Ok(VVal::None)
+            }
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+}
+
+pub fn setup_session_fns(st: &mut SymbolTable, ctx: &HxContext) {
+    let session = ctx.session.clone();
+    st.fun(
+        "session_info",
+        move |_env: &mut Env, _argc: usize| {
+            if let Some(session) = &session {
+                Ok(VVal::map3(
+                    "file",
+                    VVal::new_str_mv(session.file().to_string_lossy().to_string()),
+                    "recovered",
+                    VVal::Bol(session.is_recovered()),
+                    "last_patch_path",
+                    session.last_patch_path().map(VVal::new_str_mv).unwrap_or(VVal::None),
+                ))
+            } else {
+                Ok(VVal::None)
+            }
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+
+    let session = ctx.session.clone();
+    st.fun(
+        "session_set_patch_path",
+        move |env: &mut Env, _argc: usize| {
+            if let Some(session) = &session {
+                session.set_last_patch_path(&env.arg(0).s_raw());
+            }
+            Ok(VVal::None)
+        },
+        Some(1),
+        Some(1),
+        false,
+    );
+}
+
+pub fn setup_transport_fns(st: &mut SymbolTable, ctx: &HxContext) {
+    let transport = ctx.frontend.transport.clone();
+    st.fun(
+        "transport_info",
+        move |_env: &mut Env, _argc: usize| {
+            let info = transport.info();
+            let v = VVal::map3(
+                "playing",
+                VVal::Bol(info.playing),
+                "bpm",
+                VVal::Flt(info.bpm),
+                "pos_beats",
+                VVal::Flt(info.pos_beats),
+            );
+            let _ = v.set_key_str(
+                "time_sig",
+                VVal::ivec2(info.time_sig.0 as i64, info.time_sig.1 as i64),
+            );
+            let _ = v.set_key_str("internal", VVal::Bol(transport.is_internal()));
+            Ok(v)
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+
+    let transport = ctx.frontend.transport.clone();
+    st.fun(
+        "transport_set",
+        move |env: &mut Env, _argc: usize| {
+            if !transport.is_internal() {
+                return Ok(VVal::err_msg("The transport is controlled by the host (DAW or JACK)"));
+            }
+
+            let v = env.arg(0);
+            if v.v_k("playing").is_some() {
+                transport.set_playing(v.v_k("playing").b());
+            }
+            if v.v_k("bpm").is_some() {
+                transport.set_bpm(v.v_fk("bpm"));
+            }
+            if v.v_k("time_sig").is_some() {
+                let sig = v.v_k("time_sig");
+                transport.set_time_sig(sig.v_i(0).max(1) as u32, sig.v_i(1).max(1) as u32);
+            }
+            if v.v_k("rewind").b() {
+                transport.rewind();
+            }
+
+            Ok(VVal::Bol(true))
+        },
+        Some(1),
+        Some(1),
+        false,
+    );
+}
+
+pub fn setup_midi_fns(st: &mut SymbolTable, ctx: &HxContext) {
+    let midi_expression = ctx.frontend.midi_expression.clone();
+    st.fun(
+        "inject_midi_expression",
+        move |env: &mut Env, _argc: usize| {
+            if let Some(ev) = vv2midi_expr(&env.arg(0)) {
+                midi_expression.handle(ev);
+                Ok(VVal::None)
+            } else {
+                Ok(VVal::err_msg(&format!("Not a MIDI expression event: {}", env.arg(0).s())))
+            }
+        },
+        Some(1),
+        Some(1),
+        false,
+    );
+
+    st.fun(
+        "midi_out_queue",
+        move |_env: &mut Env, _argc: usize| Ok(VVal::new_usr(VValMidiOutQueue::new())),
+        Some(0),
+        Some(0),
+        false,
+    );
+}
+
+pub fn setup_ext_param_fns(st: &mut SymbolTable, ctx: &HxContext) {
+    let param_set = ctx.param_set.clone();
+    st.fun(
+        "ext_param_get",
+        move |env: &mut Env, _argc: usize| {
+            use hexotk::ParamModel;
+
+            let name = env.arg(0).s_raw();
+            match param_set.by_name(&name) {
+                Some(param) => Ok(VVal::Flt(param.get() as f64)),
+                None => Ok(VVal::err_msg(&format!("Unknown external parameter: {}", name))),
+            }
+        },
+        Some(1),
+        Some(1),
+        false,
+    );
+
+    let param_set = ctx.param_set.clone();
+    st.fun(
+        "ext_param_set",
+        move |env: &mut Env, _argc: usize| {
+            use hexotk::ParamModel;
+
+            let name = env.arg(0).s_raw();
+            match param_set.by_name(&name) {
+                Some(param) => {
+                    param.clone().set_denorm(env.arg(1).f() as f32);
+                    Ok(VVal::Bol(true))
+                }
+                None => Ok(VVal::err_msg(&format!("Unknown external parameter: {}", name))),
+            }
+        },
+        Some(2),
+        Some(2),
+        false,
+    );
+}
@@ -3,28 +3,26 @@
 // See README.md and COPYING for details.
 
 //use crate::arg_chk;
-use hexodsp::{dsp::GraphAtomData, dsp::GraphFun, Matrix, NodeId, SAtom};
+use crate::matrix_snapshot::MatrixView;
+use hexodsp::{dsp::GraphAtomData, dsp::GraphFun, NodeId, SAtom};
 use hexotk::GraphModel;
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::sync::{Arc, Mutex};
 use wlambda::*;
 
 struct NodeGraphAtomData {
-    matrix: Arc<Mutex<Matrix>>,
+    view: MatrixView,
     node_id: NodeId,
 }
 
 impl GraphAtomData for NodeGraphAtomData {
     fn get(&self, param_idx: u32) -> Option<SAtom> {
-        let m = self.matrix.lock().expect("Matrix lockable");
         let pid = self.node_id.param_by_idx(param_idx as usize)?;
-        m.get_param(&pid)
+        self.view.get_param(&pid)
     }
     fn get_denorm(&self, param_idx: u32) -> f32 {
-        let m = self.matrix.lock().expect("Matrix lockable");
         if let Some(pid) = self.node_id.param_by_idx(param_idx as usize) {
-            if let Some(at) = m.get_param(&pid) {
+            if let Some(at) = self.view.get_param(&pid) {
                 pid.denorm(at.f())
             } else {
                 0.0
@@ -34,9 +32,8 @@ impl GraphAtomData for NodeGraphAtomData {
         }
     }
     fn get_norm(&self, param_idx: u32) -> f32 {
-        let m = self.matrix.lock().expect("Matrix lockable");
         if let Some(pid) = self.node_id.param_by_idx(param_idx as usize) {
-            if let Some(at) = m.get_param(&pid) {
+            if let Some(at) = self.view.get_param(&pid) {
                 at.f()
             } else {
                 0.0
@@ -46,25 +43,22 @@ impl GraphAtomData for NodeGraphAtomData {
         }
     }
     fn get_phase(&self) -> f32 {
-        let m = self.matrix.lock().expect("Matrix lockable");
-        m.phase_value_for(&self.node_id)
+        self.view.snapshot().phase_value_for(&self.node_id)
     }
     fn get_led(&self) -> f32 {
-        let m = self.matrix.lock().expect("Matrix lockable");
-        m.led_value_for(&self.node_id)
+        self.view.snapshot().led_value_for(&self.node_id)
     }
 }
 
 struct NodeGraphModel {
-    matrix: Arc<Mutex<Matrix>>,
+    view: MatrixView,
     nga_data: Box<dyn GraphAtomData>,
     fun: Option<GraphFun>,
 }
 
 impl GraphModel for NodeGraphModel {
     fn get_generation(&self) -> u64 {
-        let m = self.matrix.lock().expect("Matrix lockable");
-        m.get_generation() as u64
+        self.view.snapshot().get_generation() as u64
     }
     fn f(&mut self, init: bool, x: f64, x_next: f64) -> f64 {
         if let Some(fun) = &mut self.fun {
@@ -85,14 +79,11 @@ impl GraphModel for NodeGraphModel {
 pub struct VGraphModel(Rc<RefCell<dyn GraphModel>>);
 
 impl VGraphModel {
-    pub fn new(matrix: Arc<Mutex<Matrix>>, node_id: NodeId) -> Self {
+    pub fn new(view: MatrixView, node_id: NodeId) -> Self {
         Self(Rc::new(RefCell::new(NodeGraphModel {
-            nga_data: Box::new(NodeGraphAtomData {
-                matrix: matrix.clone(),
-                node_id: node_id.clone(),
-            }),
+            nga_data: Box::new(NodeGraphAtomData { view: view.clone(), node_id: node_id.clone() }),
             fun: node_id.graph_fun(),
-            matrix,
+            view,
         })))
     }
 }
@@ -6,6 +6,7 @@ use crate::arg_chk;
 use hexotk::{HexCell, HexDir, HexEdge, HexGridModel, HexHLight};
 use wlambda::*;
 
+use crate::matrix_snapshot::MatrixView;
 use hexodsp::{Matrix, NodeId, SAtom};
 
 use std::collections::HashMap;
@@ -15,28 +16,20 @@ use std::cell::RefCell;
 use std::rc::Rc;
 
 pub struct MatrixUIModel {
+    /// Only used for the `node_colors` property.
     matrix: Arc<Mutex<Matrix>>,
+    view: MatrixView,
     w: usize,
     h: usize,
     node_colors: HashMap<NodeId, u8>,
     focus: (usize, usize),
-    edge_led_cache_generation: Rc<RefCell<usize>>,
-    edge_led_cache: Rc<RefCell<std::collections::HashMap<(usize, usize, HexDir), (NodeId, u8)>>>,
 }
 
 impl MatrixUIModel {
-    pub fn new(matrix: Arc<Mutex<Matrix>>) -> Self {
-        let (w, h) = matrix.lock().expect("matrix is lockable").size();
-
-        let mut s = Self {
-            matrix,
-            w,
-            h,
-            node_colors: HashMap::new(),
-            focus: (0, 0),
-            edge_led_cache_generation: Rc::new(RefCell::new(0)),
-            edge_led_cache: Rc::new(RefCell::new(std::collections::HashMap::new())),
-        };
+    pub fn new(matrix: Arc<Mutex<Matrix>>, view: MatrixView) -> Self {
+        let (w, h) = view.snapshot().size();
+
+        let mut s = Self { matrix, view, w, h, node_colors: HashMap::new(), focus: (0, 0) };
 
         s.sync_from_matrix();
 
@@ -103,8 +96,7 @@ impl HexGridModel for MatrixUIModel {
     }
 
     fn get_generation(&self) -> u64 {
-        let m = self.matrix.lock().expect("matrix lockable");
-        m.get_generation() as u64
+        self.view.snapshot().get_generation() as u64
     }
 
     //    fn cell_click(&self, x: usize, y: usize, btn: MButton, modkey: bool) {
@@ -112,9 +104,7 @@ impl HexGridModel for MatrixUIModel {
     //    }
 
     fn cell_empty(&self, x: usize, y: usize) -> bool {
-        let m = self.matrix.lock().expect("matrix lockable");
-
-        if let Some(cell) = m.get(x, y) {
+        if let Some(cell) = self.view.snapshot().get(x, y) {
             cell.node_id() == NodeId::Nop
         } else {
             true
@@ -133,9 +123,7 @@ impl HexGridModel for MatrixUIModel {
             return 0;
         }
 
-        let m = self.matrix.lock().expect("matrix lockable");
-
-        let node_id: Option<NodeId> = m.get(x, y).map(|c| c.node_id());
+        let node_id: Option<NodeId> = self.view.snapshot().get(x, y).map(|c| c.node_id());
 
         if let Some(node_id) = node_id {
             self.color_for_node(node_id)
@@ -148,20 +136,16 @@ impl HexGridModel for MatrixUIModel {
         if x >= self.w || y >= self.h {
             return None;
         }
-        let mut m = self.matrix.lock().expect("matrix lockable");
-        let cell = m.get_copy(x, y)?;
-        let node_id = cell.node_id();
-        Some(m.filtered_led_for(&node_id))
+        let snapshot = self.view.snapshot();
+        let node_id = snapshot.get(x, y)?.node_id();
+        Some(snapshot.filtered_led_for(&node_id))
     }
 
     fn cell_label<'a>(&self, x: usize, y: usize, buf: &'a mut [u8]) -> Option<HexCell<'a>> {
         if x >= self.w || y >= self.h {
             return None;
         }
-        let cell = {
-            let m = self.matrix.lock().expect("matrix lockable");
-            m.get_copy(x, y)?
-        };
+        let cell = *self.view.snapshot().get(x, y)?;
 
         let label = cell.label(buf)?;
 
@@ -170,33 +154,11 @@ impl HexGridModel for MatrixUIModel {
         Some(HexCell { label, hlight: hl })
     }
 
-    //    fn cell_edge_led(&self, x: usize, y: usize, edge: HexDir) -> Option<HexEdge> {
-    //        let mut m = self.matrix.lock().expect("matrix lockable");
-    //        let mut out_fb_info = None;
-    //
-    //        if let Some(cell) = m.get(x, y) {
-    //            let cell_dir = edge.into();
-    //
-    //            if let Some((lbl, is_connected)) =
-    //                m.edge_label(&cell, cell_dir, buf)
-    //            {
-    //                edge_lbl = Some(lbl);
-    //
-    //                if is_connected {
-    //                    if let Some(out_idx) = cell.local_port_idx(cell_dir) {
-    //                        out_fb_info = Some((cell.node_id(), out_idx));
-    //                    }
-    //                }
-    //            }
-    //        }
-    //    }
-
     fn cell_edge(&self, x: usize, y: usize, edge: HexDir) -> HexEdge {
-        let mut m = self.matrix.lock().expect("matrix lockable");
-        let cache = self.edge_led_cache.borrow();
+        let snapshot = self.view.snapshot();
 
-        if let Some((node_id, out_idx)) = cache.get(&(x, y, edge)) {
-            let val = m.filtered_out_fb_for(node_id, *out_idx);
+        if let Some((_, Some((node_id, out_idx)))) = snapshot.edge_label(x, y, edge.into()) {
+            let val = snapshot.filtered_out_fb_for(&node_id, out_idx);
 
             HexEdge::ArrowValue { value: val }
         } else {
@@ -211,32 +173,12 @@ impl HexGridModel for MatrixUIModel {
         edge: HexDir,
         buf: &'a mut [u8],
     ) -> Option<&'a str> {
-        let m = self.matrix.lock().expect("matrix lockable");
-        let mut edge_lbl = None;
-
-        let matrix_gen = m.get_generation();
-        if *self.edge_led_cache_generation.borrow() != matrix_gen {
-            self.edge_led_cache.borrow_mut().clear();
-            *self.edge_led_cache_generation.borrow_mut() = matrix_gen;
-        }
-
-        if let Some(cell) = m.get(x, y) {
-            let cell_dir = edge.into();
-
-            if let Some((lbl, is_connected)) = m.edge_label(&cell, cell_dir, buf) {
-                edge_lbl = Some(lbl);
-
-                if is_connected {
-                    if let Some(out_idx) = cell.local_port_idx(cell_dir) {
-                        self.edge_led_cache
-                            .borrow_mut()
-                            .insert((x, y, edge), (cell.node_id(), out_idx));
-                    }
-                }
-            }
-        }
+        let snapshot = self.view.snapshot();
+        let (lbl, _) = snapshot.edge_label(x, y, edge.into())?;
 
-        edge_lbl
+        let len = lbl.len().min(buf.len());
+        buf[0..len].copy_from_slice(&lbl.as_bytes()[0..len]);
+        std::str::from_utf8(&buf[0..len]).ok()
     }
 }
 
@@ -12,13 +12,33 @@ use super::super::VVPatEditFb;
 use super::super::VVPatModel;
 use super::super::VValHexKnobModel;
 
+use crate::auto_place::{auto_place, PlaceEdge, PlaceGraph, PlaceNode, PlaceResult};
+use crate::cluster::{insert_cluster_text, paste_cluster_text};
+use crate::dyn_nodes::DynNodeRegistry;
+use crate::host_params::{
+    host_params_from_matrix, host_params_store_in_matrix, is_host_param_name, HostParamInfo,
+    HostParamTarget, HOST_PARAM_POOL_SIZE,
+};
+use crate::matrix_history::{param_label, MatrixCapture, MatrixEdit, MatrixHistory};
 use crate::matrix_param_model::KnobParam;
+use crate::matrix_snapshot::MatrixView;
+use crate::midi_learn::{MidiLearnCurve, MidiLearnState, MidiLearnWorker};
+use crate::oversampling::{
+    is_oversampling_factor, oversampling_from_matrix, oversampling_store_in_matrix,
+};
+use crate::patch_migration::{
+    load_patch_migrated_from_file, load_patch_migrated_from_mem, PatchLoadReport,
+};
+use crate::poly::{
+    make_voices, poly_config_from_matrix, poly_config_store_in_matrix, PolyConfig, VoiceStealing,
+};
+use crate::preset_library::PresetMeta;
 
 use hexodsp;
 use wlambda::*;
 
 use hexodsp::matrix::MatrixError;
-use hexodsp::{Cell, CellDir, Matrix, MatrixCellChain, NodeId};
+use hexodsp::{Cell, CellDir, Matrix, MatrixCellChain, NodeId, SAtom};
 
 use hexotk::DummyParamModel;
 pub use hexotk::PatternEditorFeedback;
@@ -115,6 +135,20 @@ fn matrix_error2vval_err(err: MatrixError) -> VVal {
     VVal::Err(Rc::new(RefCell::new((err_val, wlambda::vval::SynPos::empty()))))
 }
 
+pub fn patch_load_report2vv(report: &PatchLoadReport) -> VVal {
+    let migrated = VVal::vec();
+    for msg in report.migrated.iter() {
+        migrated.push(VVal::new_str(msg));
+    }
+
+    let ignored = VVal::vec();
+    for msg in report.ignored.iter() {
+        ignored.push(VVal::new_str(msg));
+    }
+
+    VVal::map3("version", VVal::Int(report.version), "migrated", migrated, "ignored", ignored)
+}
+
 fn build_cell_chain(dir: CellDir, v: &VVal) -> MatrixCellChain {
     let mut chain = MatrixCellChain::new(dir);
 
@@ -140,6 +174,10 @@ fn build_cell_chain(dir: CellDir, v: &VVal) -> MatrixCellChain {
 #[derive(Clone)]
 pub struct VValMatrix {
     matrix: Arc<Mutex<hexodsp::Matrix>>,
+    history: Rc<RefCell<MatrixHistory>>,
+    view: MatrixView,
+    midi_learn: Arc<MidiLearnWorker>,
+    dyn_nodes: Arc<DynNodeRegistry>,
 }
 
 impl vval::VValUserData for VValMatrix {
@@ -163,6 +201,7 @@ impl vval::VValUserData for VValMatrix {
                 return Ok(VVal::new_usr(VValHexGridModel {
                     model: HexGridModelType::Matrix(Rc::new(RefCell::new(MatrixUIModel::new(
                         matrix,
+                        self.view.clone(),
                     )))),
                 }));
             }
@@ -176,10 +215,13 @@ impl vval::VValUserData for VValMatrix {
             "create_hex_knob_model" => {
                 arg_chk!(args, 1, "matrix.create_hex_knob_model[param_id]");
 
-                let matrix = self.matrix.clone();
                 if let Some(param_id) = vv2param_id(env.arg(0)) {
                     return Ok(VVal::new_usr(VValHexKnobModel {
-                        model: Rc::new(RefCell::new(KnobParam::new(matrix, param_id))),
+                        model: Rc::new(RefCell::new(KnobParam::new(
+                            self.view.clone(),
+                            param_id,
+                            self.midi_learn.learn().clone(),
+                        ))),
                     }));
                 } else {
                     wl_panic!(
@@ -191,9 +233,8 @@ impl vval::VValUserData for VValMatrix {
             "create_octave_keys_model" => {
                 arg_chk!(args, 1, "matrix.create_octave_keys_model[param_id]");
 
-                let matrix = self.matrix.clone();
                 if let Some(param_id) = vv2param_id(env.arg(0)) {
-                    return Ok(VVal::new_usr(VOctaveKeysModel::new(matrix, param_id)));
+                    return Ok(VVal::new_usr(VOctaveKeysModel::new(self.view.clone(), param_id)));
                 } else {
                     wl_panic!(
                         "matrix.create_octave_keys_model[param_id] requires \
@@ -204,10 +245,9 @@ impl vval::VValUserData for VValMatrix {
             "create_graph_model" => {
                 arg_chk!(args, 1, "matrix.create_graph_model[node_id]");
 
-                let matrix = self.matrix.clone();
                 let node_id = vv2node_id(&args[0]);
                 if node_id.graph_fun().is_some() {
-                    return Ok(VVal::new_usr(VGraphModel::new(matrix, node_id)));
+                    return Ok(VVal::new_usr(VGraphModel::new(self.view.clone(), node_id)));
                 } else {
                     return Ok(VVal::None);
                 }
@@ -225,7 +265,7 @@ impl vval::VValUserData for VValMatrix {
                 arg_chk!(args, 1, "matrix.get_scope_handle[scope_node_id]");
 
                 return Ok(VVal::new_usr(VScopeModel::new(
-                    self.matrix.clone(),
+                    self.view.clone(),
                     vv2node_id(&args[0]),
                 )));
             }
@@ -263,7 +303,18 @@ impl vval::VValUserData for VValMatrix {
                             return Ok(VVal::None);
                         };
 
+                        let old = m.get_copy(x, y).unwrap_or_else(|| Cell::empty(NodeId::Nop));
                         m.place(x, y, cell);
+                        let new = m.get_copy(x, y).unwrap_or_else(|| Cell::empty(NodeId::Nop));
+
+                        let label = if new.node_id() == NodeId::Nop {
+                            format!("Remove {} at ({}, {})", old.node_id(), x, y)
+                        } else {
+                            format!("Set {} at ({}, {})", new.node_id(), x, y)
+                        };
+                        self.history
+                            .borrow_mut()
+                            .record(label, MatrixEdit::Cell { pos: (x, y), old, new });
 
                         Ok(VVal::Bol(true))
                     } else {
@@ -304,9 +355,15 @@ impl vval::VValUserData for VValMatrix {
                         }
                     });
 
+                    let capture = MatrixCapture::capture(&m);
+
                     if let Err(e) = chain.place(&mut m, x, y) {
                         Ok(VVal::err_msg(&format!("Couldn't place DSP chain: {:?}", e)))
                     } else {
+                        self.history.borrow_mut().record_edits(
+                            format!("Place chain at ({}, {})", x, y),
+                            capture.diff(&m),
+                        );
                         Ok(VVal::None)
                     }
                 }
@@ -317,7 +374,13 @@ impl vval::VValUserData for VValMatrix {
                     let at = vv2atom(env.arg(1));
 
                     if let Some(pid) = pid {
-                        m.set_param(pid, at);
+                        let old = m.get_param(&pid);
+                        m.set_param(pid, at.clone());
+
+                        self.history.borrow_mut().record(
+                            format!("Set {}", param_label(&pid)),
+                            MatrixEdit::Param { param_id: pid, old, new: at },
+                        );
                         Ok(VVal::Bol(true))
                     } else {
                         Ok(VVal::None)
@@ -361,9 +424,16 @@ impl vval::VValUserData for VValMatrix {
 
                     if let Some(pid) = pid {
                         let ma = if ma.is_some() { Some(ma.f() as f32) } else { None };
+                        let old = m.get_param_modamt(&pid);
 
                         match m.set_param_modamt(pid, ma) {
-                            Ok(_) => Ok(VVal::Bol(true)),
+                            Ok(_) => {
+                                self.history.borrow_mut().record(
+                                    format!("Set modulation of {}", param_label(&pid)),
+                                    MatrixEdit::ModAmt { param_id: pid, old, new: ma },
+                                );
+                                Ok(VVal::Bol(true))
+                            }
                             Err(e) => Ok(matrix_error2vval_err(e)),
                         }
                     } else {
@@ -373,31 +443,13 @@ impl vval::VValUserData for VValMatrix {
                 "inject_midi_event" => {
                     arg_chk!(args, 1, "matrix.inject_midi_event[${type=str,cc=num,note=num,channel=num,value=flt}]");
 
-                    let typ = args[0].v_s_rawk("type");
-
-                    let event = match &typ[..] {
-                        "note_on" => hexodsp::HxMidiEvent::NoteOn {
-                            channel: args[0].v_ik("channel") as u8,
-                            note: args[0].v_ik("note") as u8,
-                            vel: args[0].v_fk("velocity") as f32,
-                        },
-                        "note_off" => hexodsp::HxMidiEvent::NoteOff {
-                            channel: args[0].v_ik("channel") as u8,
-                            note: args[0].v_ik("note") as u8,
-                        },
-                        "cc" => hexodsp::HxMidiEvent::CC {
-                            channel: args[0].v_ik("channel") as u8,
-                            cc: args[0].v_ik("cc") as u8,
-                            value: args[0].v_fk("value") as f32,
-                        },
-                        _ => {
-                            return Ok(VVal::err_msg(&format!(
-                                "Not a MIDI message 'type': {:?}",
-                                args[0].s()
-                            )))
-                        }
+                    let event = match vv2midi_event(&args[0]) {
+                        Ok(event) => event,
+                        Err(e) => return Ok(VVal::err_msg(&e)),
                     };
 
+                    // The injected events pass the MIDI learn like those of the frontends:
+                    self.midi_learn.handle_midi_event(&event);
                     m.inject_midi_event(event);
 
                     Ok(VVal::None)
@@ -591,6 +643,7 @@ impl vval::VValUserData for VValMatrix {
                 "restore_snapshot" => {
                     arg_chk!(args, 0, "matrix.restore_snapshot[]");
                     m.restore_matrix();
+                    self.history.borrow_mut().discard_group();
                     Ok(VVal::Bol(true))
                 }
                 "save_snapshot" => {
@@ -609,37 +662,37 @@ impl vval::VValUserData for VValMatrix {
                 "load_init_patch" => {
                     arg_chk!(args, 0, "matrix.load_init_patch[]");
 
-                    use hexodsp::matrix_repr::load_patch_from_mem;
+                    self.history.borrow_mut().clear();
 
-                    match load_patch_from_mem(
+                    let report = match load_patch_migrated_from_mem(
                         &mut m,
                         include_bytes!("../../res/init_patch_2022_1.hxy"),
                     ) {
-                        Ok(_) => {}
+                        Ok(report) => report,
                         Err(e) => {
-                            return Ok(VVal::err_msg(&format!("{:?}", e)));
+                            return Ok(VVal::err_msg(&e));
                         }
-                    }
+                    };
 
                     match m.sync() {
-                        Ok(_) => Ok(VVal::Bol(true)),
+                        Ok(_) => Ok(patch_load_report2vv(&report)),
                         Err(e) => Ok(matrix_error2vval_err(e)),
                     }
                 }
                 "load_patch" => {
                     arg_chk!(args, 1, "matrix.load_patch[filepath]");
 
-                    use hexodsp::matrix_repr::load_patch_from_file;
+                    self.history.borrow_mut().clear();
 
-                    match load_patch_from_file(&mut m, &env.arg(0).s_raw()) {
-                        Ok(_) => {}
+                    let report = match load_patch_migrated_from_file(&mut m, &env.arg(0).s_raw()) {
+                        Ok(report) => report,
                         Err(e) => {
-                            return Ok(VVal::err_msg(&format!("{:?}", e)));
+                            return Ok(VVal::err_msg(&e));
                         }
-                    }
+                    };
 
                     match m.sync() {
-                        Ok(_) => Ok(VVal::Bol(true)),
+                        Ok(_) => Ok(patch_load_report2vv(&report)),
                         Err(e) => Ok(matrix_error2vval_err(e)),
                     }
                 }
@@ -658,6 +711,268 @@ impl vval::VValUserData for VValMatrix {
                         Err(e) => Ok(VVal::err_msg(&format!("{}", e))),
                     }
                 }
+                "get_preset_meta" => {
+                    arg_chk!(args, 0, "matrix.get_preset_meta[]");
+
+                    Ok(PresetMeta::from_matrix(&m)
+                        .map(|meta| preset_meta2vv(&meta))
+                        .unwrap_or(VVal::None))
+                }
+                "set_preset_meta" => {
+                    arg_chk!(args, 1, "matrix.set_preset_meta[meta]");
+
+                    vv2preset_meta(&env.arg(0)).store_in_matrix(&mut m);
+                    Ok(VVal::Bol(true))
+                }
+                "get_host_params" => {
+                    arg_chk!(args, 0, "matrix.get_host_params[]");
+
+                    let out = VVal::map();
+                    for (name, info) in host_params_from_matrix(&m).iter() {
+                        let v = VVal::map3(
+                            "label",
+                            VVal::new_str(&info.label),
+                            "unit",
+                            VVal::new_str(&info.unit),
+                            "precision",
+                            VVal::Int(info.precision as i64),
+                        );
+                        let _ = v.set_key_str("min", VVal::Flt(info.min as f64));
+                        let _ = v.set_key_str("max", VVal::Flt(info.max as f64));
+                        let _ = v.set_key_str(
+                            "target",
+                            info.target
+                                .as_ref()
+                                .and_then(|t| t.param_id())
+                                .map(param_id2vv)
+                                .unwrap_or(VVal::None),
+                        );
+                        let _ = out.set_key_str(name, v);
+                    }
+                    Ok(out)
+                }
+                "set_host_param" => {
+                    arg_chk!(args, 2, "matrix.set_host_param[name, info]");
+
+                    let name = env.arg(0).s_raw();
+                    if !is_host_param_name(&name) {
+                        return Ok(VVal::err_msg(&format!("Unknown host parameter: {}", name)));
+                    }
+
+                    let mut infos = host_params_from_matrix(&m);
+
+                    let v = env.arg(1);
+                    if v.is_none() {
+                        infos.remove(&name);
+                    } else {
+                        let mut info = HostParamInfo::default();
+                        if v.v_k("label").is_some() {
+                            info.label = v.v_s_rawk("label");
+                        }
+                        if v.v_k("unit").is_some() {
+                            info.unit = v.v_s_rawk("unit");
+                        }
+                        if v.v_k("min").is_some() {
+                            info.min = v.v_fk("min") as f32;
+                        }
+                        if v.v_k("max").is_some() {
+                            info.max = v.v_fk("max") as f32;
+                        }
+                        if v.v_k("precision").is_some() {
+                            info.precision = v.v_ik("precision").clamp(0, 9) as usize;
+                        }
+
+                        let target = v.v_k("target");
+                        if target.is_some() {
+                            if name.starts_with('P') {
+                                if let Some(param_id) = vv2param_id(target) {
+                                    info.target = Some(HostParamTarget::from_param_id(param_id));
+                                } else {
+                                    return Ok(VVal::err_msg(
+                                        "The target of a host parameter must be a $<HexoDSP::ParamId>",
+                                    ));
+                                }
+                            } else {
+                                return Ok(VVal::err_msg(&format!(
+                                    "Only the pool parameters P1 to P{} can have a target",
+                                    HOST_PARAM_POOL_SIZE
+                                )));
+                            }
+                        }
+
+                        infos.insert(name, info);
+                    }
+
+                    host_params_store_in_matrix(&mut m, &infos);
+                    Ok(VVal::Bol(true))
+                }
+                "get_oversampling" => {
+                    arg_chk!(args, 0, "matrix.get_oversampling[]");
+
+                    Ok(VVal::Int(oversampling_from_matrix(&m) as i64))
+                }
+                "set_oversampling" => {
+                    arg_chk!(args, 1, "matrix.set_oversampling[factor]");
+
+                    let factor = env.arg(0).i();
+                    if factor < 0 || !is_oversampling_factor(factor as usize) {
+                        return Ok(VVal::err_msg(&format!(
+                            "The oversampling factor must be 1, 2, 4 or 8: {}",
+                            factor
+                        )));
+                    }
+
+                    oversampling_store_in_matrix(&mut m, factor as usize);
+                    Ok(VVal::Bol(true))
+                }
+                "get_poly" => {
+                    arg_chk!(args, 0, "matrix.get_poly[]");
+
+                    Ok(poly_config_from_matrix(&m)
+                        .map(|c| poly_config2vv(&c))
+                        .unwrap_or(VVal::None))
+                }
+                "set_poly" => {
+                    arg_chk!(args, 1, "matrix.set_poly[${voices, stealing, channel} | $none]");
+
+                    let v = env.arg(0);
+                    if v.is_none() {
+                        poly_config_store_in_matrix(&mut m, None);
+                        return Ok(VVal::Bol(true));
+                    }
+
+                    let mut config = if let Some(config) = poly_config_from_matrix(&m) {
+                        config
+                    } else {
+                        return Ok(VVal::err_msg(
+                            "The matrix has no voices, create them with cluster.make_voices",
+                        ));
+                    };
+
+                    // The MidiP and MidiCC nodes of the voices are set to their channel:
+                    let voice_channel = config.voice_channel;
+                    if let Err(e) = vv2poly_config(&v, &mut config) {
+                        return Ok(VVal::err_msg(&e));
+                    }
+                    if config.voice_channel != voice_channel {
+                        return Ok(VVal::err_msg(
+                            "The channels of the voices are set by cluster.make_voices",
+                        ));
+                    }
+
+                    poly_config_store_in_matrix(&mut m, Some(&config));
+                    Ok(VVal::Bol(true))
+                }
+                "midi_learn_start" => {
+                    arg_chk!(args, 0, "matrix.midi_learn_start[]");
+
+                    if let Ok(mut ml) = self.midi_learn.learn().lock() {
+                        ml.start();
+                    }
+                    Ok(VVal::Bol(true))
+                }
+                "midi_learn_arm" => {
+                    arg_chk!(args, 2, "matrix.midi_learn_arm[param_id, ${min, max, curve}]");
+
+                    let param_id = if let Some(param_id) = vv2param_id(env.arg(0)) {
+                        param_id
+                    } else {
+                        wl_panic!(
+                            "matrix.midi_learn_arm[param_id, ${min, max, curve}] requires \
+                            a $<HexoDSP::ParamId> as first argument."
+                        );
+                    };
+
+                    let opts = env.arg(1);
+                    let min = opts.v_k("min");
+                    let max = opts.v_k("max");
+                    let curve = opts.v_k("curve");
+                    let curve = if curve.is_none() {
+                        MidiLearnCurve::Linear
+                    } else if let Some(curve) = MidiLearnCurve::from_name(&curve.s_raw()) {
+                        curve
+                    } else {
+                        return Ok(VVal::err_msg(&format!(
+                            "Unknown MIDI learn curve: {}",
+                            curve.s_raw()
+                        )));
+                    };
+
+                    if let Ok(mut ml) = self.midi_learn.learn().lock() {
+                        ml.arm(
+                            param_id,
+                            if min.is_none() { 0.0 } else { min.f() as f32 },
+                            if max.is_none() { 1.0 } else { max.f() as f32 },
+                            curve,
+                        );
+                    }
+                    Ok(VVal::Bol(true))
+                }
+                "midi_learn_cancel" => {
+                    arg_chk!(args, 0, "matrix.midi_learn_cancel[]");
+
+                    if let Ok(mut ml) = self.midi_learn.learn().lock() {
+                        ml.cancel();
+                    }
+                    Ok(VVal::Bol(true))
+                }
+                "midi_learn_state" => {
+                    arg_chk!(args, 0, "matrix.midi_learn_state[]");
+
+                    let ml = match self.midi_learn.learn().lock() {
+                        Ok(ml) => ml,
+                        Err(_) => return Ok(VVal::None),
+                    };
+
+                    Ok(match ml.state() {
+                        MidiLearnState::Idle => VVal::None,
+                        MidiLearnState::WaitKnob => VVal::new_sym("wait_knob"),
+                        MidiLearnState::Armed { param_id, .. } => param_id2vv(*param_id),
+                    })
+                }
+                "midi_learn_bindings" => {
+                    arg_chk!(args, 0, "matrix.midi_learn_bindings[]");
+
+                    let mut ml = match self.midi_learn.learn().lock() {
+                        Ok(ml) => ml,
+                        Err(_) => return Ok(VVal::None),
+                    };
+                    ml.load_from_matrix(&m);
+
+                    let out = VVal::vec();
+                    for (i, b) in ml.bindings().iter().enumerate() {
+                        let v = VVal::map3(
+                            "index",
+                            VVal::Int(i as i64),
+                            "channel",
+                            VVal::Int(b.channel as i64),
+                            "cc",
+                            VVal::Int(b.cc as i64),
+                        );
+                        let _ = v.set_key_str("param", param_id2vv(b.param_id));
+                        let _ = v.set_key_str("min", VVal::Flt(b.min as f64));
+                        let _ = v.set_key_str("max", VVal::Flt(b.max as f64));
+                        let _ = v.set_key_str("curve", VVal::new_str(b.curve.name()));
+                        out.push(v);
+                    }
+                    Ok(out)
+                }
+                "midi_learn_remove" => {
+                    arg_chk!(args, 1, "matrix.midi_learn_remove[index]");
+
+                    let mut ml = match self.midi_learn.learn().lock() {
+                        Ok(ml) => ml,
+                        Err(_) => return Ok(VVal::Bol(false)),
+                    };
+                    ml.load_from_matrix(&m);
+
+                    if ml.remove(args[0].i().max(0) as usize) {
+                        ml.store_in_matrix(&mut m);
+                        Ok(VVal::Bol(true))
+                    } else {
+                        Ok(VVal::Bol(false))
+                    }
+                }
                 "sync" => {
                     arg_chk!(args, 0, "matrix.sync[]");
 
@@ -670,8 +985,63 @@ impl vval::VValUserData for VValMatrix {
                     arg_chk!(args, 0, "matrix.clear[]");
 
                     m.clear();
+                    self.history.borrow_mut().clear();
                     Ok(VVal::Bol(true))
                 }
+                "undo" => {
+                    arg_chk!(args, 0, "matrix.undo[]");
+
+                    match self.history.borrow_mut().undo(&mut m) {
+                        Ok(Some(label)) => Ok(VVal::new_str_mv(label)),
+                        Ok(None) => Ok(VVal::None),
+                        Err(e) => Ok(matrix_error2vval_err(e)),
+                    }
+                }
+                "redo" => {
+                    arg_chk!(args, 0, "matrix.redo[]");
+
+                    match self.history.borrow_mut().redo(&mut m) {
+                        Ok(Some(label)) => Ok(VVal::new_str_mv(label)),
+                        Ok(None) => Ok(VVal::None),
+                        Err(e) => Ok(matrix_error2vval_err(e)),
+                    }
+                }
+                "history_list" => {
+                    arg_chk!(args, 0, "matrix.history_list[]");
+
+                    let history = self.history.borrow();
+
+                    let undo = VVal::vec();
+                    history.for_undo_steps(|step| undo.push(VVal::new_str(step.label())));
+                    let redo = VVal::vec();
+                    history.for_redo_steps(|step| redo.push(VVal::new_str(step.label())));
+
+                    Ok(VVal::map2("undo", undo, "redo", redo))
+                }
+                "history_begin_group" => {
+                    arg_chk!(args, 0, "matrix.history_begin_group[]");
+
+                    self.history.borrow_mut().begin_group();
+                    Ok(VVal::Bol(true))
+                }
+                "history_end_group" => {
+                    arg_chk!(args, 1, "matrix.history_end_group[label or $none]");
+
+                    let label = if args[0].is_str() { Some(args[0].s_raw()) } else { None };
+                    self.history.borrow_mut().end_group(label);
+                    Ok(VVal::Bol(true))
+                }
+                "set_history_depth" => {
+                    arg_chk!(args, 1, "matrix.set_history_depth[depth]");
+
+                    self.history.borrow_mut().set_depth(args[0].i().max(0) as usize);
+                    Ok(VVal::Bol(true))
+                }
+                "history_depth" => {
+                    arg_chk!(args, 0, "matrix.history_depth[]");
+
+                    Ok(VVal::Int(self.history.borrow().depth() as i64))
+                }
                 "monitored_cell" => {
                     arg_chk!(args, 0, "matrix.monitored_cell[]");
 
@@ -689,6 +1059,68 @@ impl vval::VValUserData for VValMatrix {
 
                     Ok(m.pop_error().map(|s| VVal::new_str_mv(s)).unwrap_or_else(|| VVal::None))
                 }
+                "paste_cluster_text" => {
+                    arg_chk!(args, 2, "matrix.paste_cluster_text[text, $i(x, y)]");
+
+                    let pos = env.arg(1);
+                    let pos = (pos.v_i(0).max(0) as usize, pos.v_i(1).max(0) as usize);
+
+                    let capture = MatrixCapture::capture(&m);
+                    match paste_cluster_text(&mut m, &env.arg(0).s_raw(), pos) {
+                        Ok(placed) => {
+                            self.history
+                                .borrow_mut()
+                                .record_edits("Paste cluster".to_string(), capture.diff(&m));
+
+                            let v = VVal::vec();
+                            for (x, y) in placed.iter() {
+                                v.push(VVal::ivec2(*x as i64, *y as i64));
+                            }
+                            Ok(v)
+                        }
+                        Err(e) => Ok(VVal::err_msg(&e)),
+                    }
+                }
+                "insert_cluster_text" => {
+                    arg_chk!(args, 2, "matrix.insert_cluster_text[text, $i(x, y)]");
+
+                    let pos = env.arg(1);
+                    let pos = (pos.v_i(0).max(0) as usize, pos.v_i(1).max(0) as usize);
+
+                    let capture = MatrixCapture::capture(&m);
+                    match insert_cluster_text(&mut m, &env.arg(0).s_raw(), pos) {
+                        Ok(placed) => {
+                            self.history
+                                .borrow_mut()
+                                .record_edits("Insert sub-patch".to_string(), capture.diff(&m));
+
+                            let v = VVal::vec();
+                            for (x, y) in placed.iter() {
+                                v.push(VVal::ivec2(*x as i64, *y as i64));
+                            }
+                            Ok(v)
+                        }
+                        Err(e) => Ok(VVal::err_msg(&e)),
+                    }
+                }
+                "auto_place" => {
+                    arg_chk!(args, 2, "matrix.auto_place[graph, $i(x, y)]");
+
+                    let origin = env.arg(1);
+                    let origin = (origin.v_i(0).max(0) as usize, origin.v_i(1).max(0) as usize);
+                    let graph = vv2place_graph(&env.arg(0));
+
+                    let capture = MatrixCapture::capture(&m);
+                    match auto_place(&mut m, &graph, origin) {
+                        Ok(res) => {
+                            self.history
+                                .borrow_mut()
+                                .record_edits("Auto place graph".to_string(), capture.diff(&m));
+                            Ok(place_result2vv(&res))
+                        }
+                        Err(reasons) => Ok(VVal::err_msg(&reasons.join("\n"))),
+                    }
+                }
                 "get_unused_instance_node_id" => {
                     arg_chk!(args, 1, "matrix.get_unused_instance_node_id[node_id]");
 
@@ -884,6 +1316,117 @@ pub fn vv2cell(v: &VVal) -> Cell {
     m_cell
 }
 
+fn poly_config2vv(config: &PolyConfig) -> VVal {
+    let v = VVal::map3(
+        "voices",
+        VVal::Int(config.voices as i64),
+        "copies",
+        VVal::Int(config.copies as i64),
+        "stealing",
+        VVal::new_sym(config.stealing.name()),
+    );
+    let _ = v.set_key_str("channel", VVal::Int(config.channel as i64 + 1));
+    let _ = v.set_key_str("voice_channel", VVal::Int(config.voice_channel as i64 + 1));
+    v
+}
+
+/// Applies the keys `voices`, `stealing`, `channel` and `voice_channel`
+/// (1 to 16) that are present in `v` to the `config`. The voices are limited
+/// to the copies that were made, if there are any.
+fn vv2poly_config(v: &VVal, config: &mut PolyConfig) -> Result<(), String> {
+    if v.v_k("voices").is_some() {
+        let voices = v.v_ik("voices").max(0) as usize;
+        config.voices = if config.copies > 0 { voices.clamp(1, config.copies) } else { voices };
+    }
+    if v.v_k("stealing").is_some() {
+        let name = v.v_s_rawk("stealing");
+        config.stealing = VoiceStealing::from_name(&name)
+            .ok_or_else(|| format!("Unknown voice stealing mode: {}", name))?;
+    }
+    if v.v_k("channel").is_some() {
+        let channel = v.v_ik("channel");
+        if !(1..=16).contains(&channel) {
+            return Err(format!("The MIDI channel must be between 1 and 16: {}", channel));
+        }
+        config.channel = (channel - 1) as u8;
+    }
+    if v.v_k("voice_channel").is_some() {
+        let channel = v.v_ik("voice_channel");
+        if !(1..=16).contains(&channel) {
+            return Err(format!("The MIDI channel must be between 1 and 16: {}", channel));
+        }
+        config.voice_channel = (channel - 1) as u8;
+    }
+    Ok(())
+}
+
+/// Reads the graph of `matrix.auto_place`. The nodes are sorted by their
+/// key, because the order of the keys in a map is not stable.
+fn vv2place_graph(v: &VVal) -> PlaceGraph {
+    let mut graph = PlaceGraph::default();
+
+    v.v_k("nodes").with_iter(|it| {
+        for (node, key) in it {
+            let key = key.map(|k| k.s_raw()).unwrap_or_default();
+            let mut params = vec![];
+            if node.is_map() {
+                node.v_k("params").with_iter(|it| {
+                    for (value, name) in it {
+                        let name = name.map(|n| n.s_raw()).unwrap_or_default();
+                        let atom = match value {
+                            VVal::Flt(denorm) => SAtom::param(denorm as f32),
+                            value => vv2atom(value),
+                        };
+                        params.push((name, atom));
+                    }
+                });
+            }
+
+            let typ = if node.is_map() { node.v_s_rawk("node") } else { node.s_raw() };
+
+            graph.nodes.push(PlaceNode { key, node_id: NodeId::from_str(&typ), params });
+        }
+    });
+    graph.nodes.sort_by(|a, b| a.key.cmp(&b.key));
+
+    v.v_k("edges").with_iter(|it| {
+        for (edge, _) in it {
+            graph.edges.push(PlaceEdge {
+                from: edge.v_s_raw(0),
+                output: edge.v_s_raw(1),
+                to: edge.v_s_raw(2),
+                input: edge.v_s_raw(3),
+            });
+        }
+    });
+
+    graph
+}
+
+fn place_result2vv(res: &PlaceResult) -> VVal {
+    let nodes = VVal::map();
+    for (key, node_id) in res.nodes.iter() {
+        let _ = nodes.set_key_str(key, node_id2vv(*node_id));
+    }
+
+    let poses2vv = |poses: &[(usize, usize)]| {
+        let v = VVal::vec();
+        for (x, y) in poses.iter() {
+            v.push(VVal::ivec2(*x as i64, *y as i64));
+        }
+        v
+    };
+
+    VVal::map3(
+        "nodes",
+        nodes,
+        "cells",
+        poses2vv(&res.cells),
+        "helper_cells",
+        poses2vv(&res.helper_cells),
+    )
+}
+
 #[derive(Clone)]
 pub struct VValCluster {
     cluster: Rc<RefCell<crate::cluster::Cluster>>,
@@ -957,9 +1500,15 @@ impl vval::VValUserData for VValCluster {
 
                 let mut m = env.arg(0);
 
-                if let Some(matrix) = m.with_usr_ref(|m: &mut VValMatrix| m.matrix.clone()) {
+                if let Some((matrix, history)) =
+                    m.with_usr_ref(|m: &mut VValMatrix| (m.matrix.clone(), m.history.clone()))
+                {
                     if let Ok(mut m) = matrix.lock() {
+                        let capture = MatrixCapture::capture(&m);
                         self.cluster.borrow_mut().remove_cells(&mut m);
+                        history
+                            .borrow_mut()
+                            .record_edits("Remove cluster".to_string(), capture.diff(&m));
                     }
                 }
 
@@ -970,10 +1519,25 @@ impl vval::VValUserData for VValCluster {
 
                 let mut m = env.arg(0);
 
-                if let Some(matrix) = m.with_usr_ref(|m: &mut VValMatrix| m.matrix.clone()) {
+                if let Some((matrix, history)) =
+                    m.with_usr_ref(|m: &mut VValMatrix| (m.matrix.clone(), m.history.clone()))
+                {
                     if let Ok(mut m) = matrix.lock() {
+                        if let Some((x, y)) = self.cluster.borrow().find_collision(&m) {
+                            return Ok(VVal::err_msg(&format!(
+                                "The cluster collides with the cell at ({}, {})",
+                                x, y
+                            )));
+                        }
+
+                        let capture = MatrixCapture::capture(&m);
                         return match self.cluster.borrow_mut().place(&mut m) {
-                            Ok(_) => Ok(VVal::Bol(true)),
+                            Ok(_) => {
+                                history
+                                    .borrow_mut()
+                                    .record_edits("Move cluster".to_string(), capture.diff(&m));
+                                Ok(VVal::Bol(true))
+                            }
                             Err(e) => Ok(matrix_error2vval_err(e)),
                         };
                     }
@@ -981,6 +1545,84 @@ impl vval::VValUserData for VValCluster {
 
                 Ok(VVal::None)
             }
+            "make_voices" => {
+                arg_chk!(
+                    args,
+                    2,
+                    "cluster.make_voices[matrix, ${voices, stealing, channel, voice_channel}]"
+                );
+
+                let mut m = env.arg(0);
+                let v = env.arg(1);
+
+                let mut config = PolyConfig {
+                    voices: v.v_ik("voices").max(0) as usize,
+                    ..PolyConfig::default()
+                };
+                if let Err(e) = vv2poly_config(&v, &mut config) {
+                    return Ok(VVal::err_msg(&e));
+                }
+
+                if let Some((matrix, history, dyn_nodes)) = m.with_usr_ref(|m: &mut VValMatrix| {
+                    (m.matrix.clone(), m.history.clone(), m.dyn_nodes.clone())
+                }) {
+                    if let Ok(mut m) = matrix.lock() {
+                        let capture = MatrixCapture::capture(&m);
+                        let cluster = self.cluster.borrow();
+                        let assignments = dyn_nodes.assignments(&m);
+                        return match make_voices(&mut m, cluster.cells(), &config, assignments) {
+                            Ok(config) => {
+                                dyn_nodes.load_from_matrix(&m);
+                                history
+                                    .borrow_mut()
+                                    .record_edits("Make voices".to_string(), capture.diff(&m));
+                                Ok(poly_config2vv(&config))
+                            }
+                            Err(e) => Ok(VVal::err_msg(&e)),
+                        };
+                    }
+                }
+
+                Ok(VVal::None)
+            }
+            "rotate" => {
+                arg_chk!(args, 1, "cluster.rotate[steps]");
+
+                match self.cluster.borrow_mut().rotate(env.arg(0).i(), None) {
+                    Ok(_) => Ok(VVal::Bol(true)),
+                    Err(e) => Ok(VVal::err_msg(&e)),
+                }
+            }
+            "mirror" => {
+                arg_chk!(args, 1, "cluster.mirror[axis]");
+
+                let mut axis = env.arg(0);
+                let axis = match axis.with_usr_ref(|v: &mut VValCellDir| v.dir) {
+                    Some(dir) => dir,
+                    None => vv2cell_dir(&axis),
+                };
+
+                match self.cluster.borrow_mut().mirror(axis, None) {
+                    Ok(_) => Ok(VVal::Bol(true)),
+                    Err(e) => Ok(VVal::err_msg(&e)),
+                }
+            }
+            "to_text" => {
+                arg_chk!(args, 1, "cluster.to_text[matrix]");
+
+                let mut m = env.arg(0);
+
+                if let Some(matrix) = m.with_usr_ref(|m: &mut VValMatrix| m.matrix.clone()) {
+                    if let Ok(mut m) = matrix.lock() {
+                        return match self.cluster.borrow().to_text(&mut m) {
+                            Ok(text) => Ok(VVal::new_str_mv(text)),
+                            Err(e) => Ok(VVal::err_msg(&e)),
+                        };
+                    }
+                }
+
+                Ok(VVal::None)
+            }
             "move_cluster_cells_dir_path" => {
                 arg_chk!(args, 1, "cluster.move_cluster_cells_dir_path[$[CellDir, ...]]");
 
@@ -1014,6 +1656,12 @@ impl vval::VValUserData for VValCluster {
     }
 }
 
-pub fn matrix2vv(matrix: Arc<Mutex<Matrix>>) -> VVal {
-    VVal::new_usr(VValMatrix { matrix })
+pub fn matrix2vv(
+    matrix: Arc<Mutex<Matrix>>,
+    history: Rc<RefCell<MatrixHistory>>,
+    view: MatrixView,
+    midi_learn: Arc<MidiLearnWorker>,
+    dyn_nodes: Arc<DynNodeRegistry>,
+) -> VVal {
+    VVal::new_usr(VValMatrix { matrix, history, view, midi_learn, dyn_nodes })
 }
@@ -6,17 +6,22 @@ use hexodsp::matrix::MatrixObserver;
 use hexodsp::{Cell, HxMidiEvent, ParamId};
 use wlambda::*;
 
-use std::sync::Mutex;
+use crate::matrix_snapshot::MatrixSnapshotDirty;
+use crate::midi_expression::MidiExprEvent;
+use crate::wlapi::midi_event2vv;
+
+use std::sync::{Arc, Mutex};
 
 use super::*;
 
 pub struct MatrixRecorder {
     changes: Mutex<Vec<VVal>>,
+    snapshot_dirty: Arc<MatrixSnapshotDirty>,
 }
 
 impl MatrixRecorder {
-    pub fn new() -> Self {
-        Self { changes: Mutex::new(vec![]) }
+    pub fn new(snapshot_dirty: Arc<MatrixSnapshotDirty>) -> Self {
+        Self { changes: Mutex::new(vec![]), snapshot_dirty }
     }
 
     pub fn get_records(&self) -> VVal {
@@ -39,6 +44,8 @@ impl MatrixRecorder {
 
 impl MatrixObserver for MatrixRecorder {
     fn update_prop(&self, key: &str) {
+        self.snapshot_dirty.props_changed();
+
         if let Ok(mut changes) = self.changes.lock() {
             changes.push(VVal::pair(VVal::new_sym("matrix_property"), VVal::new_str(key)));
         }
@@ -51,18 +58,25 @@ impl MatrixObserver for MatrixRecorder {
     }
 
     fn update_param(&self, param_id: &ParamId) {
+        self.snapshot_dirty.param_changed(*param_id);
+
         if let Ok(mut changes) = self.changes.lock() {
             changes.push(VVal::pair(VVal::new_sym("matrix_param"), param_id2vv(param_id.clone())));
         }
     }
 
     fn update_matrix(&self) {
+        self.snapshot_dirty.graph_changed();
+
         if let Ok(mut changes) = self.changes.lock() {
             changes.push(VVal::pair(VVal::new_sym("matrix_graph"), VVal::None));
         }
     }
 
     fn update_all(&self) {
+        self.snapshot_dirty.graph_changed();
+        self.snapshot_dirty.props_changed();
+
         if let Ok(mut changes) = self.changes.lock() {
             changes.push(VVal::pair(VVal::new_sym("matrix_all"), VVal::None));
         }
@@ -70,40 +84,68 @@ impl MatrixObserver for MatrixRecorder {
 
     fn midi_event(&self, midi_ev: HxMidiEvent) {
         if let Ok(mut changes) = self.changes.lock() {
-            let ev_vv = match midi_ev {
-                HxMidiEvent::NoteOn { channel, note, vel } => {
-                    let v = VVal::map3(
-                        "channel",
-                        VVal::Int(channel as i64),
-                        "note",
-                        VVal::Int(note as i64),
-                        "velocity",
-                        VVal::Flt(vel as f64),
-                    );
-                    v.set_key_str("type", VVal::new_sym("note_on"));
-                    v
-                }
-                HxMidiEvent::NoteOff { channel, note } => VVal::map3(
-                    "type",
-                    VVal::new_sym("note_off"),
+            changes.push(VVal::pair(VVal::new_sym("midi_event"), midi_event2vv(midi_ev)));
+        }
+    }
+}
+
+impl MatrixRecorder {
+    /// Records the MIDI events that don't pass through the matrix,
+    /// so they show up in the MIDI log like the `midi_event` above.
+    pub fn record_midi_expr(&self, ev: MidiExprEvent) {
+        let ev_vv = match ev {
+            MidiExprEvent::PitchBend { channel, value } => VVal::map3(
+                "type",
+                VVal::new_sym("pitch_bend"),
+                "channel",
+                VVal::Int(channel as i64),
+                "value",
+                VVal::Flt(value as f64),
+            ),
+            MidiExprEvent::ChannelPressure { channel, value } => VVal::map3(
+                "type",
+                VVal::new_sym("channel_pressure"),
+                "channel",
+                VVal::Int(channel as i64),
+                "value",
+                VVal::Flt(value as f64),
+            ),
+            MidiExprEvent::PolyPressure { channel, note, value } => {
+                let v = VVal::map3(
                     "channel",
                     VVal::Int(channel as i64),
                     "note",
                     VVal::Int(note as i64),
-                ),
-                HxMidiEvent::CC { channel, cc, value } => {
-                    let v = VVal::map3(
-                        "channel",
-                        VVal::Int(channel as i64),
-                        "cc",
-                        VVal::Int(cc as i64),
-                        "value",
-                        VVal::Flt(value as f64),
-                    );
-                    v.set_key_str("type", VVal::new_sym("cc"));
-                    v
-                }
-            };
+                    "value",
+                    VVal::Flt(value as f64),
+                );
+                v.set_key_str("type", VVal::new_sym("poly_pressure"));
+                v
+            }
+            MidiExprEvent::ProgramChange { channel, program } => VVal::map3(
+                "type",
+                VVal::new_sym("program_change"),
+                "channel",
+                VVal::Int(channel as i64),
+                "program",
+                VVal::Int(program as i64),
+            ),
+            MidiExprEvent::NoteExpression { channel, note, expr, value } => {
+                let v = VVal::map3(
+                    "channel",
+                    VVal::Int(channel as i64),
+                    "note",
+                    VVal::Int(note as i64),
+                    "value",
+                    VVal::Flt(value as f64),
+                );
+                v.set_key_str("type", VVal::new_sym("note_expression"));
+                v.set_key_str("expression", VVal::new_sym(expr.name()));
+                v
+            }
+        };
+
+        if let Ok(mut changes) = self.changes.lock() {
             changes.push(VVal::pair(VVal::new_sym("midi_event"), ev_vv));
         }
     }
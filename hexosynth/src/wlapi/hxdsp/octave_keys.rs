@@ -2,39 +2,39 @@
 // This file is a part of HexoSynth. Released under GPL-3.0-or-later.
 // See README.md and COPYING for details.
 
-use hexodsp::{Matrix, NodeId, ParamId, SAtom};
+use crate::matrix_snapshot::{MatrixCommand, MatrixView};
+use hexodsp::{NodeId, ParamId, SAtom};
 use hexotk::OctaveKeysModel;
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::sync::{Arc, Mutex};
 use wlambda::*;
 
 #[derive(Clone)]
 struct OctaveKeysNodeData {
-    matrix: Arc<Mutex<Matrix>>,
+    view: MatrixView,
     node_id: NodeId,
     param_id: ParamId,
 }
 
 impl OctaveKeysModel for OctaveKeysNodeData {
     fn key_mask(&self) -> i64 {
-        let m = self.matrix.lock().expect("matrix lockable");
-        m.get_param(&self.param_id).map(|a| a.i()).unwrap_or(0x0)
+        self.view.get_param(&self.param_id).map(|a| a.i()).unwrap_or(0x0)
     }
 
     fn phase_value(&self) -> f64 {
-        let m = self.matrix.lock().expect("matrix lockable");
-        m.phase_value_for(&self.node_id) as f64
+        self.view.snapshot().phase_value_for(&self.node_id) as f64
     }
 
     fn get_generation(&self) -> u64 {
-        let m = self.matrix.lock().expect("matrix lockable");
-        m.get_generation() as u64
+        self.view.snapshot().get_generation() as u64
     }
 
     fn change(&mut self, new_mask: i64) {
-        let mut m = self.matrix.lock().expect("matrix lockable");
-        m.set_param(self.param_id, SAtom::setting(new_mask));
+        self.view.push(MatrixCommand::Param {
+            param_id: self.param_id,
+            value: SAtom::setting(new_mask),
+            record: false,
+        });
     }
 }
 
@@ -42,9 +42,9 @@ impl OctaveKeysModel for OctaveKeysNodeData {
 pub struct VOctaveKeysModel(Rc<RefCell<dyn OctaveKeysModel>>);
 
 impl VOctaveKeysModel {
-    pub fn new(matrix: Arc<Mutex<Matrix>>, param_id: ParamId) -> Self {
+    pub fn new(view: MatrixView, param_id: ParamId) -> Self {
         Self(Rc::new(RefCell::new(OctaveKeysNodeData {
-            matrix,
+            view,
             node_id: param_id.node_id(),
             param_id,
         })))
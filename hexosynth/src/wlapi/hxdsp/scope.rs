@@ -4,30 +4,32 @@
 
 use super::vv2node_id;
 use crate::arg_chk;
-use hexodsp::{Matrix, NodeId, ScopeHandle};
+use crate::matrix_snapshot::{MatrixSnapshot, MatrixView};
+use hexodsp::{NodeId, ScopeHandle};
 use hexotk::ScopeModel;
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use wlambda::*;
 
+fn scope_handle_for(snapshot: &MatrixSnapshot, node_id: NodeId) -> Arc<ScopeHandle> {
+    let handle = snapshot.get_scope_handle(node_id.instance() as usize);
+    if let Some(handle) = handle {
+        handle
+    } else {
+        snapshot.get_scope_handle(0).unwrap()
+    }
+}
+
 struct ScopeData {
-    matrix: Arc<Mutex<Matrix>>,
+    view: MatrixView,
     handle: Arc<ScopeHandle>,
     node_id: NodeId,
 }
 
 impl ScopeData {
     pub fn set_node_id(&mut self, node_id: NodeId) {
-        self.handle = {
-            let m = self.matrix.lock().expect("Matrix lockable");
-            let handle = m.get_scope_handle(node_id.instance() as usize);
-            if let Some(handle) = handle {
-                handle
-            } else {
-                m.get_scope_handle(0).unwrap()
-            }
-        };
+        self.handle = scope_handle_for(&self.view.snapshot(), node_id);
         self.node_id = node_id;
     }
 }
@@ -81,22 +83,10 @@ impl ScopeModel for ScopeData {
 pub struct VScopeModel(Rc<RefCell<ScopeData>>);
 
 impl VScopeModel {
-    pub fn new(matrix: Arc<Mutex<Matrix>>, node_id: NodeId) -> Self {
-        let handle = {
-            let m = matrix.lock().expect("Matrix lockable");
-            let handle = m.get_scope_handle(node_id.instance() as usize);
-            if let Some(handle) = handle {
-                handle
-            } else {
-                m.get_scope_handle(0).unwrap()
-            }
-        };
+    pub fn new(view: MatrixView, node_id: NodeId) -> Self {
+        let handle = scope_handle_for(&view.snapshot(), node_id);
 
-        Self(Rc::new(RefCell::new(ScopeData {
-            matrix: matrix.clone(),
-            handle,
-            node_id: node_id.clone(),
-        })))
+        Self(Rc::new(RefCell::new(ScopeData { view, handle, node_id: node_id.clone() })))
     }
 }
 
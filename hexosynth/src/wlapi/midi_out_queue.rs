@@ -0,0 +1,128 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use crate::arg_chk;
+use crate::midi_output::MidiOutput;
+use hexodsp::HxMidiEvent;
+use std::sync::Arc;
+use wlambda::*;
+
+/// Converts a MIDI event map `${type=:note_on, channel, note, velocity}`,
+/// `${type=:note_off, channel, note}` or `${type=:cc, channel, cc, value}`.
+pub fn vv2midi_event(v: &VVal) -> Result<HxMidiEvent, String> {
+    match &v.v_s_rawk("type")[..] {
+        "note_on" => Ok(HxMidiEvent::NoteOn {
+            channel: v.v_ik("channel") as u8,
+            note: v.v_ik("note") as u8,
+            vel: v.v_fk("velocity") as f32,
+        }),
+        "note_off" => Ok(HxMidiEvent::NoteOff {
+            channel: v.v_ik("channel") as u8,
+            note: v.v_ik("note") as u8,
+        }),
+        "cc" => Ok(HxMidiEvent::CC {
+            channel: v.v_ik("channel") as u8,
+            cc: v.v_ik("cc") as u8,
+            value: v.v_fk("value") as f32,
+        }),
+        _ => Err(format!("Not a MIDI message 'type': {:?}", v.s())),
+    }
+}
+
+/// The inverse of [vv2midi_event].
+pub fn midi_event2vv(ev: HxMidiEvent) -> VVal {
+    match ev {
+        HxMidiEvent::NoteOn { channel, note, vel } => {
+            let v = VVal::map3(
+                "channel",
+                VVal::Int(channel as i64),
+                "note",
+                VVal::Int(note as i64),
+                "velocity",
+                VVal::Flt(vel as f64),
+            );
+            let _ = v.set_key_str("type", VVal::new_sym("note_on"));
+            v
+        }
+        HxMidiEvent::NoteOff { channel, note } => VVal::map3(
+            "type",
+            VVal::new_sym("note_off"),
+            "channel",
+            VVal::Int(channel as i64),
+            "note",
+            VVal::Int(note as i64),
+        ),
+        HxMidiEvent::CC { channel, cc, value } => {
+            let v = VVal::map3(
+                "channel",
+                VVal::Int(channel as i64),
+                "cc",
+                VVal::Int(cc as i64),
+                "value",
+                VVal::Flt(value as f64),
+            );
+            let _ = v.set_key_str("type", VVal::new_sym("cc"));
+            v
+        }
+    }
+}
+
+/// A [MidiOutput] queue of its own, to try out the ordering and timing
+/// of the events that the frontends send for the MIDI output nodes.
+#[derive(Clone)]
+pub struct VValMidiOutQueue(Arc<MidiOutput>);
+
+impl VValMidiOutQueue {
+    pub fn new() -> Self {
+        Self(MidiOutput::new())
+    }
+}
+
+impl VValUserData for VValMidiOutQueue {
+    fn s(&self) -> String {
+        format!("$<HexoSynth::MidiOutQueue>")
+    }
+    fn as_any(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+    fn clone_ud(&self) -> Box<dyn vval::VValUserData> {
+        Box::new(self.clone())
+    }
+
+    fn call_method(&self, key: &str, env: &mut Env) -> Result<VVal, StackAction> {
+        let args = env.argv_ref();
+
+        match key {
+            "send" => {
+                arg_chk!(args, 2, "$<HexoSynth::MidiOutQueue>.send[timing, event]");
+
+                match vv2midi_event(&env.arg(1)) {
+                    Ok(ev) => {
+                        self.0.send(env.arg(0).i().max(0) as usize, ev);
+                        Ok(VVal::Bol(true))
+                    }
+                    Err(e) => Ok(VVal::err_msg(&e)),
+                }
+            }
+            "drain_block" => {
+                arg_chk!(
+                    args,
+                    4,
+                    "$<HexoSynth::MidiOutQueue>.drain_block[offs, factor, latency, nframes]"
+                );
+
+                let out = VVal::vec();
+                self.0.drain_block(
+                    env.arg(0).i().max(0) as usize,
+                    env.arg(1).i().max(1) as usize,
+                    env.arg(2).i().max(0) as usize,
+                    env.arg(3).i().max(0) as usize,
+                    |ev| out.push(VVal::pair(VVal::Int(ev.timing as i64), midi_event2vv(ev.event))),
+                );
+                Ok(out)
+            }
+            _ => Ok(VVal::err_msg(&format!("Unknown method called: {}", key))),
+        }
+    }
+}
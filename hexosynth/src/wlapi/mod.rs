@@ -23,7 +23,37 @@ pub use pattern_editor::*;
 pub mod list;
 pub use list::*;
 
+pub mod midi_out_queue;
+pub use midi_out_queue::*;
+
+pub mod preset_library;
+pub use preset_library::*;
+
+pub mod subpatch_library;
+pub use subpatch_library::*;
+
+pub mod app_log;
+pub use app_log::*;
+
+pub mod daw_state;
+pub use daw_state::*;
+
+pub mod dyn_nodes;
+pub use dyn_nodes::*;
+
+pub mod frontend;
+pub use frontend::*;
+
+use crate::audio_device::AudioDeviceInfoFn;
+use crate::daw_state::DawState;
+use crate::frontend_state::FrontendState;
+use crate::matrix_history::MatrixHistory;
+use crate::matrix_snapshot::MatrixView;
+use crate::session::Session;
+use crate::ExtParamSet;
 use directories::UserDirs;
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 use wlambda::*;
 
@@ -49,12 +79,42 @@ macro_rules! wl_panic {
     };
 }
 
-pub fn setup_hx_module(matrix: Arc<Mutex<Matrix>>) -> wlambda::SymbolTable {
+/// The state the functions of the `hx` module work on.
+#[derive(Clone)]
+pub struct HxContext {
+    pub matrix: Arc<Mutex<Matrix>>,
+    pub history: Rc<RefCell<MatrixHistory>>,
+    pub view: MatrixView,
+    pub frontend: FrontendState,
+    pub param_set: ExtParamSet,
+    /// Set by the standalone frontends, provides `hx:audio_device_info[]`.
+    pub audio_device_info: Option<AudioDeviceInfoFn>,
+    /// The session of the standalone frontends, provides `hx:session_info[]`.
+    pub session: Option<Arc<Session>>,
+    /// The patch state of the plugin, provides `hx:daw_state_info[]`
+    /// and the functions to repair or discard a state that failed to load.
+    pub daw_state: Option<Arc<DawState>>,
+}
+
+pub fn setup_hx_module(ctx: &HxContext) -> wlambda::SymbolTable {
     let mut st = wlambda::SymbolTable::new();
 
+    let matrix = ctx.matrix.clone();
+    let history = ctx.history.clone();
+    let view = ctx.view.clone();
+    let midi_learn = ctx.frontend.midi_learn.clone();
+    let dyn_nodes = ctx.frontend.dyn_nodes.clone();
     st.fun(
         "get_main_matrix_handle",
-        move |_env: &mut Env, _argc: usize| Ok(matrix2vv(matrix.clone())),
+        move |_env: &mut Env, _argc: usize| {
+            Ok(matrix2vv(
+                matrix.clone(),
+                history.clone(),
+                view.clone(),
+                midi_learn.clone(),
+                dyn_nodes.clone(),
+            ))
+        },
         Some(0),
         Some(0),
         false,
@@ -273,5 +333,17 @@ pub fn setup_hx_module(matrix: Arc<Mutex<Matrix>>) -> wlambda::SymbolTable {
         false,
     );
 
+    setup_preset_library_fns(&mut st);
+    setup_subpatch_library_fns(&mut st);
+    setup_audio_device_fns(&mut st, ctx);
+    setup_session_fns(&mut st, ctx);
+    setup_transport_fns(&mut st, ctx);
+    setup_midi_fns(&mut st, ctx);
+    setup_dyn_node_fns(&mut st, ctx);
+    setup_ext_param_fns(&mut st, ctx);
+    setup_log_fns(&mut st);
+    setup_clipboard_fns(&mut st);
+    setup_daw_state_fns(&mut st, ctx);
+
     st
 }
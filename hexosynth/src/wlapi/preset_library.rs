@@ -0,0 +1,172 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use crate::preset_library::{PresetEntry, PresetLibrary, PresetMeta, PresetQuery, PresetSort};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use wlambda::*;
+
+fn strings2vv(list: &[String]) -> VVal {
+    let v = VVal::vec();
+    for s in list.iter() {
+        v.push(VVal::new_str(s));
+    }
+    v
+}
+
+fn vv2strings(v: &VVal) -> Vec<String> {
+    let mut out = vec![];
+    v.with_iter(|iter| {
+        for (s, _) in iter {
+            out.push(s.s_raw());
+        }
+    });
+    out
+}
+
+pub fn preset_meta2vv(meta: &PresetMeta) -> VVal {
+    let v = VVal::map();
+    let _ = v.set_key_str("name", VVal::new_str(&meta.name));
+    let _ = v.set_key_str("author", VVal::new_str(&meta.author));
+    let _ = v.set_key_str("tags", strings2vv(&meta.tags));
+    let _ = v.set_key_str("description", VVal::new_str(&meta.description));
+    let _ = v.set_key_str("created", VVal::new_str(&meta.created));
+    v
+}
+
+pub fn vv2preset_meta(v: &VVal) -> PresetMeta {
+    let opt_str = |key: &str| {
+        let s = v.v_k(key);
+        if s.is_none() {
+            String::new()
+        } else {
+            s.s_raw()
+        }
+    };
+
+    PresetMeta {
+        name: opt_str("name"),
+        author: opt_str("author"),
+        tags: vv2strings(&v.v_k("tags")),
+        description: opt_str("description"),
+        created: opt_str("created"),
+    }
+}
+
+fn preset_entry2vv(entry: &PresetEntry) -> VVal {
+    let v = preset_meta2vv(&entry.meta);
+    let _ = v.set_key_str("path", VVal::new_str_mv(entry.path.to_string_lossy().to_string()));
+    let _ = v.set_key_str("rel_path", VVal::new_str(&entry.rel_path));
+    let _ = v.set_key_str("has_meta", VVal::Bol(entry.has_meta));
+    let _ = v.set_key_str(
+        "modified",
+        entry
+            .modified
+            .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| VVal::Int(d.as_secs() as i64))
+            .unwrap_or(VVal::None),
+    );
+    v
+}
+
+fn vv2preset_query(v: &VVal) -> Result<PresetQuery, String> {
+    let sort = v.v_k("sort");
+    let sort = if sort.is_none() {
+        PresetSort::Name
+    } else {
+        let name = sort.s_raw();
+        PresetSort::from_name(&name)
+            .ok_or_else(|| format!("Unknown preset sort order: {}", name))?
+    };
+
+    let text = v.v_k("text");
+    let author = v.v_k("author");
+
+    Ok(PresetQuery {
+        text: if text.is_none() { String::new() } else { text.s_raw() },
+        tags: vv2strings(&v.v_k("tags")),
+        author: if author.is_none() { String::new() } else { author.s_raw() },
+        sort,
+        reverse: v.v_k("reverse").b(),
+    })
+}
+
+/// Registers the `hx:preset_*` functions, which all work on the same [PresetLibrary].
+pub fn setup_preset_library_fns(st: &mut SymbolTable) {
+    let library = Rc::new(RefCell::new(PresetLibrary::new(
+        PresetLibrary::default_root().unwrap_or_else(|| std::path::PathBuf::from("patches")),
+    )));
+
+    let lib = library.clone();
+    st.fun(
+        "preset_library_root",
+        move |_env: &mut Env, _argc: usize| {
+            Ok(VVal::new_str_mv(lib.borrow().root().to_string_lossy().to_string()))
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+
+    let lib = library.clone();
+    st.fun(
+        "preset_rescan",
+        move |_env: &mut Env, _argc: usize| {
+            let errors = lib.borrow_mut().rescan();
+            Ok(VVal::map2(
+                "count",
+                VVal::Int(lib.borrow().entries().len() as i64),
+                "errors",
+                strings2vv(&errors),
+            ))
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+
+    let lib = library.clone();
+    st.fun(
+        "preset_query",
+        move |env: &mut Env, argc: usize| {
+            let query = if argc > 0 {
+                match vv2preset_query(&env.arg(0)) {
+                    Ok(query) => query,
+                    Err(e) => return Ok(VVal::err_msg(&e)),
+                }
+            } else {
+                PresetQuery::default()
+            };
+
+            if !lib.borrow().is_scanned() {
+                lib.borrow_mut().rescan();
+            }
+
+            let out = VVal::vec();
+            for entry in lib.borrow().query(&query) {
+                out.push(preset_entry2vv(entry));
+            }
+            Ok(out)
+        },
+        Some(0),
+        Some(1),
+        false,
+    );
+
+    let lib = library;
+    st.fun(
+        "preset_tags",
+        move |_env: &mut Env, _argc: usize| {
+            if !lib.borrow().is_scanned() {
+                lib.borrow_mut().rescan();
+            }
+
+            Ok(strings2vv(&lib.borrow().tags()))
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+}
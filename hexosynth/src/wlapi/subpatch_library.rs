@@ -0,0 +1,107 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use crate::app_log::log_warn;
+use crate::subpatch_library::{SubPatchEntry, SubPatchLibrary, SubPatchMeta};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use wlambda::*;
+
+fn subpatch_entry2vv(entry: &SubPatchEntry) -> VVal {
+    let nodes = VVal::vec();
+    for n in entry.nodes.iter() {
+        nodes.push(VVal::new_str(n));
+    }
+
+    let v = VVal::map();
+    let _ = v.set_key_str("name", VVal::new_str(&entry.meta.name));
+    let _ = v.set_key_str("description", VVal::new_str(&entry.meta.description));
+    let _ = v.set_key_str("path", VVal::new_str_mv(entry.path.to_string_lossy().to_string()));
+    let _ = v.set_key_str("rel_path", VVal::new_str(&entry.rel_path));
+    let _ = v.set_key_str("nodes", nodes);
+    let _ = v.set_key_str("cell_count", VVal::Int(entry.cell_count as i64));
+    let _ = v.set_key_str(
+        "modified",
+        entry
+            .modified
+            .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| VVal::Int(d.as_secs() as i64))
+            .unwrap_or(VVal::None),
+    );
+    v
+}
+
+/// Registers the `hx:subpatch_*` functions, which all work on the same [SubPatchLibrary].
+pub fn setup_subpatch_library_fns(st: &mut SymbolTable) {
+    let library = Rc::new(RefCell::new(SubPatchLibrary::new(
+        SubPatchLibrary::default_root().unwrap_or_else(|| std::path::PathBuf::from("subpatches")),
+    )));
+
+    let lib = library.clone();
+    st.fun(
+        "subpatch_library_root",
+        move |_env: &mut Env, _argc: usize| {
+            Ok(VVal::new_str_mv(lib.borrow().root().to_string_lossy().to_string()))
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+
+    let lib = library.clone();
+    st.fun(
+        "subpatch_list",
+        move |_env: &mut Env, _argc: usize| {
+            let errors = lib.borrow_mut().rescan();
+            for e in errors.iter() {
+                log_warn("subpatch", e);
+            }
+
+            let out = VVal::vec();
+            for entry in lib.borrow().entries() {
+                out.push(subpatch_entry2vv(entry));
+            }
+            Ok(out)
+        },
+        Some(0),
+        Some(0),
+        false,
+    );
+
+    let lib = library.clone();
+    st.fun(
+        "subpatch_save",
+        move |env: &mut Env, _argc: usize| {
+            let meta = env.arg(0);
+            let meta = SubPatchMeta {
+                name: meta.v_s_rawk("name"),
+                description: meta.v_s_rawk("description"),
+            };
+
+            match lib.borrow_mut().save(&meta, &env.arg(1).s_raw()) {
+                Ok(path) => Ok(VVal::new_str_mv(path.to_string_lossy().to_string())),
+                Err(e) => Ok(VVal::err_msg(&e)),
+            }
+        },
+        Some(2),
+        Some(2),
+        false,
+    );
+
+    let lib = library;
+    st.fun(
+        "subpatch_load_text",
+        move |env: &mut Env, _argc: usize| {
+            let path = std::path::PathBuf::from(env.arg(0).s_raw());
+            match lib.borrow().load_text(&path) {
+                Ok(text) => Ok(VVal::new_str_mv(text)),
+                Err(e) => Ok(VVal::err_msg(&e)),
+            }
+        },
+        Some(1),
+        Some(1),
+        false,
+    );
+}
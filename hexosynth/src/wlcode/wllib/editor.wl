@@ -53,6 +53,13 @@
                 context_cell            = $n,
                 context_pos             = $i(0, 0),
                 midi_log                = $[],
+                log_level               = "info",
+                log_seq                 = 0,
+                daw_state_error         = $n,
+                daw_state_repairing     = $f,
+                subpatches              = $[],
+                subpatch_idx            = $n,
+                subpatch_pos            = $i(0, 0),
                 midi_learn_action       = $n,
                 cbs                     = ${},
             },
@@ -140,8 +147,167 @@
             !cluster = hx:new_cluster[];
             cluster.add_cluster_at matrix pos;
             cluster.remove_cells matrix;
+        } "Remove chain";
+    },
+    # Uses the chain at `pos` as voice template and instanciates it
+    # `voices` times. The chain needs a MidiP and a Rust1x1 as voice output.
+    make_voices = {!(pos, voices) = @;
+        $self.matrix_apply_change {!(matrix) = @;
+            !cluster = hx:new_cluster[];
+            cluster.add_cluster_at matrix pos;
+            cluster.make_voices matrix ${ voices = voices };
+        } "Make voices";
+    },
+    copy_cluster = {!(pos) = @;
+        !cluster = hx:new_cluster[];
+        cluster.add_cluster_at $data.matrix pos;
+
+        !text = cluster.to_text $data.matrix;
+        match text
+            ($error v) => {
+                $self.show_cluster_error "Copying" $\.v;
+                return $n;
+            };
+
+        match (hx:clipboard_set_text text)
+            ($error v) => { $self.show_cluster_error "Copying" $\.v; };
+    },
+    paste_cluster = {!(pos) = @;
+        !text = hx:clipboard_get_text[];
+        match text
+            ($error v) => {
+                $self.show_cluster_error "Pasting" $\.v;
+                return $n;
+            };
+
+        !res = $n;
+        $self.matrix_apply_change {!(matrix) = @;
+            .res = matrix.paste_cluster_text text pos;
+            res
+        } "Paste cluster";
+
+        match res
+            ($error v) => { $self.show_cluster_error "Pasting" $\.v; };
+    },
+    # Asks for a name and saves the chain at `pos` to the sub-patch library.
+    save_subpatch = {!(pos) = @;
+        !cluster = hx:new_cluster[];
+        cluster.add_cluster_at $data.matrix pos;
+
+        !text = cluster.to_text $data.matrix;
+        match text
+            ($error v) => {
+                $self.show_cluster_error "Saving" $\.v;
+                return $n;
+            };
+
+        !editor = $self;
+        $self.emit :query_text ($data.matrix.get pos).node_id.0 {!(name) = @;
+            editor.store_subpatch name text;
+        };
+    },
+    store_subpatch = {!(name, text) = @;
+        !path = hx:subpatch_save ${ name = name } text;
+        if is_err[path] {
+            $self.show_cluster_error "Saving" ~ unwrap_err path;
+        } {
+            hx:log :info "subpatch" ~ $F"Saved sub-patch '{}' to {}" name path;
+        };
+    },
+    open_subpatch_browser = {!(pos) = @;
+        $data.subpatch_pos = pos;
+        $data.subpatches   = hx:subpatch_list[];
+        $data.subpatch_idx = $n;
+        $self.emit :show_subpatch_browser ($@vec iter e $data.subpatches { $+ e.name });
+    },
+    select_subpatch = {!(idx) = @;
+        $data.subpatch_idx = idx;
+    },
+    get_subpatch_info_text = {
+        !esc = { _ $p("]", "]]") };
+
+        if len[$data.subpatches] == 0 {
+            return ~ $F"No sub-patches in [c11:{}] yet.\nUse [c14:Save Sub-Patch] on a cell to add one."
+                esc[hx:subpatch_library_root[]];
+        };
+
+        if is_none[$data.subpatch_idx] {
+            return "Select a sub-patch to insert it.";
+        };
+
+        !e = $data.subpatches.($data.subpatch_idx);
+        $F"[c14:{}]\n{}\nCells: {} ({})\n[c11:{}]"
+            esc[e.name]
+            esc[e.description]
+            e.cell_count
+            (std:str:join ", " e.nodes)
+            esc[e.rel_path];
+    },
+    insert_selected_subpatch = {
+        if is_none[$data.subpatch_idx] { return $n; };
+
+        !e = $data.subpatches.($data.subpatch_idx);
+        $self.insert_subpatch e.path $data.subpatch_pos;
+    },
+    # Inserts the sub-patch file at `path` with its top left at `pos`.
+    # Reports an error if the area is blocked.
+    insert_subpatch = {!(path, pos) = @;
+        !text = hx:subpatch_load_text path;
+        match text
+            ($error v) => {
+                $self.show_cluster_error "Inserting" $\.v;
+                return $n;
+            };
+
+        !res = $n;
+        $self.matrix_apply_change {!(matrix) = @;
+            .res = matrix.insert_cluster_text text pos;
+            res
+        } "Insert sub-patch";
+
+        match res
+            ($error v) => { $self.show_cluster_error "Inserting" $\.v; };
+        res
+    },
+    # Rotates or mirrors the chain at `pos` with `cb`, which gets the cluster.
+    transform_cluster = {!(pos, label, action, cb) = @;
+        !err = $n;
+        $self.matrix_apply_change {!(matrix) = @;
+            !cluster = hx:new_cluster[];
+            cluster.add_cluster_at matrix pos;
+            cluster.remove_cells matrix;
+
+            match (cb cluster)
+                ($error v) => {
+                    .err = $\.v;
+                    return $e err;
+                };
+            match (cluster.place matrix)
+                ($error v) => {
+                    .err = $\.v;
+                    return $e err;
+                };
+            $true
+        } label;
+
+        if is_some[err] {
+            $self.show_cluster_error action err;
+        };
+    },
+    rotate_cluster = {!(pos, steps) = @;
+        $self.transform_cluster pos "Rotate chain" "Rotating" {!(cluster) = @;
+            cluster.rotate steps
+        };
+    },
+    mirror_cluster = {!(pos, axis) = @;
+        $self.transform_cluster pos "Mirror chain" "Mirroring" {!(cluster) = @;
+            cluster.mirror axis
         };
     },
+    show_cluster_error = {!(action, err) = @;
+        $self.emit :show_main_help ~ ui:mkd2wt ~
+            $F"## Error {} Cluster\n\n{}\n" action err;
+    },
     remove_unused_ports = {!(pos, dir) = @;
         $self.matrix_apply_change {!(matrix) = @;
             !unused_dirs = $data.matrix.find_unconnected_ports pos dir;
@@ -272,7 +438,7 @@
             iter set set_other {
                 matrix.set set.0 set.1;
             };
-        }
+        } "Move cell"
     },
     matrix_split_cluster_at = {!(pos_a, pos_b) = @;
         !adj = hx:pos_are_adjacent pos_a pos_b;
@@ -286,14 +452,18 @@
             cluster.remove_cells matrix;
             cluster.move_cluster_cells_dir_path $[adj];
             cluster.place matrix;
-        };
+        } "Split chain";
     },
-    matrix_apply_change = {!(cb) = @;
+    # Applies the changes done by `cb` to the matrix and records them
+    # as one undo step. The step is labelled with the string returned
+    # by `cb` or with `label`.
+    matrix_apply_change = {|1<2| !(cb, label) = @;
         !matrix = $data.matrix;
         if $data.matrix.in_apply {
             return ~ cb matrix;
         };
         matrix.save_snapshot[];
+        matrix.history_begin_group[];
 
         $data.matrix.in_apply = $t;
         !change_text = cb matrix;
@@ -303,6 +473,7 @@
             ($error v) => {
                 std:displayln "ERROR1:" $\.v;
                 matrix.restore_snapshot[];
+                matrix.history_end_group $n;
                 $data.matrix.in_apply = $f;
                 return $n;
             };
@@ -310,9 +481,12 @@
         !check_res = matrix.check[];
         if check_res {
             matrix.sync[];
+            matrix.history_end_group
+                (if is_str[change_text] { change_text } { label });
             $t
         } {
             matrix.restore_snapshot[];
+            matrix.history_end_group $n;
             match check_res
                 ($error v) => {
                     std:displayln change_text "ERROR2:" $\.v;
@@ -321,6 +495,41 @@
             $t
         };
     },
+    undo = {
+        !label = $data.matrix.undo[];
+        if is_some[label] {
+            $self.show_markdown_desc ~ $F"Undone: *{}*" label;
+        };
+        $self.emit :update_param_ui;
+        label
+    },
+    redo = {
+        !label = $data.matrix.redo[];
+        if is_some[label] {
+            $self.show_markdown_desc ~ $F"Redone: *{}*" label;
+        };
+        $self.emit :update_param_ui;
+        label
+    },
+    get_history_desc = {|1| !(kind) = @;
+        !hist = $data.matrix.history_list[];
+        !(title, steps) =
+            match kind
+                :undo => $p("Undo", hist.undo)
+                :redo => $p("Redo", hist.redo);
+
+        !desc = $F"## {} Button\n" title;
+        if len[steps] == 0 {
+            .desc +>= ~ $F"Nothing to {}." ~ std:str:to_lowercase title;
+            return desc;
+        };
+
+        .desc +>= ~ $F"Next: **{}**\n" steps.0;
+        iter step ($i(1, 10) steps) {
+            .desc +>= ~ $F"- {}\n" step;
+        };
+        desc
+    },
     spawn_default_connected_node = {|3<5|!(node_id, dst, mode, dir, any_input) = @;
         .dir = if is_none[dir] { :T } { dir };
         !free = $data.matrix.find_first_adjacent_free dst dir;
@@ -455,7 +664,7 @@
                     _? ~ clust.move_cluster_cells_dir_path path;
                     _? ~ clust.place matrix;
                     $true
-                };
+                } "Move chain";
 
                 if move_ok {
                     $self.set_focus_cell dst;
@@ -614,8 +823,15 @@
                 $self.user_confirm_query
                     "Really delete everything and load the patch?"
                     {
-                        matrix.load_patch arg;
+                        !report = matrix.load_patch arg;
+                        match report
+                            ($error v) => {
+                                self.show_patch_load_error $\.v;
+                                return $n;
+                            };
+                        self.show_patch_load_report report;
                         self.post_load :load_file;
+                        hx:session_set_patch_path arg;
                     }
             }
             :demo => {
@@ -627,18 +843,87 @@
                     }
             };
     },
+    save_patch = {!(path) = @;
+        match $data.matrix.save_patch path
+            ($error v) => {
+                $self.emit :show_main_help ~ ui:mkd2wt ~
+                    $F"## Error Saving Patch\n\n{}\n" $\.v;
+                return $n;
+            };
+        hx:session_set_patch_path path;
+    },
+    show_session_recovered = {!(session) = @;
+        $self.emit :show_main_help ~ ui:mkd2wt ~
+            $F"## Session Recovered\n\nHexoSynth did not shut down cleanly last time. The patch was restored from the last autosave in:\n\n{}\n"
+                session.file;
+    },
+    show_patch_load_error = {!(err) = @;
+        $self.emit :show_main_help ~ ui:mkd2wt ~
+            $F"## Error Loading Patch\n\n{}\n" err;
+    },
+    show_patch_load_report = {!(report) = @;
+        if len[report.migrated] == 0 &and len[report.ignored] == 0 {
+            return $n;
+        };
+
+        !text = "## Patch Updated\n\n";
+        if len[report.migrated] > 0 {
+            .text +>= "The patch was saved by an older version and was updated:\n\n";
+            iter msg report.migrated {
+                .text +>= ~ $F"- {}\n" msg;
+            };
+        };
+        if len[report.ignored] > 0 {
+            .text +>= "\nThese parts of the patch are unknown and were ignored:\n\n";
+            iter msg report.ignored {
+                .text +>= ~ $F"- {}\n" msg;
+            };
+        };
+
+        $self.emit :show_main_help ui:mkd2wt[text];
+    },
     handle_top_menu_click = {!(button_tag) = @;
         match button_tag
             :help => { $self.show_help[]; }
             :about => { $self.emit :show_main_help ui:mkd2wt[texts:about]; }
             :midi => { $self.emit :show_midi_log; }
-            :save => { $data.matrix.save_patch "init.hxy"; }
+            :log => { $self.emit :show_log; }
+            :learn => { $self.start_midi_learn[]; }
+            :play => { $self.toggle_transport[]; }
+            :save => { $self.save_patch "init.hxy"; }
+            :undo => { $self.undo[]; }
+            :redo => { $self.redo[]; }
             :load => { $self.do_load :load_file "init.hxy" }
             :init => { $self.do_load :demo }
     },
     show_help = {
         $self.emit :show_main_help ui:mkd2wt[texts:help];
     },
+    toggle_transport = {
+        !info = hx:transport_info[];
+        if is_none[info] { return $n; };
+
+        if info.internal {
+            hx:transport_set ${ playing = not[info.playing] };
+        } {
+            $self.emit :show_main_help ~ ui:mkd2wt ~
+                $F"## Transport\n\nThe transport follows the host: {} at {:5.1} BPM, {}/{}.\n"
+                    (if info.playing { "playing" } { "stopped" })
+                    info.bpm info.time_sig.0 info.time_sig.1;
+        };
+    },
+    start_midi_learn = {
+        $data.matrix.midi_learn_start[];
+
+        !bindings = $@s iter b $data.matrix.midi_learn_bindings[] {
+            $+ ~ $F"- {}: chan={} cc={} ({}, {:5.3} to {:5.3})\n"
+                b.index b.channel b.cc b.curve b.min b.max;
+        };
+
+        $self.emit :show_main_help ~ ui:mkd2wt ~
+            $F"## MIDI Learn\n\nMove the knob of a parameter and then the knob on your MIDI controller. The received CC is bound to the parameter. The bindings are saved with the patch.\n\nCurrent bindings:\n\n{}"
+                bindings;
+    },
     get_midi_log_text = {
         $@s iter ev $data.midi_log {
             match ev.type
@@ -653,9 +938,151 @@
                 :cc => {
                     $+ ~ $F"[c11:{:8}] chan={:2!i} cc=[c14:{:3!i}] value=[c7:{:5.3}]\n"
                         ev.type ev.channel ev.cc ev.value;
+                }
+                :pitch_bend => {
+                    $+ ~ $F"[c11:{:8}] chan={:2!i} value=[c7:{:6.3}]\n"
+                        "bend" ev.channel ev.value;
+                }
+                :channel_pressure => {
+                    $+ ~ $F"[c11:{:8}] chan={:2!i} value=[c7:{:5.3}]\n"
+                        "pressure" ev.channel ev.value;
+                }
+                :poly_pressure => {
+                    $+ ~ $F"[c11:{:8}] chan={:2!i} note=[c14:{:3!i}] value=[c7:{:5.3}]\n"
+                        "polyprs" ev.channel ev.note ev.value;
+                }
+                :program_change => {
+                    $+ ~ $F"[c11:{:8}] chan={:2!i} program=[c14:{:3!i}]\n"
+                        "program" ev.channel ev.program;
+                }
+                :note_expression => {
+                    $+ ~ $F"[c11:{:8}] chan={:2!i} note=[c14:{:3!i}] {}=[c7:{:6.3}]\n"
+                        "note_exp" ev.channel ev.note ev.expression ev.value;
                 };
         }
     },
+    get_log_filter = {
+        ${ level = $data.log_level, limit = 200 }
+    },
+    set_log_level = {!(level) = @;
+        $data.log_level = level;
+        $self.emit :update_log;
+    },
+    get_log_text = {
+        !entries = hx:log_query $self.get_log_filter[];
+        if len[entries] == 0 {
+            return ~ $F"No log entries with level [c11:{}] or above." $data.log_level;
+        };
+
+        !level_colors = ${ debug = 15, info = 7, warn = 4, error = 2 };
+        !esc = { _ $p("]", "]]") };
+
+        $@s iter e entries {
+            $+ ~ $F"[c11:{}] [c{}:{:5}] [c14:{}] {}\n"
+                e.time level_colors.(e.level) e.level esc[e.source] esc[e.message];
+        }
+    },
+    copy_log = {
+        !res = hx:log_copy_to_clipboard $self.get_log_filter[];
+        if is_err[res] {
+            hx:log :error "clipboard" ~ unwrap_err res;
+        } {
+            hx:log :info "clipboard" ~ $F"Copied {} log entries" res;
+        };
+    },
+    clear_log = {
+        hx:log_clear[];
+        $self.emit :update_log;
+    },
+    check_log_update = {
+        !seq = hx:log_latest_seq[];
+        if seq != $data.log_seq {
+            $data.log_seq = seq;
+            $self.emit :update_log;
+        };
+    },
+    check_daw_state = {
+        if $data.daw_state_repairing {
+            !report = hx:daw_state_repair_result[];
+            if is_none[report] { return $n; };
+
+            $data.daw_state_repairing = $f;
+            $self.finish_daw_state_repair report;
+        };
+
+        !info = hx:daw_state_info[];
+        if is_none[info] {
+            $data.daw_state_error = $n;
+            return $n;
+        };
+
+        if info.error != $data.daw_state_error {
+            $data.daw_state_error = info.error;
+            $self.show_daw_state_failed info.error;
+        };
+    },
+    show_daw_state_failed = {!(error) = @;
+        !self = $self;
+        !text =
+            $F"## Can't Load the Patch of the DAW Project
+
+{}
+
+The patch of the project is kept and saved with the project unchanged. Changes to the current patch are not saved until you repair or discard it.
+"
+                error;
+
+        $self.emit :dialog_query :choice (ui:mkd2wt text 50) $[
+            $["Repair", { self.repair_daw_state[] }],
+            $["Discard", { self.discard_daw_state[] }],
+            $["Keep", {}],
+        ];
+    },
+    repair_daw_state = {
+        # The repair is queued, the result is picked up by check_daw_state:
+        match hx:daw_state_repair[]
+            ($error v) => {
+                $data.daw_state_error = $\.v;
+                $self.show_daw_state_failed $\.v;
+                return $n;
+            };
+        $data.daw_state_repairing = $t;
+    },
+    finish_daw_state_repair = {!(report) = @;
+        match report
+            ($error v) => {
+                $data.daw_state_error = $\.v;
+                $self.show_daw_state_failed $\.v;
+                return $n;
+            };
+        $data.daw_state_error = $n;
+        $self.show_patch_load_report report;
+        $self.post_load :load_file;
+    },
+    discard_daw_state = {
+        !path =
+            $F"{}/daw_state_backup_{}.hxy" hx:preset_library_root[] (std:time:now :s);
+        match hx:daw_state_export path
+            ($error v) => {
+                $self.emit :show_main_help ~ ui:mkd2wt ~
+                    $F"## Error Saving the Patch of the DAW Project
+
+{}
+
+The patch was not discarded.
+" $\.v;
+                return $n;
+            };
+        hx:daw_state_discard[];
+        $data.daw_state_error = $n;
+        $self.emit :show_main_help ~ ui:mkd2wt ~
+            $F"## Patch of the DAW Project Discarded
+
+The current patch is saved with the project from now on. A copy of the old patch was saved to:
+
+{}
+" path;
+    },
     handle_midi_event = {!(event) = @;
         while len[$data.midi_log] > 50 {
             std:pop $data.midi_log;
@@ -798,6 +1225,12 @@
             $[:remove_out, "Cleanup Outputs"],
             $[:remove_cell, "Remove Cell"],
             $[:remove_chain, "Remove Chain"],
+            $[:make_voices, "Make 4 Voices"],
+            $[:copy_cluster, "Copy Cluster"],
+            $[:save_subpatch, "Save Sub-Patch"],
+            $[:rotate_cw, "Rotate Right"],
+            $[:rotate_ccw, "Rotate Left"],
+            $[:mirror_cluster, "Mirror"],
         ];
         iter item list { item.2 = texts:cell_context.(item.0) };
         list
@@ -807,6 +1240,8 @@
             $[:rand_here, "Random"],
             $[:rand_6_here, "Random 6"],
             $[:global_remove_any, "Cleanup All Ports"],
+            $[:paste_cluster, "Paste Cluster"],
+            $[:insert_subpatch, "Insert Sub-Patch"],
         ];
         iter item list { item.2 = texts:matrix_context.(item.0) };
         list
@@ -821,6 +1256,14 @@
                     "Really delete the complete cell chain?"
                     { editor.remove_chain pos; }
             }
+            :make_voices => { $self.make_voices pos 4 }
+            :copy_cluster => { $self.copy_cluster pos }
+            :save_subpatch => { $self.save_subpatch pos }
+            :rotate_cw => { $self.rotate_cluster pos 1 }
+            :rotate_ccw => { $self.rotate_cluster pos -1 }
+            :mirror_cluster => { $self.mirror_cluster pos :TL }
+            :paste_cluster => { $self.paste_cluster pos }
+            :insert_subpatch => { $self.open_subpatch_browser pos }
             :remove_any => { $self.remove_unused_ports pos :C }
             :remove_inp => { $self.remove_unused_ports pos :T }
             :remove_out => { $self.remove_unused_ports pos :B }
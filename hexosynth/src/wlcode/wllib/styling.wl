@@ -492,6 +492,25 @@ layout.dialog_popup_button_bar = ${
     height = :pixels => 40,
 };
 
+style.subpatch_list = ${
+    bg_color = ui:UI_ACCENT_BG2_CLR,
+    color = ui:UI_PRIM_CLR,
+    pad_item = 4,
+    border2 = 1,
+    border_color = ui:UI_ACCENT_CLR,
+    color2 = ui:UI_BG2_CLR,
+};
+layout.subpatch_list = ${
+    height = :stretch => 1,
+};
+
+style.subpatch_info = ${
+    parent = :wichtext,
+};
+layout.subpatch_info = ${
+    height = :pixels => 100,
+};
+
 style.scope_panel = ${
     parent = :panel,
 };
@@ -385,6 +385,14 @@ value_entry.reg :enter {!(wid, ev) = @;
     entry_popup.show[];
 };
 
+# Asks for a line of text, `cb` is called with the entered text.
+editor.reg :query_text {!(text, cb) = @;
+    .ENTRY_ACTION = cb;
+    value_tf.set text;
+    entry_popup.popup_at_mouse_offs $f(-50, -20);
+    value_entry.activate[];
+};
+
 !lang = fun.language[];
 #d# std:displayln lang.get_type_list[];
 
@@ -536,7 +544,12 @@ top_menu_button_bar.enable_cache[];
     $["Help", texts:top_menu_texts.help, :help],
     $["About", texts:top_menu_texts.about, :about],
     $["MIDI", texts:top_menu_texts.midi, :midi],
+    $["Log", texts:top_menu_texts.log, :log],
+    $["Learn", texts:top_menu_texts.learn, :learn],
+    $["Play", texts:top_menu_texts.play, :play],
     $["Save", texts:top_menu_texts.save, :save],
+    $["Undo", { editor.get_history_desc :undo }, :undo],
+    $["Redo", { editor.get_history_desc :redo }, :redo],
     $["Load", texts:top_menu_texts.load, :load],
     $["Demo", texts:top_menu_texts.demo, :init],
     $["Code", texts:top_menu_texts.code, on_code_menu_toggle],
@@ -582,7 +595,8 @@ iter action top_menu_actions {
         }
     };
     btn.reg :hover {
-        editor.show_markdown_desc desc_text;
+        editor.show_markdown_desc
+            (if is_fun[desc_text] { desc_text[] } { desc_text });
     };
     top_menu_button_bar.add btn;
 };
@@ -881,6 +895,11 @@ editor.reg :show_main_help {!(text) = @;
     help_wichtext.show[];
 };
 
+!session = hx:session_info[];
+if is_some[session] &and session.recovered {
+    editor.show_session_recovered session;
+};
+
 !debug_panel = popup_debug_browser:DebugPanel.new[];
 editor.reg :show_dbg_help {!(labels) = @;
     debug_panel.show labels;
@@ -901,6 +920,103 @@ editor.reg :update_midi_log {
     midi_log_text.set_text ~ editor.get_midi_log_text[];
 };
 
+!log_popup = styling:new_widget :dialog_popup;
+log_popup.change_layout ${
+    position_type = :self,
+    width         = :pixels  => 800,
+    height        = :percent => 70,
+    top           = :stretch => 1,
+    bottom        = :stretch => 1,
+    left          = :stretch => 1,
+    right         = :stretch => 1,
+    visible       = $f,
+};
+log_popup.auto_hide[];
+log_popup.set_ctrl :rect $n;
+
+!log_wichtext = styling:new_widget :wichtext;
+!log_wtd = ui:wichtext_simple_data_store[];
+log_wichtext.set_ctrl :wichtext log_wtd;
+
+!log_button_bar = styling:new_widget :dialog_popup_button_bar;
+log_button_bar.add ~ styling:new_button_with_label :button_label "All" {
+    editor.set_log_level "debug";
+};
+log_button_bar.add ~ styling:new_button_with_label :button_label "Info" {
+    editor.set_log_level "info";
+};
+log_button_bar.add ~ styling:new_button_with_label :button_label "Warnings" {
+    editor.set_log_level "warn";
+};
+log_button_bar.add ~ styling:new_button_with_label :button_label "Errors" {
+    editor.set_log_level "error";
+};
+log_button_bar.add ~ styling:new_button_with_label :button_label "Copy" {
+    editor.copy_log[];
+};
+log_button_bar.add ~ styling:new_button_with_label :button_label "Clear" {
+    editor.clear_log[];
+};
+
+log_popup.add log_wichtext;
+log_popup.add log_button_bar;
+
+editor.reg :show_log {
+    log_wtd.set_text editor.get_log_text[];
+    log_popup.show[];
+};
+
+editor.reg :update_log {
+    log_wtd.set_text editor.get_log_text[];
+};
+
+!subpatch_popup = styling:new_widget :dialog_popup;
+subpatch_popup.change_layout ${
+    position_type = :self,
+    width         = :pixels  => 500,
+    height        = :percent => 60,
+    top           = :stretch => 1,
+    bottom        = :stretch => 1,
+    left          = :stretch => 1,
+    right         = :stretch => 1,
+    visible       = $f,
+};
+subpatch_popup.auto_hide[];
+subpatch_popup.set_ctrl :rect $n;
+
+!subpatch_list_data = ui:list_data[];
+!subpatch_list = styling:new_widget :subpatch_list;
+subpatch_list.set_ctrl :list_selector subpatch_list_data;
+
+!subpatch_wichtext = styling:new_widget :subpatch_info;
+!subpatch_wtd = ui:wichtext_simple_data_store[];
+subpatch_wichtext.set_ctrl :wichtext subpatch_wtd;
+
+subpatch_list.reg :select {!(wid, idx) = @;
+    editor.select_subpatch idx;
+    subpatch_wtd.set_text editor.get_subpatch_info_text[];
+};
+
+!subpatch_button_bar = styling:new_widget :dialog_popup_button_bar;
+subpatch_button_bar.add ~ styling:new_button_with_label :button_big "✔ Insert" {
+    subpatch_popup.hide[];
+    editor.insert_selected_subpatch[];
+};
+subpatch_button_bar.add ~ styling:new_button_with_label :button_big "✘ Cancel" {
+    subpatch_popup.hide[];
+};
+
+subpatch_popup.add subpatch_list;
+subpatch_popup.add subpatch_wichtext;
+subpatch_popup.add subpatch_button_bar;
+
+editor.reg :show_subpatch_browser {!(names) = @;
+    subpatch_list_data.clear[];
+    iter name names \subpatch_list_data.push name;
+    subpatch_wtd.set_text editor.get_subpatch_info_text[];
+    subpatch_popup.show[];
+};
+
 !sample_list_popup = styling:new_widget :sample_list_popup;
 sample_list_popup.change_layout ${
     position_type = :self,
@@ -950,6 +1066,21 @@ editor.reg :dialog_query {!(mode, text, ok_cb) = @;
             dialog_popup.add dialog_wichtext;
             dialog_popup.add row;
             dialog_popup.show[];
+        }
+        # For :choice the ok_cb is a list of $[label, callback] pairs:
+        :choice => {
+            dialog_popup.remove_childs[];
+            !row = styling:new_widget :dialog_popup_button_bar;
+            iter choice ok_cb {
+                !cb = choice.1;
+                row.add ~ styling:new_button_with_label :button_big choice.0 {
+                    dialog_popup.hide[];
+                    cb[];
+                };
+            };
+            dialog_popup.add dialog_wichtext;
+            dialog_popup.add row;
+            dialog_popup.show[];
         };
 };
 
@@ -1043,6 +1174,8 @@ popup_layer.add debug_panel.build[];
 popup_layer.add midi_log_wichtext;
 popup_layer.add sample_list_popup;
 popup_layer.add dialog_popup;
+popup_layer.add log_popup;
+popup_layer.add subpatch_popup;
 popup_layer.add blockcode_picker_popup;
 popup_layer.add blockcode_context_popup;
 popup_layer.add entry_popup;
@@ -1367,6 +1500,8 @@ root.add app_panel;
 
 !@export on_frame = {!(matrix_records) = @;
     editor.check_pattern_data[];
+    editor.check_log_update[];
+    editor.check_daw_state[];
     # TODO: FIXME:
     unwrap ~ matrix.check_block_function 0;
     matrix.handle_graph_events[];
@@ -2,6 +2,7 @@
 !@import std;
 !@import ui;
 !@import hx;
+!@import node_id;
 
 !inside_rect = {!(target, test) = @;
          test.x >= target.x
@@ -1146,6 +1147,8 @@
             $p("About", "*About*"),
             $p("Help", "*Help*"),
             $p("Save", "*Save*"),
+            $p("Undo", "*Undo*"),
+            $p("Redo", "*Redo*"),
             $p("Load", "*Load*"),
             $p("Demo", "*Demo*"),
             $p("Code", "*WBlockDSP*")
@@ -1164,6 +1167,568 @@
         };
     };
 
+    add_test "matrix_undo_redo" {!(test) = @;
+        test.add_step :init {||
+            matrix_init $i(1, 1) :B ${chain=$[
+                $[:sin, :sig],
+                $[:amp, :inp, :sig],
+                $[:out, :ch1, $n],
+            ]};
+        };
+        test.add_step :edit_and_undo {||
+            !matrix = hx:get_main_matrix_handle[];
+            !gain = node_id:inp_param $p(:amp, 0) :gain;
+            !old_gain = (matrix.get_param gain).f[];
+            !undo_len = len[matrix.history_list[].undo];
+
+            matrix.set $i(4, 4) ${node_id = $p(:sin, 1)};
+            matrix.set_param gain 0.25;
+            std:assert_eq len[matrix.history_list[].undo] undo_len + 2;
+
+            std:assert is_some[matrix.undo[]];
+            std:assert_eq (matrix.get_param gain).f[] old_gain;
+
+            std:assert is_some[matrix.undo[]];
+            std:assert_eq (matrix.get $i(4, 4)).node_id.0 "nop";
+            std:assert_eq len[matrix.history_list[].redo] 2;
+        };
+        test.add_step :redo {||
+            !matrix = hx:get_main_matrix_handle[];
+            !gain = node_id:inp_param $p(:amp, 0) :gain;
+
+            std:assert is_some[matrix.redo[]];
+            std:assert_eq (matrix.get $i(4, 4)).node_id.0 "sin";
+            std:assert is_some[matrix.redo[]];
+            std:assert_eq (matrix.get_param gain).f[] 0.25;
+            std:assert is_none[matrix.redo[]];
+        };
+        test.add_step :undo_open_group {||
+            !matrix = hx:get_main_matrix_handle[];
+            !gain = node_id:inp_param $p(:amp, 0) :gain;
+
+            # An aborted knob drag leaves its group open:
+            matrix.history_begin_group[];
+            matrix.set_param gain 0.5;
+
+            std:assert is_some[matrix.undo[]];
+            std:assert_eq (matrix.get_param gain).f[] 0.25;
+            std:assert is_some[matrix.redo[]];
+            std:assert_eq (matrix.get_param gain).f[] 0.5;
+        };
+    };
+
+    add_test "load_init_patch_report" {!(test) = @;
+        test.add_step :load {||
+            !matrix = hx:get_main_matrix_handle[];
+            !report = matrix.load_init_patch[];
+
+            std:assert_eq report.version 2;
+            std:assert_eq len[report.migrated] 0;
+            std:assert_eq len[report.ignored] 0;
+            std:assert is_err[matrix.load_patch "does_not_exist.hxy"];
+        };
+    };
+
+    add_test "preset_meta" {!(test) = @;
+        test.add_step :meta {||
+            !matrix = hx:get_main_matrix_handle[];
+            matrix.set_preset_meta ${
+                name = "Test Bass",
+                author = "Tester",
+                tags = $["bass", "mono"],
+                created = "2022-08-14",
+            };
+
+            !meta = matrix.get_preset_meta[];
+            std:assert_eq meta.name "Test Bass";
+            std:assert_eq meta.author "Tester";
+            std:assert_eq (std:str:join "," meta.tags) "bass,mono";
+            std:assert_eq meta.description "";
+            std:assert_eq meta.created "2022-08-14";
+
+            std:assert is_err[hx:preset_query ${ sort = :bogus }];
+            std:assert is_vec[hx:preset_query ${ text = "bass", sort = :created, reverse = $t }];
+        };
+    };
+
+    add_test "midi_learn" {!(test) = @;
+        test.add_step :arm {||
+            !matrix = hx:get_main_matrix_handle[];
+            !param = node_id:inp_param $p(:sin, 0) :freq;
+
+            matrix.midi_learn_start[];
+            std:assert_eq matrix.midi_learn_state[] :wait_knob;
+
+            matrix.midi_learn_arm param ${ min = 0.2, max = 0.8, curve = :exp };
+            std:assert is_some[matrix.midi_learn_state[]];
+
+            std:assert is_err[matrix.midi_learn_arm param ${ curve = :bogus }];
+
+            matrix.midi_learn_cancel[];
+            std:assert is_none[matrix.midi_learn_state[]];
+
+            std:assert is_vec[matrix.midi_learn_bindings[]];
+            std:assert not[matrix.midi_learn_remove 999];
+        };
+    };
+
+    add_test "host_params" {!(test) = @;
+        test.add_step :set {||
+            !matrix = hx:get_main_matrix_handle[];
+            !param = node_id:inp_param $p(:sin, 0) :freq;
+
+            matrix.set_host_param "A1" ${ label = "Cutoff", unit = "Hz", min = 100, max = 8000 };
+            matrix.set_host_param "P1" ${ label = "Pitch", target = param };
+
+            !infos = matrix.get_host_params[];
+            std:assert_eq infos.A1.label "Cutoff";
+            std:assert_eq infos.A1.unit "Hz";
+            std:assert_eq infos.A1.max 8000.0;
+            std:assert is_none[infos.A1.target];
+            std:assert is_some[infos.P1.target];
+
+            std:assert is_err[matrix.set_host_param "X9" ${ label = "?" }];
+            std:assert is_err[matrix.set_host_param "A2" ${ target = param }];
+
+            matrix.set_host_param "A1" $n;
+            matrix.set_host_param "P1" $n;
+            std:assert_eq len[matrix.get_host_params[]] 0;
+        };
+    };
+
+    add_test "poly" {!(test) = @;
+        test.add_step :init {||
+            matrix_init $i(1, 1) :B ${chain=$[
+                $[:midip, :gate],
+                $[:rust1x1, :inp, $n],
+            ]};
+        };
+        test.add_step :make_voices {||
+            !matrix = hx:get_main_matrix_handle[];
+            std:assert is_none[matrix.get_poly[]];
+
+            !cluster = hx:new_cluster[];
+            cluster.add_cluster_at matrix $i(1, 1);
+            std:assert is_err[cluster.make_voices matrix ${ voices = 3, stealing = :foo }];
+            std:assert is_err[cluster.make_voices matrix ${ voices = 99 }];
+            std:assert is_err[cluster.make_voices matrix ${ voices = 3, voice_channel = 15 }];
+            std:assert is_err[cluster.make_voices matrix ${
+                voices = 3, channel = 13, voice_channel = 12
+            }];
+
+            !cfg = cluster.make_voices matrix ${
+                voices = 3, stealing = :lowest, voice_channel = 12
+            };
+            matrix.sync[];
+            std:assert_eq cfg.copies 3;
+            std:assert_eq cfg.voice_channel 12;
+            std:assert_eq (matrix.get $i(3, 1)).node_id.1 1;
+
+            # The voice sends are chained below the voices and end in the sum:
+            !sends = $[];
+            !sum = $n;
+            iter a hx:dyn_node_assignments[] {
+                if a.1 == "voice_send" { std:push sends a.0 };
+                if a.1 == "voice_sum" { .sum = a.0 };
+            };
+            std:assert_eq len[sends] 3;
+            std:assert is_some[sum];
+            !send = (matrix.get $i(1, 2)).node_id.1;
+            std:assert_eq (matrix.get $i(1, 4)).node_id.1 send;
+            std:assert (matrix.get $i(5, 2)).node_id.1 != send;
+
+            matrix.set_poly ${ voices = 10, channel = 2 };
+            !cfg = matrix.get_poly[];
+            std:assert_eq cfg.voices 3;
+            std:assert_eq cfg.channel 2;
+            std:assert_eq cfg.stealing :lowest;
+            std:assert is_err[matrix.set_poly ${ channel = 17 }];
+            std:assert is_err[matrix.set_poly ${ voice_channel = 2 }];
+
+            matrix.set_poly $n;
+            std:assert is_none[matrix.get_poly[]];
+        };
+    };
+
+    add_test "cluster_text" {!(test) = @;
+        test.add_step :init {||
+            matrix_init $i(1, 1) :B ${chain=$[
+                $[:sin, :sig],
+                $[:amp, :inp, $n],
+            ]};
+        };
+        test.add_step :copy_paste {||
+            !matrix = hx:get_main_matrix_handle[];
+            matrix.set_param (node_id:inp_param $p(:amp, 0) :gain) 0.25;
+
+            !cluster = hx:new_cluster[];
+            cluster.add_cluster_at matrix $i(1, 1);
+            !text = cluster.to_text matrix;
+            std:assert is_str[text];
+
+            std:assert is_err[matrix.paste_cluster_text "foo" $i(6, 1)];
+
+            !placed = matrix.paste_cluster_text text $i(6, 1);
+            matrix.sync[];
+            std:assert_eq len[placed] 2;
+            std:assert_eq placed.0 $i(7, 1);
+
+            std:assert_eq (matrix.get $i(7, 1)).node_id.0 "sin";
+            std:assert_eq (matrix.get $i(7, 1)).node_id.1 1;
+            std:assert_eq (matrix.get $i(7, 2)).node_id.1 1;
+            std:assert_eq
+                (matrix.get_param ~ node_id:inp_param $p(:amp, 1) :gain).f[]
+                (matrix.get_param ~ node_id:inp_param $p(:amp, 0) :gain).f[];
+        };
+    };
+
+    add_test "cluster_rotate_mirror" {!(test) = @;
+        test.add_step :init {||
+            matrix_init $i(1, 1) :B ${chain=$[
+                $[:sin, :sig],
+                $[:amp, :inp, $n],
+            ]};
+        };
+        test.add_step :rotate {||
+            !matrix = hx:get_main_matrix_handle[];
+            !cluster = hx:new_cluster[];
+            cluster.add_cluster_at matrix $i(1, 1);
+            cluster.remove_cells matrix;
+
+            # The output at B would end up on the BL input edge:
+            std:assert is_err[cluster.rotate 1];
+            std:assert is_err[cluster.mirror :TR];
+
+            cluster.rotate -1;
+            matrix.set $i(2, 2) ${node_id = $p(:sin, 2)};
+            std:assert is_err[cluster.place matrix];
+
+            matrix.set $i(2, 2) $n;
+            std:assert ~ cluster.place matrix;
+            matrix.sync[];
+            std:assert_eq (matrix.get $i(1, 1)).node_id.0 "sin";
+            std:assert_eq (matrix.get $i(2, 2)).node_id.0 "amp";
+        };
+        test.add_step :mirror {||
+            !matrix = hx:get_main_matrix_handle[];
+            !cluster = hx:new_cluster[];
+            cluster.add_cluster_at matrix $i(1, 1);
+            cluster.remove_cells matrix;
+
+            # The output at BR would end up on the BL input edge:
+            std:assert is_err[cluster.mirror :T];
+
+            # The amp is on the TL-BR axis and stays where it is:
+            cluster.mirror :TL;
+            std:assert ~ cluster.place matrix;
+            matrix.sync[];
+            std:assert_eq (matrix.get $i(1, 1)).node_id.0 "sin";
+            std:assert_eq (matrix.get $i(2, 2)).node_id.0 "amp";
+        };
+    };
+
+    add_test "subpatch_insert" {!(test) = @;
+        test.add_step :init {||
+            matrix_init $i(1, 1) :B ${chain=$[
+                $[:sin, :sig],
+                $[:amp, :inp, $n],
+            ]};
+        };
+        test.add_step :insert {||
+            !matrix = hx:get_main_matrix_handle[];
+            std:assert is_str[hx:subpatch_library_root[]];
+            std:assert is_vec[hx:subpatch_list[]];
+
+            !cluster = hx:new_cluster[];
+            cluster.add_cluster_at matrix $i(1, 1);
+            !text = cluster.to_text matrix;
+
+            matrix.set $i(5, 1) ${node_id = $p(:sin, 3)};
+            matrix.sync[];
+            std:assert is_err[matrix.insert_cluster_text text $i(4, 0)];
+            std:assert is_err[matrix.insert_cluster_text text $i(100, 100)];
+
+            # An odd x is rounded down to keep the hex grid connections:
+            !placed = matrix.insert_cluster_text text $i(7, 0);
+            matrix.sync[];
+            std:assert_eq len[placed] 2;
+            std:assert_eq placed.0 $i(7, 0);
+            std:assert_eq (matrix.get $i(7, 0)).node_id.0 "sin";
+            std:assert_eq (matrix.get $i(7, 0)).node_id.1 1;
+            std:assert_eq (matrix.get $i(7, 1)).node_id.0 "amp";
+            std:assert_eq (matrix.get $i(7, 1)).node_id.1 1;
+        };
+    };
+
+    add_test "auto_place" {!(test) = @;
+        test.add_step :chain {||
+            !matrix = hx:get_main_matrix_handle[];
+            matrix.clear[];
+
+            !res = matrix.auto_place ${
+                nodes = ${
+                    osc = :sin,
+                    vca = ${ node = :amp, params = ${ gain = 0.5 } },
+                    out = :out,
+                },
+                edges = $[
+                    $[:osc, :sig, :vca, :inp],
+                    $[:vca, :sig, :out, :ch1],
+                ],
+            } $i(0, 0);
+            matrix.sync[];
+
+            std:assert_eq len[res.cells] 3;
+            std:assert_eq len[res.helper_cells] 0;
+            std:assert_eq res.nodes.vca.0 "amp";
+            std:assert_eq (matrix.get $i(0, 0)).node_id.0 "sin";
+            std:assert_eq (matrix.get $i(0, 1)).node_id.0 "amp";
+            std:assert_eq (matrix.get $i(0, 2)).node_id.0 "out";
+        };
+        test.add_step :helper_cells {||
+            !matrix = hx:get_main_matrix_handle[];
+            matrix.clear[];
+
+            # The sine has only 3 output edges for the 4 amplifiers:
+            !res = matrix.auto_place ${
+                nodes = ${ osc = :sin, a = :amp, b = :amp, c = :amp, d = :amp },
+                edges = $[
+                    $[:osc, :sig, :a, :inp],
+                    $[:osc, :sig, :b, :inp],
+                    $[:osc, :sig, :c, :inp],
+                    $[:osc, :sig, :d, :inp],
+                ],
+            } $i(0, 0);
+            matrix.sync[];
+
+            std:assert_eq len[res.cells] 5;
+            std:assert len[res.helper_cells] > 0;
+            std:assert_eq
+                len[matrix.get_filled_positions[]]
+                (len[res.cells] + len[res.helper_cells]);
+        };
+        test.add_step :failures {||
+            !matrix = hx:get_main_matrix_handle[];
+            matrix.clear[];
+
+            std:assert is_err ~ matrix.auto_place ${
+                nodes = ${ a = :amp, b = :amp },
+                edges = $[ $[:a, :sig, :b, :inp], $[:b, :sig, :a, :inp] ],
+            } $i(0, 0);
+            std:assert is_err ~ matrix.auto_place ${
+                nodes = ${ a = :sin, b = :amp },
+                edges = $[ $[:a, :sig, :b, :no_such_input] ],
+            } $i(0, 0);
+            std:assert is_err ~ matrix.auto_place ${
+                nodes = ${ a = :no_such_node },
+                edges = $[],
+            } $i(0, 0);
+            std:assert_eq len[matrix.get_filled_positions[]] 0;
+        };
+    };
+
+    add_test "dyn_nodes" {!(test) = @;
+        test.add_step :assign {||
+            !names = ${};
+            iter info hx:dyn_node_list[] { names.(info.name) = $t };
+            std:assert names.delay_comb;
+            std:assert names.bypass;
+
+            std:assert is_err[hx:dyn_node_assign 32 "bypass"];
+            std:assert is_err[hx:dyn_node_assign 0 "no_such_node"];
+
+            !slot_name = {!(slot) = @;
+                iter a hx:dyn_node_assignments[] {
+                    if a.0 == slot { return a.1 };
+                };
+                $n
+            };
+
+            hx:dyn_node_assign 14 "soft_clip";
+            std:assert_eq slot_name[14] "soft_clip";
+
+            hx:dyn_node_assign 14 $n;
+            std:assert is_none[slot_name[14]];
+        };
+    };
+
+    add_test "midi_out_queue" {!(test) = @;
+        test.add_step :order {||
+            !q = hx:midi_out_queue[];
+            std:assert is_err[q.send 0 ${ type = :foo }];
+
+            # The nodes send the events of a whole block, one node after the other:
+            q.send 40 ${ type = :note_on, channel = 0, note = 60, velocity = 1.0 };
+            q.send 10 ${ type = :cc, channel = 0, cc = 1, value = 0.5 };
+            q.send 40 ${ type = :note_off, channel = 0, note = 59 };
+            q.send 10 ${ type = :cc, channel = 0, cc = 2, value = 0.5 };
+
+            !evs = q.drain_block 0 1 0 64;
+            std:assert_eq len[evs] 4;
+            std:assert_eq evs.0.0 10;
+            std:assert_eq evs.0.1.cc 1;
+            std:assert_eq evs.1.0 10;
+            std:assert_eq evs.1.1.cc 2;
+            std:assert_eq evs.2.0 40;
+            std:assert_eq evs.2.1.type :note_on;
+            std:assert_eq evs.3.0 40;
+            std:assert_eq evs.3.1.type :note_off;
+
+            std:assert_eq len[q.drain_block 0 1 0 64] 0;
+        };
+        test.add_step :latency {||
+            !q = hx:midi_out_queue[];
+
+            # 4x oversampling with 32 frames of latency, chunk at frame 16 of a 64 frame block:
+            q.send 8 ${ type = :note_on, channel = 0, note = 60, velocity = 1.0 };
+            q.send 96 ${ type = :note_off, channel = 0, note = 60 };
+
+            !evs = q.drain_block 16 4 32 64;
+            std:assert_eq len[evs] 1;
+            std:assert_eq evs.0.0 50;
+
+            # The note off at 16 + 96 / 4 + 32 = 72 is sent in the next block:
+            std:assert_eq len[q.drain_block 32 4 32 64] 0;
+            !evs = q.drain_block 0 4 32 64;
+            std:assert_eq len[evs] 1;
+            std:assert_eq evs.0.0 8;
+            std:assert_eq evs.0.1.type :note_off;
+        };
+    };
+
+    add_test "ext_params" {!(test) = @;
+        !old = $n;
+        test.add_step :set {||
+            std:assert is_err[hx:ext_param_get "X1"];
+            std:assert is_err[hx:ext_param_set "X1" 0.5];
+
+            .old = $[hx:ext_param_get["B2"], hx:ext_param_get["C3"]];
+            hx:ext_param_set "B2" 0.25;
+            hx:ext_param_set "C3" 1.5;
+        };
+        test.add_step :sleep {|| std:thread:sleep :ms => 100 };
+        test.add_step :get {||
+            std:assert_eq hx:ext_param_get["B2"] 0.25;
+            std:assert_eq hx:ext_param_get["C3"] 1.0;
+
+            hx:ext_param_set "B2" old.0;
+            hx:ext_param_set "C3" old.1;
+        };
+    };
+
+    add_test "oversampling" {!(test) = @;
+        test.add_step :set {||
+            !matrix = hx:get_main_matrix_handle[];
+            std:assert_eq matrix.get_oversampling[] 1;
+
+            std:assert is_err[matrix.set_oversampling 3];
+            matrix.set_oversampling 4;
+            std:assert_eq matrix.get_oversampling[] 4;
+
+            matrix.set_oversampling 1;
+            std:assert_eq matrix.get_oversampling[] 1;
+        };
+    };
+
+    add_test "log" {!(test) = @;
+        test.add_step :query {||
+            std:assert is_err[hx:log :foo "test" "x"];
+            std:assert is_err[hx:log_query ${ level = :foo }];
+
+            hx:log :warn "test" "log test warning";
+            !seq = hx:log_latest_seq[];
+            std:assert seq > 0;
+
+            !entries = hx:log_query ${ level = :warn, source = "test", text = "LOG TEST" };
+            std:assert len[entries] > 0;
+            !entry = entries.(len[entries] - 1);
+            std:assert_eq entry.seq seq;
+            std:assert_eq entry.level :warn;
+            std:assert_eq entry.message "log test warning";
+
+            std:assert_eq len[hx:log_query ${ since = seq }] 0;
+            std:assert_eq len[hx:log_query ${ level = :error, source = "test" }] 0;
+        };
+        test.add_step :click_log {!(td, labels) = @;
+            !res = $S°*:{ctrl=*Button, label=Log}° labels;
+            do_click td res.0;
+        };
+        test.add_step :check_console {!(td, labels) = @;
+            !res = $S°*:{ctrl=*WichText, label=*log*test*warning*}° labels;
+            std:assert res.0;
+        };
+    };
+
+    add_test "daw_state" {!(test) = @;
+        test.add_step :no_failed_state {||
+            # The standalone frontends have no DAW state:
+            std:assert is_none[hx:daw_state_info[]];
+            std:assert is_err[hx:daw_state_repair[]];
+            std:assert is_none[hx:daw_state_repair_result[]];
+            std:assert is_err[hx:daw_state_export "daw_state_test.hxy"];
+            hx:daw_state_discard[];
+        };
+    };
+
+    add_test "transport" {!(test) = @;
+        test.add_step :info {||
+            !info = hx:transport_info[];
+            if is_none[info] { return $n; };
+
+            std:assert info.bpm > 0.0;
+            std:assert_eq len[info.time_sig] 2;
+
+            !names = ${};
+            iter proto hx:dyn_node_list[] { names.(proto.name) = $t };
+            std:assert names.transport;
+
+            if info.internal {
+                hx:transport_set ${ bpm = 140, rewind = $t };
+                std:assert_eq hx:transport_info[].bpm 140.0;
+            } {
+                std:assert is_err[hx:transport_set ${ playing = $t }];
+            };
+        };
+    };
+
+    add_test "midi_expression" {!(test) = @;
+        test.add_step :init {||
+            matrix_init $i(1, 1) :B ${chain=$[ ]};
+        };
+        test.add_step :send_midi {||
+            !names = ${};
+            iter proto hx:dyn_node_list[] { names.(proto.name) = $t };
+            std:assert names.midi_pitch_bend;
+            std:assert names.midi_note_tuning;
+            std:assert names.midi_program;
+
+            std:assert is_err[hx:inject_midi_expression ${ type = :foo }];
+            std:assert is_err[hx:inject_midi_expression ${
+                type = :note_expression, expression = :foo
+            }];
+
+            hx:inject_midi_expression ${ type = :pitch_bend, channel = 3, value = -0.5 };
+            hx:inject_midi_expression ${ type = :program_change, channel = 3, program = 42 };
+            hx:inject_midi_expression ${
+                type = :note_expression, channel = 3, note = 60,
+                expression = :tuning, value = 2.0
+            };
+        };
+        test.add_step :sleep {|| std:thread:sleep :ms => 100 };
+        test.add_step :click_midi {!(td, labels) = @;
+            !res = $S°*:{ctrl=*Button, label=MIDI}° labels;
+            do_click td res.0;
+        };
+        test.add_step :check_log {!(td, labels) = @;
+            !res = $S°*:{ctrl=*WichText, label=*bend*}° labels;
+            std:assert res.0;
+            !res = $S°*:{ctrl=*WichText, label=*program*42*}° labels;
+            std:assert res.0;
+            !res = $S°*:{ctrl=*WichText, label=*tuning*}° labels;
+            std:assert res.0;
+        };
+    };
+
     add_test "check_help_context_menu" {!(test) = @;
         test.add_step :init {||
             matrix_init $i(1, 1) :B ${chain=$[
@@ -357,6 +357,9 @@ to place connected nodes directly!
     help = "## Help Button\nShows the HexoSynth introduction/getting started.",
     about = "## About Button\nShows licensing and credits of HexoSynth.",
     midi = "## MIDI Button\nShows a MIDI event log.",
+    log = "## Log Button\nShows the log console with the errors of the WLambda scripts and the matrix, and the messages of the DSP engine. The buttons at the bottom filter by level, copy the shown entries to the clipboard or clear the log.",
+    play = "## Play Button\nStarts and stops the internal transport of the CPAL standalone. In the plugin and with JACK the transport follows the host.",
+    learn = "## Learn Button\nStarts the MIDI learn. Move a parameter knob and then the controller knob to bind its CC to the parameter.",
     save = "## Save Button\nSaves the current patch as 'init.hxy' into the current working directory.",
     load = "## Load Button\nLoads the patch in 'init.hxy' in the current working directory and overwrites the current patch.",
     demo = "## Demo Button\nReplaces the current patch with the demo patch.",
@@ -372,10 +375,18 @@ to place connected nodes directly!
     remove_out = "## Cleanup Output Ports\nRemoves any unconnected output ports of this cell.",
     remove_cell = "## Remove Cell\nRemoves the node instance from this cell.",
     remove_chain = "## Remove Complete Cell Chain\nRemoves the node instances of a complete connected chain of cells.",
+    make_voices = "## Make 4 Voices\nUses the connected chain of cells as voice template and places 3 copies of it to the right. The chain needs a `MidiP` node and a `Rust1x1` node as voice output, the voice outputs are chained in a row below the voices to a `Rust1x1` that outputs the sum of all voices.",
+    rotate_cw = "## Rotate Right\nRotates the connected chain of cells clockwise by 60° around its center. Inputs are always on the top and left edges, a connection that would end up on the wrong side prevents the rotation.",
+    rotate_ccw = "## Rotate Left\nRotates the connected chain of cells counter clockwise by 60° around its center. Inputs are always on the top and left edges, a connection that would end up on the wrong side prevents the rotation.",
+    mirror_cluster = "## Mirror\nMirrors the connected chain of cells about the axis from the top left to the bottom right edge of its center cell. The inputs stay on the top and left edges.",
+    copy_cluster = "## Copy Cluster\nCopies the connected chain of cells with their parameters, patterns and code as text to the clipboard. Use *Paste Cluster* on an empty cell to place it in this or another patch.",
+    save_subpatch = "## Save Sub-Patch\nSaves the connected chain of cells with their parameters, patterns and code as `.hxc` file in the `subpatches` directory next to `patches`. Use *Insert Sub-Patch* on an empty cell to reuse it.",
 };
 
 !@export matrix_context = ${
     rand_here = "## Create Random Node\nCreates a new instance of a random new node in this cell.",
     rand_6_here = "## Create 6 Random Nodes\nCreates 6 new random nodes around this cell.",
     global_remove_any = "## Cleanup Any Unused Ports\nRemoves any unconnected port of all cells in this matrix.",
+    paste_cluster = "## Paste Cluster\nPlaces the cluster from the clipboard at the closest free space to this cell. All nodes get new instances.",
+    insert_subpatch = "## Insert Sub-Patch\nOpens the sub-patch browser. The selected sub-patch is placed with its top left at this cell, all nodes get new instances. If a cell of the target area is already in use, nothing is inserted and the blocking cell is reported.",
 };
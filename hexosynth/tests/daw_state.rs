@@ -0,0 +1,37 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexosynth::{init_hexosynth, DawState};
+
+use serde_json::{json, Value};
+use std::path::PathBuf;
+
+fn read_patch(name: &str) -> Value {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../misc_patches").join(name);
+    let data = std::fs::read_to_string(&path).expect("patch is readable");
+    serde_json::from_str(&data).expect("patch is valid JSON")
+}
+
+/// A state from a newer version loads without its unknown parameter,
+/// but is saved back to the DAW unchanged until it is repaired.
+#[test]
+fn daw_state_keeps_partially_loaded_state() {
+    let (mut matrix, _node_exec) = init_hexosynth();
+
+    let mut patch = read_patch("20210830_quant_trig.hxy");
+    patch["params"].as_array_mut().unwrap().push(json!(["amp", 0, "from_the_future", 0.5]));
+    let data = patch.to_string();
+
+    let daw_state = DawState::new();
+    let report = daw_state.load(&mut matrix, data.clone()).unwrap();
+    assert!(report.ignored.iter().any(|i| i.contains("from_the_future")), "{:?}", report);
+
+    let failed = daw_state.failed().expect("state is kept");
+    assert!(failed.error.contains("from_the_future"), "{}", failed.error);
+    assert_eq!(daw_state.save(&mut matrix), data);
+
+    daw_state.repair(&mut matrix).unwrap();
+    assert!(daw_state.failed().is_none());
+    assert_ne!(daw_state.save(&mut matrix), data);
+}
@@ -0,0 +1,73 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexosynth::migrate_patch;
+
+use serde_json::Value;
+use std::path::PathBuf;
+
+fn read_patch(name: &str) -> Value {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../misc_patches").join(name);
+    let data = std::fs::read_to_string(&path).expect("patch is readable");
+    serde_json::from_str(&data).expect("patch is valid JSON")
+}
+
+fn param(patch: &Value, node: &str, instance: i64, name: &str) -> Option<f64> {
+    patch["params"].as_array()?.iter().find_map(|p| {
+        if p[0].as_str() == Some(node)
+            && p[1].as_i64() == Some(instance)
+            && p[2].as_str() == Some(name)
+        {
+            p[3].as_f64()
+        } else {
+            None
+        }
+    })
+}
+
+/// Version 1 patches stored the normalized knob values, the old
+/// exponential `Amp` gain is denormalized with `2 * v * v`.
+#[test]
+fn migrate_v1_amp_gain() {
+    let mut patch = read_patch("20210604_melody.hxy");
+    assert_eq!(patch["VERSION"].as_i64(), Some(1));
+    let v = param(&patch, "amp", 0, "gain").unwrap();
+
+    let report = migrate_patch(&mut patch).unwrap();
+    assert_eq!(report.version, 1);
+    assert!(!report.migrated.is_empty());
+
+    let gain = param(&patch, "amp", 0, "gain").unwrap();
+    assert!((gain - 2.0 * v * v).abs() < 1e-5, "gain={} v={}", gain, v);
+    assert!((gain - 1.0).abs() < 1e-5, "gain={}", gain);
+}
+
+/// Patches from before 0.2.0-alpha-2 still have version 2, but use the
+/// old `Mix3` gain knobs.
+#[test]
+fn migrate_v2_mix3_gain2vol() {
+    let mut patch = read_patch("20210830_quant_trig.hxy");
+    assert_eq!(patch["VERSION"].as_i64(), Some(2));
+    let gain1 = param(&patch, "mix3", 0, "gain1").unwrap();
+
+    let report = migrate_patch(&mut patch).unwrap();
+    assert!(report.migrated.iter().any(|m| m.contains("gain1")), "{:?}", report.migrated);
+
+    assert_eq!(param(&patch, "mix3", 0, "gain1"), None);
+    assert_eq!(param(&patch, "mix3", 0, "ogain"), None);
+    let vol1 = param(&patch, "mix3", 0, "vol1").unwrap();
+    assert!((vol1 - gain1).abs() < 1e-5, "vol1={} gain1={}", vol1, gain1);
+}
+
+/// A migrated patch is left alone by a second migration.
+#[test]
+fn migrate_is_idempotent() {
+    let mut patch = read_patch("20210830_quant_trig.hxy");
+    migrate_patch(&mut patch).unwrap();
+    let migrated = patch.clone();
+
+    let report = migrate_patch(&mut patch).unwrap();
+    assert!(report.migrated.is_empty(), "{:?}", report.migrated);
+    assert_eq!(patch, migrated);
+}
@@ -2,69 +2,704 @@
 // This file is a part of HexoSynth. Released under GPL-3.0-or-later.
 // See README.md and COPYING for details.
 
+use hexosynth::nodes::EventWindowing;
 use hexosynth::*;
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Instant;
 
 use anyhow;
 use cpal;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, Sample, SizedSample};
+
+use ringbuf::{Consumer, Producer, RingBuffer};
+
+const USAGE: &str = "\
+Usage: hexosynth_cpal [options]
+
+Options:
+    --host <name>               Audio host API to use (e.g. ALSA, JACK, WASAPI, ASIO)
+    --output-device <name>      Output device, the name or a part of it
+    --input-device <name>       Input device, the name or a part of it
+    --no-input                  Don't open an audio input stream
+    --sample-rate <hz>          Sample rate (default: from the output device)
+    --buffer-size <frames>      Buffer size (default: from the output device)
+    --midi-port <name>          MIDI input port, the name or a part of it
+    --no-midi                   Don't open a MIDI input port
+    --midi-out-port <name>      MIDI output port for the MIDI output nodes,
+                                the name or a part of it (default: none)
+    -l, --list                  List the audio hosts, devices and MIDI ports
+    -h, --help                  Show this help
+
+The devices can only be selected with these options, the UI lists
+them but can't change them.
+";
+
+/// Capacity of the MIDI event queue from the MIDI input thread to the audio thread.
+const MIDI_QUEUE_SIZE: usize = 1024;
+
+/// How often the events of the MIDI output nodes are sent to the MIDI output port.
+const MIDI_OUT_INTERVAL_MS: u64 = 1;
+
+#[derive(Default)]
+struct CpalArgs {
+    host: Option<String>,
+    output_device: Option<String>,
+    input_device: Option<String>,
+    no_input: bool,
+    sample_rate: Option<u32>,
+    buffer_size: Option<u32>,
+    midi_port: Option<String>,
+    no_midi: bool,
+    midi_out_port: Option<String>,
+    list: bool,
+}
+
+fn parse_args() -> Result<CpalArgs, String> {
+    let mut cargs = CpalArgs::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().ok_or_else(|| format!("Option '{}' requires an argument", name))
+        };
+
+        match &arg[..] {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                std::process::exit(0);
+            }
+            "-l" | "--list" => cargs.list = true,
+            "--host" => cargs.host = Some(value(&arg)?),
+            "--output-device" => cargs.output_device = Some(value(&arg)?),
+            "--input-device" => cargs.input_device = Some(value(&arg)?),
+            "--no-input" => cargs.no_input = true,
+            "--sample-rate" => {
+                let v = value(&arg)?;
+                cargs.sample_rate =
+                    Some(v.parse().map_err(|_| format!("Bad sample rate: '{}'", v))?);
+            }
+            "--buffer-size" => {
+                let v = value(&arg)?;
+                cargs.buffer_size =
+                    Some(v.parse().map_err(|_| format!("Bad buffer size: '{}'", v))?);
+            }
+            "--midi-port" => cargs.midi_port = Some(value(&arg)?),
+            "--no-midi" => cargs.no_midi = true,
+            "--midi-out-port" => cargs.midi_out_port = Some(value(&arg)?),
+            _ => return Err(format!("Unknown option '{}'", arg)),
+        }
+    }
+
+    Ok(cargs)
+}
+
+fn config_range2info(cfg: &cpal::SupportedStreamConfigRange) -> AudioConfigRange {
+    AudioConfigRange {
+        channels: cfg.channels(),
+        min_sample_rate: cfg.min_sample_rate().0,
+        max_sample_rate: cfg.max_sample_rate().0,
+        buffer_size: match cfg.buffer_size() {
+            cpal::SupportedBufferSize::Range { min, max } => Some((*min, *max)),
+            cpal::SupportedBufferSize::Unknown => None,
+        },
+        sample_format: cfg.sample_format().to_string(),
+    }
+}
+
+fn midi_input_ports() -> Vec<String> {
+    let midi_in = match midir::MidiInput::new("HexoSynth") {
+        Ok(midi_in) => midi_in,
+        Err(_) => return vec![],
+    };
+
+    midi_in.ports().iter().filter_map(|p| midi_in.port_name(p).ok()).collect()
+}
+
+fn midi_output_ports() -> Vec<String> {
+    let midi_out = match midir::MidiOutput::new("HexoSynth") {
+        Ok(midi_out) => midi_out,
+        Err(_) => return vec![],
+    };
+
+    midi_out.ports().iter().filter_map(|p| midi_out.port_name(p).ok()).collect()
+}
+
+/// Enumerates all audio hosts, their devices and the MIDI input ports.
+fn collect_device_info() -> AudioDeviceInfo {
+    let mut info = AudioDeviceInfo::default();
+
+    for host_id in cpal::available_hosts() {
+        let host = match cpal::host_from_id(host_id) {
+            Ok(host) => host,
+            Err(_) => continue,
+        };
+
+        let def_in = host.default_input_device().and_then(|d| d.name().ok());
+        let def_out = host.default_output_device().and_then(|d| d.name().ok());
+
+        let mut host_info = AudioHost { name: host_id.name().to_string(), devices: vec![] };
+
+        if let Ok(devices) = host.devices() {
+            for dev in devices {
+                let name = dev.name().unwrap_or_else(|_| "?".to_string());
+
+                host_info.devices.push(AudioDevice {
+                    default_input: def_in.as_ref() == Some(&name),
+                    default_output: def_out.as_ref() == Some(&name),
+                    input_configs: dev
+                        .supported_input_configs()
+                        .map(|cfgs| cfgs.map(|c| config_range2info(&c)).collect())
+                        .unwrap_or_default(),
+                    output_configs: dev
+                        .supported_output_configs()
+                        .map(|cfgs| cfgs.map(|c| config_range2info(&c)).collect())
+                        .unwrap_or_default(),
+                    name,
+                });
+            }
+        }
+
+        info.hosts.push(host_info);
+    }
+
+    info.midi_inputs = midi_input_ports();
+
+    info
+}
+
+fn print_config_ranges(what: &str, configs: &[AudioConfigRange]) {
+    for cfg in configs.iter() {
+        let bufsize = match cfg.buffer_size {
+            Some((min, max)) => format!("{}-{} frames", min, max),
+            None => "unknown".to_string(),
+        };
+        println!(
+            "        {} {}ch {}-{}Hz {} buffer: {}",
+            what,
+            cfg.channels,
+            cfg.min_sample_rate,
+            cfg.max_sample_rate,
+            cfg.sample_format,
+            bufsize
+        );
+    }
+}
+
+fn print_device_info(info: &AudioDeviceInfo) {
+    for host in info.hosts.iter() {
+        println!("Host: {}", host.name);
+
+        for dev in host.devices.iter() {
+            let mut defaults = vec![];
+            if dev.default_input {
+                defaults.push("default input");
+            }
+            if dev.default_output {
+                defaults.push("default output");
+            }
+
+            if defaults.is_empty() {
+                println!("    Device: {}", dev.name);
+            } else {
+                println!("    Device: {} ({})", dev.name, defaults.join(", "));
+            }
+
+            print_config_ranges("in ", &dev.input_configs);
+            print_config_ranges("out", &dev.output_configs);
+        }
+    }
+
+    println!("MIDI Inputs:");
+    for name in info.midi_inputs.iter() {
+        println!("    {}", name);
+    }
+
+    println!("MIDI Outputs:");
+    for name in midi_output_ports().iter() {
+        println!("    {}", name);
+    }
+}
+
+fn find_host(name: &Option<String>) -> Result<cpal::Host, anyhow::Error> {
+    let name = match name {
+        Some(name) => name,
+        None => return Ok(cpal::default_host()),
+    };
+
+    for host_id in cpal::available_hosts() {
+        if host_id.name().eq_ignore_ascii_case(name) {
+            return Ok(cpal::host_from_id(host_id)?);
+        }
+    }
+
+    let hosts: Vec<&str> = cpal::available_hosts().iter().map(|h| h.name()).collect();
+    Err(anyhow::anyhow!("Unknown audio host '{}', available: {}", name, hosts.join(", ")))
+}
+
+/// Finds the device whose name is `name`, or else the first device that contains `name`.
+fn find_device<I: Iterator<Item = cpal::Device>>(
+    devices: I,
+    name: &str,
+) -> Result<cpal::Device, anyhow::Error> {
+    let mut partial = None;
+
+    for dev in devices {
+        let dev_name = dev.name().unwrap_or_default();
+        if dev_name == name {
+            return Ok(dev);
+        }
+        if partial.is_none() && dev_name.to_lowercase().contains(&name.to_lowercase()) {
+            partial = Some(dev);
+        }
+    }
+
+    partial.ok_or_else(|| anyhow::anyhow!("No audio device matches '{}'", name))
+}
+
+/// Selects a stream config of the device, prefering the sample format
+/// and channel count of the default config.
+fn select_config(
+    configs: Vec<cpal::SupportedStreamConfigRange>,
+    default: cpal::SupportedStreamConfig,
+    sample_rate: Option<u32>,
+    buffer_size: Option<u32>,
+) -> Result<(cpal::StreamConfig, cpal::SampleFormat), anyhow::Error> {
+    let supported = if let Some(sample_rate) = sample_rate {
+        let mut matching: Vec<cpal::SupportedStreamConfigRange> = configs
+            .into_iter()
+            .filter(|c| {
+                c.min_sample_rate().0 <= sample_rate && c.max_sample_rate().0 >= sample_rate
+            })
+            .collect();
+        matching.sort_by_key(|c| {
+            (c.sample_format() != default.sample_format(), c.channels() != default.channels())
+        });
+
+        matching
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Sample rate {}Hz is not supported", sample_rate))?
+            .with_sample_rate(cpal::SampleRate(sample_rate))
+    } else {
+        default
+    };
+
+    if let (Some(size), cpal::SupportedBufferSize::Range { min, max }) =
+        (buffer_size, supported.buffer_size())
+    {
+        if size < *min || size > *max {
+            return Err(anyhow::anyhow!(
+                "Buffer size {} is not supported, the range is {}-{}",
+                size,
+                min,
+                max
+            ));
+        }
+    }
+
+    let sample_format = supported.sample_format();
+    let mut config: cpal::StreamConfig = supported.into();
+    if let Some(size) = buffer_size {
+        config.buffer_size = cpal::BufferSize::Fixed(size);
+    }
+
+    Ok((config, sample_format))
+}
+
+fn build_input_stream<U>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut input_prod: Producer<f32>,
+) -> Result<cpal::Stream, anyhow::Error>
+where
+    U: SizedSample,
+    f32: FromSample<U>,
+{
+    let err_fn = |err| eprintln!("an error occurred on input stream: {}", err);
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[U], _: &cpal::InputCallbackInfo| {
+            for sample in data.iter() {
+                // If the output stream does not keep up, the samples are dropped:
+                let _ = input_prod.push(f32::from_sample(*sample));
+            }
+        },
+        err_fn,
+        None,
+    )?;
+
+    Ok(stream)
+}
+
+/// The audio input stream, the output callback reads
+/// the interleaved samples from `input_cons`.
+struct AudioInput {
+    stream: cpal::Stream,
+    input_cons: Consumer<f32>,
+    channels: usize,
+    name: String,
+}
+
+fn open_input(
+    host: &cpal::Host,
+    args: &CpalArgs,
+    sample_rate: u32,
+    buffer_size: Option<u32>,
+) -> Result<Option<AudioInput>, anyhow::Error> {
+    if args.no_input {
+        return Ok(None);
+    }
+
+    let device = match &args.input_device {
+        Some(name) => find_device(host.input_devices()?, name)?,
+        None => match host.default_input_device() {
+            Some(device) => device,
+            None => return Ok(None),
+        },
+    };
+
+    // The input has to run with the sample rate of the output:
+    let (config, sample_format) = select_config(
+        device.supported_input_configs()?.collect(),
+        device.default_input_config()?,
+        Some(sample_rate),
+        buffer_size,
+    )?;
+
+    let channels = config.channels as usize;
+    // Buffer up to 100ms of input:
+    let (input_prod, input_cons) =
+        RingBuffer::new((sample_rate as usize / 10).max(4096) * channels).split();
+
+    let stream = match sample_format {
+        cpal::SampleFormat::F32 => build_input_stream::<f32>(&device, &config, input_prod),
+        cpal::SampleFormat::F64 => build_input_stream::<f64>(&device, &config, input_prod),
+        cpal::SampleFormat::I8 => build_input_stream::<i8>(&device, &config, input_prod),
+        cpal::SampleFormat::I16 => build_input_stream::<i16>(&device, &config, input_prod),
+        cpal::SampleFormat::I32 => build_input_stream::<i32>(&device, &config, input_prod),
+        cpal::SampleFormat::I64 => build_input_stream::<i64>(&device, &config, input_prod),
+        cpal::SampleFormat::U8 => build_input_stream::<u8>(&device, &config, input_prod),
+        cpal::SampleFormat::U16 => build_input_stream::<u16>(&device, &config, input_prod),
+        cpal::SampleFormat::U32 => build_input_stream::<u32>(&device, &config, input_prod),
+        cpal::SampleFormat::U64 => build_input_stream::<u64>(&device, &config, input_prod),
+        fmt => Err(anyhow::anyhow!("Unsupported input sample format: {}", fmt)),
+    }?;
+
+    Ok(Some(AudioInput { stream, input_cons, channels, name: device.name().unwrap_or_default() }))
+}
+
+/// Connects to the MIDI input port matching `args.midi_port`, or the first
+/// port if none was given. The received events are pushed into `midi_prod`
+/// with the time they arrived at, pitch bend, pressure and program changes
+/// go directly to the MIDI expression state, and the CCs also to the MIDI learn.
+fn open_midi_input(
+    args: &CpalArgs,
+    mut midi_prod: Producer<(Instant, HxMidiEvent)>,
+    frontend: &FrontendState,
+) -> Result<Option<(midir::MidiInputConnection<()>, String)>, anyhow::Error> {
+    if args.no_midi {
+        return Ok(None);
+    }
+
+    let midi_expression = frontend.midi_expression.clone();
+    let midi_learn = frontend.midi_learn.clone();
+
+    let midi_in = midir::MidiInput::new("HexoSynth")?;
+    let ports = midi_in.ports();
+
+    let port = match &args.midi_port {
+        Some(name) => ports
+            .iter()
+            .find(|p| {
+                midi_in
+                    .port_name(p)
+                    .map(|pn| pn.to_lowercase().contains(&name.to_lowercase()))
+                    .unwrap_or(false)
+            })
+            .ok_or_else(|| anyhow::anyhow!("No MIDI input port matches '{}'", name))?,
+        None => match ports.first() {
+            Some(port) => port,
+            None => return Ok(None),
+        },
+    };
+
+    let port_name = midi_in.port_name(port)?;
+    let conn = midi_in
+        .connect(
+            port,
+            "hexosynth_midi_in",
+            move |_stamp, bytes, _| {
+                // The midir timestamps use a different clock per backend,
+                // the audio callback can only relate an Instant to its frames:
+                let now = Instant::now();
+                if let Some(ev) = raw_midi2expr(bytes) {
+                    midi_expression.handle(ev);
+                }
+                if let Some(ev) = raw_midi2event(bytes) {
+                    midi_expression.note_event(&ev);
+                    midi_learn.handle_midi_event(&ev);
+                    let _ = midi_prod.push((now, ev));
+                }
+            },
+            (),
+        )
+        .map_err(|e| anyhow::anyhow!("Can't connect to MIDI port '{}': {}", port_name, e))?;
+
+    Ok(Some((conn, port_name)))
+}
+
+/// Sends the events of the MIDI output nodes to a MIDI port. The port is
+/// written from its own thread, so the audio thread does not wait for the
+/// MIDI driver. The events are sent at block granularity.
+struct MidiOutSender {
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+    port_name: String,
+}
+
+impl Drop for MidiOutSender {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Connects to the MIDI output port matching `args.midi_out_port`.
+fn open_midi_output(
+    args: &CpalArgs,
+    midi_output: Arc<MidiOutput>,
+) -> Result<Option<MidiOutSender>, anyhow::Error> {
+    let name = match &args.midi_out_port {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+
+    let midi_out = midir::MidiOutput::new("HexoSynth")?;
+    let ports = midi_out.ports();
+    let port = ports
+        .iter()
+        .find(|p| {
+            midi_out
+                .port_name(p)
+                .map(|pn| pn.to_lowercase().contains(&name.to_lowercase()))
+                .unwrap_or(false)
+        })
+        .ok_or_else(|| anyhow::anyhow!("No MIDI output port matches '{}'", name))?;
+
+    let port_name = midi_out.port_name(port)?;
+    let mut conn = midi_out
+        .connect(port, "hexosynth_midi_out")
+        .map_err(|e| anyhow::anyhow!("Can't connect to MIDI port '{}': {}", port_name, e))?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = std::thread::spawn({
+        let stop = stop.clone();
+        move || {
+            while !stop.load(Ordering::Relaxed) {
+                midi_output.drain(|ev| {
+                    let _ = conn.send(&event2raw_midi(&ev.event));
+                });
+
+                std::thread::sleep(std::time::Duration::from_millis(MIDI_OUT_INTERVAL_MS));
+            }
+        }
+    });
+
+    Ok(Some(MidiOutSender { stop, thread: Some(thread), port_name }))
+}
 
 fn main() {
-    let (matrix, node_exec) = init_hexosynth();
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("ERROR: {}\n\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    if args.list {
+        print_device_info(&collect_device_info());
+        return;
+    }
+
+    let (matrix, mut node_exec, session) = init_hexosynth_with_session();
+
+    // There is no host, the values of the ExtA to ExtF nodes are set
+    // in the GUI and saved with the session:
+    let ext_params = ExtParamStore::new();
+    node_exec.set_external_params(ext_params.clone());
+
     let matrix = Arc::new(Mutex::new(matrix));
 
-    start_backend(node_exec, move || {
-        open_hexosynth(None, matrix.clone());
+    // There is no host, the tempo and start/stop are controlled from the GUI:
+    let frontend = FrontendState::new(&matrix, Transport::new_internal(), DynNodeRegistry::new());
+
+    let res = start_backend(&args, node_exec, frontend.clone(), move |setup| {
+        let mut config = OpenHexoSynthConfig::new();
+        ext_params.attach(&mut config.param_set);
+        config.frontend = Some(frontend.clone());
+        // Enumerating all hosts can take a while, the list is only
+        // collected the first time the UI asks for it:
+        let device_info: Mutex<Option<AudioDeviceInfo>> = Mutex::new(None);
+        config.audio_device_info = Some(Arc::new(move || {
+            let mut info =
+                device_info.lock().unwrap().get_or_insert_with(collect_device_info).clone();
+            info.current = Some(setup.clone());
+            info
+        }));
+
+        open_hexosynth_with_session(matrix.clone(), session.clone(), config);
     });
+
+    if let Err(e) = res {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    }
 }
 
-pub fn run<T, F: FnMut()>(
+/// The opened audio input, the MIDI input queue and the description
+/// of the setup, that [start_backend] hands to [run].
+struct BackendIo {
+    input: Option<AudioInput>,
+    midi_cons: Consumer<(Instant, HxMidiEvent)>,
+    setup: AudioSetup,
+}
+
+fn run<T, F: FnMut(AudioSetup)>(
     device: &cpal::Device,
     config: &cpal::StreamConfig,
     mut node_exec: NodeExecutor,
+    io: BackendIo,
+    frontend: FrontendState,
     mut frontend_loop: F,
 ) -> Result<(), anyhow::Error>
-where {
+where
+    T: SizedSample + FromSample<f32>,
+{
     let sample_rate = config.sample_rate.0 as f32;
     let channels = config.channels as usize;
 
     node_exec.set_sample_rate(sample_rate);
 
-    let input_bufs = [[0.0; hexodsp::dsp::MAX_BLOCK_SIZE]; 2];
+    let BackendIo { input, mut midi_cons, setup } = io;
+    let FrontendState { transport, oversampling, poly, .. } = frontend;
+    let mut voice_alloc = VoiceAllocator::new(poly);
+
+    let (input_stream, mut input_cons, input_channels) = match input {
+        Some(input) => (Some(input.stream), Some(input.input_cons), input.channels),
+        None => (None, None, 0),
+    };
+
+    let mut input_bufs = [[0.0; hexodsp::dsp::MAX_BLOCK_SIZE]; 2];
     let mut outputbufs = [[0.0; hexodsp::dsp::MAX_BLOCK_SIZE]; 2];
 
+    let mut oversampler: Oversampler<2, 2> = Oversampler::new();
+
+    // The MIDI events that arrived during the previous callback period are
+    // played in this one, at the same distance to its start:
+    let mut last_callback = Instant::now();
+
     let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
     let stream = device.build_output_stream(
         config,
-        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-            let mut frames_left = data.len() / channels;
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            let nframes = data.len() / channels;
+            let mut frames_left = nframes;
+
+            let period_start = std::mem::replace(&mut last_callback, Instant::now());
+            let stamp2frame = |time: Instant| {
+                let secs = time.saturating_duration_since(period_start).as_secs_f64();
+                ((secs * sample_rate as f64) as usize).min(nframes.saturating_sub(1))
+            };
 
             let mut out_iter = data.chunks_mut(channels);
 
             node_exec.process_graph_updates();
 
+            // The DSP graph runs at the oversampled rate:
+            if oversampling.factor() != oversampler.factor() {
+                oversampler.set_factor(oversampling.factor());
+                node_exec.set_sample_rate(sample_rate * oversampler.factor() as f32);
+            }
+            let factor = oversampler.factor();
+
+            if let Some(input_cons) = &mut input_cons {
+                // Drop input that piled up because the input device
+                // runs a bit faster than the output device:
+                let max_len = 2 * frames_left.max(hexodsp::dsp::MAX_BLOCK_SIZE) * input_channels;
+                if input_cons.len() > max_len {
+                    input_cons.discard(input_cons.len() - max_len);
+                }
+            }
+
+            let mut ev_win = EventWindowing::new();
+            let mut offs = 0;
+
             while frames_left > 0 {
-                let cur_nframes = if frames_left >= hexodsp::dsp::MAX_BLOCK_SIZE {
-                    hexodsp::dsp::MAX_BLOCK_SIZE
-                } else {
-                    frames_left
-                };
+                let cur_nframes = frames_left.min(oversampler.max_frames());
+
+                node_exec.feed_midi_events_from(|| {
+                    if ev_win.feed_me() {
+                        let (timing, ev) = loop {
+                            if let Some(routed) = voice_alloc.pop() {
+                                break routed;
+                            }
+
+                            let (time, ev) = midi_cons.pop()?;
+                            voice_alloc.route(stamp2frame(time), ev);
+                        };
+                        ev_win.feed(midi_event2timed(timing * factor, ev));
+                    }
 
-                let input = &[&input_bufs[0][0..cur_nframes], &input_bufs[1][0..cur_nframes]];
+                    ev_win.next_event_in_range(offs * factor, cur_nframes * factor)
+                });
+
+                // Deinterleave the input, a mono input is fed into both channels:
+                let (in_l, in_r) = input_bufs.split_at_mut(1);
+                for (l, r) in
+                    in_l[0][0..cur_nframes].iter_mut().zip(in_r[0][0..cur_nframes].iter_mut())
+                {
+                    let mut frame = [0.0; 2];
+
+                    if let Some(input_cons) = &mut input_cons {
+                        if input_cons.len() >= input_channels {
+                            for ch in 0..input_channels {
+                                let s = input_cons.pop().unwrap_or(0.0);
+                                if let Some(f) = frame.get_mut(ch) {
+                                    *f = s;
+                                }
+                            }
+                            if input_channels == 1 {
+                                frame[1] = frame[0];
+                            }
+                        }
+                    }
+
+                    *l = frame[0];
+                    *r = frame[1];
+                }
+
+                let input = [&input_bufs[0][0..cur_nframes], &input_bufs[1][0..cur_nframes]];
 
                 let split = outputbufs.split_at_mut(1);
 
                 let mut output =
                     [&mut ((split.0[0])[0..cur_nframes]), &mut ((split.1[0])[0..cur_nframes])];
 
-                let mut context = Context { nframes: cur_nframes, output: &mut output[..], input };
-
-                context.output[0].fill(0.0);
-                context.output[1].fill(0.0);
+                output[0].fill(0.0);
+                output[1].fill(0.0);
 
-                node_exec.process(&mut context);
+                oversampler.process(&input, &mut output, |nframes, inp, out| {
+                    let mut context = Context { nframes, output: &mut out[..], input: &inp[..] };
+                    node_exec.process(&mut context);
+                });
+                transport.advance(cur_nframes, sample_rate as f64);
 
                 // This copy loop is a bit inefficient, it's likely you can
                 // pass the right array slices directly into node_exec.process()
@@ -75,16 +710,17 @@ where {
                     if let Some(frame) = out_iter.next() {
                         let mut ctx_chan = 0;
                         for sample in frame.iter_mut() {
-                            *sample = context.output[ctx_chan][i];
+                            *sample = T::from_sample(output[ctx_chan][i]);
 
                             ctx_chan += 1;
-                            if ctx_chan > context.output.len() {
-                                ctx_chan = context.output.len() - 1;
+                            if ctx_chan >= output.len() {
+                                ctx_chan = output.len() - 1;
                             }
                         }
                     }
                 }
 
+                offs += cur_nframes;
                 frames_left -= cur_nframes;
             }
         },
@@ -93,30 +729,117 @@ where {
     )?;
     stream.play()?;
 
-    frontend_loop();
+    if let Some(input_stream) = &input_stream {
+        input_stream.play()?;
+    }
+
+    frontend_loop(setup);
 
     Ok(())
 }
 
 // This function starts the CPAL backend and
 // runs the audio loop with the NodeExecutor.
-fn start_backend<F: FnMut()>(node_exec: NodeExecutor, frontend_loop: F) {
-    let host = cpal::default_host();
-    let device = host.default_output_device().expect("Finding useable audio device");
-    let config = device.default_output_config().expect("A workable output config");
-
-    match config.sample_format() {
-        cpal::SampleFormat::F32 => run::<f32, F>(&device, &config.into(), node_exec, frontend_loop),
-        cpal::SampleFormat::F64 => run::<f64, F>(&device, &config.into(), node_exec, frontend_loop),
-        cpal::SampleFormat::I8 => run::<i8, F>(&device, &config.into(), node_exec, frontend_loop),
-        cpal::SampleFormat::I16 => run::<i16, F>(&device, &config.into(), node_exec, frontend_loop),
-        cpal::SampleFormat::I32 => run::<i32, F>(&device, &config.into(), node_exec, frontend_loop),
-        cpal::SampleFormat::I64 => run::<i64, F>(&device, &config.into(), node_exec, frontend_loop),
-        cpal::SampleFormat::U8 => run::<u8, F>(&device, &config.into(), node_exec, frontend_loop),
-        cpal::SampleFormat::U16 => run::<u16, F>(&device, &config.into(), node_exec, frontend_loop),
-        cpal::SampleFormat::U32 => run::<u32, F>(&device, &config.into(), node_exec, frontend_loop),
-        cpal::SampleFormat::U64 => run::<u64, F>(&device, &config.into(), node_exec, frontend_loop),
-        _ => todo!(),
-    }
-    .expect("cpal works fine");
+fn start_backend<F: FnMut(AudioSetup)>(
+    args: &CpalArgs,
+    node_exec: NodeExecutor,
+    frontend: FrontendState,
+    frontend_loop: F,
+) -> Result<(), anyhow::Error> {
+    let host = find_host(&args.host)?;
+
+    let device = match &args.output_device {
+        Some(name) => find_device(host.output_devices()?, name)?,
+        None => host
+            .default_output_device()
+            .ok_or_else(|| anyhow::anyhow!("No default audio output device found"))?,
+    };
+
+    let (config, sample_format) = select_config(
+        device.supported_output_configs()?.collect(),
+        device.default_output_config()?,
+        args.sample_rate,
+        args.buffer_size,
+    )?;
+
+    let input = match open_input(&host, args, config.sample_rate.0, args.buffer_size) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("WARNING: Audio input disabled: {}", e);
+            None
+        }
+    };
+
+    let (midi_prod, midi_cons) = RingBuffer::new(MIDI_QUEUE_SIZE).split();
+    let midi_conn = match open_midi_input(args, midi_prod, &frontend) {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("WARNING: MIDI input disabled: {}", e);
+            None
+        }
+    };
+
+    let midi_out_sender = match open_midi_output(args, frontend.midi_output.clone()) {
+        Ok(sender) => sender,
+        Err(e) => {
+            eprintln!("WARNING: MIDI output disabled: {}", e);
+            None
+        }
+    };
+
+    let setup = AudioSetup {
+        host: host.id().name().to_string(),
+        output_device: device.name().unwrap_or_default(),
+        input_device: input.as_ref().map(|i| i.name.clone()),
+        sample_rate: config.sample_rate.0,
+        buffer_size: args.buffer_size,
+        sample_format: sample_format.to_string(),
+        midi_input: midi_conn.as_ref().map(|(_, name)| name.clone()),
+    };
+
+    println!(
+        "Audio: {} / {} ({}Hz, {}), input: {}, MIDI input: {}",
+        setup.host,
+        setup.output_device,
+        setup.sample_rate,
+        setup.sample_format,
+        setup.input_device.as_deref().unwrap_or("none"),
+        setup.midi_input.as_deref().unwrap_or("none")
+    );
+    if let Some(sender) = &midi_out_sender {
+        println!("MIDI output: {}", sender.port_name);
+    }
+
+    macro_rules! run_with {
+        ($t: ty) => {
+            run::<$t, F>(
+                &device,
+                &config,
+                node_exec,
+                BackendIo { input, midi_cons, setup },
+                frontend,
+                frontend_loop,
+            )
+        };
+    }
+
+    let res = match sample_format {
+        cpal::SampleFormat::F32 => run_with!(f32),
+        cpal::SampleFormat::F64 => run_with!(f64),
+        cpal::SampleFormat::I8 => run_with!(i8),
+        cpal::SampleFormat::I16 => run_with!(i16),
+        cpal::SampleFormat::I32 => run_with!(i32),
+        cpal::SampleFormat::I64 => run_with!(i64),
+        cpal::SampleFormat::U8 => run_with!(u8),
+        cpal::SampleFormat::U16 => run_with!(u16),
+        cpal::SampleFormat::U32 => run_with!(u32),
+        cpal::SampleFormat::U64 => run_with!(u64),
+        fmt => Err(anyhow::anyhow!("Unsupported output sample format: {}", fmt)),
+    };
+
+    // Keep the MIDI connections open until the frontend loop returns:
+    drop(midi_conn);
+    drop(midi_out_sender);
+
+    res
 }
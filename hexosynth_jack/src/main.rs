@@ -2,11 +2,166 @@
 // This file is a part of HexoSynth. Released under GPL-3.0-or-later.
 // See README.md and COPYING for details.
 
+use hexosynth::nodes::EventWindowing;
 use hexosynth::*;
 
+use serde::Deserialize;
+
 use std::sync::Arc;
 use std::sync::Mutex;
 
+const USAGE: &str = "\
+Usage: hexosynth_jack [options]
+
+Options:
+    -c, --config <file>          TOML config file, hexosynth_jack.toml is
+                                 loaded by default if it exists
+    -n, --name <name>            JACK client name (default: HexoSynth)
+    -i, --inputs <n>             Number of audio input ports, 0 to 2 (default: 2)
+    -o, --outputs <n>            Number of audio output ports, 1 or 2 (default: 2)
+        --no-midi                Don't register a MIDI input port
+        --no-midi-out            Don't register a MIDI output port
+        --no-auto-connect        Don't connect any ports on startup
+        --connect-out <ports>    Comma separated ports to connect the outputs to
+                                 (default: system:playback_1,system:playback_2)
+        --connect-in <ports>     Comma separated ports to connect the inputs from
+        --connect-midi <ports>   Comma separated MIDI ports to connect the
+                                 MIDI input from
+        --connect-midi-out <ports>
+                                 Comma separated MIDI ports to connect the
+                                 MIDI output to
+    -h, --help                   Show this help
+
+The config file uses the same names as the options:
+
+    name = \"HexoSynth\"
+    inputs = 2
+    outputs = 2
+    midi = true
+    midi_out = true
+    auto_connect = true
+    connect_out = [\"system:playback_1\", \"system:playback_2\"]
+    connect_in = [\"system:capture_1\", \"system:capture_2\"]
+    connect_midi = [\"a2j:Keystation [20] (capture): Keystation MIDI 1\"]
+    connect_midi_out = [\"a2j:Midi Through [14] (playback): Midi Through Port-0\"]
+
+The DSP graph has two input and two output channels. A single input port
+feeds both input channels, a single output port gets the mono sum.
+The MIDI output port sends the notes and CCs of the MIDI output nodes.
+";
+
+/// The number of input and output channels of the DSP graph.
+const NUM_CHANNELS: usize = 2;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct JackConfig {
+    name: String,
+    inputs: usize,
+    outputs: usize,
+    midi: bool,
+    midi_out: bool,
+    auto_connect: bool,
+    connect_out: Vec<String>,
+    connect_in: Vec<String>,
+    connect_midi: Vec<String>,
+    connect_midi_out: Vec<String>,
+}
+
+impl Default for JackConfig {
+    fn default() -> Self {
+        Self {
+            name: "HexoSynth".to_string(),
+            inputs: 2,
+            outputs: 2,
+            midi: true,
+            midi_out: true,
+            auto_connect: true,
+            connect_out: vec!["system:playback_1".to_string(), "system:playback_2".to_string()],
+            connect_in: vec![],
+            connect_midi: vec![],
+            connect_midi_out: vec![],
+        }
+    }
+}
+
+fn split_ports(ports: &str) -> Vec<String> {
+    ports.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+}
+
+fn load_config(filename: &str) -> Result<JackConfig, String> {
+    let data = std::fs::read_to_string(filename)
+        .map_err(|e| format!("Can't read '{}': {}", filename, e))?;
+    toml::from_str(&data).map_err(|e| format!("Can't parse '{}': {}", filename, e))
+}
+
+fn parse_args() -> Result<JackConfig, String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let config_file = args
+        .iter()
+        .position(|a| a == "-c" || a == "--config")
+        .map(|i| args.get(i + 1).cloned().ok_or_else(|| "Option '--config' requires an argument"))
+        .transpose()?;
+
+    let mut config = if let Some(config_file) = config_file {
+        load_config(&config_file)?
+    } else if std::path::Path::new("hexosynth_jack.toml").exists() {
+        load_config("hexosynth_jack.toml")?
+    } else {
+        JackConfig::default()
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().ok_or_else(|| format!("Option '{}' requires an argument", name))
+        };
+
+        match &arg[..] {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                std::process::exit(0);
+            }
+            "-c" | "--config" => {
+                value(&arg)?;
+            }
+            "-n" | "--name" => config.name = value(&arg)?,
+            "-i" | "--inputs" => {
+                let v = value(&arg)?;
+                config.inputs = v.parse().map_err(|_| format!("Bad input count: '{}'", v))?;
+            }
+            "-o" | "--outputs" => {
+                let v = value(&arg)?;
+                config.outputs = v.parse().map_err(|_| format!("Bad output count: '{}'", v))?;
+            }
+            "--no-midi" => config.midi = false,
+            "--no-midi-out" => config.midi_out = false,
+            "--no-auto-connect" => config.auto_connect = false,
+            "--connect-out" => config.connect_out = split_ports(&value(&arg)?),
+            "--connect-in" => config.connect_in = split_ports(&value(&arg)?),
+            "--connect-midi" => config.connect_midi = split_ports(&value(&arg)?),
+            "--connect-midi-out" => config.connect_midi_out = split_ports(&value(&arg)?),
+            _ => return Err(format!("Unknown option '{}'", arg)),
+        }
+    }
+
+    if config.inputs > NUM_CHANNELS {
+        return Err(format!(
+            "Bad input count: {}, at most {} are supported",
+            config.inputs, NUM_CHANNELS
+        ));
+    }
+    if config.outputs < 1 || config.outputs > NUM_CHANNELS {
+        return Err(format!(
+            "Bad output count: {}, 1 or {} are supported",
+            config.outputs, NUM_CHANNELS
+        ));
+    }
+
+    Ok(config)
+}
+
 struct Notifications {
     node_exec: Arc<Mutex<NodeExecutor>>,
 }
@@ -109,100 +264,283 @@ impl jack::NotificationHandler for Notifications {
     //    }
 }
 
-fn start_backend<F: FnMut()>(node_exec: NodeExecutor, mut f: F) {
-    let (client, _status) =
-        jack::Client::new("HexoSynth", jack::ClientOptions::NO_START_SERVER).unwrap();
+fn connect(client: &jack::Client, src: &str, dst: &str) {
+    match client.connect_ports_by_name(src, dst) {
+        Ok(_) => println!("JACK: connected {} to {}", src, dst),
+        Err(e) => println!("JACK: can't connect {} to {}: {:?}", src, dst, e),
+    }
+}
 
-    let in_a = client.register_port("hexosynth_in1", jack::AudioIn::default()).unwrap();
-    let in_b = client.register_port("hexosynth_in2", jack::AudioIn::default()).unwrap();
-    let mut out_a = client.register_port("hexosynth_out1", jack::AudioOut::default()).unwrap();
-    let mut out_b = client.register_port("hexosynth_out2", jack::AudioOut::default()).unwrap();
+/// Reads the JACK transport. Without a timebase master there is no bar
+/// and beat information, then the position is derived from the frame at
+/// the default tempo.
+fn jack_transport_info(client: &jack::Client) -> TransportInfo {
+    let defaults = TransportInfo::default();
 
-    let ne = Arc::new(Mutex::new(node_exec));
-    let ne2 = ne.clone();
+    let state = match client.transport().query() {
+        Ok(state) => state,
+        Err(_) => return defaults,
+    };
+
+    let playing = state.state == jack::TransportState::Rolling;
+
+    match state.pos.bbt() {
+        Some(bbt) => {
+            // JACK counts the beats in the beat type of the time signature:
+            let quarters_per_beat = 4.0 / (bbt.sig_denom as f64).max(1.0);
+            let beats = (bbt.bar.max(1) - 1) as f64 * bbt.sig_num as f64
+                + (bbt.beat.max(1) - 1) as f64
+                + bbt.tick as f64 / bbt.ticks_per_beat.max(1.0);
+
+            TransportInfo {
+                playing,
+                bpm: bbt.bpm * quarters_per_beat,
+                time_sig: (bbt.sig_num as u32, bbt.sig_denom as u32),
+                pos_beats: beats * quarters_per_beat,
+            }
+        }
+        None => {
+            let frame_rate = state.pos.frame_rate().unwrap_or(48000).max(1) as f64;
+            TransportInfo {
+                playing,
+                pos_beats: state.pos.frame() as f64 / frame_rate * defaults.bpm / 60.0,
+                ..defaults
+            }
+        }
+    }
+}
 
-    let oversample_simulation =
-        if let Some(arg) = std::env::args().nth(1) { arg == "4x" } else { false };
+fn start_backend<F: FnMut()>(
+    config: JackConfig,
+    node_exec: NodeExecutor,
+    frontend: FrontendState,
+    mut f: F,
+) {
+    let FrontendState {
+        transport,
+        midi_expression,
+        midi_learn,
+        midi_output,
+        poly,
+        oversampling,
+        ..
+    } = frontend;
 
-    let mut first = true;
-    let process_callback = move |client: &jack::Client, ps: &jack::ProcessScope| -> jack::Control {
-        let out_a_p = out_a.as_mut_slice(ps);
-        let out_b_p = out_b.as_mut_slice(ps);
-        let in_a_p = in_a.as_slice(ps);
-        let in_b_p = in_b.as_slice(ps);
+    let (client, _status) =
+        jack::Client::new(&config.name, jack::ClientOptions::NO_START_SERVER).unwrap();
 
-        if first {
+    let in_ports: Vec<jack::Port<jack::AudioIn>> = (0..config.inputs)
+        .map(|i| {
             client
-                .connect_ports_by_name("HexoSynth:hexosynth_out1", "system:playback_1")
-                .expect("jack connect ports works");
+                .register_port(&format!("hexosynth_in{}", i + 1), jack::AudioIn::default())
+                .unwrap()
+        })
+        .collect();
+    let mut out_ports: Vec<jack::Port<jack::AudioOut>> = (0..config.outputs)
+        .map(|i| {
             client
-                .connect_ports_by_name("HexoSynth:hexosynth_out2", "system:playback_2")
-                .expect("jack connect ports works");
-            first = false;
-        }
-
-        let nframes = out_a_p.len();
-
-        let mut node_exec = ne.lock().unwrap();
+                .register_port(&format!("hexosynth_out{}", i + 1), jack::AudioOut::default())
+                .unwrap()
+        })
+        .collect();
+    let midi_in = if config.midi {
+        Some(client.register_port("hexosynth_midi_in", jack::MidiIn::default()).unwrap())
+    } else {
+        None
+    };
+    let mut midi_out = if config.midi_out {
+        Some(client.register_port("hexosynth_midi_out", jack::MidiOut::default()).unwrap())
+    } else {
+        None
+    };
 
-        node_exec.process_graph_updates();
+    let in_names: Vec<String> = in_ports.iter().filter_map(|p| p.name().ok()).collect();
+    let out_names: Vec<String> = out_ports.iter().filter_map(|p| p.name().ok()).collect();
+    let midi_name = midi_in.as_ref().and_then(|p| p.name().ok());
+    let midi_out_name = midi_out.as_ref().and_then(|p| p.name().ok());
 
-        let mut frames_left = nframes;
-        let mut offs = 0;
+    let ne = Arc::new(Mutex::new(node_exec));
+    let ne2 = ne.clone();
 
-        while frames_left > 0 {
-            let cur_nframes = if frames_left >= hexodsp::dsp::MAX_BLOCK_SIZE {
-                hexodsp::dsp::MAX_BLOCK_SIZE
-            } else {
-                frames_left
-            };
+    let mut voice_alloc = VoiceAllocator::new(poly);
+    let mut oversampler: Oversampler<2, 2> = Oversampler::new();
+    let mut sample_rate = 0;
 
-            node_exec.feed_midi_events_from(|| None);
+    let mut input_bufs = [[0.0; hexodsp::dsp::MAX_BLOCK_SIZE]; 2];
+    let mut output_bufs = [[0.0; hexodsp::dsp::MAX_BLOCK_SIZE]; 2];
 
-            frames_left -= cur_nframes;
+    let process_callback =
+        move |jack_client: &jack::Client, ps: &jack::ProcessScope| -> jack::Control {
+            let nframes = ps.n_frames() as usize;
 
-            let output = &mut [
-                &mut out_a_p[offs..(offs + cur_nframes)],
-                &mut out_b_p[offs..(offs + cur_nframes)],
-            ];
-            let input = &[&in_a_p[offs..(offs + cur_nframes)], &in_b_p[offs..(offs + cur_nframes)]];
+            let mut node_exec = ne.lock().unwrap();
 
-            let mut context = Context { nframes: cur_nframes, output, input };
+            node_exec.process_graph_updates();
 
-            for i in 0..context.nframes {
-                context.output[0][i] = 0.0;
-                context.output[1][i] = 0.0;
+            // The DSP graph runs at the oversampled rate, the MIDI event timings
+            // are scaled up and those of the MIDI output scaled down:
+            let factor = oversampling.factor();
+            if factor != oversampler.factor() || jack_client.sample_rate() != sample_rate {
+                oversampler.set_factor(factor);
+                sample_rate = jack_client.sample_rate();
+                node_exec.set_sample_rate(sample_rate as f32 * oversampler.factor() as f32);
             }
+            let factor = oversampler.factor();
 
-            node_exec.process(&mut context);
+            let host_transport = jack_transport_info(jack_client);
+            let beats_per_sample = if host_transport.playing {
+                host_transport.beats_per_sample(jack_client.sample_rate() as f64)
+            } else {
+                0.0
+            };
 
-            if oversample_simulation {
-                node_exec.process(&mut context);
-                node_exec.process(&mut context);
-                node_exec.process(&mut context);
+            let mut ev_win = EventWindowing::new();
+            let mut midi_iter = midi_in.as_ref().map(|p| p.iter(ps));
+            let mut midi_writer = midi_out.as_mut().map(|p| p.writer(ps));
+
+            let mut frames_left = nframes;
+            let mut offs = 0;
+
+            while frames_left > 0 {
+                let cur_nframes = frames_left.min(oversampler.max_frames());
+
+                transport.update(&TransportInfo {
+                    pos_beats: host_transport.pos_beats + offs as f64 * beats_per_sample,
+                    ..host_transport
+                });
+
+                node_exec.feed_midi_events_from(|| {
+                    if ev_win.feed_me() {
+                        let (timing, ev) = loop {
+                            if let Some(routed) = voice_alloc.pop() {
+                                break routed;
+                            }
+
+                            let raw = midi_iter.as_mut()?.next()?;
+                            if let Some(ev) = raw_midi2expr(raw.bytes) {
+                                midi_expression.handle(ev);
+                            }
+                            if let Some(ev) = raw_midi2event(raw.bytes) {
+                                midi_expression.note_event(&ev);
+                                midi_learn.handle_midi_event(&ev);
+                                voice_alloc.route(raw.time as usize, ev);
+                            }
+                        };
+                        ev_win.feed(midi_event2timed(timing * factor, ev));
+                    }
+
+                    ev_win.next_event_in_range(offs * factor, cur_nframes * factor)
+                });
+
+                for (ch, buf) in input_bufs.iter_mut().enumerate() {
+                    if in_ports.is_empty() {
+                        buf[0..cur_nframes].fill(0.0);
+                    } else {
+                        let port = &in_ports[ch % in_ports.len()];
+                        buf[0..cur_nframes]
+                            .copy_from_slice(&port.as_slice(ps)[offs..(offs + cur_nframes)]);
+                    }
+                }
+
+                let input = [&input_bufs[0][0..cur_nframes], &input_bufs[1][0..cur_nframes]];
+
+                let split = output_bufs.split_at_mut(1);
+
+                let mut output =
+                    [&mut ((split.0[0])[0..cur_nframes]), &mut ((split.1[0])[0..cur_nframes])];
+
+                output[0].fill(0.0);
+                output[1].fill(0.0);
+
+                oversampler.process(&input, &mut output, |nframes, inp, out| {
+                    let mut context = Context { nframes, output: &mut out[..], input: &inp[..] };
+                    node_exec.process(&mut context);
+                });
+
+                // The events are delayed like the audio by the oversampling filters:
+                midi_output.drain_block(offs, factor, oversampler.latency(), nframes, |ev| {
+                    if let Some(writer) = midi_writer.as_mut() {
+                        let bytes = event2raw_midi(&ev.event);
+                        let time = ev.timing as u32;
+                        let _ = writer.write(&jack::RawMidi { time, bytes: &bytes[..] });
+                    }
+                });
+
+                let mono = out_ports.len() == 1;
+                for (i, port) in out_ports.iter_mut().enumerate() {
+                    let out = &mut port.as_mut_slice(ps)[offs..(offs + cur_nframes)];
+
+                    if mono {
+                        for (j, s) in out.iter_mut().enumerate() {
+                            *s = 0.5 * (output[0][j] + output[1][j]);
+                        }
+                    } else {
+                        out.copy_from_slice(&output[i][..]);
+                    }
+                }
+
+                offs += cur_nframes;
+                frames_left -= cur_nframes;
             }
 
-            offs += cur_nframes;
-        }
-
-        jack::Control::Continue
-    };
+            jack::Control::Continue
+        };
 
     let process = jack::ClosureProcessHandler::new(process_callback);
 
     // Activate the client, which starts the processing.
     let active_client = client.activate_async(Notifications { node_exec: ne2 }, process).unwrap();
 
+    if config.auto_connect {
+        let client = active_client.as_client();
+
+        for (port, target) in out_names.iter().zip(config.connect_out.iter()) {
+            connect(client, port, target);
+        }
+        for (port, source) in in_names.iter().zip(config.connect_in.iter()) {
+            connect(client, source, port);
+        }
+        if let Some(port) = &midi_name {
+            for source in config.connect_midi.iter() {
+                connect(client, source, port);
+            }
+        }
+        if let Some(port) = &midi_out_name {
+            for target in config.connect_midi_out.iter() {
+                connect(client, port, target);
+            }
+        }
+    }
+
     f();
 
     active_client.deactivate().unwrap();
 }
 
 fn main() {
-    let (matrix, node_exec) = init_hexosynth();
+    let config = match parse_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("ERROR: {}\n\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    let (matrix, mut node_exec, session) = init_hexosynth_with_session();
+
+    // There is no host, the values of the ExtA to ExtF nodes are set
+    // in the GUI and saved with the session:
+    let ext_params = ExtParamStore::new();
+    node_exec.set_external_params(ext_params.clone());
+
     let matrix = Arc::new(Mutex::new(matrix));
 
-    start_backend(node_exec, move || {
-        open_hexosynth(None, matrix.clone());
+    let frontend = FrontendState::new(&matrix, Transport::new_host(), DynNodeRegistry::new());
+
+    start_backend(config, node_exec, frontend.clone(), move || {
+        let mut config = OpenHexoSynthConfig::new();
+        ext_params.attach(&mut config.param_set);
+        config.frontend = Some(frontend.clone());
+        open_hexosynth_with_session(matrix.clone(), session.clone(), config);
     });
 }
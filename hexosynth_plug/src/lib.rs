@@ -1,47 +1,145 @@
 use nih_plug::prelude::*;
 
-use hexodsp::matrix_repr::MatrixRepr;
-use hexodsp::{DynNode1x1Context, DynamicNode1x1};
-use hexosynth::nodes::{EventWindowing, HxMidiEvent, HxTimedEvent};
+use hexosynth::nodes::{EventWindowing, HxMidiEvent};
 use hexosynth::*;
 use std::any::Any;
 //use hexodsp::*;
 
 use raw_window_handle::HasRawWindowHandle;
 
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 
 use nih_plug::params::persist::PersistentField;
 
+/// The patch state of the DAW project.
+///
+/// The state from the host is loaded into the matrix by the pool thread,
+/// see [start_pool_thread], so the host thread does not wait for the GUI
+/// or the MIDI learn. Until then it is also the state returned to the host.
 pub struct HexoSynthState {
     matrix: Arc<Mutex<Matrix>>,
+    /// Keeps the state of the project if it can't be loaded,
+    /// the GUI offers to repair or discard it.
+    daw_state: Arc<DawState>,
+    /// The state from the host that was not loaded yet.
+    pending: Mutex<Option<String>>,
+    pool_wakeup: Arc<PoolWakeup>,
+}
+
+impl HexoSynthState {
+    /// Loads the state queued by [PersistentField::set], if there is one.
+    fn load_pending(&self, matrix: &mut Matrix) {
+        let pending = self.pending.lock().expect("HexoSynthState is ok").take();
+
+        if let Some(state) = pending {
+            let _ = self.daw_state.load(matrix, state);
+        }
+    }
 }
 
 impl<'a> PersistentField<'a, String> for HexoSynthState {
     fn set(&self, new_value: String) {
-        let mut m = self.matrix.lock().expect("Matrix is ok");
-        if let Ok(repr) = MatrixRepr::deserialize(&new_value) {
-            let _ = m.from_repr(&repr);
-        }
+        *self.pending.lock().expect("HexoSynthState is ok") = Some(new_value);
+        self.pool_wakeup.patch_loaded();
     }
 
     fn map<F, R>(&self, f: F) -> R
     where
         F: Fn(&String) -> R,
     {
+        if let Some(state) = &*self.pending.lock().expect("HexoSynthState is ok") {
+            return f(state);
+        }
+
+        // The patch is serialized from the matrix itself, a copy would be
+        // outdated by every parameter change of the GUI, MIDI learn or host:
         let mut m = self.matrix.lock().expect("Matrix is ok");
-        let mut repr = m.to_repr();
-        let s = repr.serialize();
+        let s = self.daw_state.save(&mut m);
         f(&s)
     }
 }
 
+/// The oversampled input channels: The main input pair for the `Inp`
+/// node, then the sidechain pair for the `sidechain_in` prototype.
+const NUM_INPUT_CHANNELS: usize = 2 + SIDECHAIN_CHANNELS;
+/// The oversampled output channels: The main output pair of the `Out`
+/// node, then the aux outputs of the `aux_out` prototype.
+const NUM_OUTPUT_CHANNELS: usize = 2 + AUX_OUT_CHANNELS;
+
 pub struct HexoSynthPlug {
     params: Arc<HexoSynthPlugParams>,
     matrix: Arc<Mutex<Matrix>>,
     node_exec: Box<NodeExecutor>,
     proc_log: bool,
+    pool_wakeup: Arc<PoolWakeup>,
+    pool_thread: Option<JoinHandle<()>>,
+    stop_log_thread: Arc<AtomicBool>,
+    log_thread: Option<JoinHandle<()>>,
+    frontend: FrontendState,
+    aux_buses: Arc<AuxBuses>,
+    voice_alloc: VoiceAllocator,
+    oversampler: Oversampler<NUM_INPUT_CHANNELS, NUM_OUTPUT_CHANNELS>,
+    sample_rate: f32,
+}
+
+impl Drop for HexoSynthPlug {
+    fn drop(&mut self) {
+        self.pool_wakeup.stop.store(true, Ordering::Relaxed);
+        self.pool_wakeup.wake();
+
+        if let Some(thread) = self.pool_thread.take() {
+            let _ = thread.join();
+        }
+
+        self.stop_log_thread.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.log_thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Wakes up the pool thread, see [start_pool_thread].
+#[derive(Default)]
+struct PoolWakeup {
+    /// One bit for each pool parameter that changed.
+    changed: AtomicU64,
+    /// Set when the host passed a new patch state.
+    reload: AtomicBool,
+    stop: AtomicBool,
+    thread: Mutex<Option<std::thread::Thread>>,
+}
+
+impl PoolWakeup {
+    /// Called by the host parameters, also on the audio thread, so it does not block.
+    fn param_changed(&self, idx: usize) {
+        self.changed.fetch_or(1 << idx, Ordering::SeqCst);
+        self.wake();
+    }
+
+    fn patch_loaded(&self) {
+        self.reload.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    fn wake(&self) {
+        // A missed wake up is caught up with the next one, the flags stay set:
+        if let Ok(thread) = self.thread.try_lock() {
+            if let Some(thread) = thread.as_ref() {
+                thread.unpark();
+            }
+        }
+    }
+}
+
+/// One of the host parameters `P1` to `P32`, that are bound to
+/// matrix parameters by the `host_params` property of the patch.
+#[derive(Params)]
+struct PoolParam {
+    #[id = "p"]
+    pub value: FloatParam,
 }
 
 #[derive(Params)]
@@ -82,8 +180,12 @@ struct HexoSynthPlugParams {
     pub f2: FloatParam,
     #[id = "f3"]
     pub f3: FloatParam,
+    #[nested(array, group = "Pool")]
+    pub pool: Vec<PoolParam>,
     #[persist = "HexSta"]
     pub matrix: HexoSynthState,
+    /// The labels and formatters of the parameters, from the patch.
+    pub labels: HostParamLabels,
 }
 
 impl hexodsp::nodes::ExternalParams for HexoSynthPlugParams {
@@ -143,87 +245,68 @@ impl hexodsp::nodes::ExternalParams for HexoSynthPlugParams {
     }
 }
 
-use synfx_dsp::{Comb, DCBlockFilter, DelayBuffer};
-
-struct Proto1 {
-    buf: DelayBuffer<f32>,
-    comb: Comb,
-    dc: DCBlockFilter<f32>,
-}
-
-impl Proto1 {
-    pub fn new() -> Self {
-        Self { buf: DelayBuffer::new(), comb: Comb::new(), dc: DCBlockFilter::new() }
-    }
-}
-
-impl DynamicNode1x1 for Proto1 {
-    fn set_sample_rate(&mut self, srate: f32) {
-        self.buf.set_sample_rate(srate);
-        self.comb.set_sample_rate(srate);
-        self.dc.set_sample_rate(srate);
-    }
-
-    fn reset(&mut self) {}
-
-    fn process(&mut self, input: &[f32], output: &mut [f32], ctx: &DynNode1x1Context) {
-        let drywet = 0.5;
-
-        for (i, (inp, out)) in input.iter().zip(output.iter_mut()).enumerate() {
-            let del_time_ms = ctx.alpha_slice()[i].clamp(0.0, 1.0) * 2000.0;
-            let fb = ctx.beta_slice()[i];
-            let comb_ms = ctx.gamma_slice()[i].clamp(0.0, 1.0) * 10.0;
-            let g = ctx.delta_slice()[i].clamp(-1.0, 1.0);
-
-            let delayed = self.buf.tap_c(del_time_ms);
-            let mix = delayed + inp;
-            let comb_out = self.comb.next_feedforward(comb_ms, -1.0 * g, mix * fb);
-            let comb_out = self.dc.next(comb_out);
-            self.buf.feed(comb_out);
-            *out = delayed * drywet + inp * (1.0 - drywet);
-        }
-    }
-}
-
 impl Default for HexoSynthPlug {
     fn default() -> Self {
         let (matrix, mut node_exec) = init_hexosynth();
+        let matrix = Arc::new(Mutex::new(matrix));
 
-        matrix.set_dynamic_node1x1(0, Box::new(Proto1::new()));
+        // Patches without assignments get the delay that used to be hardcoded here:
+        let dyn_nodes = DynNodeRegistry::new();
+        dyn_nodes.set_default(0, "delay_comb");
+        let aux_buses = AuxBuses::new();
+        register_aux_bus_nodes(&dyn_nodes, aux_buses.clone());
+        let frontend = FrontendState::new(&matrix, hexosynth::Transport::new_host(), dyn_nodes);
 
         hexodsp::log::init_thread_logger("init");
 
-        std::thread::spawn(|| loop {
-            hexodsp::log::retrieve_log_messages(|name, s| {
-                use std::io::Write;
-                let mut file = std::fs::OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .append(true)
-                    .open("/tmp/hexosynth.log");
-                if let Ok(mut file) = file {
-                    let _ = writeln!(file, "{}/{}", name, s);
-                }
-            });
+        // Collects the log messages also while the GUI is closed,
+        // they are shown in the log console of the GUI:
+        let stop_log_thread = Arc::new(AtomicBool::new(false));
+        let log_thread = std::thread::spawn({
+            let stop = stop_log_thread.clone();
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    hexosynth::collect_hexodsp_log();
 
-            std::thread::sleep(std::time::Duration::from_millis(100));
+                    std::thread::park_timeout(std::time::Duration::from_millis(100));
+                }
+            }
         });
         use hexodsp::log::log;
         use std::io::Write;
 
         log(|w| write!(w, "INIT").unwrap());
 
-        let matrix = Arc::new(Mutex::new(matrix));
-
-        let params = Arc::new(HexoSynthPlugParams::new(matrix.clone()));
+        let pool_wakeup = Arc::new(PoolWakeup::default());
+        let params = Arc::new(HexoSynthPlugParams::new(
+            matrix.clone(),
+            HostParamLabels::new(),
+            pool_wakeup.clone(),
+        ));
 
         node_exec.set_external_params(params.clone());
 
+        let pool_thread = start_pool_thread(
+            matrix.clone(),
+            params.clone(),
+            frontend.clone(),
+            pool_wakeup.clone(),
+        );
+
         Self {
             matrix,
             node_exec: Box::new(node_exec),
             params,
             proc_log: false,
+            pool_wakeup,
+            pool_thread: Some(pool_thread),
+            stop_log_thread,
+            log_thread: Some(log_thread),
+            voice_alloc: VoiceAllocator::new(frontend.poly.clone()),
+            frontend,
+            aux_buses,
+            oversampler: Oversampler::new(),
+            sample_rate: 44100.0,
             //            editor_state: editor::default_state(),
 
             //            peak_meter_decay_weight: 1.0,
@@ -232,34 +315,62 @@ impl Default for HexoSynthPlug {
     }
 }
 
+/// Creates a host parameter, that is displayed with the
+/// label, unit and range from the `host_params` of the patch.
+///
+/// The label only shows up in the value text: nih_plug reports the
+/// name once when the plugin is loaded, and can't rename it later.
+fn new_host_param(name: &str, labels: &HostParamLabels) -> FloatParam {
+    let labels = labels.clone();
+    let fmt_name = name.to_string();
+
+    FloatParam::new(name, 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+        .with_smoother(SmoothingStyle::None)
+        .with_step_size(0.01)
+        .with_value_to_string(Arc::new(move |v| labels.format_value(&fmt_name, v)))
+}
+
 macro_rules! mkparam {
-    ($field: ident, $name: literal) => {
-        let $field = FloatParam::new($name, 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
-            .with_smoother(SmoothingStyle::None)
-            .with_step_size(0.01);
+    ($field: ident, $name: literal, $labels: ident) => {
+        let $field = new_host_param($name, &$labels);
     };
 }
 
 impl HexoSynthPlugParams {
-    fn new(matrix: Arc<Mutex<Matrix>>) -> Self {
-        mkparam! {a1, "A1"}
-        mkparam! {a2, "A2"}
-        mkparam! {a3, "A3"}
-        mkparam! {b1, "B1"}
-        mkparam! {b2, "B2"}
-        mkparam! {b3, "B3"}
-        mkparam! {c1, "C1"}
-        mkparam! {c2, "C2"}
-        mkparam! {c3, "C3"}
-        mkparam! {d1, "D1"}
-        mkparam! {d2, "D2"}
-        mkparam! {d3, "D3"}
-        mkparam! {e1, "E1"}
-        mkparam! {e2, "E2"}
-        mkparam! {e3, "E3"}
-        mkparam! {f1, "F1"}
-        mkparam! {f2, "F2"}
-        mkparam! {f3, "F3"}
+    fn new(
+        matrix: Arc<Mutex<Matrix>>,
+        labels: HostParamLabels,
+        pool_wakeup: Arc<PoolWakeup>,
+    ) -> Self {
+        mkparam! {a1, "A1", labels}
+        mkparam! {a2, "A2", labels}
+        mkparam! {a3, "A3", labels}
+        mkparam! {b1, "B1", labels}
+        mkparam! {b2, "B2", labels}
+        mkparam! {b3, "B3", labels}
+        mkparam! {c1, "C1", labels}
+        mkparam! {c2, "C2", labels}
+        mkparam! {c3, "C3", labels}
+        mkparam! {d1, "D1", labels}
+        mkparam! {d2, "D2", labels}
+        mkparam! {d3, "D3", labels}
+        mkparam! {e1, "E1", labels}
+        mkparam! {e2, "E2", labels}
+        mkparam! {e3, "E3", labels}
+        mkparam! {f1, "F1", labels}
+        mkparam! {f2, "F2", labels}
+        mkparam! {f3, "F3", labels}
+
+        let pool = (0..HOST_PARAM_POOL_SIZE)
+            .map(|idx| {
+                let pool_wakeup = pool_wakeup.clone();
+                PoolParam {
+                    value: new_host_param(&host_pool_param_name(idx), &labels)
+                        .with_callback(Arc::new(move |_| pool_wakeup.param_changed(idx))),
+                }
+            })
+            .collect();
+
         Self {
             a1,
             a2,
@@ -279,11 +390,80 @@ impl HexoSynthPlugParams {
             f1,
             f2,
             f3,
-            matrix: HexoSynthState { matrix },
+            pool,
+            matrix: HexoSynthState {
+                matrix,
+                daw_state: DawState::new(),
+                pending: Mutex::new(None),
+                pool_wakeup,
+            },
+            labels,
         }
     }
 }
 
+/// Starts the thread that sets the matrix parameters bound to the pool
+/// parameters `P1` to `P32`, and loads the patch state from the host
+/// with the labels, voices, prototypes and oversampling of it.
+///
+/// The pool parameters are applied at control rate, unlike `A1` to `F3`,
+/// which are read sample accurately by the `ExtA` to `ExtF` nodes. The
+/// thread sleeps until it is woken up by the [PoolWakeup], and locks the
+/// matrix only for the changes.
+fn start_pool_thread(
+    matrix: Arc<Mutex<Matrix>>,
+    params: Arc<HexoSynthPlugParams>,
+    frontend: FrontendState,
+    wakeup: Arc<PoolWakeup>,
+) -> JoinHandle<()> {
+    let thread = std::thread::spawn({
+        let wakeup = wakeup.clone();
+        move || {
+            while !wakeup.stop.load(Ordering::SeqCst) {
+                let reload = wakeup.reload.swap(false, Ordering::SeqCst);
+                let mut changed = wakeup.changed.swap(0, Ordering::SeqCst);
+
+                if !reload && changed == 0 {
+                    std::thread::park();
+                    continue;
+                }
+
+                let mut m = match matrix.lock() {
+                    Ok(m) => m,
+                    Err(_) => continue,
+                };
+
+                if reload {
+                    params.matrix.load_pending(&mut m);
+
+                    if params.labels.load_from_matrix(&m) {
+                        // The bindings changed, apply all current values:
+                        changed = u64::MAX;
+                    }
+                    // The GUI might be closed, so the voices, Rust1x1 prototypes
+                    // and oversampling of a new patch are picked up here too:
+                    frontend.poly.load_from_matrix(&m);
+                    frontend.dyn_nodes.load_from_matrix(&m);
+                    frontend.oversampling.load_from_matrix(&m);
+                }
+
+                for (idx, param_id, info) in params.labels.pool_targets() {
+                    if changed & (1 << idx) != 0 {
+                        let v = params.pool[idx].value.value();
+                        m.set_param(param_id, SAtom::param(param_id.norm(info.denorm(v))));
+                    }
+                }
+            }
+        }
+    });
+
+    if let Ok(mut t) = wakeup.thread.lock() {
+        *t = Some(thread.thread().clone());
+    }
+
+    thread
+}
+
 fn blip(s: &str) {
     use std::io::Write;
     let mut file = std::fs::OpenOptions::new()
@@ -296,24 +476,96 @@ fn blip(s: &str) {
     }
 }
 
-fn note_event2hxevent<S>(event: NoteEvent<S>) -> Option<HxTimedEvent> {
+/// Returns the timing and the event for the [VoiceAllocator].
+fn note_event2hxevent<S>(event: NoteEvent<S>) -> Option<(usize, HxMidiEvent)> {
     match event {
         NoteEvent::NoteOn { timing, channel, note, velocity, .. } => {
-            Some(HxTimedEvent::note_on(timing as usize, channel, note, velocity))
+            Some((timing as usize, HxMidiEvent::NoteOn { channel, note, vel: velocity }))
         }
         NoteEvent::NoteOff { timing, channel, note, velocity, .. } => {
-            Some(HxTimedEvent::note_off(timing as usize, channel, note))
+            Some((timing as usize, HxMidiEvent::NoteOff { channel, note }))
         }
         NoteEvent::MidiCC { timing, channel, cc, value, .. } => {
-            Some(HxTimedEvent::cc(timing as usize, channel, cc, value))
+            Some((timing as usize, HxMidiEvent::CC { channel, cc, value }))
         }
         NoteEvent::Choke { timing, voice_id, channel, note, .. } => {
-            Some(HxTimedEvent::note_off(timing as usize, channel, note))
+            Some((timing as usize, HxMidiEvent::NoteOff { channel, note }))
         }
         _ => None,
     }
 }
 
+/// Passes the events that HexoDSP does not handle to the [MidiExpression].
+/// They take effect for the whole block they were received in.
+fn note_event2expr<S>(event: &NoteEvent<S>, midi_expression: &MidiExpression) {
+    let ev = match *event {
+        NoteEvent::NoteOn { channel, note, .. } => {
+            midi_expression.note_on(channel, note);
+            return;
+        }
+        NoteEvent::MidiPitchBend { channel, value, .. } => {
+            // nih_plug delivers 0.0 to 1.0, with 0.5 for no bend:
+            MidiExprEvent::PitchBend { channel, value: (value * 2.0 - 1.0).clamp(-1.0, 1.0) }
+        }
+        NoteEvent::MidiChannelPressure { channel, pressure, .. } => {
+            MidiExprEvent::ChannelPressure { channel, value: pressure }
+        }
+        NoteEvent::PolyPressure { channel, note, pressure, .. } => {
+            MidiExprEvent::PolyPressure { channel, note, value: pressure }
+        }
+        NoteEvent::MidiProgramChange { channel, program, .. } => {
+            MidiExprEvent::ProgramChange { channel, program }
+        }
+        NoteEvent::PolyVolume { channel, note, gain, .. } => {
+            MidiExprEvent::NoteExpression { channel, note, expr: NoteExpr::Volume, value: gain }
+        }
+        NoteEvent::PolyPan { channel, note, pan, .. } => {
+            MidiExprEvent::NoteExpression { channel, note, expr: NoteExpr::Pan, value: pan }
+        }
+        NoteEvent::PolyTuning { channel, note, tuning, .. } => {
+            MidiExprEvent::NoteExpression { channel, note, expr: NoteExpr::Tuning, value: tuning }
+        }
+        NoteEvent::PolyVibrato { channel, note, vibrato, .. } => {
+            MidiExprEvent::NoteExpression { channel, note, expr: NoteExpr::Vibrato, value: vibrato }
+        }
+        NoteEvent::PolyExpression { channel, note, expression, .. } => {
+            MidiExprEvent::NoteExpression {
+                channel,
+                note,
+                expr: NoteExpr::Expression,
+                value: expression,
+            }
+        }
+        NoteEvent::PolyBrightness { channel, note, brightness, .. } => {
+            MidiExprEvent::NoteExpression {
+                channel,
+                note,
+                expr: NoteExpr::Brightness,
+                value: brightness,
+            }
+        }
+        _ => return,
+    };
+
+    midi_expression.handle(ev);
+}
+
+/// Converts an event of the MIDI output nodes, with the timing
+/// already converted by [MidiOutput::drain_block].
+fn midi_out2note_event<S>(ev: MidiOutEvent) -> NoteEvent<S> {
+    let timing = ev.timing as u32;
+
+    match ev.event {
+        HxMidiEvent::NoteOn { channel, note, vel } => {
+            NoteEvent::NoteOn { timing, voice_id: None, channel, note, velocity: vel }
+        }
+        HxMidiEvent::NoteOff { channel, note } => {
+            NoteEvent::NoteOff { timing, voice_id: None, channel, note, velocity: 0.0 }
+        }
+        HxMidiEvent::CC { channel, cc, value } => NoteEvent::MidiCC { timing, channel, cc, value },
+    }
+}
+
 impl Plugin for HexoSynthPlug {
     type BackgroundTask = ();
     type SysExMessage = ();
@@ -325,19 +577,52 @@ impl Plugin for HexoSynthPlug {
 
     const VERSION: &'static str = "0.0.2";
 
-    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[AudioIOLayout {
-        main_input_channels: NonZeroU32::new(2),
-        main_output_channels: NonZeroU32::new(2),
-
-        aux_input_ports: &[],
-        aux_output_ports: &[],
-
-        names: PortNames::const_default(),
-    }];
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(2),
+            main_output_channels: NonZeroU32::new(2),
+
+            aux_input_ports: &[],
+            aux_output_ports: &[],
+
+            names: PortNames::const_default(),
+        },
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(2),
+            main_output_channels: NonZeroU32::new(2),
+
+            aux_input_ports: &[new_nonzero_u32(2)],
+            aux_output_ports: &[],
+
+            names: PortNames {
+                layout: Some("Stereo with Sidechain"),
+                main_input: Some("Input"),
+                main_output: Some("Output"),
+                aux_inputs: &["Sidechain"],
+                aux_outputs: &[],
+            },
+        },
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(2),
+            main_output_channels: NonZeroU32::new(2),
+
+            aux_input_ports: &[new_nonzero_u32(2)],
+            aux_output_ports: &[new_nonzero_u32(2); AUX_OUTPUTS],
+
+            names: PortNames {
+                layout: Some("Multi-Out with Sidechain"),
+                main_input: Some("Input"),
+                main_output: Some("Output"),
+                aux_inputs: &["Sidechain"],
+                aux_outputs: &["Aux 1", "Aux 2", "Aux 3", "Aux 4"],
+            },
+        },
+    ];
 
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
     const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
+    const MIDI_OUTPUT: MidiConfig = MidiConfig::MidiCCs;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
@@ -353,6 +638,8 @@ impl Plugin for HexoSynthPlug {
             matrix: self.matrix.clone(),
             params: self.params.clone(),
             gen_counter: Arc::new(AtomicU64::new(0)),
+            frontend: self.frontend.clone(),
+            daw_state: self.params.matrix.daw_state.clone(),
         }))
     }
 
@@ -360,20 +647,23 @@ impl Plugin for HexoSynthPlug {
         &mut self,
         _bus_config: &AudioIOLayout,
         buffer_config: &BufferConfig,
-        _context: &mut impl InitContext<Self>,
+        context: &mut impl InitContext<Self>,
     ) -> bool {
         use hexodsp::log::log;
         use std::io::Write;
         hexodsp::log::init_thread_logger("proc_init");
         log(|w| write!(w, "PROC INIT").unwrap());
-        self.node_exec.set_sample_rate(buffer_config.sample_rate);
+        self.sample_rate = buffer_config.sample_rate;
+        self.oversampler.set_factor(self.frontend.oversampling.factor());
+        self.node_exec.set_sample_rate(self.sample_rate * self.oversampler.factor() as f32);
+        context.set_latency_samples(self.oversampler.latency() as u32);
         true
     }
 
     fn process(
         &mut self,
         buffer: &mut Buffer,
-        _aux: &mut AuxiliaryBuffers,
+        aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
         use hexodsp::log::log;
@@ -390,68 +680,135 @@ impl Plugin for HexoSynthPlug {
 
         self.node_exec.process_graph_updates();
 
+        // The DSP graph runs at the oversampled rate, the MIDI event timings
+        // are scaled up and those of the MIDI output scaled down:
+        if self.frontend.oversampling.factor() != self.oversampler.factor() {
+            self.oversampler.set_factor(self.frontend.oversampling.factor());
+            self.node_exec.set_sample_rate(self.sample_rate * self.oversampler.factor() as f32);
+            context.set_latency_samples(self.oversampler.latency() as u32);
+        }
+        let factor = self.oversampler.factor();
+
+        let host = context.transport();
+        let defaults = hexosynth::TransportInfo::default();
+        let transport = hexosynth::TransportInfo {
+            playing: host.playing,
+            bpm: host.tempo.unwrap_or(defaults.bpm),
+            time_sig: (
+                host.time_sig_numerator.map(|n| n.max(1) as u32).unwrap_or(defaults.time_sig.0),
+                host.time_sig_denominator.map(|d| d.max(1) as u32).unwrap_or(defaults.time_sig.1),
+            ),
+            pos_beats: host.pos_beats().unwrap_or(defaults.pos_beats),
+        };
+        let beats_per_sample = if transport.playing {
+            transport.beats_per_sample(host.sample_rate as f64)
+        } else {
+            0.0
+        };
+
         let mut offs = 0;
 
         let channel_buffers = buffer.as_slice();
         let mut frames_left = if channel_buffers.len() > 0 { channel_buffers[0].len() } else { 0 };
+        let nframes = frames_left;
 
-        let mut input_bufs = [[0.0; hexodsp::dsp::MAX_BLOCK_SIZE]; 2];
+        let mut input_bufs = [[0.0; hexodsp::dsp::MAX_BLOCK_SIZE]; NUM_INPUT_CHANNELS];
+        let mut output_bufs = [[0.0; hexodsp::dsp::MAX_BLOCK_SIZE]; NUM_OUTPUT_CHANNELS];
 
-        let mut cnt = 0;
         while frames_left > 0 {
-            let cur_nframes = if frames_left >= hexodsp::dsp::MAX_BLOCK_SIZE {
-                hexodsp::dsp::MAX_BLOCK_SIZE
-            } else {
-                frames_left
-            };
+            let cur_nframes = frames_left.min(self.oversampler.max_frames());
 
+            self.frontend.transport.update(&hexosynth::TransportInfo {
+                pos_beats: transport.pos_beats + offs as f64 * beats_per_sample,
+                ..transport
+            });
+
+            let midi_expression = &self.frontend.midi_expression;
+            let midi_learn = &self.frontend.midi_learn;
+            let voice_alloc = &mut self.voice_alloc;
             self.node_exec.feed_midi_events_from(|| {
                 if ev_win.feed_me() {
-                    let mut new_event = None;
+                    let mut new_event = voice_alloc.pop();
                     while new_event.is_none() {
                         if let Some(event) = context.next_event() {
-                            new_event = note_event2hxevent(event);
+                            note_event2expr(&event, midi_expression);
+                            if let Some((timing, event)) = note_event2hxevent(event) {
+                                midi_learn.handle_midi_event(&event);
+                                voice_alloc.route(timing, event);
+                                new_event = voice_alloc.pop();
+                            }
                         } else {
                             return None;
                         }
                     }
 
-                    if let Some(event) = new_event {
-                        ev_win.feed(event);
+                    if let Some((timing, event)) = new_event {
+                        ev_win.feed(midi_event2timed(timing * factor, event));
                     } else {
                         return None;
                     }
                 }
 
-                ev_win.next_event_in_range(offs, cur_nframes)
+                ev_win.next_event_in_range(offs * factor, cur_nframes * factor)
             });
 
-            input_bufs[0][0..cur_nframes]
-                .copy_from_slice(&channel_buffers[0][offs..(offs + cur_nframes)]);
-            input_bufs[1][0..cur_nframes]
-                .copy_from_slice(&channel_buffers[1][offs..(offs + cur_nframes)]);
-
-            let input = &[&input_bufs[0][0..cur_nframes], &input_bufs[1][0..cur_nframes]];
-
-            let split = channel_buffers.split_at_mut(1);
-
-            let mut output = [
-                &mut ((*split.0[0])[offs..(offs + cur_nframes)]),
-                &mut ((*split.1[0])[offs..(offs + cur_nframes)]),
-            ];
-
-            //            let output = &mut [&mut out_a_p[offs..(offs + cur_nframes)],
-            //                               &mut out_b_p[offs..(offs + cur_nframes)]];
-            //            let input =
-            //                &[&in_a_p[offs..(offs + cur_nframes)],
-            //                  &in_b_p[offs..(offs + cur_nframes)]];
-
-            let mut context = Context { nframes: cur_nframes, output: &mut output[..], input };
+            // Channels 0 and 1 are the main input, 2 and 3 the sidechain:
+            let range = offs..(offs + cur_nframes);
+            for (ch, buf) in input_bufs.iter_mut().enumerate() {
+                let src = if ch < 2 {
+                    channel_buffers.get(ch).map(|b| &b[range.clone()])
+                } else {
+                    aux.inputs.get(0).and_then(|sc| {
+                        sc.as_slice_immutable().get(ch - 2).map(|b| &b[range.clone()])
+                    })
+                };
+
+                match src {
+                    Some(src) => buf[0..cur_nframes].copy_from_slice(src),
+                    None => buf[0..cur_nframes].fill(0.0),
+                }
+            }
+
+            let mut inputs = input_bufs.iter();
+            let input: [&[f32]; NUM_INPUT_CHANNELS] =
+                std::array::from_fn(|_| inputs.next().map(|b| &b[0..cur_nframes]).unwrap());
+
+            let mut outputs = output_bufs.iter_mut();
+            let mut output: [&mut [f32]; NUM_OUTPUT_CHANNELS] =
+                std::array::from_fn(|_| outputs.next().map(|b| &mut b[0..cur_nframes]).unwrap());
+            for out in output.iter_mut() {
+                out.fill(0.0);
+            }
+
+            // The `Out` and `Inp` nodes only know the main pairs, the sidechain
+            // and the aux outputs go through the `AuxBuses` of the prototypes:
+            let node_exec = &mut self.node_exec;
+            let aux_buses = &self.aux_buses;
+            self.oversampler.process(&input, &mut output, |nframes, input, output| {
+                aux_buses.start_block(&input[2..]);
+
+                let (main_output, aux_output) = output.split_at_mut(2);
+                let mut dsp_context = Context { nframes, output: main_output, input: &input[0..2] };
+                node_exec.process(&mut dsp_context);
+
+                aux_buses.read_outputs(aux_output);
+            });
 
-            context.output[0].fill(0.0);
-            context.output[1].fill(0.0);
+            // Channels 0 and 1 are the main output, the aux outputs follow in pairs:
+            for (ch, buf) in channel_buffers.iter_mut().enumerate().take(2) {
+                buf[range.clone()].copy_from_slice(output[ch]);
+            }
+            for (i, aux_out) in aux.outputs.iter_mut().enumerate().take(AUX_OUTPUTS) {
+                for (ch, buf) in aux_out.as_slice().iter_mut().enumerate().take(2) {
+                    buf[range.clone()].copy_from_slice(output[2 + i * 2 + ch]);
+                }
+            }
 
-            self.node_exec.process(&mut context);
+            // The events are delayed like the audio by the oversampling filters:
+            let latency = self.oversampler.latency();
+            self.frontend.midi_output.drain_block(offs, factor, latency, nframes, |ev| {
+                context.send_event(midi_out2note_event(ev))
+            });
 
             offs += cur_nframes;
             frames_left -= cur_nframes;
@@ -466,6 +823,8 @@ struct HexoSynthEditor {
     matrix: Arc<Mutex<Matrix>>,
     params: Arc<HexoSynthPlugParams>,
     gen_counter: Arc<AtomicU64>,
+    frontend: FrontendState,
+    daw_state: Arc<DawState>,
 }
 
 struct UnsafeWindowHandle {
@@ -518,6 +877,9 @@ impl Editor for HexoSynthEditor {
         context: Arc<dyn GuiContext>,
     ) -> Box<dyn Any + Send> {
         let mut config = OpenHexoSynthConfig::new();
+        config.param_set.set_labels(self.params.labels.clone());
+        config.frontend = Some(self.frontend.clone());
+        config.daw_state = Some(self.daw_state.clone());
 
         setup_param!(self, config, context, 0, a, a1);
         setup_param!(self, config, context, 1, a, a2);
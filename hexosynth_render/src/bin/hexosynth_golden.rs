@@ -0,0 +1,144 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+//! Runs the golden audio regression checks of [hexosynth_render::golden]
+//! from the command line, and writes the golden files.
+
+use hexosynth_render::golden::*;
+
+use std::path::PathBuf;
+
+const USAGE: &str = "\
+Usage: hexosynth_golden [options] [<patch dir> [<golden dir>]]
+
+Renders all .hxy patches in <patch dir> (default: misc_patches) and compares
+the output against the golden files in <golden dir> (default: misc_patches/golden).
+
+Options:
+    -u, --update              Write new golden files instead of comparing
+    -v, --verbose             Show how old patches were migrated while loading
+    -t, --tolerance <db>      Allowed deviation in dB (default: 0.5)
+    -b, --blocks <n>          Number of blocks to render (default: 1024)
+    -h, --help                Show this help
+";
+
+struct GoldenArgs {
+    patch_dir: PathBuf,
+    golden_dir: PathBuf,
+    update: bool,
+    verbose: bool,
+    tolerance: f32,
+    blocks: usize,
+}
+
+fn parse_args() -> Result<GoldenArgs, String> {
+    let mut positional = vec![];
+    let mut update = false;
+    let mut verbose = false;
+    let mut tolerance = 0.5;
+    let mut blocks = 1024;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().ok_or_else(|| format!("Option '{}' requires an argument", name))
+        };
+
+        match &arg[..] {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                std::process::exit(0);
+            }
+            "-u" | "--update" => update = true,
+            "-v" | "--verbose" => verbose = true,
+            "-t" | "--tolerance" => {
+                let v = value(&arg)?;
+                tolerance = v.parse().map_err(|_| format!("Bad tolerance: '{}'", v))?;
+            }
+            "-b" | "--blocks" => {
+                let v = value(&arg)?;
+                blocks = v.parse().map_err(|_| format!("Bad block count: '{}'", v))?;
+            }
+            _ if arg.starts_with('-') => return Err(format!("Unknown option '{}'", arg)),
+            _ => positional.push(PathBuf::from(arg)),
+        }
+    }
+
+    if positional.len() > 2 {
+        return Err("Too many arguments".to_string());
+    }
+
+    let mut positional = positional.into_iter();
+    let patch_dir = positional.next().unwrap_or_else(|| PathBuf::from("misc_patches"));
+    let golden_dir = positional.next().unwrap_or_else(|| patch_dir.join("golden"));
+
+    Ok(GoldenArgs { patch_dir, golden_dir, update, verbose, tolerance, blocks })
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("ERROR: {}\n\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    let patches = match list_patches(&args.patch_dir) {
+        Ok(patches) => patches,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if args.update {
+        if let Err(e) = std::fs::create_dir_all(&args.golden_dir) {
+            eprintln!("ERROR: Can't create {}: {}", args.golden_dir.display(), e);
+            std::process::exit(1);
+        }
+    }
+
+    let opts = GoldenOptions {
+        golden_dir: args.golden_dir.clone(),
+        update: args.update,
+        tolerance: args.tolerance,
+        blocks: args.blocks,
+    };
+
+    let mut failed = 0;
+    let mut skipped = 0;
+    for path in patches.iter() {
+        let (problems, notes) = match check_patch(path, &opts) {
+            PatchCheck::Checked { problems, notes } => (problems, notes),
+            PatchCheck::NoGolden(golden_path) => {
+                println!("skip   {} (no {})", path.display(), golden_path.display());
+                skipped += 1;
+                continue;
+            }
+        };
+
+        if problems.is_empty() {
+            println!("ok     {}", path.display());
+        } else {
+            println!("FAILED {}", path.display());
+            for p in problems.iter() {
+                println!("       - {}", p);
+            }
+            failed += 1;
+        }
+
+        if args.verbose {
+            for n in notes.iter() {
+                println!("       * {}", n);
+            }
+        }
+    }
+
+    println!("{} patches, {} failed, {} without golden file", patches.len(), failed, skipped);
+
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
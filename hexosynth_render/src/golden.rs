@@ -0,0 +1,221 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+//! Golden audio regression checks for the patches in `misc_patches/`.
+//!
+//! Every patch is loaded headlessly and rendered for a fixed number
+//! of blocks. The RMS, peak and a coarse spectral fingerprint of the
+//! output are compared against the golden files. Nodes and parameters
+//! in the patch file that are unknown to the current DSP engine and
+//! are ignored by the patch migration are reported as failures too.
+
+use hexosynth::*;
+
+use serde::{Deserialize, Serialize};
+
+use std::path::{Path, PathBuf};
+
+const SAMPLE_RATE: f32 = 44100.0;
+const SPECTRUM_WINDOW: usize = 4096;
+const SPECTRUM_BANDS: usize = 24;
+const SPECTRUM_MIN_FREQ: f32 = 40.0;
+const SPECTRUM_MAX_FREQ: f32 = 16000.0;
+const DB_FLOOR: f32 = -120.0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChannelFingerprint {
+    rms_db: f32,
+    peak_db: f32,
+    bands_db: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GoldenFile {
+    sample_rate: f32,
+    frames: usize,
+    channels: Vec<ChannelFingerprint>,
+}
+
+fn amp2db(amp: f32) -> f32 {
+    if amp <= 0.0 {
+        DB_FLOOR
+    } else {
+        (20.0 * amp.log10()).max(DB_FLOOR)
+    }
+}
+
+/// Goertzel power of the frequency `freq` in the Hann windowed `buf`.
+fn goertzel_power(buf: &[f32], freq: f32) -> f32 {
+    let len = buf.len() as f32;
+    let w = 2.0 * std::f32::consts::PI * freq / SAMPLE_RATE;
+    let coeff = 2.0 * w.cos();
+
+    let (mut s1, mut s2) = (0.0, 0.0);
+    for (i, s) in buf.iter().enumerate() {
+        let win = 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / len).cos();
+        let s0 = s * win + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+    }
+
+    (s1 * s1 + s2 * s2 - coeff * s1 * s2) / (len * len)
+}
+
+fn fingerprint(buf: &[f32]) -> ChannelFingerprint {
+    let mut sum = 0.0;
+    let mut peak: f32 = 0.0;
+    for s in buf.iter() {
+        sum += (*s as f64) * (*s as f64);
+        peak = peak.max(s.abs());
+    }
+    let rms = if buf.is_empty() { 0.0 } else { (sum / buf.len() as f64).sqrt() as f32 };
+
+    let mut bands = vec![0.0; SPECTRUM_BANDS];
+    let mut windows = 0;
+    for chunk in buf.chunks_exact(SPECTRUM_WINDOW) {
+        for (i, band) in bands.iter_mut().enumerate() {
+            let x = i as f32 / (SPECTRUM_BANDS - 1) as f32;
+            let freq = SPECTRUM_MIN_FREQ * (SPECTRUM_MAX_FREQ / SPECTRUM_MIN_FREQ).powf(x);
+            *band += goertzel_power(chunk, freq);
+        }
+        windows += 1;
+    }
+
+    let bands_db = bands
+        .iter()
+        .map(|p| {
+            let p = if windows > 0 { p / windows as f32 } else { 0.0 };
+            amp2db(p.sqrt())
+        })
+        .collect();
+
+    ChannelFingerprint { rms_db: amp2db(rms), peak_db: amp2db(peak), bands_db }
+}
+
+pub fn compare(golden: &GoldenFile, now: &GoldenFile, tolerance: f32) -> Vec<String> {
+    let mut diffs = vec![];
+
+    if golden.frames != now.frames || golden.sample_rate != now.sample_rate {
+        diffs.push(format!(
+            "rendered {} frames at {}Hz, golden file has {} frames at {}Hz",
+            now.frames, now.sample_rate, golden.frames, golden.sample_rate
+        ));
+        return diffs;
+    }
+
+    for (ch, (g, n)) in golden.channels.iter().zip(now.channels.iter()).enumerate() {
+        let mut check = |what: String, g: f32, n: f32| {
+            if (g - n).abs() > tolerance {
+                diffs.push(format!("ch{} {}: {:.2}dB, expected {:.2}dB", ch + 1, what, n, g));
+            }
+        };
+
+        check("rms".to_string(), g.rms_db, n.rms_db);
+        check("peak".to_string(), g.peak_db, n.peak_db);
+
+        for (i, (gb, nb)) in g.bands_db.iter().zip(n.bands_db.iter()).enumerate() {
+            check(format!("band {}", i), *gb, *nb);
+        }
+    }
+
+    diffs
+}
+
+pub fn render_patch(path: &Path, blocks: usize) -> Result<(GoldenFile, PatchLoadReport), String> {
+    let (mut matrix, node_exec) = new_hexosynth_engine();
+
+    let report = load_patch_migrated_from_file(&mut matrix, &path.to_string_lossy())
+        .map_err(|e| format!("load error: {}", e))?;
+    let _ = matrix.sync();
+
+    let mut renderer = OfflineRenderer::new(matrix, node_exec, SAMPLE_RATE);
+    let frames = blocks * hexodsp::dsp::MAX_BLOCK_SIZE;
+    let (out_l, out_r) = renderer.render(frames);
+
+    let golden = GoldenFile {
+        sample_rate: SAMPLE_RATE,
+        frames,
+        channels: vec![fingerprint(&out_l), fingerprint(&out_r)],
+    };
+
+    Ok((golden, report))
+}
+
+/// How the patches are checked, see [check_patch].
+pub struct GoldenOptions {
+    pub golden_dir: PathBuf,
+    /// Write new golden files instead of comparing.
+    pub update: bool,
+    /// Allowed deviation in dB.
+    pub tolerance: f32,
+    /// Number of blocks to render.
+    pub blocks: usize,
+}
+
+/// The result of [check_patch].
+pub enum PatchCheck {
+    /// The found problems, empty if the patch passed, and the notes
+    /// about the migrated parts of the patch.
+    Checked { problems: Vec<String>, notes: Vec<String> },
+    /// There is no golden file for the patch yet, it was not rendered.
+    NoGolden(PathBuf),
+}
+
+pub fn golden_path(path: &Path, golden_dir: &Path) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    golden_dir.join(format!("{}.json", stem))
+}
+
+/// Returns the `.hxy` patches in `patch_dir`, sorted by name.
+pub fn list_patches(patch_dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut patches: Vec<PathBuf> = std::fs::read_dir(patch_dir)
+        .map_err(|e| format!("Can't read {}: {}", patch_dir.display(), e))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().map(|e| e == "hxy").unwrap_or(false))
+        .collect();
+    patches.sort();
+    Ok(patches)
+}
+
+/// Renders the patch and compares it against its golden file,
+/// or writes the golden file if [GoldenOptions::update] is set.
+pub fn check_patch(path: &Path, opts: &GoldenOptions) -> PatchCheck {
+    let golden_path = golden_path(path, &opts.golden_dir);
+    if !opts.update && !golden_path.exists() {
+        return PatchCheck::NoGolden(golden_path);
+    }
+
+    let (now, report) = match render_patch(path, opts.blocks) {
+        Ok(res) => res,
+        Err(e) => return PatchCheck::Checked { problems: vec![e], notes: vec![] },
+    };
+
+    let notes = report.migrated;
+    let mut problems: Vec<String> =
+        report.ignored.into_iter().map(|msg| format!("ignored: {}", msg)).collect();
+
+    if opts.update {
+        let res = serde_json::to_string_pretty(&now)
+            .map_err(|e| e.to_string())
+            .and_then(|s| std::fs::write(&golden_path, s).map_err(|e| e.to_string()));
+        if let Err(e) = res {
+            problems.push(format!("can't write {}: {}", golden_path.display(), e));
+        }
+        return PatchCheck::Checked { problems, notes };
+    }
+
+    let golden: GoldenFile = match std::fs::read_to_string(&golden_path)
+        .map_err(|e| e.to_string())
+        .and_then(|s| serde_json::from_str(&s).map_err(|e| e.to_string()))
+    {
+        Ok(golden) => golden,
+        Err(e) => {
+            problems.push(format!("can't read {}: {}", golden_path.display(), e));
+            return PatchCheck::Checked { problems, notes };
+        }
+    };
+
+    problems.append(&mut compare(&golden, &now, opts.tolerance));
+    PatchCheck::Checked { problems, notes }
+}
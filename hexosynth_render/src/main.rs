@@ -0,0 +1,255 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexosynth::*;
+
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+const USAGE: &str = "\
+Usage: hexosynth_render [options] <patch.hxy> <output.wav>
+
+Renders a HexoSynth patch offline into a stereo 32 bit float WAV file.
+
+Options:
+    -d, --duration <seconds>      Length of the rendered audio (default: 10)
+    -r, --sample-rate <hz>        Sample rate (default: 44100)
+    -m, --midi <file.mid>         Standard MIDI file to play into the patch
+    -a, --automation <file.txt>   Parameter automation file
+    -h, --help                    Show this help
+
+The automation file contains one parameter change per line:
+
+    <seconds> <node> <instance> <param> <value>
+
+For example:
+
+    # Sweep the frequency of the first sine oscillator
+    0.0  sin 0 freq 220
+    2.0  sin 0 freq 440
+    4.0  sin 0 freq 880
+
+The value is denormalized, like it is shown in the GUI. For setting
+parameters (like the oscillator type) the value is the setting index.
+";
+
+struct RenderArgs {
+    patch: String,
+    output: String,
+    duration: f64,
+    sample_rate: f32,
+    midi: Option<String>,
+    automation: Option<String>,
+}
+
+fn parse_args() -> Result<RenderArgs, String> {
+    let mut positional = vec![];
+    let mut duration = 10.0;
+    let mut sample_rate = 44100.0;
+    let mut midi = None;
+    let mut automation = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().ok_or_else(|| format!("Option '{}' requires an argument", name))
+        };
+
+        match &arg[..] {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                std::process::exit(0);
+            }
+            "-d" | "--duration" => {
+                let v = value(&arg)?;
+                duration = v.parse().map_err(|_| format!("Bad duration: '{}'", v))?;
+            }
+            "-r" | "--sample-rate" => {
+                let v = value(&arg)?;
+                sample_rate = v.parse().map_err(|_| format!("Bad sample rate: '{}'", v))?;
+            }
+            "-m" | "--midi" => midi = Some(value(&arg)?),
+            "-a" | "--automation" => automation = Some(value(&arg)?),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option '{}'", arg)),
+            _ => positional.push(arg),
+        }
+    }
+
+    if positional.len() != 2 {
+        return Err("Expected a patch file and an output file".to_string());
+    }
+
+    let output = positional.pop().unwrap();
+    let patch = positional.pop().unwrap();
+
+    Ok(RenderArgs { patch, output, duration, sample_rate, midi, automation })
+}
+
+fn load_midi_file(renderer: &mut OfflineRenderer, filename: &str) -> Result<(), String> {
+    let data = std::fs::read(filename).map_err(|e| format!("Can't read '{}': {}", filename, e))?;
+    let smf = Smf::parse(&data).map_err(|e| format!("Can't parse '{}': {}", filename, e))?;
+
+    // Merge all tracks into one list of events with absolute ticks.
+    // The sort is stable, so events with the same tick stay in track order.
+    let mut events = vec![];
+    for track in smf.tracks.iter() {
+        let mut tick: u64 = 0;
+        for ev in track.iter() {
+            tick += ev.delta.as_int() as u64;
+            events.push((tick, ev.kind));
+        }
+    }
+    events.sort_by_key(|(tick, _)| *tick);
+
+    // Default tempo of 120 BPM, until the first tempo event:
+    let mut us_per_beat = 500000.0;
+    let mut secs = 0.0;
+    let mut last_tick = 0;
+
+    for (tick, kind) in events {
+        let ticks = (tick - last_tick) as f64;
+        last_tick = tick;
+
+        secs += match smf.header.timing {
+            Timing::Metrical(tpb) => ticks * us_per_beat / (tpb.as_int() as f64 * 1000000.0),
+            Timing::Timecode(fps, subframes) => ticks / (fps.as_f32() as f64 * subframes as f64),
+        };
+
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(t)) => {
+                us_per_beat = t.as_int() as f64;
+            }
+            TrackEventKind::Midi { channel, message } => {
+                let channel = channel.as_int();
+
+                let ev = match message {
+                    MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => HxMidiEvent::NoteOn {
+                        channel,
+                        note: key.as_int(),
+                        vel: vel.as_int() as f32 / 127.0,
+                    },
+                    MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                        HxMidiEvent::NoteOff { channel, note: key.as_int() }
+                    }
+                    MidiMessage::Controller { controller, value } => HxMidiEvent::CC {
+                        channel,
+                        cc: controller.as_int(),
+                        value: value.as_int() as f32 / 127.0,
+                    },
+                    _ => continue,
+                };
+
+                let frame = renderer.secs2frames(secs);
+                renderer.add_event(frame, RenderEvent::Midi(ev));
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+fn find_param(node_id: NodeId, name: &str) -> Option<(ParamId, bool)> {
+    if let Some(param_id) = node_id.inp_param(name) {
+        return Some((param_id, false));
+    }
+
+    let mut i = 0;
+    while let Some(param_id) = node_id.atom_param_by_idx(i) {
+        if param_id.name() == name {
+            return Some((param_id, true));
+        }
+        i += 1;
+    }
+
+    None
+}
+
+fn load_automation_file(renderer: &mut OfflineRenderer, filename: &str) -> Result<(), String> {
+    let data = std::fs::read_to_string(filename)
+        .map_err(|e| format!("Can't read '{}': {}", filename, e))?;
+
+    for (line_idx, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let err = |msg: &str| format!("{}:{}: {}: '{}'", filename, line_idx + 1, msg, line);
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(err("Expected '<seconds> <node> <instance> <param> <value>'"));
+        }
+
+        let secs: f64 = fields[0].parse().map_err(|_| err("Bad time"))?;
+        let instance: usize = fields[2].parse().map_err(|_| err("Bad node instance"))?;
+        let value: f32 = fields[4].parse().map_err(|_| err("Bad value"))?;
+
+        let node_id = NodeId::from_str(fields[1]);
+        if node_id == NodeId::Nop {
+            return Err(err("Unknown node"));
+        }
+        let node_id = node_id.to_instance(instance);
+
+        let (param_id, is_setting) =
+            find_param(node_id, fields[3]).ok_or_else(|| err("Unknown parameter"))?;
+
+        let at =
+            if is_setting { SAtom::setting(value as i64) } else { param_id.norm(value).into() };
+
+        let frame = renderer.secs2frames(secs);
+        renderer.add_event(frame, RenderEvent::Param(param_id, at));
+    }
+
+    Ok(())
+}
+
+fn render(args: RenderArgs) -> Result<(), String> {
+    let (mut matrix, node_exec) = new_hexosynth_engine();
+
+    let report = load_patch_migrated_from_file(&mut matrix, &args.patch)
+        .map_err(|e| format!("Can't load patch '{}': {}", args.patch, e))?;
+    for msg in report.migrated.iter().chain(report.ignored.iter()) {
+        eprintln!("{}: {}", args.patch, msg);
+    }
+    let _ = matrix.sync();
+
+    let mut renderer = OfflineRenderer::new(matrix, node_exec, args.sample_rate);
+
+    if let Some(midi) = &args.midi {
+        load_midi_file(&mut renderer, midi)?;
+    }
+
+    if let Some(automation) = &args.automation {
+        load_automation_file(&mut renderer, automation)?;
+    }
+
+    let nframes = renderer.secs2frames(args.duration);
+    let (out_l, out_r) = renderer.render(nframes);
+
+    write_wav(&args.output, args.sample_rate as u32, &out_l, &out_r)
+        .map_err(|e| format!("Can't write '{}': {}", args.output, e))?;
+
+    println!(
+        "Rendered {} frames ({:.2}s at {}Hz) of '{}' to '{}'",
+        nframes, args.duration, args.sample_rate, args.patch, args.output
+    );
+
+    Ok(())
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("ERROR: {}\n\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = render(args) {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    }
+}
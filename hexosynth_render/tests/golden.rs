@@ -0,0 +1,36 @@
+// Copyright (c) 2022 Weird Constructor <weirdconstructor@gmail.com>
+// This file is a part of HexoSynth. Released under GPL-3.0-or-later.
+// See README.md and COPYING for details.
+
+use hexosynth_render::golden::*;
+
+use std::path::PathBuf;
+
+/// Checks the patches in `misc_patches/` that have a golden file,
+/// the others are skipped until their golden file is written with
+/// `hexosynth_golden --update`.
+#[test]
+fn check_golden_patches() {
+    let patch_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../misc_patches");
+    let opts = GoldenOptions {
+        golden_dir: patch_dir.join("golden"),
+        update: false,
+        tolerance: 0.5,
+        blocks: 1024,
+    };
+
+    let mut failures = vec![];
+    for path in list_patches(&patch_dir).expect("misc_patches is readable") {
+        match check_patch(&path, &opts) {
+            PatchCheck::Checked { problems, .. } if !problems.is_empty() => {
+                failures.push(format!("{}:\n  - {}", path.display(), problems.join("\n  - ")));
+            }
+            PatchCheck::Checked { .. } => (),
+            PatchCheck::NoGolden(golden_path) => {
+                println!("skipped {}, no {}", path.display(), golden_path.display());
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "Golden check failed for:\n{}", failures.join("\n"));
+}